@@ -0,0 +1,64 @@
+# Applies any pending schema migrations to a sqlite database, following
+# the same rules as database::migrate in the server. The server migrates
+# the database itself on startup, but the schema must already be up to
+# date before `cargo sqlx prepare` can check the queries at compile time
+#
+# usage: migrate.sh <database file> [migrations directory]
+set -e
+
+DB=${1:-/persistent/stourney.db}
+MIGRATIONS=${2:-/stourney_platform/server/src/migrations}
+
+sqlite3 "$DB" "CREATE TABLE IF NOT EXISTS schema_version (
+  version INTEGER PRIMARY KEY,
+  name TEXT NOT NULL,
+  applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);"
+
+CURRENT=$(sqlite3 "$DB" "SELECT IFNULL(MAX(version), -1) FROM schema_version;")
+
+# What each schema version left behind in databases created before
+# versioned migrations existed, the same as LEGACY_MARKERS in the server
+LEGACY_MARKERS=(
+  "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'games';"
+  "SELECT COUNT(*) FROM pragma_table_info('games') WHERE name = 'finished_at';"
+)
+
+# Databases created before versioned migrations existed are at the last
+# version whose marker they have, adopted in a single transaction
+if [[ "$CURRENT" == "-1" ]]; then
+  CURRENT=0
+  for marker in "${LEGACY_MARKERS[@]}"; do
+    if [[ $(sqlite3 "$DB" "$marker") == "0" ]]; then
+      break
+    fi
+    CURRENT=$((CURRENT + 1))
+  done
+  {
+    echo "BEGIN;"
+    for file in $(ls "$MIGRATIONS"/*.sql | sort); do
+      name=$(basename "$file" .sql)
+      version=$((10#${name%%_*}))
+      if (( version <= CURRENT )); then
+        echo "INSERT INTO schema_version (version, name) VALUES ($version, '${name#*_}');"
+      fi
+    done
+    echo "COMMIT;"
+  } | sqlite3 -bail "$DB"
+fi
+
+LATEST=0
+for file in $(ls "$MIGRATIONS"/*.sql | sort); do
+  name=$(basename "$file" .sql)
+  version=$((10#${name%%_*}))
+  LATEST=$version
+  if (( version > CURRENT )); then
+    echo "Applying migration $name..."
+    { echo "BEGIN;"; cat "$file"; echo "INSERT INTO schema_version (version, name) VALUES ($version, '${name#*_}');"; echo "COMMIT;"; } | sqlite3 -bail "$DB"
+  fi
+done
+
+if (( CURRENT > LATEST )); then
+  echo "Database schema is at version $CURRENT, newer than the latest known migration $LATEST"
+  exit 1
+fi
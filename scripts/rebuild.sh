@@ -10,8 +10,8 @@ do
       git pull
       cd /stourney_platform
 
-      # Alter database if necessary
-      sqlite3 /persistent/stourney.db < /stourney_platform/server/src/schema.sql
+      # Apply any pending schema migrations
+      bash /stourney_platform/scripts/migrate.sh /persistent/stourney.db
       cd /stourney_platform/server
       cargo sqlx prepare 
 
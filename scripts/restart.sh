@@ -1,5 +1,10 @@
-# TODO: send shutdown signal to give the server time to save state
-pkill -f stourney_server
+# SIGTERM lets the server save the updates it has queued before exiting,
+# anything it does not get to is replayed from its spool on startup
+pkill -TERM -f stourney_server
+for _ in $(seq 1 30); do
+    pgrep -f stourney_server > /dev/null || break
+    sleep 1
+done
 pkill -f npm
 pkill -f node
 pkill -f vite
@@ -8,6 +13,9 @@ pkill -f vite
 cd /stourney_platform/web
 npm run preview -- --host 0.0.0.0 2> /persistent/logs & 
 
-# Send all logs to file for debugging
+# The server writes its own logs as JSON, rotated daily, and keeps
+# uploaded bots on the persistent volume
 cd /stourney_platform/server
-RUST_LOG=stourney_server=trace /stourney_platform/server/target/release/stourney_server 2> /persistent/logs &
+RUST_LOG=stourney_server=trace LOG_FORMAT=json LOG_DIR=/persistent/server_logs \
+    ARTIFACT_DIR=/persistent/artifacts \
+    /stourney_platform/server/target/release/stourney_server &
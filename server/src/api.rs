@@ -1,14 +1,72 @@
+#[cfg(test)]
+pub mod tests;
+
+use crate::artifacts::{self, ArtifactKind, ArtifactStore};
+use crate::auth;
+use crate::config;
 use crate::database;
+use crate::delta::{self, TurnDelta};
+use crate::lobby::{self, TableState};
+use crate::metrics;
+use crate::ratings;
+use crate::runner::{self, MatchState};
+use crate::slugs;
+use crate::tournaments::{self, schedule};
+use futures::TryStreamExt;
 use serde::{Deserialize, Serialize};
 use splendor_arena::models::GameUpdate;
 use splendor_arena::*;
 use sqlx::sqlite::SqlitePool;
-use warp::{Filter, Rejection, Reply};
+use std::convert::Infallible;
+use tracing::{error, info};
+use warp::http::StatusCode;
+use warp::multipart::{FormData, Part};
+use warp::{Buf, Filter, Rejection, Reply};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Success {
     #[serde(rename = "game_update")]
     GameUpdate(DetailedGameUpdate),
+    #[serde(rename = "replay")]
+    Replay(Vec<DetailedGameUpdate>),
+    #[serde(rename = "delta")]
+    Delta(TurnDelta),
+    #[serde(rename = "games")]
+    Games(GameList),
+    #[serde(rename = "api_key")]
+    ApiKey(NewApiKey),
+    #[serde(rename = "api_keys")]
+    ApiKeys(Vec<ApiKeyDescription>),
+    #[serde(rename = "revoked")]
+    Revoked { id: i64 },
+    #[serde(rename = "user")]
+    User(UserDescription),
+    #[serde(rename = "logged_out")]
+    LoggedOut,
+    #[serde(rename = "players")]
+    Players(Vec<SeatDescription>),
+    #[serde(rename = "leaderboard")]
+    Leaderboard(Vec<LeaderboardEntry>),
+    #[serde(rename = "rating_history")]
+    RatingHistory(Vec<RatingHistoryEntry>),
+    #[serde(rename = "slug")]
+    Slug(SlugDescription),
+    #[serde(rename = "bot_version")]
+    BotVersion(BotVersionDescription),
+    #[serde(rename = "bot_versions")]
+    BotVersions(Vec<BotVersionDescription>),
+    #[serde(rename = "match")]
+    Match(MatchDescription),
+    #[serde(rename = "tournament")]
+    Tournament(Box<TournamentDescription>),
+    #[serde(rename = "lobby")]
+    Lobby(LobbyDescription),
+    #[serde(rename = "table")]
+    Table(TableDescription),
+    #[serde(rename = "ticket")]
+    Ticket(TicketDescription),
+    #[serde(rename = "left")]
+    Left,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -26,6 +84,659 @@ pub struct UpdateRequest {
     pub turn_number: usize,
 }
 
+/// Optional turn range for a replay request, both ends inclusive,
+/// and whether to describe the action taken on each turn
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReplayQuery {
+    pub from: Option<usize>,
+    pub to: Option<usize>,
+    pub deltas: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum GameStatus {
+    #[serde(rename = "finished")]
+    Finished,
+    #[serde(rename = "in_progress")]
+    InProgress,
+}
+
+/// Filters and pagination for listing games, timestamps are compared
+/// as UTC "YYYY-MM-DD HH:MM:SS" strings so a bare date also works
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GameListQuery {
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub status: Option<GameStatus>,
+    pub players: Option<i64>,
+    pub min_turns: Option<i64>,
+    /// Only the games uploaded by the logged in user
+    pub mine: Option<bool>,
+    /// The nextCursor of the previous page
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GameSummary {
+    pub slug: Option<String>,
+    #[serde(rename = "numPlayers")]
+    pub num_players: Option<i64>,
+    #[serde(rename = "latestTurn")]
+    pub latest_turn: Option<i64>,
+    #[serde(rename = "lastUpdated")]
+    pub last_updated: String,
+    #[serde(rename = "finishedAt")]
+    pub finished_at: Option<String>,
+    /// Username of the account that uploaded the game
+    pub owner: Option<String>,
+    /// The seat that forfeited the game by breaking a limit of its match
+    #[serde(rename = "forfeitSeat")]
+    pub forfeit_seat: Option<i64>,
+    #[serde(rename = "forfeitReason")]
+    pub forfeit_reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GameList {
+    pub games: Vec<GameSummary>,
+    /// Pass as the cursor to get the next page, None on the last page
+    #[serde(rename = "nextCursor")]
+    pub next_cursor: Option<String>,
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiKeyRequest {
+    pub owner: String,
+    #[serde(default)]
+    pub label: String,
+}
+
+/// A freshly minted api key, the only time the key itself is shown
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NewApiKey {
+    pub id: i64,
+    pub key: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiKeyDescription {
+    pub id: i64,
+    pub owner: String,
+    pub label: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: String,
+    #[serde(rename = "lastUsedAt")]
+    pub last_used_at: Option<String>,
+    pub revoked: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserDescription {
+    pub id: i64,
+    pub username: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: String,
+}
+
+impl From<database::UserRecord> for UserDescription {
+    fn from(user: database::UserRecord) -> Self {
+        UserDescription {
+            id: user.user_id,
+            username: user.username,
+            created_at: user.created_at,
+        }
+    }
+}
+
+/// A label for an api key a user mints for themselves
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OwnApiKeyRequest {
+    #[serde(default)]
+    pub label: String,
+}
+
+/// The bot names sitting in each seat of a game, in seat order
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SeatsRequest {
+    pub players: Vec<String>,
+}
+
+/// A custom slug to rename a game to
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SlugRequest {
+    pub slug: String,
+}
+
+/// The current slug of a game, and its old slugs that still lead to it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SlugDescription {
+    pub slug: String,
+    pub aliases: Vec<String>,
+}
+
+/// The bot an arena registers for a seat
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SeatRegistration {
+    pub name: String,
+    pub version: Option<String>,
+    pub owner: Option<String>,
+}
+
+impl From<SeatRegistration> for database::SeatRecord {
+    fn from(seat: SeatRegistration) -> Self {
+        let trim = |value: Option<String>| {
+            value
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        database::SeatRecord {
+            name: seat.name.trim().to_string(),
+            version: trim(seat.version),
+            owner: trim(seat.owner),
+        }
+    }
+}
+
+/// Checks there are 2 to 4 seats, and that every name, version and
+/// owner is at most 64 characters, with a name in every seat
+pub fn validate_seats(seats: &[database::SeatRecord]) -> Result<(), String> {
+    if !(2..=4).contains(&seats.len()) {
+        return Err("a game has between 2 and 4 players".to_string());
+    }
+    for seat in seats {
+        if seat.name.is_empty() || seat.name.len() > 64 {
+            return Err("bot names must be between 1 and 64 characters".to_string());
+        }
+        let too_long = |value: &Option<String>| value.as_ref().is_some_and(|v| v.len() > 64);
+        if too_long(&seat.version) || too_long(&seat.owner) {
+            return Err("bot versions and owners must be at most 64 characters".to_string());
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SeatDescription {
+    pub seat: i64,
+    #[serde(rename = "botId")]
+    pub bot_id: i64,
+    pub name: String,
+    pub version: Option<String>,
+    pub owner: Option<String>,
+    pub rating: Option<f64>,
+}
+
+impl From<database::GamePlayer> for SeatDescription {
+    fn from(player: database::GamePlayer) -> Self {
+        SeatDescription {
+            seat: player.seat,
+            bot_id: player.bot_id,
+            name: player.name,
+            version: player.version,
+            owner: player.owner,
+            rating: player.rating,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LeaderboardQuery {
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LeaderboardEntry {
+    #[serde(rename = "botId")]
+    pub bot_id: i64,
+    pub name: String,
+    pub owner: Option<String>,
+    pub rating: f64,
+    #[serde(rename = "gamesPlayed")]
+    pub games_played: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RatingHistoryEntry {
+    pub slug: Option<String>,
+    #[serde(rename = "ratingBefore")]
+    pub rating_before: f64,
+    #[serde(rename = "ratingAfter")]
+    pub rating_after: f64,
+    #[serde(rename = "ratedAt")]
+    pub rated_at: String,
+}
+
+/// An uploaded build of a bot
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BotVersionDescription {
+    #[serde(rename = "botId")]
+    pub bot_id: i64,
+    pub name: String,
+    pub version: i64,
+    pub kind: String,
+    pub filename: String,
+    pub sha256: String,
+    pub size: i64,
+    #[serde(rename = "uploadedAt")]
+    pub uploaded_at: String,
+}
+
+impl From<database::BotVersion> for BotVersionDescription {
+    fn from(version: database::BotVersion) -> Self {
+        BotVersionDescription {
+            bot_id: version.bot_id,
+            name: version.name,
+            version: version.version,
+            kind: version.kind,
+            filename: version.filename,
+            sha256: version.sha256,
+            size: version.size,
+            uploaded_at: version.uploaded_at,
+        }
+    }
+}
+
+/// A bot version to seat in a hosted match
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MatchSeat {
+    #[serde(rename = "botId")]
+    pub bot_id: i64,
+    pub version: i64,
+}
+
+/// The bot versions to play a hosted match between, in seat order
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MatchRequest {
+    pub seats: Vec<MatchSeat>,
+}
+
+/// A hosted match, with its game once it is playing and its results once
+/// it is finished
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MatchDescription {
+    pub number: u64,
+    pub state: String,
+    pub slug: Option<String>,
+    pub reason: Option<String>,
+    pub places: Option<Vec<usize>>,
+    pub points: Option<Vec<u8>>,
+    pub faults: Option<Vec<usize>>,
+    /// The seat that broke a limit and forfeited, placing last
+    #[serde(rename = "forfeitSeat")]
+    pub forfeit_seat: Option<usize>,
+    #[serde(rename = "forfeitReason")]
+    pub forfeit_reason: Option<String>,
+}
+
+impl MatchDescription {
+    fn new(number: u64, state: MatchState) -> Self {
+        let described = |state: &str| MatchDescription {
+            number,
+            state: state.to_string(),
+            ..Default::default()
+        };
+        match state {
+            MatchState::Waiting => described("waiting"),
+            MatchState::Starting => described("starting"),
+            MatchState::Playing { slug, .. } => MatchDescription {
+                slug: Some(slug),
+                ..described("playing")
+            },
+            MatchState::Finished(result) => {
+                let (forfeit_seat, forfeit_reason) = result.forfeit.unzip();
+                MatchDescription {
+                    slug: Some(result.slug),
+                    places: Some(result.places),
+                    points: Some(result.points),
+                    faults: Some(result.faults),
+                    forfeit_seat,
+                    forfeit_reason,
+                    ..described("finished")
+                }
+            }
+            MatchState::Failed(failure) => MatchDescription {
+                reason: Some(failure.reason),
+                ..described("failed")
+            },
+        }
+    }
+}
+
+fn default_seats() -> usize {
+    2
+}
+
+fn default_games_per_pairing() -> usize {
+    1
+}
+
+fn default_rotation() -> String {
+    "rotate".to_string()
+}
+
+/// A tournament to play between bot versions, in seed order
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TournamentRequest {
+    pub name: String,
+    /// round_robin, swiss or knockout
+    pub format: String,
+    #[serde(default = "default_seats")]
+    pub seats: usize,
+    #[serde(rename = "gamesPerPairing", default = "default_games_per_pairing")]
+    pub games_per_pairing: usize,
+    /// fixed, rotate or all_orders
+    #[serde(default = "default_rotation")]
+    pub rotation: String,
+    /// How many rounds a swiss tournament plays, enough to leave a single
+    /// participant undefeated by default
+    pub rounds: Option<usize>,
+    pub participants: Vec<MatchSeat>,
+}
+
+/// A bot version playing in a tournament
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ParticipantDescription {
+    /// The seed of the participant, 0 for the top seed
+    pub participant: usize,
+    #[serde(rename = "botId")]
+    pub bot_id: i64,
+    pub name: String,
+    pub version: i64,
+}
+
+/// Where a participant stands in a tournament
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StandingDescription {
+    pub participant: usize,
+    /// 0 for first, participants that are level share a place
+    pub place: usize,
+    pub score: f64,
+    pub played: usize,
+    pub wins: usize,
+}
+
+/// A game of a tournament, with its live state while it is played
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TournamentGameDescription {
+    pub game: usize,
+    /// The participant in each seat
+    pub seating: Vec<usize>,
+    /// pending, waiting, starting, playing, finished or failed
+    pub state: String,
+    #[serde(rename = "matchNumber")]
+    pub match_number: Option<u64>,
+    pub slug: Option<String>,
+    /// The place of each seat once the game is finished
+    pub places: Option<Vec<usize>>,
+    pub reason: Option<String>,
+}
+
+/// Participants drawn to play each other in a round
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PairingDescription {
+    pub pairing: usize,
+    pub participants: Vec<usize>,
+    pub bye: bool,
+    /// The participant going through, in a knockout once every game of
+    /// the pairing is over
+    pub winner: Option<usize>,
+    pub games: Vec<TournamentGameDescription>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoundDescription {
+    pub round: usize,
+    pub pairings: Vec<PairingDescription>,
+}
+
+/// A tournament with its bracket and standings so far
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TournamentDescription {
+    pub id: i64,
+    pub name: String,
+    pub format: String,
+    pub seats: usize,
+    #[serde(rename = "gamesPerPairing")]
+    pub games_per_pairing: usize,
+    pub rotation: String,
+    pub rounds: usize,
+    /// running or finished
+    pub state: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: String,
+    #[serde(rename = "finishedAt")]
+    pub finished_at: Option<String>,
+    pub participants: Vec<ParticipantDescription>,
+    pub standings: Vec<StandingDescription>,
+    pub bracket: Vec<RoundDescription>,
+}
+
+impl From<tournaments::Tournament> for TournamentDescription {
+    fn from(tournament: tournaments::Tournament) -> Self {
+        let settings = &tournament.settings;
+        let standings =
+            schedule::standings(settings, tournament.participants.len(), &tournament.rounds)
+                .into_iter()
+                .map(|standing| StandingDescription {
+                    participant: standing.participant,
+                    place: standing.place,
+                    score: standing.score,
+                    played: standing.played,
+                    wins: standing.wins,
+                })
+                .collect();
+
+        let mut bracket: Vec<RoundDescription> = vec![];
+        for (pairing, played) in tournament
+            .pairings
+            .iter()
+            .zip(tournament.rounds.iter().flatten())
+        {
+            if pairing.round == bracket.len() {
+                bracket.push(RoundDescription {
+                    round: pairing.round,
+                    pairings: vec![],
+                });
+            }
+            let over = played.games.iter().all(schedule::Game::is_over);
+            let winner = (settings.format == schedule::Format::Knockout && over)
+                .then(|| schedule::pairing_winner(played))
+                .flatten();
+            let games = tournament
+                .games_of(pairing)
+                .map(TournamentGameDescription::from)
+                .collect();
+            bracket[pairing.round].pairings.push(PairingDescription {
+                pairing: pairing.pairing,
+                participants: pairing.participants.clone(),
+                bye: played.is_bye(),
+                winner,
+                games,
+            });
+        }
+
+        let record = tournament.record;
+        TournamentDescription {
+            id: record.tournament_id,
+            name: record.name,
+            format: record.format,
+            seats: settings.seats,
+            games_per_pairing: settings.games_per_pairing,
+            rotation: record.rotation,
+            rounds: settings.rounds,
+            state: match record.finished_at {
+                Some(_) => "finished".to_string(),
+                None => "running".to_string(),
+            },
+            created_at: record.created_at,
+            finished_at: record.finished_at,
+            participants: tournament
+                .participants
+                .into_iter()
+                .enumerate()
+                .map(|(participant, version)| ParticipantDescription {
+                    participant,
+                    bot_id: version.bot_id,
+                    name: version.name,
+                    version: version.version,
+                })
+                .collect(),
+            standings,
+            bracket,
+        }
+    }
+}
+
+impl From<&database::TournamentGame> for TournamentGameDescription {
+    fn from(game: &database::TournamentGame) -> Self {
+        let mut slug = game.slug.clone();
+        let state = if game.places.is_some() {
+            "finished"
+        } else if game.reason.is_some() {
+            "failed"
+        } else {
+            match game.match_number.and_then(runner::status) {
+                Some(MatchState::Waiting) => "waiting",
+                Some(MatchState::Starting) => "starting",
+                Some(MatchState::Playing { slug: playing, .. }) => {
+                    slug = Some(playing);
+                    "playing"
+                }
+                // Being recorded
+                Some(MatchState::Finished(_) | MatchState::Failed(_)) => "playing",
+                None => "pending",
+            }
+        };
+        TournamentGameDescription {
+            game: game.game,
+            seating: game.seating.clone(),
+            state: state.to_string(),
+            match_number: game.match_number,
+            slug,
+            places: game.places.clone(),
+            reason: game.reason.clone(),
+        }
+    }
+}
+
+/// A table to open or a game to queue for in the lobby, played as the
+/// bot of the account with the given name, the username by default
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LobbyRequest {
+    pub players: usize,
+    pub bot: Option<String>,
+}
+
+/// An open table to join in the lobby, as in LobbyRequest
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JoinRequest {
+    pub bot: Option<String>,
+}
+
+/// A player sitting at a table of the lobby
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LobbyPlayerDescription {
+    #[serde(rename = "botId")]
+    pub bot_id: i64,
+    pub name: String,
+    pub owner: String,
+    pub rating: f64,
+}
+
+impl From<lobby::Player> for LobbyPlayerDescription {
+    fn from(player: lobby::Player) -> Self {
+        LobbyPlayerDescription {
+            bot_id: player.bot_id,
+            name: player.name,
+            owner: player.owner,
+            rating: player.rating,
+        }
+    }
+}
+
+/// A table of the lobby, with its game once it is playing
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TableDescription {
+    pub id: u64,
+    pub players: usize,
+    pub seats: Vec<LobbyPlayerDescription>,
+    /// open, starting, playing, finished or abandoned
+    pub state: String,
+    /// Whether the matchmaking queue seated the players
+    pub matched: bool,
+    pub slug: Option<String>,
+    /// The place of each seat once the game is finished
+    pub places: Option<Vec<usize>>,
+    pub reason: Option<String>,
+}
+
+impl From<lobby::TableStatus> for TableDescription {
+    fn from(table: lobby::TableStatus) -> Self {
+        let (state, slug, places, reason) = match table.state {
+            TableState::Open => ("open", None, None, None),
+            TableState::Starting => ("starting", None, None, None),
+            TableState::Playing { slug, .. } => ("playing", Some(slug), None, None),
+            TableState::Finished { slug, places } => ("finished", Some(slug), Some(places), None),
+            TableState::Abandoned(reason) => ("abandoned", None, None, Some(reason)),
+        };
+        TableDescription {
+            id: table.id,
+            players: table.players,
+            seats: table.seats.into_iter().map(Into::into).collect(),
+            state: state.to_string(),
+            matched: table.matched,
+            slug,
+            places,
+            reason,
+        }
+    }
+}
+
+/// How many players wait in the matchmaking queue for a game
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueuedDescription {
+    pub players: usize,
+    pub waiting: usize,
+}
+
+/// The tables of the lobby that are open or playing
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LobbyDescription {
+    pub tables: Vec<TableDescription>,
+    pub queued: Vec<QueuedDescription>,
+}
+
+/// A seat taken in the lobby, played on the websocket at url
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TicketDescription {
+    pub ticket: String,
+    pub url: String,
+    /// None while the ticket waits in the matchmaking queue
+    pub table: Option<u64>,
+    pub seat: Option<usize>,
+}
+
+impl From<lobby::Entered> for TicketDescription {
+    fn from(entered: lobby::Entered) -> Self {
+        TicketDescription {
+            url: format!("/api/lobby/play/{}", entered.ticket),
+            ticket: entered.ticket,
+            table: entered.seating.map(|seating| seating.table),
+            seat: entered.seating.map(|seating| seating.seat),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum GemDescription {
     #[serde(rename = "onyx")]
@@ -95,7 +806,7 @@ impl BoardDescription {
             .map(|cards| {
                 cards
                     .iter()
-                    .map(|&id| all_cards[id as usize].clone())
+                    .map(|&id| all_cards[id as usize])
                     .map(|card| CardDescription {
                         id: card.id(),
                         cost: card.cost(),
@@ -110,7 +821,7 @@ impl BoardDescription {
             deck_counts: board.deck_counts,
             available_cards,
             nobles,
-            bank: board.gems.clone(),
+            bank: board.gems,
         }
     }
 }
@@ -123,6 +834,10 @@ pub struct PlayerDescription {
     pub num_reserved_cards: usize,
     #[serde(rename = "totalPoints")]
     pub total_points: usize,
+    /// The bot in this seat, None until the arena registers it
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub owner: Option<String>,
 }
 
 impl PlayerDescription {
@@ -132,6 +847,9 @@ impl PlayerDescription {
             developments: player.developments,
             num_reserved_cards: player.num_reserved,
             total_points: player.points as usize,
+            name: None,
+            version: None,
+            owner: None,
         }
     }
 }
@@ -144,6 +862,9 @@ pub struct DetailedGameUpdate {
     pub players: Vec<PlayerDescription>,
     #[serde(rename = "currentPlayer")]
     pub current_player: usize,
+    /// What happened since the previous turn, only sent when requested
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub delta: Option<TurnDelta>,
 }
 
 impl DetailedGameUpdate {
@@ -153,15 +874,28 @@ impl DetailedGameUpdate {
             .info
             .players
             .iter()
-            .map(|player| PlayerDescription::from_player(player))
+            .map(PlayerDescription::from_player)
             .collect();
 
         DetailedGameUpdate {
-            turn_number: game_update.update_num as usize,
+            turn_number: game_update.update_num,
             board,
             players,
-            current_player: game_update.info.current_player_num as usize,
+            current_player: game_update.info.current_player_num,
+            delta: None,
+        }
+    }
+
+    /// Names the bot in each seat
+    pub fn with_seats(mut self, seats: &[database::GamePlayer]) -> Self {
+        for seat in seats {
+            if let Some(player) = self.players.get_mut(seat.seat as usize) {
+                player.name = Some(seat.name.clone());
+                player.version = seat.version.clone();
+                player.owner = seat.owner.clone();
+            }
         }
+        self
     }
 }
 
@@ -181,18 +915,1588 @@ pub async fn load_game(
     let slug = update.uuid;
     let turn_id = update.turn_number;
 
+    let not_found = || {
+        metrics::LOAD_GAME_REQUESTS
+            .with_label_values(&["not_found"])
+            .inc();
+        warp::reject::not_found()
+    };
+    let timer = metrics::time_db("load_game");
     let uuid = database::load_uuid_from_slug(&db_pool, &slug)
         .await
-        .map_err(|_| warp::reject::not_found())?;
+        .map_err(|_| not_found())?;
     let game = database::load_game_update(&db_pool, uuid, turn_id as i32).await;
-    let game = game.map(|game| DetailedGameUpdate::from_game_update(&game));
+    let seats = database::load_game_players(&db_pool, uuid).await;
+    drop(timer);
+    let game = match game {
+        Ok(game) => game,
+        Err(error) => return Ok(corrupt_game(&slug, error)),
+    };
+    let game = game.map(|game| DetailedGameUpdate::from_game_update(&game).with_seats(&seats));
 
     if let Some(game) = game {
-        Ok(warp::reply::json(&Response::Success(Success::GameUpdate(
-            game,
-        ))))
+        metrics::LOAD_GAME_REQUESTS
+            .with_label_values(&["found"])
+            .inc();
+        Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Success(Success::GameUpdate(game))),
+            StatusCode::OK,
+        ))
+    } else {
+        Err(not_found())
+    }
+}
+
+/// GET /api/games/{slug}/replay?from=<turn>&to=<turn>
+/// load every turn of the requested game in order, optionally restricted
+/// to a range of turns, so a client can scrub through a whole game
+/// with a single request
+pub async fn load_replay(
+    slug: String,
+    query: ReplayQuery,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            let reason = format!("invalid turn range: from ({}) is after to ({})", from, to);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&Response::Failure { reason }),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    }
+
+    let uuid = database::load_uuid_from_canonical_slug(&db_pool, &slug)
+        .await
+        .ok_or_else(warp::reject::not_found)?;
+    let with_deltas = query.deltas.unwrap_or(false);
+    // The delta of the first requested turn needs the turn before it
+    let from = match (query.from, with_deltas) {
+        (Some(from), true) => Some(from.saturating_sub(1) as i32),
+        (from, _) => from.map(|from| from as i32),
+    };
+    let to = query.to.map(|to| to as i32);
+    let updates = match database::load_game_updates(&db_pool, uuid, from, to).await {
+        Ok(updates) => updates,
+        Err(error) => return Ok(corrupt_game(&slug, error)),
+    };
+    let seats = database::load_game_players(&db_pool, uuid).await;
+
+    let mut games = vec![];
+    for (index, update) in updates.iter().enumerate() {
+        if update.update_num < query.from.unwrap_or(0) {
+            continue;
+        }
+        let mut game = DetailedGameUpdate::from_game_update(update).with_seats(&seats);
+        if with_deltas && index > 0 {
+            let previous = &updates[index - 1];
+            if previous.update_num + 1 == update.update_num {
+                game.delta = delta::infer(previous, update);
+            }
+        }
+        games.push(game);
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::Replay(games))),
+        StatusCode::OK,
+    ))
+}
+
+/// GET /api/games/{slug}/turns/{turn}/delta
+/// describe the action that was taken to go from the previous turn
+/// of the requested game to the given turn
+pub async fn load_delta(
+    slug: String,
+    turn_id: usize,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    if turn_id == 0 {
+        let reason = "turn 0 is the initial state and has no previous turn".to_string();
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Failure { reason }),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let uuid = database::load_uuid_from_canonical_slug(&db_pool, &slug)
+        .await
+        .ok_or_else(warp::reject::not_found)?;
+    let previous = database::load_game_update(&db_pool, uuid, turn_id as i32 - 1).await;
+    let next = database::load_game_update(&db_pool, uuid, turn_id as i32).await;
+    let (previous, next) = match (previous, next) {
+        (Ok(previous), Ok(next)) => (previous, next),
+        (Err(error), _) | (_, Err(error)) => return Ok(corrupt_game(&slug, error)),
+    };
+
+    let (Some(previous), Some(next)) = (previous, next) else {
+        return Err(warp::reject::not_found());
+    };
+    match delta::infer(&previous, &next) {
+        Some(delta) => Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Success(Success::Delta(delta))),
+            StatusCode::OK,
+        )),
+        None => {
+            let reason = "the player of the previous turn has no seat in the game".to_string();
+            Ok(warp::reply::with_status(
+                warp::reply::json(&Response::Failure { reason }),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ))
+        }
+    }
+}
+
+/// GET /api/games?since=&until=&status=&players=&min_turns=&mine=&cursor=&limit=
+/// list the games matching the filters, most recently updated first,
+/// one page at a time, mine=true requires a session cookie
+pub async fn list_games(
+    query: GameListQuery,
+    session: Option<String>,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    let failure = |reason: String| {
+        Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Failure { reason }),
+            StatusCode::BAD_REQUEST,
+        ))
+    };
+
+    let owner_id = if query.mine == Some(true) {
+        match auth::session_user(&db_pool, session.as_deref()).await {
+            Some(user) => Some(user.user_id),
+            None => return Ok(not_logged_in()),
+        }
+    } else {
+        None
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return failure(format!("limit must be between 1 and {}", MAX_PAGE_SIZE));
+    }
+
+    // The cursor is the position of the last game of the previous page
+    let after = match query.cursor {
+        Some(cursor) => match cursor.rsplit_once('|') {
+            Some((last_updated, uuid)) => Some((last_updated.to_string(), uuid.to_string())),
+            None => return failure("malformed cursor".to_string()),
+        },
+        None => None,
+    };
+
+    let filter = database::GameFilter {
+        since: query.since,
+        until: query.until.map(end_of_day),
+        finished: query.status.map(|status| status == GameStatus::Finished),
+        num_players: query.players,
+        min_turns: query.min_turns,
+        owner_id,
+        after,
+        limit,
+    };
+    let games = database::list_games(&db_pool, &filter).await;
+
+    let next_cursor = match games.last() {
+        Some(last) if games.len() as i64 == limit => {
+            Some(format!("{}|{}", last.last_updated, last.uuid))
+        }
+        _ => None,
+    };
+    let games = games
+        .into_iter()
+        .map(|game| GameSummary {
+            slug: game.slug,
+            num_players: game.num_players,
+            latest_turn: game.latest_turn,
+            last_updated: game.last_updated,
+            finished_at: game.finished_at,
+            owner: game.owner,
+            forfeit_seat: game.forfeit_seat,
+            forfeit_reason: game.forfeit_reason,
+        })
+        .collect();
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::Games(GameList {
+            games,
+            next_cursor,
+        }))),
+        StatusCode::OK,
+    ))
+}
+
+/// A bare date, like 2024-05-01, as the last second of that day so
+/// that `until` includes the whole day. Timestamps are left as they are
+fn end_of_day(until: String) -> String {
+    let is_date = until.len() == 10
+        && until.bytes().enumerate().all(|(i, byte)| {
+            if i == 4 || i == 7 {
+                byte == b'-'
+            } else {
+                byte.is_ascii_digit()
+            }
+        });
+    if is_date {
+        format!("{} 23:59:59", until)
+    } else {
+        until
+    }
+}
+
+/// The reply when a saved turn of a game cannot be decoded
+fn corrupt_game(slug: &str, error: String) -> warp::reply::WithStatus<warp::reply::Json> {
+    error!("[!] Game {} cannot be loaded: {}", slug, error);
+    warp::reply::with_status(
+        warp::reply::json(&Response::Failure {
+            reason: "the game could not be loaded".to_string(),
+        }),
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+}
+
+fn not_logged_in() -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&Response::Failure {
+            reason: "not logged in".to_string(),
+        }),
+        StatusCode::UNAUTHORIZED,
+    )
+}
+
+fn unauthorized() -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&Response::Failure {
+            reason: "admin token required".to_string(),
+        }),
+        StatusCode::UNAUTHORIZED,
+    )
+}
+
+/// POST /api/admin/keys
+/// mint a new api key for an owner, requires the x-admin-token header
+pub async fn create_api_key(
+    admin_token: Option<String>,
+    request: ApiKeyRequest,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    if !auth::is_admin(admin_token.as_deref()) {
+        return Ok(unauthorized());
+    }
+    if request.owner.trim().is_empty() {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Failure {
+                reason: "an api key needs an owner".to_string(),
+            }),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let key = auth::generate_api_key();
+    let id = database::save_api_key(
+        &db_pool,
+        &auth::hash_api_key(&key),
+        request.owner.trim(),
+        None,
+        &request.label,
+    )
+    .await;
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::ApiKey(NewApiKey { id, key }))),
+        StatusCode::CREATED,
+    ))
+}
+
+/// GET /api/admin/keys
+/// list every api key without the keys themselves, requires the x-admin-token header
+pub async fn list_api_keys(
+    admin_token: Option<String>,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    if !auth::is_admin(admin_token.as_deref()) {
+        return Ok(unauthorized());
+    }
+
+    let keys = database::list_api_keys(&db_pool)
+        .await
+        .into_iter()
+        .map(|key| ApiKeyDescription {
+            id: key.key_id,
+            owner: key.owner,
+            label: key.label,
+            created_at: key.created_at,
+            last_used_at: key.last_used_at,
+            revoked: key.revoked,
+        })
+        .collect();
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::ApiKeys(keys))),
+        StatusCode::OK,
+    ))
+}
+
+/// DELETE /api/admin/keys/{id}
+/// revoke an api key so it can no longer authenticate, requires the x-admin-token header
+pub async fn revoke_api_key(
+    id: i64,
+    admin_token: Option<String>,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    if !auth::is_admin(admin_token.as_deref()) {
+        return Ok(unauthorized());
+    }
+
+    if database::revoke_api_key(&db_pool, id).await {
+        Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Success(Success::Revoked { id })),
+            StatusCode::OK,
+        ))
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
+
+/// POST /api/admin/matches
+/// play a match between uploaded bot versions on the server, requires the
+/// x-admin-token header
+pub async fn submit_match(
+    admin_token: Option<String>,
+    request: MatchRequest,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    if !auth::is_admin(admin_token.as_deref()) {
+        return Ok(unauthorized());
+    }
+    let failure = |reason: String, status| {
+        Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Failure { reason }),
+            status,
+        ))
+    };
+    if !(2..=4).contains(&request.seats.len()) {
+        return failure(
+            "a match has between 2 and 4 bots".to_string(),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+
+    let mut seats = vec![];
+    for seat in &request.seats {
+        match database::load_bot_version(&db_pool, seat.bot_id, seat.version).await {
+            Some(version) => seats.push(version),
+            None => {
+                return failure(
+                    format!("bot {} has no version {}", seat.bot_id, seat.version),
+                    StatusCode::NOT_FOUND,
+                )
+            }
+        }
+    }
+    let number = runner::submit(runner::MatchRequest {
+        seats,
+        owner_id: None,
+    });
+    info!("[+] Submitted match {}", number);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::Match(MatchDescription::new(
+            number,
+            MatchState::Waiting,
+        )))),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+/// GET /api/admin/matches/{number}
+/// the state of a hosted match, requires the x-admin-token header
+pub async fn match_status(
+    number: u64,
+    admin_token: Option<String>,
+) -> Result<impl Reply, Rejection> {
+    if !auth::is_admin(admin_token.as_deref()) {
+        return Ok(unauthorized());
+    }
+    match runner::status(number) {
+        Some(state) => Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Success(Success::Match(MatchDescription::new(
+                number, state,
+            )))),
+            StatusCode::OK,
+        )),
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Failure {
+                reason: format!("no match {}", number),
+            }),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+/// POST /api/admin/tournaments
+/// start a tournament between uploaded bot versions, played by the
+/// runner, requires the x-admin-token header
+pub async fn create_tournament(
+    admin_token: Option<String>,
+    request: TournamentRequest,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    if !auth::is_admin(admin_token.as_deref()) {
+        return Ok(unauthorized());
+    }
+    let failure = |reason: String, status| {
+        Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Failure { reason }),
+            status,
+        ))
+    };
+    let settings = match (
+        schedule::Format::parse(&request.format),
+        schedule::Rotation::parse(&request.rotation),
+    ) {
+        (Ok(format), Ok(rotation)) => schedule::Settings {
+            format,
+            seats: request.seats,
+            games_per_pairing: request.games_per_pairing,
+            rotation,
+            rounds: request
+                .rounds
+                .unwrap_or(schedule::default_rounds(request.participants.len())),
+        },
+        (Err(reason), _) | (_, Err(reason)) => return failure(reason, StatusCode::BAD_REQUEST),
+    };
+
+    let mut participants = vec![];
+    for seat in &request.participants {
+        match database::load_bot_version(&db_pool, seat.bot_id, seat.version).await {
+            Some(version) => participants.push(version),
+            None => {
+                return failure(
+                    format!("bot {} has no version {}", seat.bot_id, seat.version),
+                    StatusCode::NOT_FOUND,
+                )
+            }
+        }
+    }
+    let id = match tournaments::create(&db_pool, &request.name, settings, &participants).await {
+        Ok(id) => id,
+        Err(reason) => return failure(reason, StatusCode::BAD_REQUEST),
+    };
+    let tournament = tournaments::load(&db_pool, id)
+        .await
+        .expect("The tournament was just saved");
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::Tournament(Box::new(
+            tournament.into(),
+        )))),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+/// GET /api/tournaments/{id}
+/// the bracket, games and standings of a tournament
+pub async fn tournament_status(id: i64, db_pool: SqlitePool) -> Result<impl Reply, Rejection> {
+    match tournaments::load(&db_pool, id).await {
+        Some(tournament) => Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Success(Success::Tournament(Box::new(
+                tournament.into(),
+            )))),
+            StatusCode::OK,
+        )),
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Failure {
+                reason: format!("no tournament {}", id),
+            }),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+/// The player entering the lobby, logged in with a session or an api key
+/// of their account
+async fn lobby_player(
+    db_pool: &SqlitePool,
+    session: Option<String>,
+    api_key: Option<String>,
+    bot: Option<String>,
+) -> Result<lobby::Player, warp::reply::WithStatus<warp::reply::Json>> {
+    let (user_id, default_name) = match (session, api_key) {
+        (_, Some(key)) => match auth::verify_api_key(db_pool, &key).await {
+            Ok(auth::ApiKeyOwner {
+                user_id: Some(user_id),
+                owner,
+                ..
+            }) => (user_id, owner),
+            Ok(_) => {
+                return Err(lobby_failure(
+                    "this api key does not belong to an account".to_string(),
+                    StatusCode::UNAUTHORIZED,
+                ))
+            }
+            Err(reason) => return Err(lobby_failure(reason, StatusCode::UNAUTHORIZED)),
+        },
+        (session, None) => match auth::session_user(db_pool, session.as_deref()).await {
+            Some(user) => (user.user_id, user.username),
+            None => return Err(not_logged_in()),
+        },
+    };
+    let name = bot.unwrap_or(default_name);
+    if name.is_empty() || name.chars().count() > 64 {
+        return Err(lobby_failure(
+            "a bot name has between 1 and 64 characters".to_string(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    let bot = database::load_or_create_bot(db_pool, user_id, &name).await;
+    Ok(lobby::Player {
+        user_id,
+        bot_id: bot.bot_id,
+        name: bot.name,
+        owner: bot.owner,
+        rating: bot.rating.unwrap_or(ratings::INITIAL_RATING),
+    })
+}
+
+fn lobby_failure(reason: String, status: StatusCode) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(&Response::Failure { reason }), status)
+}
+
+fn lobby_refused(refused: lobby::Refused) -> warp::reply::WithStatus<warp::reply::Json> {
+    match refused {
+        lobby::Refused::NotFound(reason) => lobby_failure(reason, StatusCode::NOT_FOUND),
+        lobby::Refused::Conflict(reason) => lobby_failure(reason, StatusCode::CONFLICT),
+    }
+}
+
+fn ticket_reply(
+    entered: Result<lobby::Entered, lobby::Refused>,
+    status: StatusCode,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    match entered {
+        Ok(entered) => warp::reply::with_status(
+            warp::reply::json(&Response::Success(Success::Ticket(entered.into()))),
+            status,
+        ),
+        Err(refused) => lobby_refused(refused),
+    }
+}
+
+/// GET /api/lobby/tables
+/// the tables of the lobby that are open or playing, and how many players
+/// wait in the matchmaking queue
+pub async fn list_tables() -> Result<impl Reply, Rejection> {
+    let (tables, queued) = {
+        let mut lobby = lobby::shared().lock().unwrap();
+        lobby::expire_open_tables(&mut lobby);
+        (lobby.tables(), lobby.queued())
+    };
+    let description = LobbyDescription {
+        tables: tables.into_iter().map(Into::into).collect(),
+        queued: queued
+            .into_iter()
+            .zip(2..)
+            .map(|(waiting, players)| QueuedDescription { players, waiting })
+            .collect(),
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::Lobby(description))),
+        StatusCode::OK,
+    ))
+}
+
+/// POST /api/lobby/tables
+/// open a table for 2 to 4 players, seated at its first seat
+pub async fn open_table(
+    session: Option<String>,
+    api_key: Option<String>,
+    request: LobbyRequest,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    let player = match lobby_player(&db_pool, session, api_key, request.bot).await {
+        Ok(player) => player,
+        Err(reply) => return Ok(reply),
+    };
+    if !(2..=4).contains(&request.players) {
+        return Ok(lobby_failure(
+            "a table has between 2 and 4 players".to_string(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    Ok(ticket_reply(
+        lobby::open_table(request.players, player),
+        StatusCode::CREATED,
+    ))
+}
+
+/// POST /api/lobby/tables/{id}/join
+/// sit at the next free seat of an open table, its game starts once it
+/// is full
+pub async fn join_table(
+    id: u64,
+    session: Option<String>,
+    api_key: Option<String>,
+    request: JoinRequest,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    match lobby_player(&db_pool, session, api_key, request.bot).await {
+        Ok(player) => Ok(ticket_reply(lobby::join_table(id, player), StatusCode::OK)),
+        Err(reply) => Ok(reply),
+    }
+}
+
+/// GET /api/lobby/tables/{id}
+/// a table of the lobby, with its game once it is playing
+pub async fn table_status(id: u64) -> Result<impl Reply, Rejection> {
+    let table = lobby::shared().lock().unwrap().table(id);
+    match table {
+        Some(table) => Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Success(Success::Table(table.into()))),
+            StatusCode::OK,
+        )),
+        None => Ok(lobby_failure(
+            format!("no table {}", id),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+/// POST /api/lobby/queue
+/// wait for a game of 2 to 4 players against players of a similar rating
+pub async fn join_queue(
+    session: Option<String>,
+    api_key: Option<String>,
+    request: LobbyRequest,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    let player = match lobby_player(&db_pool, session, api_key, request.bot).await {
+        Ok(player) => player,
+        Err(reply) => return Ok(reply),
+    };
+    if !(2..=4).contains(&request.players) {
+        return Ok(lobby_failure(
+            "a game has between 2 and 4 players".to_string(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    Ok(ticket_reply(
+        lobby::enqueue(request.players, player),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+/// DELETE /api/lobby/tickets/{ticket}
+/// leave the matchmaking queue or a table whose game has not started
+pub async fn leave_lobby(ticket: String) -> Result<impl Reply, Rejection> {
+    match lobby::leave(&ticket) {
+        Ok(()) => Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Success(Success::Left)),
+            StatusCode::OK,
+        )),
+        Err(refused) => Ok(lobby_refused(refused)),
+    }
+}
+
+/// Replies with the user and a cookie holding a new session for them
+async fn reply_with_session(
+    db_pool: &SqlitePool,
+    user: database::UserRecord,
+    status: StatusCode,
+) -> warp::reply::Response {
+    let token = auth::start_session(db_pool, user.user_id).await;
+    let reply = warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::User(user.into()))),
+        status,
+    );
+    warp::reply::with_header(reply, "set-cookie", auth::session_cookie(&token)).into_response()
+}
+
+/// POST /api/auth/register
+/// create an account and log in to it
+pub async fn register(
+    credentials: Credentials,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    let username = credentials.username.trim();
+    let valid = auth::validate_username(username)
+        .and_then(|_| auth::validate_password(&credentials.password));
+    if let Err(reason) = valid {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Failure { reason }),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response());
+    }
+
+    let password_hash = auth::hash_password(&credentials.password).await;
+    if database::save_user(&db_pool, username, &password_hash)
+        .await
+        .is_none()
+    {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Failure {
+                reason: "that username is taken".to_string(),
+            }),
+            StatusCode::CONFLICT,
+        )
+        .into_response());
+    }
+
+    let user = database::load_user_by_name(&db_pool, username)
+        .await
+        .expect("Failed to load the user just registered");
+    Ok(reply_with_session(&db_pool, user, StatusCode::CREATED).await)
+}
+
+/// POST /api/auth/login
+/// start a session for an existing account
+pub async fn login(credentials: Credentials, db_pool: SqlitePool) -> Result<impl Reply, Rejection> {
+    let user = database::load_user_by_name(&db_pool, credentials.username.trim()).await;
+    let password_hash = user.as_ref().map(|user| user.password_hash.as_str());
+    let verified = auth::verify_login(&credentials.password, password_hash).await;
+    match user {
+        Some(user) if verified => Ok(reply_with_session(&db_pool, user, StatusCode::OK).await),
+        _ => Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Failure {
+                reason: "wrong username or password".to_string(),
+            }),
+            StatusCode::UNAUTHORIZED,
+        )
+        .into_response()),
+    }
+}
+
+/// POST /api/auth/logout
+/// end the current session and clear its cookie
+pub async fn logout(session: Option<String>, db_pool: SqlitePool) -> Result<impl Reply, Rejection> {
+    if let Some(token) = session {
+        database::delete_session(&db_pool, &auth::hash_token(&token)).await;
+    }
+
+    let reply = warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::LoggedOut)),
+        StatusCode::OK,
+    );
+    Ok(warp::reply::with_header(
+        reply,
+        "set-cookie",
+        auth::expired_session_cookie(),
+    ))
+}
+
+/// GET /api/auth/me
+/// the user logged in with the session cookie
+pub async fn current_user(
+    session: Option<String>,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    match auth::session_user(&db_pool, session.as_deref()).await {
+        Some(user) => Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Success(Success::User(user.into()))),
+            StatusCode::OK,
+        )),
+        None => Ok(not_logged_in()),
+    }
+}
+
+/// POST /api/auth/keys
+/// mint an api key for the logged in user, games uploaded with it are
+/// attributed to their account
+pub async fn create_own_api_key(
+    session: Option<String>,
+    request: OwnApiKeyRequest,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    let user = match auth::session_user(&db_pool, session.as_deref()).await {
+        Some(user) => user,
+        None => return Ok(not_logged_in()),
+    };
+
+    let key = auth::generate_api_key();
+    let id = database::save_api_key(
+        &db_pool,
+        &auth::hash_api_key(&key),
+        &user.username,
+        Some(user.user_id),
+        &request.label,
+    )
+    .await;
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::ApiKey(NewApiKey { id, key }))),
+        StatusCode::CREATED,
+    ))
+}
+
+/// DELETE /api/auth/keys/{id}
+/// revoke one of the logged in user's own api keys
+pub async fn revoke_own_api_key(
+    id: i64,
+    session: Option<String>,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    let user = match auth::session_user(&db_pool, session.as_deref()).await {
+        Some(user) => user,
+        None => return Ok(not_logged_in()),
+    };
+
+    // Keys of other accounts are as good as missing
+    if database::revoke_user_api_key(&db_pool, id, user.user_id).await {
+        Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Success(Success::Revoked { id })),
+            StatusCode::OK,
+        ))
     } else {
         Err(warp::reject::not_found())
     }
 }
 
+/// PUT /api/games/{slug}/players
+/// record which bot sat in each seat of a game, only the account that
+/// uploaded the game can do this. Finished games are rated right away
+pub async fn set_game_players(
+    slug: String,
+    session: Option<String>,
+    request: SeatsRequest,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    let failure = |reason: &str, status| {
+        Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Failure {
+                reason: reason.to_string(),
+            }),
+            status,
+        ))
+    };
+
+    let user = match auth::session_user(&db_pool, session.as_deref()).await {
+        Some(user) => user,
+        None => return Ok(not_logged_in()),
+    };
+    let uuid = database::load_uuid_from_slug(&db_pool, &slug)
+        .await
+        .map_err(|_| warp::reject::not_found())?;
+    if database::load_game_owner(&db_pool, uuid).await != Some(user.user_id) {
+        return failure(
+            "only the uploader of a game can set its players",
+            StatusCode::FORBIDDEN,
+        );
+    }
+
+    let seats: Vec<database::SeatRecord> = request
+        .players
+        .into_iter()
+        .map(|name| {
+            database::SeatRecord::from(SeatRegistration {
+                name,
+                version: None,
+                owner: None,
+            })
+        })
+        .collect();
+    if let Err(reason) = validate_seats(&seats) {
+        return failure(&reason, StatusCode::BAD_REQUEST);
+    }
+
+    let players = database::save_game_players(&db_pool, uuid, Some(user.user_id), &seats).await;
+    let players = match ratings::rate_game(&db_pool, uuid).await {
+        Some(_) => database::load_game_players(&db_pool, uuid).await,
+        None => players,
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::Players(
+            players.into_iter().map(SeatDescription::from).collect(),
+        ))),
+        StatusCode::OK,
+    ))
+}
+
+/// GET /api/games/{slug}/slug
+/// the current slug of a game and its aliases
+pub async fn load_game_slug(slug: String, db_pool: SqlitePool) -> Result<impl Reply, Rejection> {
+    let uuid = database::load_uuid_from_canonical_slug(&db_pool, &slug)
+        .await
+        .ok_or_else(warp::reject::not_found)?;
+    let description = SlugDescription {
+        slug: database::load_slug(&db_pool, uuid)
+            .await
+            .ok_or_else(warp::reject::not_found)?,
+        aliases: database::load_slug_aliases(&db_pool, uuid).await,
+    };
+    Ok(warp::reply::json(&Response::Success(Success::Slug(
+        description,
+    ))))
+}
+
+/// PUT /api/games/{slug}/slug
+/// rename a game to a custom slug, only the account that uploaded the
+/// game can do this. The old slug is kept as an alias that redirects
+pub async fn set_game_slug(
+    slug: String,
+    session: Option<String>,
+    request: SlugRequest,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    let failure = |reason: String, status| {
+        Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Failure { reason }),
+            status,
+        ))
+    };
+
+    let user = match auth::session_user(&db_pool, session.as_deref()).await {
+        Some(user) => user,
+        None => return Ok(not_logged_in()),
+    };
+    let uuid = database::load_uuid_from_slug(&db_pool, &slug)
+        .await
+        .map_err(|_| warp::reject::not_found())?;
+    if database::load_game_owner(&db_pool, uuid).await != Some(user.user_id) {
+        return failure(
+            "only the uploader of a game can change its slug".to_string(),
+            StatusCode::FORBIDDEN,
+        );
+    }
+    let new_slug = match slugs::validate(&request.slug) {
+        Ok(new_slug) => new_slug,
+        Err(reason) => return failure(reason, StatusCode::BAD_REQUEST),
+    };
+    if let Err(reason) = database::save_custom_slug(&db_pool, uuid, &new_slug).await {
+        return failure(reason, StatusCode::CONFLICT);
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::Slug(SlugDescription {
+            slug: new_slug,
+            aliases: database::load_slug_aliases(&db_pool, uuid).await,
+        }))),
+        StatusCode::OK,
+    ))
+}
+
+/// GET /api/games/{alias}/...
+/// redirect a request for a game by one of its old slugs to the same path
+/// under its current slug. Only tried once the other routes did not find
+/// the slug, websocket upgrades are never redirected
+pub async fn redirect_alias(
+    slug: String,
+    tail: warp::path::Tail,
+    upgrade: Option<String>,
+    query: String,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    if upgrade.is_some() {
+        return Err(warp::reject::not_found());
+    }
+    let canonical = match database::load_canonical_slug(&db_pool, &slug).await {
+        Some(canonical) if canonical != slug => canonical,
+        _ => return Err(warp::reject::not_found()),
+    };
+    let mut location = format!("/api/games/{}/{}", canonical, tail.as_str());
+    if !query.is_empty() {
+        location = format!("{}?{}", location, query);
+    }
+    let location: warp::http::Uri = location.parse().map_err(|_| warp::reject::not_found())?;
+    Ok(warp::redirect::permanent(location))
+}
+
+/// GET /api/leaderboard?limit=
+/// the highest rated bots, best first
+pub async fn load_leaderboard(
+    query: LeaderboardQuery,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Failure {
+                reason: format!("limit must be between 1 and {}", MAX_PAGE_SIZE),
+            }),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let entries = database::load_leaderboard(&db_pool, limit)
+        .await
+        .into_iter()
+        .map(|entry| LeaderboardEntry {
+            bot_id: entry.bot_id,
+            name: entry.name,
+            owner: entry.owner,
+            rating: entry.rating,
+            games_played: entry.games_played,
+        })
+        .collect();
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::Leaderboard(entries))),
+        StatusCode::OK,
+    ))
+}
+
+/// GET /api/bots/{id}/rating-history
+/// how every rated game changed the rating of a bot, oldest first
+pub async fn load_rating_history(id: i64, db_pool: SqlitePool) -> Result<impl Reply, Rejection> {
+    let history = database::load_rating_history(&db_pool, id)
+        .await
+        .ok_or_else(warp::reject::not_found)?
+        .into_iter()
+        .map(|entry| RatingHistoryEntry {
+            slug: entry.slug,
+            rating_before: entry.rating_before,
+            rating_after: entry.rating_after,
+            rated_at: entry.rated_at,
+        })
+        .collect();
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::RatingHistory(history))),
+        StatusCode::OK,
+    ))
+}
+
+/// The most bytes a text field of an upload may hold
+const MAX_UPLOAD_FIELD_BYTES: usize = 1024;
+
+/// Room in an upload for the text fields and the multipart boundaries
+const MAX_UPLOAD_OVERHEAD: u64 = 64 * 1024;
+
+/// Reads a part of an upload, failing with 413 if it is longer than limit
+async fn read_part(part: Part, limit: usize) -> Result<Vec<u8>, (String, StatusCode)> {
+    let name = part.name().to_string();
+    let mut bytes = vec![];
+    let mut stream = part.stream();
+    while let Some(chunk) = stream.try_next().await.map_err(|error| {
+        (
+            format!("could not read {}: {}", name, error),
+            StatusCode::BAD_REQUEST,
+        )
+    })? {
+        if bytes.len() + chunk.remaining() > limit {
+            return Err((
+                format!("{} must be at most {} bytes", name, limit),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ));
+        }
+        bytes.extend_from_slice(chunk.chunk());
+    }
+    Ok(bytes)
+}
+
+/// Reads the bot, kind and file fields of an upload
+async fn read_upload(
+    form: FormData,
+) -> Result<(String, ArtifactKind, String, Vec<u8>), (String, StatusCode)> {
+    let bad_request = |reason: String| (reason, StatusCode::BAD_REQUEST);
+    let text = |bytes: Vec<u8>| {
+        String::from_utf8(bytes)
+            .map(|text| text.trim().to_string())
+            .map_err(|_| bad_request("fields must be valid utf-8".to_string()))
+    };
+
+    let (mut bot, mut kind, mut file) = (None, None, None);
+    let mut parts = form;
+    while let Some(part) = parts
+        .try_next()
+        .await
+        .map_err(|error| bad_request(format!("invalid multipart form: {}", error)))?
+    {
+        match part.name() {
+            "bot" => bot = Some(text(read_part(part, MAX_UPLOAD_FIELD_BYTES).await?)?),
+            "kind" => kind = Some(text(read_part(part, MAX_UPLOAD_FIELD_BYTES).await?)?),
+            "file" => {
+                let filename = part.filename().unwrap_or_default().to_string();
+                let limit = config::get().max_upload_bytes as usize;
+                file = Some((filename, read_part(part, limit).await?));
+            }
+            _ => {
+                read_part(part, MAX_UPLOAD_FIELD_BYTES).await?;
+            }
+        }
+    }
+
+    let bot = bot.ok_or_else(|| bad_request("an upload needs a bot field".to_string()))?;
+    if bot.is_empty() || bot.len() > 64 {
+        return Err(bad_request(
+            "bot names must be between 1 and 64 characters".to_string(),
+        ));
+    }
+    let kind = kind.ok_or_else(|| bad_request("an upload needs a kind field".to_string()))?;
+    let kind = ArtifactKind::parse(&kind).map_err(bad_request)?;
+    let (filename, bytes) =
+        file.ok_or_else(|| bad_request("an upload needs a file field".to_string()))?;
+    // Only the name of the file is kept, never the directories it was in
+    let filename = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(255)
+        .collect::<String>();
+    if filename.is_empty() {
+        return Err(bad_request("the file field needs a filename".to_string()));
+    }
+    Ok((bot, kind, filename, bytes))
+}
+
+/// The store uploads are kept in, as configured
+fn artifact_store() -> ArtifactStore {
+    ArtifactStore::new(&config::get().artifact_dir)
+}
+
+/// Fails unless the logged in user owns the bot, not found if the bot
+/// does not exist so other accounts cannot probe for it
+async fn check_bot_owner(
+    db_pool: &SqlitePool,
+    session: Option<&str>,
+    bot_id: i64,
+) -> Result<Result<(), warp::reply::WithStatus<warp::reply::Json>>, Rejection> {
+    let user = match auth::session_user(db_pool, session).await {
+        Some(user) => user,
+        None => return Ok(Err(not_logged_in())),
+    };
+    match database::load_bot_owner(db_pool, bot_id).await {
+        Some(owner) if owner == user.user_id => Ok(Ok(())),
+        _ => Err(warp::reject::not_found()),
+    }
+}
+
+/// POST /api/bots/uploads
+/// upload a build of a bot as a multipart form with a bot name, the kind
+/// of artifact, and the file itself. It is recorded as the next version of
+/// the bot, which is created for the logged in user if it is new
+pub async fn upload_bot(
+    session: Option<String>,
+    content_length: Option<u64>,
+    form: FormData,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    let failure = |reason: String, status| {
+        Ok(warp::reply::with_status(
+            warp::reply::json(&Response::Failure { reason }),
+            status,
+        ))
+    };
+
+    let user = match auth::session_user(&db_pool, session.as_deref()).await {
+        Some(user) => user,
+        None => return Ok(not_logged_in()),
+    };
+    let max_upload_bytes = config::get().max_upload_bytes;
+    if content_length.is_some_and(|length| length > max_upload_bytes + MAX_UPLOAD_OVERHEAD) {
+        return failure(
+            format!("uploads must be at most {} bytes", max_upload_bytes),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        );
+    }
+    let (bot, kind, filename, bytes) = match read_upload(form).await {
+        Ok(upload) => upload,
+        Err((reason, status)) => return failure(reason, status),
+    };
+
+    let size = bytes.len() as i64;
+    let name = filename.clone();
+    let (bytes, valid) = tokio::task::spawn_blocking(move || {
+        let valid = artifacts::validate(kind, &name, &bytes);
+        (bytes, valid)
+    })
+    .await
+    .expect("Failed to validate upload");
+    if let Err(reason) = valid {
+        return failure(reason, StatusCode::BAD_REQUEST);
+    }
+
+    // Held until the version is recorded, so the artifact cannot be
+    // removed by a delete in between
+    let _lock = artifacts::STORE_LOCK.lock().await;
+    let sha256 = tokio::task::spawn_blocking(move || artifact_store().put(&bytes))
+        .await
+        .expect("Failed to store upload");
+    let sha256 = match sha256 {
+        Ok(sha256) => sha256,
+        Err(error) => {
+            error!("[!] Failed to store artifact: {}", error);
+            return failure(
+                "could not store the upload".to_string(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            );
+        }
+    };
+    let version = database::save_bot_version(
+        &db_pool,
+        user.user_id,
+        &bot,
+        kind.as_str(),
+        &filename,
+        &sha256,
+        size,
+    )
+    .await;
+    info!(
+        "[+] Uploaded {} version {} of bot {} ({} bytes)",
+        kind.as_str(),
+        version.version,
+        version.bot_id,
+        size
+    );
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::BotVersion(version.into()))),
+        StatusCode::CREATED,
+    ))
+}
+
+/// GET /api/bots/{id}/versions
+/// the versions of a bot of the logged in user, newest first
+pub async fn list_bot_versions(
+    id: i64,
+    session: Option<String>,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    if let Err(reply) = check_bot_owner(&db_pool, session.as_deref(), id).await? {
+        return Ok(reply);
+    }
+    let versions = database::load_bot_versions(&db_pool, id)
+        .await
+        .into_iter()
+        .map(BotVersionDescription::from)
+        .collect();
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::BotVersions(versions))),
+        StatusCode::OK,
+    ))
+}
+
+/// GET /api/bots/{id}/versions/{version}
+/// download the artifact uploaded as a version of a bot of the logged in user
+pub async fn download_bot_version(
+    id: i64,
+    version: i64,
+    session: Option<String>,
+    db_pool: SqlitePool,
+) -> Result<warp::reply::Response, Rejection> {
+    if let Err(reply) = check_bot_owner(&db_pool, session.as_deref(), id).await? {
+        return Ok(reply.into_response());
+    }
+    let version = database::load_bot_version(&db_pool, id, version)
+        .await
+        .ok_or_else(warp::reject::not_found)?;
+    let sha256 = version.sha256.clone();
+    let bytes = tokio::task::spawn_blocking(move || artifact_store().get(&sha256))
+        .await
+        .expect("Failed to read artifact")
+        .map_err(|error| {
+            error!("[!] Failed to read artifact {}: {}", version.sha256, error);
+            warp::reject::not_found()
+        })?;
+
+    let reply = warp::reply::with_header(bytes, "content-type", "application/octet-stream");
+    let reply = warp::reply::with_header(
+        reply,
+        "content-disposition",
+        format!("attachment; filename=\"{}\"", version.filename),
+    );
+    Ok(warp::reply::with_header(reply, "x-sha256", version.sha256).into_response())
+}
+
+/// DELETE /api/bots/{id}/versions/{version}
+/// delete a version of a bot of the logged in user. Its number is not
+/// reused, and its artifact is removed once no other version is stored as it
+pub async fn delete_bot_version(
+    id: i64,
+    version: i64,
+    session: Option<String>,
+    db_pool: SqlitePool,
+) -> Result<impl Reply, Rejection> {
+    if let Err(reply) = check_bot_owner(&db_pool, session.as_deref(), id).await? {
+        return Ok(reply);
+    }
+    let _lock = artifacts::STORE_LOCK.lock().await;
+    let deleted = database::load_bot_version(&db_pool, id, version)
+        .await
+        .ok_or_else(warp::reject::not_found)?;
+    if !database::delete_bot_version(&db_pool, id, version).await {
+        return Err(warp::reject::not_found());
+    }
+    if !database::is_artifact_used(&db_pool, &deleted.sha256).await {
+        let sha256 = deleted.sha256.clone();
+        let removed = tokio::task::spawn_blocking(move || artifact_store().remove(&sha256))
+            .await
+            .expect("Failed to remove artifact");
+        if let Err(error) = removed {
+            error!(
+                "[!] Failed to remove artifact {}: {}",
+                deleted.sha256, error
+            );
+        }
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Response::Success(Success::BotVersion(deleted.into()))),
+        StatusCode::OK,
+    ))
+}
+
+fn with_db(
+    db_pool: SqlitePool,
+) -> impl Filter<Extract = (SqlitePool,), Error = Infallible> + Clone {
+    warp::any().map(move || db_pool.clone())
+}
+
+/// The routes under /api/games, /api/auth, /api/bots, /api/leaderboard,
+/// /api/admin, /api/tournaments and /api/lobby, to be served alongside the
+/// original POST /api endpoint, which only matches /api itself
+pub fn routes(
+    db_pool: SqlitePool,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let session = warp::cookie::optional::<String>(auth::SESSION_COOKIE);
+    let alias = warp::path!("api" / "games" / String / ..)
+        .and(warp::get())
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("upgrade"))
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(with_db(db_pool.clone()))
+        .and_then(redirect_alias);
+
+    let list = warp::path!("api" / "games")
+        .and(warp::get())
+        .and(warp::query::<GameListQuery>())
+        .and(session)
+        .and(with_db(db_pool.clone()))
+        .and_then(list_games);
+
+    let replay = warp::path!("api" / "games" / String / "replay")
+        .and(warp::get())
+        .and(warp::query::<ReplayQuery>())
+        .and(with_db(db_pool.clone()))
+        .and_then(load_replay);
+
+    let delta = warp::path!("api" / "games" / String / "turns" / usize / "delta")
+        .and(warp::get())
+        .and(with_db(db_pool.clone()))
+        .and_then(load_delta);
+
+    let players = warp::path!("api" / "games" / String / "players")
+        .and(warp::put())
+        .and(session)
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_db(db_pool.clone()))
+        .and_then(set_game_players);
+
+    let load_slug = warp::path!("api" / "games" / String / "slug")
+        .and(warp::get())
+        .and(with_db(db_pool.clone()))
+        .and_then(load_game_slug);
+
+    let set_slug = warp::path!("api" / "games" / String / "slug")
+        .and(warp::put())
+        .and(session)
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_db(db_pool.clone()))
+        .and_then(set_game_slug);
+
+    let leaderboard = warp::path!("api" / "leaderboard")
+        .and(warp::get())
+        .and(warp::query::<LeaderboardQuery>())
+        .and(with_db(db_pool.clone()))
+        .and_then(load_leaderboard);
+
+    let rating_history = warp::path!("api" / "bots" / i64 / "rating-history")
+        .and(warp::get())
+        .and(with_db(db_pool.clone()))
+        .and_then(load_rating_history);
+
+    let upload = warp::path!("api" / "bots" / "uploads")
+        .and(warp::post())
+        .and(session)
+        .and(warp::header::optional::<u64>("content-length"))
+        // Limited by upload_bot instead, so it can reply with the limit
+        .and(warp::multipart::form().max_length(None))
+        .and(with_db(db_pool.clone()))
+        .and_then(upload_bot);
+
+    let list_versions = warp::path!("api" / "bots" / i64 / "versions")
+        .and(warp::get())
+        .and(session)
+        .and(with_db(db_pool.clone()))
+        .and_then(list_bot_versions);
+
+    let download_version = warp::path!("api" / "bots" / i64 / "versions" / i64)
+        .and(warp::get())
+        .and(session)
+        .and(with_db(db_pool.clone()))
+        .and_then(download_bot_version);
+
+    let delete_version = warp::path!("api" / "bots" / i64 / "versions" / i64)
+        .and(warp::delete())
+        .and(session)
+        .and(with_db(db_pool.clone()))
+        .and_then(delete_bot_version);
+
+    let register = warp::path!("api" / "auth" / "register")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_db(db_pool.clone()))
+        .and_then(register);
+
+    let login = warp::path!("api" / "auth" / "login")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_db(db_pool.clone()))
+        .and_then(login);
+
+    let logout = warp::path!("api" / "auth" / "logout")
+        .and(warp::post())
+        .and(session)
+        .and(with_db(db_pool.clone()))
+        .and_then(logout);
+
+    let me = warp::path!("api" / "auth" / "me")
+        .and(warp::get())
+        .and(session)
+        .and(with_db(db_pool.clone()))
+        .and_then(current_user);
+
+    let create_own_key = warp::path!("api" / "auth" / "keys")
+        .and(warp::post())
+        .and(session)
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_db(db_pool.clone()))
+        .and_then(create_own_api_key);
+
+    let revoke_own_key = warp::path!("api" / "auth" / "keys" / i64)
+        .and(warp::delete())
+        .and(session)
+        .and(with_db(db_pool.clone()))
+        .and_then(revoke_own_api_key);
+
+    let admin_token = warp::header::optional::<String>("x-admin-token");
+    let create_key = warp::path!("api" / "admin" / "keys")
+        .and(warp::post())
+        .and(admin_token)
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_db(db_pool.clone()))
+        .and_then(create_api_key);
+
+    let list_keys = warp::path!("api" / "admin" / "keys")
+        .and(warp::get())
+        .and(admin_token)
+        .and(with_db(db_pool.clone()))
+        .and_then(list_api_keys);
+
+    let revoke_key = warp::path!("api" / "admin" / "keys" / i64)
+        .and(warp::delete())
+        .and(admin_token)
+        .and(with_db(db_pool.clone()))
+        .and_then(revoke_api_key);
+
+    let submit_match = warp::path!("api" / "admin" / "matches")
+        .and(warp::post())
+        .and(admin_token)
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_db(db_pool.clone()))
+        .and_then(submit_match);
+
+    let match_status = warp::path!("api" / "admin" / "matches" / u64)
+        .and(warp::get())
+        .and(admin_token)
+        .and_then(match_status);
+
+    let create_tournament = warp::path!("api" / "admin" / "tournaments")
+        .and(warp::post())
+        .and(admin_token)
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::json())
+        .and(with_db(db_pool.clone()))
+        .and_then(create_tournament);
+
+    let tournament_status = warp::path!("api" / "tournaments" / i64)
+        .and(warp::get())
+        .and(with_db(db_pool.clone()))
+        .and_then(tournament_status);
+
+    let api_key = warp::header::optional::<String>("x-api-key");
+    let list_tables = warp::path!("api" / "lobby" / "tables")
+        .and(warp::get())
+        .and_then(list_tables);
+
+    let open_table = warp::path!("api" / "lobby" / "tables")
+        .and(warp::post())
+        .and(session)
+        .and(api_key)
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_db(db_pool.clone()))
+        .and_then(open_table);
+
+    let join_table = warp::path!("api" / "lobby" / "tables" / u64 / "join")
+        .and(warp::post())
+        .and(session)
+        .and(api_key)
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_db(db_pool.clone()))
+        .and_then(join_table);
+
+    let table_status = warp::path!("api" / "lobby" / "tables" / u64)
+        .and(warp::get())
+        .and_then(table_status);
+
+    let join_queue = warp::path!("api" / "lobby" / "queue")
+        .and(warp::post())
+        .and(session)
+        .and(api_key)
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_db(db_pool.clone()))
+        .and_then(join_queue);
+
+    let leave_lobby = warp::path!("api" / "lobby" / "tickets" / String)
+        .and(warp::delete())
+        .and_then(leave_lobby);
+
+    list.or(replay)
+        .or(delta)
+        .or(players)
+        .or(load_slug)
+        .or(set_slug)
+        .or(leaderboard)
+        .or(rating_history)
+        .or(upload)
+        .or(list_versions)
+        .or(download_version)
+        .or(delete_version)
+        .or(register)
+        .or(login)
+        .or(logout)
+        .or(me)
+        .or(create_own_key)
+        .or(revoke_own_key)
+        .or(create_key)
+        .or(list_keys)
+        .or(revoke_key)
+        .or(submit_match)
+        .or(match_status)
+        .or(create_tournament)
+        .or(tournament_status)
+        .or(list_tables)
+        .or(open_table)
+        .or(join_table)
+        .or(table_status)
+        .or(join_queue)
+        .or(leave_lobby)
+        .or(lobby::route(lobby::shared()))
+        // Last, so an old slug is only looked up once no route found it
+        .or(alias)
+}
@@ -0,0 +1,300 @@
+use super::*;
+use crate::database::tests::{create_test_db, default_game_update};
+use crate::websocket::server;
+
+/// A game of `turns` turns, with its slug
+async fn saved_game(db: &SqlitePool, turns: usize) -> String {
+    let id = database::generate_new_id(db).await;
+    for turn in 0..turns {
+        let mut update = default_game_update();
+        update.update_num = turn;
+        database::tests::simple_save_game_update(db, update, id).await;
+    }
+    database::load_slug_default(db, id).await
+}
+
+/// The turns of the replay at `path`
+async fn replay_turns(
+    filter: &(impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static),
+    path: &str,
+) -> Vec<u64> {
+    let response = warp::test::request().path(path).reply(filter).await;
+    assert_eq!(response.status(), StatusCode::OK, "GET {}", path);
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    body["success"]["replay"]
+        .as_array()
+        .expect("expected a replay")
+        .iter()
+        .map(|game| game["turnNumber"].as_u64().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+pub async fn replay_returns_every_turn_in_order() {
+    let db = create_test_db().await;
+    let slug = saved_game(&db, 5).await;
+    let path = format!("/api/games/{}/replay", slug);
+    assert_eq!(replay_turns(&routes(db), &path).await, vec![0, 1, 2, 3, 4]);
+}
+
+#[tokio::test]
+pub async fn replay_is_limited_to_the_requested_turns() {
+    let db = create_test_db().await;
+    let slug = saved_game(&db, 5).await;
+    let filter = routes(db);
+
+    let path = format!("/api/games/{}/replay?from=1&to=3", slug);
+    assert_eq!(replay_turns(&filter, &path).await, vec![1, 2, 3]);
+    let path = format!("/api/games/{}/replay?from=3", slug);
+    assert_eq!(replay_turns(&filter, &path).await, vec![3, 4]);
+
+    // The turn before the range is loaded for the delta, but not returned
+    let path = format!("/api/games/{}/replay?from=2&to=3&deltas=true", slug);
+    let response = warp::test::request().path(&path).reply(&filter).await;
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    let replay = body["success"]["replay"].as_array().unwrap();
+    assert_eq!(replay.len(), 2);
+    assert!(replay.iter().all(|game| game.get("delta").is_some()));
+}
+
+#[tokio::test]
+pub async fn replay_rejects_an_inverted_range_and_unknown_games() {
+    let db = create_test_db().await;
+    let slug = saved_game(&db, 5).await;
+    let filter = routes(db);
+
+    let response = warp::test::request()
+        .path(&format!("/api/games/{}/replay?from=3&to=1", slug))
+        .reply(&filter)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = warp::test::request()
+        .path("/api/games/no-such-game/replay")
+        .reply(&filter)
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+pub async fn corrupt_keyframe_is_a_server_error() {
+    let db = create_test_db().await;
+    let slug = saved_game(&db, 3).await;
+    let uuid = database::load_uuid_from_slug(&db, &slug).await.unwrap();
+    sqlx::query("UPDATE game_updates SET payload = x'00' WHERE update_uuid = ? AND turn_id = 0")
+        .bind(uuid.to_string())
+        .execute(&db)
+        .await
+        .unwrap();
+    let filter = routes(db);
+
+    for path in [
+        format!("/api/games/{}/replay", slug),
+        format!("/api/games/{}/turns/2/delta", slug),
+    ] {
+        let response = warp::test::request().path(&path).reply(&filter).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "GET {}",
+            path
+        );
+    }
+}
+
+#[tokio::test]
+pub async fn server_serves_the_api_next_to_arenas() {
+    let db = create_test_db().await;
+    let slug = saved_game(&db, 3).await;
+    let (queue, _receiver) = crate::queue::with_capacity(16);
+    let filter = server::routes(server::Connections::new(db, queue));
+
+    let path = format!("/api/games/{}/replay", slug);
+    assert_eq!(replay_turns(&filter, &path).await, vec![0, 1, 2]);
+
+    let response = warp::test::request()
+        .method("POST")
+        .path("/api")
+        .json(&serde_json::json!({ "uuid": slug, "turnNumber": 2 }))
+        .reply(&filter)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(body["success"]["game_update"]["turnNumber"], 2);
+}
+
+#[tokio::test]
+pub async fn users_can_only_revoke_their_own_keys() {
+    let db = create_test_db().await;
+    let mut users = vec![];
+    for name in ["owner", "other"] {
+        let password_hash = auth::hash_password("correct horse").await;
+        let user_id = database::save_user(&db, name, &password_hash)
+            .await
+            .unwrap();
+        let key = auth::generate_api_key();
+        let key_id =
+            database::save_api_key(&db, &auth::hash_api_key(&key), name, Some(user_id), "").await;
+        let session = auth::start_session(&db, user_id).await;
+        users.push((key, key_id, session));
+    }
+    let filter = routes(db.clone());
+    let revoke = |key_id: i64, session: &str| {
+        warp::test::request()
+            .method("DELETE")
+            .path(&format!("/api/auth/keys/{}", key_id))
+            .header("cookie", format!("{}={}", auth::SESSION_COOKIE, session))
+    };
+
+    let (key, key_id, _) = &users[0];
+    let response = revoke(*key_id, &users[1].2).reply(&filter).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(auth::verify_api_key(&db, key).await.is_ok());
+
+    let response = revoke(*key_id, &users[0].2).reply(&filter).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(auth::verify_api_key(&db, key).await.is_err());
+}
+
+#[tokio::test]
+pub async fn sessions_and_api_keys_are_hashed_apart() {
+    let db = create_test_db().await;
+    let password_hash = auth::hash_password("correct horse").await;
+    let user_id = database::save_user(&db, "owner", &password_hash)
+        .await
+        .unwrap();
+    let session = auth::start_session(&db, user_id).await;
+    assert_ne!(auth::hash_token(&session), auth::hash_api_key(&session));
+
+    // A key stored under the hash of a session does not authenticate
+    let key = auth::generate_api_key();
+    database::save_api_key(&db, &auth::hash_token(&key), "owner", Some(user_id), "").await;
+    assert!(auth::verify_api_key(&db, &key).await.is_err());
+    assert!(auth::session_user(&db, Some(&session)).await.is_some());
+}
+
+#[tokio::test]
+pub async fn login_fails_the_same_for_unknown_users_and_wrong_passwords() {
+    let db = create_test_db().await;
+    let password_hash = auth::hash_password("correct horse").await;
+    database::save_user(&db, "owner", &password_hash)
+        .await
+        .unwrap();
+    let filter = routes(db);
+
+    let login = |username: &str, password: &str| {
+        warp::test::request()
+            .method("POST")
+            .path("/api/auth/login")
+            .json(&serde_json::json!({ "username": username, "password": password }))
+    };
+    let unknown = login("nobody", "correct horse").reply(&filter).await;
+    let wrong = login("owner", "wrong horse").reply(&filter).await;
+    assert_eq!(unknown.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(unknown.body(), wrong.body());
+    let right = login("owner", "correct horse").reply(&filter).await;
+    assert_eq!(right.status(), StatusCode::OK);
+}
+
+/// Saves a game of `turns` turns last updated at `last_updated`
+async fn game_updated_at(db: &SqlitePool, turns: usize, last_updated: &str) -> String {
+    let slug = saved_game(db, turns).await;
+    set_last_updated(db, &slug, last_updated).await;
+    slug
+}
+
+async fn set_last_updated(db: &SqlitePool, slug: &str, last_updated: &str) {
+    let uuid = database::load_uuid_from_slug(db, slug).await.unwrap();
+    sqlx::query("UPDATE games SET last_updated = ? WHERE game_uuid = ?")
+        .bind(last_updated)
+        .bind(uuid.to_string())
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+/// The slugs of a page of games and the cursor of the next page
+async fn list_page(
+    filter: &(impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static),
+    query: &str,
+) -> (Vec<String>, Option<String>) {
+    let path = format!("/api/games?{}", query);
+    let response = warp::test::request().path(&path).reply(filter).await;
+    assert_eq!(response.status(), StatusCode::OK, "GET {}", path);
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    let list = &body["success"]["games"];
+    let slugs = list["games"]
+        .as_array()
+        .expect("expected a list of games")
+        .iter()
+        .map(|game| game["slug"].as_str().unwrap().to_string())
+        .collect();
+    (slugs, list["nextCursor"].as_str().map(str::to_string))
+}
+
+#[tokio::test]
+pub async fn games_are_filtered_by_date_status_and_turns() {
+    let db = create_test_db().await;
+    let first = game_updated_at(&db, 2, "2024-05-01 08:00:00").await;
+    let late = game_updated_at(&db, 5, "2024-05-01 23:30:00").await;
+    let next_day = game_updated_at(&db, 1, "2024-05-02 00:00:00").await;
+    let uuid = database::load_uuid_from_slug(&db, &late).await.unwrap();
+    database::save_game_over(&db, uuid).await;
+    set_last_updated(&db, &late, "2024-05-01 23:30:00").await;
+    let filter = routes(db);
+
+    // A bare date includes the whole day
+    let (slugs, _) = list_page(&filter, "until=2024-05-01").await;
+    assert_eq!(slugs, vec![late.clone(), first.clone()]);
+    let (slugs, _) = list_page(&filter, "since=2024-05-02").await;
+    assert_eq!(slugs, vec![next_day.clone()]);
+    let (slugs, _) = list_page(&filter, "until=2024-05-01%2012:00:00").await;
+    assert_eq!(slugs, vec![first.clone()]);
+
+    let (slugs, _) = list_page(&filter, "status=finished").await;
+    assert_eq!(slugs, vec![late.clone()]);
+    let (slugs, _) = list_page(&filter, "status=in_progress").await;
+    assert_eq!(slugs, vec![next_day.clone(), first.clone()]);
+    let (slugs, _) = list_page(&filter, "min_turns=1").await;
+    assert_eq!(slugs, vec![late, first]);
+    let (slugs, _) = list_page(&filter, "players=3&min_turns=5").await;
+    assert!(slugs.is_empty());
+}
+
+#[tokio::test]
+pub async fn games_are_paged_with_the_cursor() {
+    let db = create_test_db().await;
+    let mut expected = vec![];
+    for hour in 0..5 {
+        let last_updated = format!("2024-05-01 0{}:00:00", hour);
+        expected.push(game_updated_at(&db, 1, &last_updated).await);
+    }
+    // Two games updated in the same second are still both listed
+    expected.push(game_updated_at(&db, 1, "2024-05-01 04:00:00").await);
+    let filter = routes(db);
+
+    let mut listed = vec![];
+    let mut query = "limit=2".to_string();
+    loop {
+        let (slugs, cursor) = list_page(&filter, &query).await;
+        listed.extend(slugs);
+        match cursor {
+            Some(cursor) => query = format!("limit=2&cursor={}", cursor.replace(' ', "%20")),
+            None => break,
+        }
+    }
+    // Most recently updated first, each game exactly once
+    let mut newest = listed[..2].to_vec();
+    let mut tied = expected[4..].to_vec();
+    newest.sort();
+    tied.sort();
+    assert_eq!(newest, tied);
+    let older: Vec<String> = expected[..4].iter().rev().cloned().collect();
+    assert_eq!(listed[2..], older[..]);
+
+    let response = warp::test::request()
+        .path("/api/games?cursor=no-separator")
+        .reply(&filter)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
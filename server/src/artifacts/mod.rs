@@ -0,0 +1,273 @@
+// Uploaded bot artifacts, and where they are stored.
+//
+// A bot version is uploaded as one of three kinds of artifact:
+//
+//      project  a gzipped tarball of a `stourney new` project, with a
+//               Cargo.toml or main.py at its root, or inside the single
+//               directory at its root
+//      binary   a prebuilt Linux executable, in ELF format
+//      python   a single python file run as the entrypoint of the bot
+//
+// Artifacts are stored by the sha256 of their contents, so uploading the
+// same build twice stores it once:
+//
+//      <artifact_dir>/<first two hex digits>/<sha256>
+//
+// Files are written to a temporary name and renamed into place, so a
+// stored artifact is always complete. They are never modified, and are
+// removed once no bot version refers to them.
+
+#[cfg(test)]
+pub mod tests;
+
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf};
+use tokio::sync::Mutex;
+
+/// The most bytes a project archive may unpack to
+const MAX_UNPACKED_BYTES: u64 = 512 * 1024 * 1024;
+
+/// The most files and directories a project archive may hold
+const MAX_ENTRIES: usize = 10_000;
+
+/// A file at the root of a project that tells how to build and run it
+const PROJECT_MARKERS: [&str; 2] = ["Cargo.toml", "main.py"];
+
+/// Held while an artifact is stored and its version recorded, or while a
+/// version is deleted and its artifact removed, so an artifact is never
+/// removed while a new version of it is being recorded
+pub static STORE_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// The kinds of executable that can be uploaded
+const ELF_EXECUTABLE: u16 = 2;
+const ELF_SHARED_OBJECT: u16 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArtifactKind {
+    Project,
+    Binary,
+    Python,
+}
+
+impl ArtifactKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ArtifactKind::Project => "project",
+            ArtifactKind::Binary => "binary",
+            ArtifactKind::Python => "python",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Result<ArtifactKind, String> {
+        match kind {
+            "project" => Ok(ArtifactKind::Project),
+            "binary" => Ok(ArtifactKind::Binary),
+            "python" => Ok(ArtifactKind::Python),
+            _ => Err(format!(
+                "kind must be one of project, binary or python, got {}",
+                kind
+            )),
+        }
+    }
+}
+
+/// Checks an upload is a well formed artifact of its kind
+pub fn validate(kind: ArtifactKind, filename: &str, bytes: &[u8]) -> Result<(), String> {
+    if bytes.is_empty() {
+        return Err("the uploaded file is empty".to_string());
+    }
+    match kind {
+        ArtifactKind::Project => validate_project(bytes),
+        ArtifactKind::Binary => validate_binary(bytes),
+        ArtifactKind::Python => validate_python(filename, bytes),
+    }
+}
+
+/// A gzipped tarball of regular files and directories, with relative
+/// paths that stay inside it, and a project marker at its root
+fn validate_project(bytes: &[u8]) -> Result<(), String> {
+    let invalid = |error: io::Error| format!("project is not a valid .tar.gz archive: {}", error);
+    let mut archive = tar::Archive::new(GzDecoder::new(bytes));
+    let mut files = vec![];
+    let mut unpacked = 0;
+    for (count, entry) in archive.entries().map_err(invalid)?.enumerate() {
+        if count >= MAX_ENTRIES {
+            return Err(format!("project has more than {} entries", MAX_ENTRIES));
+        }
+        let entry = entry.map_err(invalid)?;
+        let path = entry.path().map_err(invalid)?.into_owned();
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(format!(
+                "project path {} leaves the project",
+                path.display()
+            ));
+        }
+        let kind = entry.header().entry_type();
+        if kind.is_file() {
+            // Without the ./ that archives made with `tar -C dir .` start with
+            let path = path
+                .components()
+                .filter(|component| matches!(component, Component::Normal(_)))
+                .collect::<PathBuf>();
+            files.push(path);
+        } else if !kind.is_dir() {
+            return Err(format!(
+                "project entry {} is not a file or directory",
+                path.display()
+            ));
+        }
+        unpacked += entry.size();
+        if unpacked > MAX_UNPACKED_BYTES {
+            return Err(format!(
+                "project unpacks to more than {} bytes",
+                MAX_UNPACKED_BYTES
+            ));
+        }
+    }
+
+    let root = project_root(&files);
+    let has_marker = PROJECT_MARKERS
+        .iter()
+        .any(|marker| files.iter().any(|file| *file == root.join(marker)));
+    if !has_marker {
+        return Err(format!(
+            "project must have one of {} at its root",
+            PROJECT_MARKERS.join(" or ")
+        ));
+    }
+    Ok(())
+}
+
+/// The directory every file of a project is in, if there is only one
+/// at the top of the archive
+fn project_root(files: &[PathBuf]) -> PathBuf {
+    let mut tops = HashSet::new();
+    for file in files {
+        let mut components = file.components();
+        let top = components.next();
+        if components.next().is_none() {
+            // A file at the top of the archive
+            return PathBuf::new();
+        }
+        tops.insert(top);
+    }
+    match tops.into_iter().collect::<Vec<_>>()[..] {
+        [Some(top)] => PathBuf::from(top.as_os_str()),
+        _ => PathBuf::new(),
+    }
+}
+
+/// An ELF executable or position independent executable for Linux
+fn validate_binary(bytes: &[u8]) -> Result<(), String> {
+    if bytes.len() < 52 || &bytes[..4] != b"\x7fELF" {
+        return Err("binary is not an ELF executable".to_string());
+    }
+    // e_ident: class, data encoding, version and OS ABI
+    let (class, encoding, abi) = (bytes[4], bytes[5], bytes[7]);
+    if !matches!(class, 1 | 2) || !matches!(encoding, 1 | 2) {
+        return Err("binary has an invalid ELF header".to_string());
+    }
+    if !matches!(abi, 0 | 3) {
+        return Err("binary is not built for Linux".to_string());
+    }
+    let e_type = [bytes[16], bytes[17]];
+    let e_type = match encoding {
+        1 => u16::from_le_bytes(e_type),
+        _ => u16::from_be_bytes(e_type),
+    };
+    if e_type != ELF_EXECUTABLE && e_type != ELF_SHARED_OBJECT {
+        return Err("binary is not an executable".to_string());
+    }
+    Ok(())
+}
+
+/// A utf-8 .py file
+fn validate_python(filename: &str, bytes: &[u8]) -> Result<(), String> {
+    if !filename.ends_with(".py") {
+        return Err("python entrypoint must be a .py file".to_string());
+    }
+    let source = std::str::from_utf8(bytes)
+        .map_err(|_| "python entrypoint is not valid utf-8".to_string())?;
+    if source.contains('\0') || source.trim().is_empty() {
+        return Err("python entrypoint is not python source".to_string());
+    }
+    Ok(())
+}
+
+/// The sha256 of an artifact, in hex
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// Artifacts stored by their sha256
+pub struct ArtifactStore {
+    dir: PathBuf,
+}
+
+impl ArtifactStore {
+    pub fn new(dir: &Path) -> Self {
+        ArtifactStore {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// Where the artifact with the sha256 is stored
+    pub fn path(&self, sha256: &str) -> PathBuf {
+        assert!(
+            sha256.len() == 64 && sha256.bytes().all(|b| b.is_ascii_hexdigit()),
+            "Invalid sha256 {}",
+            sha256
+        );
+        self.dir.join(&sha256[..2]).join(sha256)
+    }
+
+    /// Stores an artifact unless it already is, returns its sha256
+    pub fn put(&self, bytes: &[u8]) -> io::Result<String> {
+        let sha256 = sha256_hex(bytes);
+        let path = self.path(&sha256);
+        if path.exists() {
+            return Ok(sha256);
+        }
+        // Only the server may read them, sandboxed bots have them hidden
+        // as well
+        let parent = path.parent().expect("Artifacts are in a directory");
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+        let temporary = parent.join(format!(".{}.tmp", sha256));
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temporary)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &path)?;
+        Ok(sha256)
+    }
+
+    /// Reads a stored artifact
+    pub fn get(&self, sha256: &str) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![];
+        fs::File::open(self.path(sha256))?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Removes a stored artifact, if it is there
+    pub fn remove(&self, sha256: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(sha256)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
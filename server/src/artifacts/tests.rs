@@ -0,0 +1,245 @@
+use super::*;
+use crate::database::tests::create_test_db;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::os::unix::fs::PermissionsExt;
+
+/// A gzipped tarball of the files, given as path and contents
+pub fn project(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, path, contents.as_bytes())
+            .unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+/// The header of a 64 bit little endian ELF file of the type
+fn elf(abi: u8, e_type: u16) -> Vec<u8> {
+    let mut bytes = vec![0; 64];
+    bytes[..4].copy_from_slice(b"\x7fELF");
+    bytes[4] = 2;
+    bytes[5] = 1;
+    bytes[6] = 1;
+    bytes[7] = abi;
+    bytes[16..18].copy_from_slice(&e_type.to_le_bytes());
+    bytes
+}
+
+/// A store in a fresh directory
+fn temporary_store(name: &str) -> (ArtifactStore, PathBuf) {
+    let dir = std::env::temp_dir().join(format!(
+        "stourney_artifacts_{}_{}",
+        name,
+        uuid::Uuid::new_v4()
+    ));
+    (ArtifactStore::new(&dir), dir)
+}
+
+#[test]
+pub fn projects_need_a_marker_at_their_root() {
+    let rust = project(&[
+        ("my_bot/Cargo.toml", "[package]"),
+        ("my_bot/src/main.rs", "fn main() {}"),
+    ]);
+    assert_eq!(
+        validate(ArtifactKind::Project, "my_bot.tar.gz", &rust),
+        Ok(())
+    );
+
+    let python = project(&[("./main.py", "print()"), ("./lib/util.py", "")]);
+    assert_eq!(
+        validate(ArtifactKind::Project, "bot.tar.gz", &python),
+        Ok(())
+    );
+
+    let nested = project(&[("my_bot/src/Cargo.toml", "[package]")]);
+    assert!(validate(ArtifactKind::Project, "bot.tar.gz", &nested).is_err());
+
+    let two_roots = project(&[("a/Cargo.toml", ""), ("b/main.rs", "")]);
+    assert!(validate(ArtifactKind::Project, "bot.tar.gz", &two_roots).is_err());
+}
+
+#[test]
+pub fn projects_cannot_leave_their_directory() {
+    let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(0);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, "main.py", &[][..])
+        .unwrap();
+    // append_data refuses paths with .., so the name is written raw
+    let mut header = tar::Header::new_gnu();
+    header.as_old_mut().name[..11].copy_from_slice(b"../evil.txt");
+    header.set_size(0);
+    header.set_cksum();
+    builder.append(&header, &[][..]).unwrap();
+    let archive = builder.into_inner().unwrap().finish().unwrap();
+
+    let error = validate(ArtifactKind::Project, "bot.tar.gz", &archive).unwrap_err();
+    assert!(error.contains("leaves the project"), "{}", error);
+}
+
+#[test]
+pub fn projects_cannot_hold_links() {
+    let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(0);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, "main.py", &[][..])
+        .unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    builder
+        .append_link(&mut header, "passwd", "/etc/passwd")
+        .unwrap();
+    let archive = builder.into_inner().unwrap().finish().unwrap();
+
+    assert!(validate(ArtifactKind::Project, "bot.tar.gz", &archive).is_err());
+    assert!(validate(ArtifactKind::Project, "bot.tar.gz", b"not gzip").is_err());
+}
+
+#[test]
+pub fn binaries_must_be_linux_executables() {
+    assert_eq!(
+        validate(ArtifactKind::Binary, "bot", &elf(0, ELF_EXECUTABLE)),
+        Ok(())
+    );
+    assert_eq!(
+        validate(ArtifactKind::Binary, "bot", &elf(3, ELF_SHARED_OBJECT)),
+        Ok(())
+    );
+    // Relocatable object files cannot be run
+    assert!(validate(ArtifactKind::Binary, "bot", &elf(0, 1)).is_err());
+    // FreeBSD
+    assert!(validate(ArtifactKind::Binary, "bot", &elf(9, ELF_EXECUTABLE)).is_err());
+    assert!(validate(ArtifactKind::Binary, "bot.exe", b"MZ\x90\x00").is_err());
+}
+
+#[test]
+pub fn python_entrypoints_must_be_python_source() {
+    let source = b"import sys\nprint('hello')\n";
+    assert_eq!(validate(ArtifactKind::Python, "bot.py", source), Ok(()));
+    assert!(validate(ArtifactKind::Python, "bot.txt", source).is_err());
+    assert!(validate(ArtifactKind::Python, "bot.py", b"\xff\xfe").is_err());
+    assert!(validate(ArtifactKind::Python, "bot.py", b"  \n").is_err());
+    assert!(validate(ArtifactKind::Python, "bot.py", b"").is_err());
+}
+
+#[test]
+pub fn artifacts_are_stored_once_by_their_sha256() {
+    let (store, dir) = temporary_store("put");
+    let sha256 = store.put(b"print()").unwrap();
+    assert_eq!(sha256, sha256_hex(b"print()"));
+    assert_eq!(store.path(&sha256), dir.join(&sha256[..2]).join(&sha256));
+    assert_eq!(store.put(b"print()").unwrap(), sha256);
+    assert_eq!(store.get(&sha256).unwrap(), b"print()");
+    // Nothing is left behind but the artifact
+    assert_eq!(fs::read_dir(dir.join(&sha256[..2])).unwrap().count(), 1);
+    // Which only the server may read
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(&store.path(&sha256)), 0o600);
+    assert_eq!(mode(&dir.join(&sha256[..2])), 0o700);
+
+    store.remove(&sha256).unwrap();
+    assert!(store.get(&sha256).is_err());
+    store.remove(&sha256).unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+pub async fn bot_versions_count_up_and_are_never_reused() {
+    let db = create_test_db().await;
+    let owner = crate::database::save_user(&db, "owner", "hash")
+        .await
+        .unwrap();
+    let save = |sha256: &'static str| {
+        let db = db.clone();
+        async move {
+            crate::database::save_bot_version(&db, owner, "my_bot", "python", "bot.py", sha256, 7)
+                .await
+        }
+    };
+
+    let first = save("a").await;
+    let second = save("b").await;
+    assert_eq!((first.version, second.version), (1, 2));
+    assert_eq!(first.bot_id, second.bot_id);
+    assert_eq!(
+        crate::database::load_bot_owner(&db, first.bot_id).await,
+        Some(owner)
+    );
+
+    assert!(crate::database::delete_bot_version(&db, first.bot_id, 2).await);
+    assert!(!crate::database::delete_bot_version(&db, first.bot_id, 2).await);
+    assert!(!crate::database::is_artifact_used(&db, "b").await);
+    assert!(crate::database::is_artifact_used(&db, "a").await);
+    assert!(crate::database::load_bot_version(&db, first.bot_id, 2)
+        .await
+        .is_none());
+
+    let third = save("a").await;
+    assert_eq!(third.version, 3);
+    let versions = crate::database::load_bot_versions(&db, first.bot_id).await;
+    let numbers: Vec<i64> = versions.iter().map(|version| version.version).collect();
+    assert_eq!(numbers, vec![3, 1]);
+}
+
+#[tokio::test]
+pub async fn bot_versions_are_only_shown_to_their_owner() {
+    let db = create_test_db().await;
+    let owner = crate::database::save_user(&db, "owner", "hash")
+        .await
+        .unwrap();
+    let other = crate::database::save_user(&db, "other", "hash")
+        .await
+        .unwrap();
+    let version =
+        crate::database::save_bot_version(&db, owner, "my_bot", "python", "bot.py", "a", 7).await;
+    let routes = crate::api::routes(db.clone());
+    let path = format!("/api/bots/{}/versions", version.bot_id);
+
+    let response = warp::test::request().path(&path).reply(&routes).await;
+    assert_eq!(response.status(), 401);
+
+    let token = crate::auth::start_session(&db, other).await;
+    let response = warp::test::request()
+        .path(&path)
+        .header("cookie", format!("session={}", token))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 404);
+
+    let token = crate::auth::start_session(&db, owner).await;
+    let response = warp::test::request()
+        .path(&path)
+        .header("cookie", format!("session={}", token))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(body["success"]["bot_versions"][0]["version"], 1);
+    assert_eq!(body["success"]["bot_versions"][0]["name"], "my_bot");
+}
+
+#[tokio::test]
+pub async fn uploads_need_a_session() {
+    let db = create_test_db().await;
+    let body = "--X\r\nContent-Disposition: form-data; name=\"bot\"\r\n\r\nmy_bot\r\n--X--\r\n";
+    let response = warp::test::request()
+        .method("POST")
+        .path("/api/bots/uploads")
+        .header("content-type", "multipart/form-data; boundary=X")
+        .body(body)
+        .reply(&crate::api::routes(db))
+        .await;
+    assert_eq!(response.status(), 401);
+}
@@ -0,0 +1,227 @@
+// Credentials for arenas, users and administrators.
+//
+// Arenas authenticate with an api key minted for a single owner. Only the
+// SHA-256 hash of a key is stored, the key itself is shown once when it is
+// created. Keys are 32 random bytes so they do not need a salt or a slow
+// hash to resist guessing, unlike passwords.
+//
+// Users of the website log in with a username and password. Passwords are
+// hashed with Argon2id and a random salt per user. Logging in starts a
+// session, whose random token is kept in an HTTP-only cookie by the browser
+// and stored as a SHA-256 hash too, prefixed with SESSION_DOMAIN so a session
+// token can never match the hash of an api key or the other way around.
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use lazy_static::lazy_static;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::SqlitePool;
+
+use crate::config;
+use crate::database::{self, UserRecord};
+
+const API_KEY_PREFIX: &str = "stk_";
+
+/// Name of the cookie holding the session token
+pub const SESSION_COOKIE: &str = "session";
+
+/// Prefixed to session tokens before they are hashed, api keys are hashed
+/// as they are so the keys already minted keep working
+const SESSION_DOMAIN: &str = "session:";
+
+/// How long a session lasts after logging in
+pub const SESSION_DAYS: i64 = 30;
+
+/// The account an authenticated arena is acting for
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKeyOwner {
+    pub key_id: i64,
+    pub owner: String,
+    /// The account games uploaded with this key are attributed to
+    pub user_id: Option<i64>,
+}
+
+/// Generates a new random api key
+pub fn generate_api_key() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("{}{}", API_KEY_PREFIX, hex::encode(bytes))
+}
+
+/// The form an api key is stored and looked up in
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// The form a session token is stored and looked up in, never the same
+/// as the hash of an api key
+pub fn hash_token(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(SESSION_DOMAIN.as_bytes());
+    hasher.update(token.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Generates a new random session token
+pub fn generate_session_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Hashes a password with a fresh salt, the salt and parameters are
+/// kept in the returned string. Argon2 takes tens of milliseconds, so it
+/// runs on the blocking threads instead of stalling the async workers
+pub async fn hash_password(password: &str) -> String {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || hash_password_now(&password))
+        .await
+        .expect("Failed to hash password")
+}
+
+fn hash_password_now(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Failed to hash password")
+        .to_string()
+}
+
+/// Whether the password matches a hash made by `hash_password`
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+lazy_static! {
+    /// Verified against when nobody has the username logging in
+    static ref DUMMY_PASSWORD_HASH: String = hash_password_now("not the password of anyone");
+}
+
+/// Whether the password matches the hash of the account logging in. With
+/// no account a dummy hash is verified instead, so logging in takes as
+/// long whether or not the username exists. Runs on the blocking threads
+/// like hash_password
+pub async fn verify_login(password: &str, password_hash: Option<&str>) -> bool {
+    let password = password.to_string();
+    let password_hash = password_hash.map(str::to_string);
+    tokio::task::spawn_blocking(move || match password_hash {
+        Some(password_hash) => verify_password(&password, &password_hash),
+        None => {
+            verify_password(&password, &DUMMY_PASSWORD_HASH);
+            false
+        }
+    })
+    .await
+    .expect("Failed to verify password")
+}
+
+/// Checks a username is 3 to 32 letters, digits, dashes or underscores
+pub fn validate_username(username: &str) -> Result<(), String> {
+    if !(3..=32).contains(&username.len()) {
+        return Err("usernames must be between 3 and 32 characters".to_string());
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err("usernames can only contain letters, digits, - and _".to_string());
+    }
+    Ok(())
+}
+
+/// Checks a password is long enough to be worth hashing, and short
+/// enough that hashing it is not a burden
+pub fn validate_password(password: &str) -> Result<(), String> {
+    if password.len() < 8 {
+        return Err("passwords must be at least 8 characters".to_string());
+    }
+    if password.len() > 128 {
+        return Err("passwords must be at most 128 characters".to_string());
+    }
+    Ok(())
+}
+
+/// Starts a session for a user and returns the token to put in its cookie
+pub async fn start_session(pool: &SqlitePool, user_id: i64) -> String {
+    let token = generate_session_token();
+    database::save_session(pool, &hash_token(&token), user_id, SESSION_DAYS).await;
+    token
+}
+
+/// The user logged in with a session token, if any
+pub async fn session_user(pool: &SqlitePool, token: Option<&str>) -> Option<UserRecord> {
+    match token {
+        Some(token) if !token.is_empty() => {
+            database::load_session_user(pool, &hash_token(token)).await
+        }
+        _ => None,
+    }
+}
+
+/// The Set-Cookie header value that stores a session token in the browser
+pub fn session_cookie(token: &str) -> String {
+    format!(
+        "{}={}; Max-Age={}; Path=/; HttpOnly; Secure; SameSite=Lax",
+        SESSION_COOKIE,
+        token,
+        SESSION_DAYS * 24 * 60 * 60
+    )
+}
+
+/// The Set-Cookie header value that removes the session cookie
+pub fn expired_session_cookie() -> String {
+    format!(
+        "{}=; Max-Age=0; Path=/; HttpOnly; Secure; SameSite=Lax",
+        SESSION_COOKIE
+    )
+}
+
+/// Looks up an api key, failing with a reason that is safe to show the
+/// arena if the key is unknown or has been revoked
+pub async fn verify_api_key(pool: &SqlitePool, key: &str) -> Result<ApiKeyOwner, String> {
+    if key.is_empty() {
+        return Err("no api key was given".to_string());
+    }
+
+    let record = database::load_api_key(pool, &hash_api_key(key))
+        .await
+        .ok_or_else(|| "unknown api key".to_string())?;
+    if record.revoked {
+        return Err("this api key has been revoked".to_string());
+    }
+
+    database::touch_api_key(pool, record.key_id).await;
+    Ok(ApiKeyOwner {
+        key_id: record.key_id,
+        owner: record.owner,
+        user_id: record.user_id,
+    })
+}
+
+/// Compares two secrets without leaking how much of them matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Whether a secret sent by a client is the expected one, an empty
+/// secret never is
+pub fn is_secret(secret: &str, expected: &str) -> bool {
+    !expected.is_empty() && constant_time_eq(secret.as_bytes(), expected.as_bytes())
+}
+
+/// Whether the token matches the admin_token of the configuration,
+/// nobody is an admin when it is not set
+pub fn is_admin(token: Option<&str>) -> bool {
+    let expected = config::get().admin_token.as_deref().unwrap_or_default();
+    match token {
+        Some(token) => is_secret(token, expected),
+        None => false,
+    }
+}
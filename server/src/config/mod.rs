@@ -0,0 +1,623 @@
+// Settings of the server binary.
+//
+// Every setting has a default, which can be overridden by a TOML file, then
+// by an environment variable, then by a command line flag:
+//
+//      defaults < stourney.toml < environment < flags
+//
+// The file is read from --config (or STOURNEY_CONFIG) if given, otherwise
+// from stourney.toml in the working directory when there is one; see
+// stourney.example.toml for every key. The settings are validated once on
+// startup, and are then available everywhere through get().
+
+#[cfg(test)]
+pub mod tests;
+
+use clap::{Args, Parser, Subcommand};
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::constants::HOST_NAME;
+
+/// The file read when --config is not given, if it exists
+const DEFAULT_CONFIG_FILE: &str = "stourney.toml";
+
+const JOURNAL_MODES: [&str; 6] = ["DELETE", "TRUNCATE", "PERSIST", "MEMORY", "WAL", "OFF"];
+const SYNCHRONOUS_MODES: [&str; 4] = ["OFF", "NORMAL", "FULL", "EXTRA"];
+const TEMP_STORES: [&str; 3] = ["DEFAULT", "FILE", "MEMORY"];
+const LOG_FORMATS: [&str; 2] = ["text", "json"];
+const LOG_ROTATIONS: [&str; 3] = ["hourly", "daily", "never"];
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The address the server listens on
+    pub bind_address: IpAddr,
+    pub port: u16,
+    /// The sqlite database, as a sqlite:// url
+    pub database_url: String,
+    /// Connections to the database, sqlite only has one writer at a time
+    pub pool_size: u32,
+    /// Where the website is hosted, used in the links handed to arenas
+    pub host_name: String,
+    /// A filter in the format of RUST_LOG, such as info or stourney_server=debug
+    pub log_level: String,
+    /// text, or json for one object per line
+    pub log_format: String,
+    /// Where log files are written, logs go to stderr if not set
+    pub log_dir: Option<PathBuf>,
+    /// How often a new log file is started: hourly, daily or never
+    pub log_rotation: String,
+    /// The most log files kept in log_dir, 0 keeps them all
+    pub log_max_files: usize,
+    /// How many updates the queue holds before arenas are told to back off
+    pub queue_capacity: usize,
+    /// How long updates wait for room in a full queue before the arena
+    /// is told to back off, in milliseconds
+    pub queue_max_wait_ms: u64,
+    /// Where queued updates are spooled until they are saved
+    pub spool_dir: PathBuf,
+    /// Where uploaded bot artifacts are stored
+    pub artifact_dir: PathBuf,
+    /// The largest bot artifact that can be uploaded, in bytes
+    pub max_upload_bytes: u64,
+    /// The x-admin-token header admin requests must send, nobody is an
+    /// admin when it is not set
+    pub admin_token: Option<String>,
+    /// Whether arenas can still authenticate with the shared server secret
+    /// instead of an api key, for development only
+    pub allow_shared_secret: bool,
+    /// The shared server secret, no arena can use it when it is not set
+    pub shared_secret: Option<String>,
+    pub sqlite: SqliteConfig,
+    pub runner: RunnerConfig,
+    pub lobby: LobbyConfig,
+}
+
+/// The pragmas set on every connection to the database
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SqliteConfig {
+    pub journal_mode: String,
+    pub synchronous: String,
+    pub temp_store: String,
+    /// The most bytes of the database mapped into memory, 0 turns it off
+    pub mmap_size: u64,
+}
+
+/// How hosted matches between uploaded bots are played
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunnerConfig {
+    /// How many matches are played at once, the others wait for a slot
+    pub max_matches: usize,
+    /// How long a bot has to play each of its turns, in milliseconds
+    pub move_time_ms: u64,
+    /// How long bots have to connect once they are started, in seconds
+    pub connect_time_secs: u64,
+    /// How long a rust project has to build, in seconds
+    pub build_time_secs: u64,
+    /// The most memory the build of a rust project may use, in MiB
+    pub build_memory_mb: u64,
+    /// A directory made by `cargo vendor` that rust projects are built
+    /// against, builds have no network so only projects without
+    /// dependencies build without it
+    pub vendor_dir: Option<PathBuf>,
+    /// Where uploaded bots are unpacked and built
+    pub work_dir: PathBuf,
+    /// The interpreter python bots are run with
+    pub python: String,
+    /// The cargo rust projects are built with
+    pub cargo: String,
+    /// Whether bots run in namespaces, as an unprivileged user and under a
+    /// seccomp filter, which needs Linux and CAP_SYS_ADMIN. Matches fail
+    /// when it is on and the sandbox cannot be set up
+    pub sandbox: bool,
+    /// The most memory a bot may use, in MiB
+    pub memory_mb: u64,
+    /// The most cpu time a bot may use over a match, in seconds
+    pub cpu_time_secs: u64,
+    /// The most wall-clock time a bot may spend on its turns over a
+    /// match, in seconds
+    pub wall_time_secs: u64,
+    /// The most processes and threads a bot may run at once
+    pub max_processes: u64,
+    /// A cgroup v2 delegated to the server, bots are limited by a cgroup
+    /// of their own inside it when it exists
+    pub cgroup_dir: PathBuf,
+    /// The user and group sandboxed bots run as
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// How online games between players in the lobby are played
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LobbyConfig {
+    /// How long a player has to play each of their turns, in seconds
+    pub move_time_secs: u64,
+    /// How long players have to connect once their table is full, in seconds
+    pub connect_time_secs: u64,
+    /// How far apart the ratings of players matched by the queue may be
+    /// at first, the window widens by as much every 30 seconds they wait
+    pub rating_spread: u64,
+    /// The most tables open or playing at once
+    pub max_tables: usize,
+    /// The most tables open or playing an account sits at
+    pub max_tables_per_user: usize,
+    /// How long a table waits for players before it is abandoned, in seconds
+    pub open_table_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: IpAddr::from([0, 0, 0, 0]),
+            port: 3031,
+            database_url: String::new(),
+            pool_size: 1,
+            host_name: HOST_NAME.to_string(),
+            log_level: "info".to_string(),
+            log_format: "text".to_string(),
+            log_dir: None,
+            log_rotation: "daily".to_string(),
+            log_max_files: 14,
+            queue_capacity: 4096,
+            queue_max_wait_ms: 10_000,
+            spool_dir: PathBuf::from("spool"),
+            artifact_dir: PathBuf::from("artifacts"),
+            max_upload_bytes: 64 * 1024 * 1024,
+            admin_token: None,
+            allow_shared_secret: false,
+            shared_secret: None,
+            sqlite: SqliteConfig::default(),
+            runner: RunnerConfig::default(),
+            lobby: LobbyConfig::default(),
+        }
+    }
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        SqliteConfig {
+            journal_mode: "WAL".to_string(),
+            synchronous: "NORMAL".to_string(),
+            temp_store: "MEMORY".to_string(),
+            mmap_size: 30_000_000_000,
+        }
+    }
+}
+
+impl Default for RunnerConfig {
+    fn default() -> Self {
+        RunnerConfig {
+            max_matches: 2,
+            move_time_ms: 5000,
+            connect_time_secs: 30,
+            build_time_secs: 600,
+            build_memory_mb: 2048,
+            vendor_dir: None,
+            work_dir: PathBuf::from("runner"),
+            python: "python3".to_string(),
+            cargo: "cargo".to_string(),
+            sandbox: true,
+            memory_mb: 512,
+            cpu_time_secs: 300,
+            wall_time_secs: 900,
+            max_processes: 64,
+            cgroup_dir: PathBuf::from("/sys/fs/cgroup/stourney"),
+            uid: 65534,
+            gid: 65534,
+        }
+    }
+}
+
+impl Default for LobbyConfig {
+    fn default() -> Self {
+        LobbyConfig {
+            move_time_secs: 60,
+            connect_time_secs: 60,
+            rating_spread: 100,
+            max_tables: 256,
+            max_tables_per_user: 2,
+            open_table_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Collects games played by arenas and serves them to stourney.com"
+)]
+pub struct Cli {
+    /// A TOML file to read settings from
+    #[arg(long, short, env = "STOURNEY_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub overrides: Overrides,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand, PartialEq)]
+pub enum Command {
+    /// Serve arenas and the website, the default
+    Serve,
+    /// Convert game updates saved as JSON by older servers to the
+    /// compressed encoding, then exit
+    Reencode,
+}
+
+/// Settings given as flags or environment variables, taking precedence
+/// over the file
+#[derive(Debug, Default, Args)]
+pub struct Overrides {
+    /// The address to listen on [default: 0.0.0.0]
+    #[arg(long, env = "BIND_ADDRESS")]
+    pub bind_address: Option<IpAddr>,
+    /// The port to listen on [default: 3031]
+    #[arg(long, env = "PORT")]
+    pub port: Option<u16>,
+    /// The sqlite database, such as sqlite://stourney.db
+    #[arg(long, env = "DATABASE_URL")]
+    pub database_url: Option<String>,
+    /// Connections to the database [default: 1]
+    #[arg(long, env = "POOL_SIZE")]
+    pub pool_size: Option<u32>,
+    /// Where the website is hosted [default: https://www.stourney.com]
+    #[arg(long, env = "HOST_NAME")]
+    pub host_name: Option<String>,
+    /// Log filter, such as info or stourney_server=debug [default: info]
+    #[arg(long, env = "RUST_LOG")]
+    pub log_level: Option<String>,
+    /// Log format, text or json [default: text]
+    #[arg(long, env = "LOG_FORMAT")]
+    pub log_format: Option<String>,
+    /// Write rotated log files to this directory instead of stderr
+    #[arg(long, env = "LOG_DIR")]
+    pub log_dir: Option<PathBuf>,
+    /// Start a new log file hourly, daily or never [default: daily]
+    #[arg(long, env = "LOG_ROTATION")]
+    pub log_rotation: Option<String>,
+    /// Log files kept, 0 keeps them all [default: 14]
+    #[arg(long, env = "LOG_MAX_FILES")]
+    pub log_max_files: Option<usize>,
+    /// Updates the queue holds before arenas back off [default: 4096]
+    #[arg(long, env = "QUEUE_CAPACITY")]
+    pub queue_capacity: Option<usize>,
+    /// Milliseconds updates wait for room in the queue [default: 10000]
+    #[arg(long, env = "QUEUE_MAX_WAIT_MS")]
+    pub queue_max_wait_ms: Option<u64>,
+    /// Where queued updates are spooled [default: spool]
+    #[arg(long, env = "SPOOL_DIR")]
+    pub spool_dir: Option<PathBuf>,
+    /// Where uploaded bot artifacts are stored [default: artifacts]
+    #[arg(long, env = "ARTIFACT_DIR")]
+    pub artifact_dir: Option<PathBuf>,
+    /// The largest bot upload in bytes [default: 67108864]
+    #[arg(long, env = "MAX_UPLOAD_BYTES")]
+    pub max_upload_bytes: Option<u64>,
+    /// The x-admin-token of admin requests, nobody is an admin if unset
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+    /// Whether arenas may use the shared secret, for development [default: false]
+    #[arg(long, env = "ALLOW_SHARED_SECRET")]
+    pub allow_shared_secret: Option<bool>,
+    /// The shared secret of allow_shared_secret, nobody can use it if unset
+    #[arg(long, env = "SHARED_SECRET", hide_env_values = true)]
+    pub shared_secret: Option<String>,
+    /// PRAGMA journal_mode [default: WAL]
+    #[arg(long, env = "SQLITE_JOURNAL_MODE")]
+    pub journal_mode: Option<String>,
+    /// PRAGMA synchronous [default: NORMAL]
+    #[arg(long, env = "SQLITE_SYNCHRONOUS")]
+    pub synchronous: Option<String>,
+    /// PRAGMA temp_store [default: MEMORY]
+    #[arg(long, env = "SQLITE_TEMP_STORE")]
+    pub temp_store: Option<String>,
+    /// PRAGMA mmap_size in bytes [default: 30000000000]
+    #[arg(long, env = "SQLITE_MMAP_SIZE")]
+    pub mmap_size: Option<u64>,
+    /// Matches played at once [default: 2]
+    #[arg(long, env = "RUNNER_MAX_MATCHES")]
+    pub max_matches: Option<usize>,
+    /// Milliseconds a bot has for each turn [default: 5000]
+    #[arg(long, env = "RUNNER_MOVE_TIME_MS")]
+    pub move_time_ms: Option<u64>,
+    /// Seconds bots have to connect [default: 30]
+    #[arg(long, env = "RUNNER_CONNECT_TIME_SECS")]
+    pub connect_time_secs: Option<u64>,
+    /// Seconds a rust project has to build [default: 600]
+    #[arg(long, env = "RUNNER_BUILD_TIME_SECS")]
+    pub build_time_secs: Option<u64>,
+    /// MiB of memory the build of a rust project may use [default: 2048]
+    #[arg(long, env = "RUNNER_BUILD_MEMORY_MB")]
+    pub build_memory_mb: Option<u64>,
+    /// The `cargo vendor` directory rust projects are built against
+    #[arg(long, env = "RUNNER_VENDOR_DIR")]
+    pub vendor_dir: Option<PathBuf>,
+    /// Where bots are unpacked and built [default: runner]
+    #[arg(long, env = "RUNNER_WORK_DIR")]
+    pub work_dir: Option<PathBuf>,
+    /// The interpreter python bots are run with [default: python3]
+    #[arg(long, env = "RUNNER_PYTHON")]
+    pub python: Option<String>,
+    /// The cargo rust projects are built with [default: cargo]
+    #[arg(long, env = "RUNNER_CARGO")]
+    pub cargo: Option<String>,
+    /// Whether bots run in a sandbox [default: true]
+    #[arg(long, env = "RUNNER_SANDBOX")]
+    pub sandbox: Option<bool>,
+    /// MiB of memory a bot may use [default: 512]
+    #[arg(long, env = "RUNNER_MEMORY_MB")]
+    pub memory_mb: Option<u64>,
+    /// Seconds of cpu time a bot may use over a match [default: 300]
+    #[arg(long, env = "RUNNER_CPU_TIME_SECS")]
+    pub cpu_time_secs: Option<u64>,
+    /// Seconds of wall-clock time a bot may spend on its turns [default: 900]
+    #[arg(long, env = "RUNNER_WALL_TIME_SECS")]
+    pub wall_time_secs: Option<u64>,
+    /// Processes and threads a bot may run at once [default: 64]
+    #[arg(long, env = "RUNNER_MAX_PROCESSES")]
+    pub max_processes: Option<u64>,
+    /// The cgroup v2 bots are limited in [default: /sys/fs/cgroup/stourney]
+    #[arg(long, env = "RUNNER_CGROUP_DIR")]
+    pub cgroup_dir: Option<PathBuf>,
+    /// The user sandboxed bots run as [default: 65534]
+    #[arg(long, env = "RUNNER_UID")]
+    pub uid: Option<u32>,
+    /// The group sandboxed bots run as [default: 65534]
+    #[arg(long, env = "RUNNER_GID")]
+    pub gid: Option<u32>,
+    /// Seconds a lobby player has for each turn [default: 60]
+    #[arg(long, env = "LOBBY_MOVE_TIME_SECS")]
+    pub lobby_move_time_secs: Option<u64>,
+    /// Seconds lobby players have to connect to a full table [default: 60]
+    #[arg(long, env = "LOBBY_CONNECT_TIME_SECS")]
+    pub lobby_connect_time_secs: Option<u64>,
+    /// Rating difference the matchmaking queue starts from [default: 100]
+    #[arg(long, env = "LOBBY_RATING_SPREAD")]
+    pub rating_spread: Option<u64>,
+    /// Lobby tables open or playing at once [default: 256]
+    #[arg(long, env = "LOBBY_MAX_TABLES")]
+    pub max_tables: Option<usize>,
+    /// Lobby tables an account sits at at once [default: 2]
+    #[arg(long, env = "LOBBY_MAX_TABLES_PER_USER")]
+    pub max_tables_per_user: Option<usize>,
+    /// Seconds a lobby table waits for players [default: 600]
+    #[arg(long, env = "LOBBY_OPEN_TABLE_SECS")]
+    pub lobby_open_table_secs: Option<u64>,
+}
+
+impl Config {
+    /// Parses the settings in a TOML file, keeping the defaults for the
+    /// ones it leaves out
+    pub fn from_toml(text: &str) -> Result<Config, String> {
+        toml::from_str(text).map_err(|error| error.to_string())
+    }
+
+    /// Reads the settings in a TOML file
+    pub fn from_file(path: &Path) -> Result<Config, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| format!("cannot read {}: {}", path.display(), error))?;
+        Config::from_toml(&text).map_err(|error| format!("in {}: {}", path.display(), error))
+    }
+
+    /// Replaces the settings given as flags or environment variables
+    pub fn apply(mut self, overrides: Overrides) -> Config {
+        fn set<T>(setting: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *setting = value;
+            }
+        }
+        set(&mut self.bind_address, overrides.bind_address);
+        set(&mut self.port, overrides.port);
+        set(&mut self.database_url, overrides.database_url);
+        set(&mut self.pool_size, overrides.pool_size);
+        set(&mut self.host_name, overrides.host_name);
+        set(&mut self.log_level, overrides.log_level);
+        set(&mut self.log_format, overrides.log_format);
+        set(&mut self.log_dir, overrides.log_dir.map(Some));
+        set(&mut self.log_rotation, overrides.log_rotation);
+        set(&mut self.log_max_files, overrides.log_max_files);
+        set(&mut self.queue_capacity, overrides.queue_capacity);
+        set(&mut self.queue_max_wait_ms, overrides.queue_max_wait_ms);
+        set(&mut self.spool_dir, overrides.spool_dir);
+        set(&mut self.artifact_dir, overrides.artifact_dir);
+        set(&mut self.max_upload_bytes, overrides.max_upload_bytes);
+        set(&mut self.admin_token, overrides.admin_token.map(Some));
+        set(&mut self.allow_shared_secret, overrides.allow_shared_secret);
+        set(&mut self.shared_secret, overrides.shared_secret.map(Some));
+        set(&mut self.sqlite.journal_mode, overrides.journal_mode);
+        set(&mut self.sqlite.synchronous, overrides.synchronous);
+        set(&mut self.sqlite.temp_store, overrides.temp_store);
+        set(&mut self.sqlite.mmap_size, overrides.mmap_size);
+        set(&mut self.runner.max_matches, overrides.max_matches);
+        set(&mut self.runner.move_time_ms, overrides.move_time_ms);
+        set(
+            &mut self.runner.connect_time_secs,
+            overrides.connect_time_secs,
+        );
+        set(&mut self.runner.build_time_secs, overrides.build_time_secs);
+        set(&mut self.runner.build_memory_mb, overrides.build_memory_mb);
+        set(&mut self.runner.vendor_dir, overrides.vendor_dir.map(Some));
+        set(&mut self.runner.work_dir, overrides.work_dir);
+        set(&mut self.runner.python, overrides.python);
+        set(&mut self.runner.cargo, overrides.cargo);
+        set(&mut self.runner.sandbox, overrides.sandbox);
+        set(&mut self.runner.memory_mb, overrides.memory_mb);
+        set(&mut self.runner.cpu_time_secs, overrides.cpu_time_secs);
+        set(&mut self.runner.wall_time_secs, overrides.wall_time_secs);
+        set(&mut self.runner.max_processes, overrides.max_processes);
+        set(&mut self.runner.cgroup_dir, overrides.cgroup_dir);
+        set(&mut self.runner.uid, overrides.uid);
+        set(&mut self.runner.gid, overrides.gid);
+        set(
+            &mut self.lobby.move_time_secs,
+            overrides.lobby_move_time_secs,
+        );
+        set(
+            &mut self.lobby.connect_time_secs,
+            overrides.lobby_connect_time_secs,
+        );
+        set(&mut self.lobby.rating_spread, overrides.rating_spread);
+        set(&mut self.lobby.max_tables, overrides.max_tables);
+        set(
+            &mut self.lobby.max_tables_per_user,
+            overrides.max_tables_per_user,
+        );
+        set(
+            &mut self.lobby.open_table_secs,
+            overrides.lobby_open_table_secs,
+        );
+        self
+    }
+
+    /// Checks the settings make sense together, normalizing the pragma
+    /// names to upper case, the log settings to lower case and removing
+    /// the trailing slash of the host
+    pub fn validate(mut self) -> Result<Config, String> {
+        if self.database_url.is_empty() {
+            return Err("database_url must be set, for example sqlite://stourney.db".to_string());
+        }
+        if !self.database_url.starts_with("sqlite:") {
+            return Err(format!(
+                "database_url must be a sqlite url, got {}",
+                self.database_url
+            ));
+        }
+        if self.port == 0 {
+            return Err("port must not be 0".to_string());
+        }
+        if self.pool_size == 0 {
+            return Err("pool_size must be at least 1".to_string());
+        }
+        if self.queue_capacity == 0 {
+            return Err("queue_capacity must be at least 1".to_string());
+        }
+        if self.max_upload_bytes == 0 {
+            return Err("max_upload_bytes must be at least 1".to_string());
+        }
+        if self.log_level.trim().is_empty() {
+            return Err("log_level must not be empty".to_string());
+        }
+        let runner = &self.runner;
+        for (name, value) in [
+            ("max_matches", runner.max_matches as u64),
+            ("move_time_ms", runner.move_time_ms),
+            ("connect_time_secs", runner.connect_time_secs),
+            ("build_time_secs", runner.build_time_secs),
+            ("build_memory_mb", runner.build_memory_mb),
+            ("memory_mb", runner.memory_mb),
+            ("cpu_time_secs", runner.cpu_time_secs),
+            ("wall_time_secs", runner.wall_time_secs),
+            ("max_processes", runner.max_processes),
+        ] {
+            if value == 0 {
+                return Err(format!("runner.{} must be at least 1", name));
+            }
+        }
+        if runner.python.trim().is_empty() || runner.cargo.trim().is_empty() {
+            return Err("runner.python and runner.cargo must not be empty".to_string());
+        }
+        let lobby = &self.lobby;
+        for (name, value) in [
+            ("move_time_secs", lobby.move_time_secs),
+            ("connect_time_secs", lobby.connect_time_secs),
+            ("max_tables", lobby.max_tables as u64),
+            ("max_tables_per_user", lobby.max_tables_per_user as u64),
+            ("open_table_secs", lobby.open_table_secs),
+        ] {
+            if value == 0 {
+                return Err(format!("lobby.{} must be at least 1", name));
+            }
+        }
+
+        for (name, value, allowed) in [
+            ("log_format", &mut self.log_format, &LOG_FORMATS[..]),
+            ("log_rotation", &mut self.log_rotation, &LOG_ROTATIONS[..]),
+        ] {
+            *value = value.to_lowercase();
+            if !allowed.contains(&value.as_str()) {
+                return Err(format!(
+                    "{} must be one of {}, got {}",
+                    name,
+                    allowed.join(", "),
+                    value
+                ));
+            }
+        }
+
+        self.host_name = self.host_name.trim_end_matches('/').to_string();
+        if !self.host_name.starts_with("http://") && !self.host_name.starts_with("https://") {
+            return Err(format!(
+                "host_name must start with http:// or https://, got {}",
+                self.host_name
+            ));
+        }
+
+        let sqlite = &mut self.sqlite;
+        for (name, value, allowed) in [
+            ("journal_mode", &mut sqlite.journal_mode, &JOURNAL_MODES[..]),
+            (
+                "synchronous",
+                &mut sqlite.synchronous,
+                &SYNCHRONOUS_MODES[..],
+            ),
+            ("temp_store", &mut sqlite.temp_store, &TEMP_STORES[..]),
+        ] {
+            *value = value.to_uppercase();
+            if !allowed.contains(&value.as_str()) {
+                return Err(format!(
+                    "sqlite.{} must be one of {}, got {}",
+                    name,
+                    allowed.join(", "),
+                    value
+                ));
+            }
+        }
+        Ok(self)
+    }
+
+    /// The file of the database, None for an in-memory one
+    pub fn database_file(&self) -> Option<PathBuf> {
+        let url = self.database_url.strip_prefix("sqlite:")?;
+        let path = url.strip_prefix("//").unwrap_or(url);
+        let path = path.split('?').next().unwrap_or_default();
+        match path {
+            "" | ":memory:" => None,
+            path => Some(PathBuf::from(path)),
+        }
+    }
+}
+
+/// Loads and validates the settings from the file, environment and flags
+pub fn load(cli: Cli) -> Result<Config, String> {
+    let config = match &cli.config {
+        Some(path) => Config::from_file(path)?,
+        None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+            Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+        }
+        None => Config::default(),
+    };
+    config.apply(cli.overrides).validate()
+}
+
+/// Makes the settings available through get(), can only be called once
+pub fn init(config: Config) {
+    CONFIG
+        .set(config)
+        .expect("Configuration was already initialized");
+}
+
+/// The settings of the server, the defaults if init() was never called
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
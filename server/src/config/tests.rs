@@ -0,0 +1,131 @@
+use super::*;
+
+fn valid() -> Config {
+    Config {
+        database_url: "sqlite://stourney.db".to_string(),
+        ..Config::default()
+    }
+}
+
+#[test]
+pub fn file_keeps_defaults_for_missing_settings() {
+    let config = Config::from_toml(
+        r#"
+        port = 4000
+        database_url = "sqlite://games.db"
+
+        [sqlite]
+        mmap_size = 268435456
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.port, 4000);
+    assert_eq!(config.database_url, "sqlite://games.db");
+    assert_eq!(config.sqlite.mmap_size, 268435456);
+    assert_eq!(config.sqlite.journal_mode, "WAL");
+    assert_eq!(config.queue_capacity, Config::default().queue_capacity);
+}
+
+#[test]
+pub fn file_with_unknown_setting_is_rejected() {
+    let error = Config::from_toml("prot = 4000").unwrap_err();
+    assert!(error.contains("prot"), "unexpected error {}", error);
+}
+
+#[test]
+pub fn flags_take_precedence_over_the_file() {
+    let config = Config::from_toml("port = 4000\npool_size = 2").unwrap();
+    let overrides = Overrides {
+        port: Some(5000),
+        journal_mode: Some("delete".to_string()),
+        ..Overrides::default()
+    };
+    let config = config.apply(overrides);
+    assert_eq!(config.port, 5000);
+    assert_eq!(config.pool_size, 2);
+    assert_eq!(config.sqlite.journal_mode, "delete");
+}
+
+#[test]
+pub fn flags_are_parsed_into_overrides() {
+    let cli = Cli::try_parse_from([
+        "stourney_server",
+        "--port",
+        "4000",
+        "--bind-address",
+        "127.0.0.1",
+        "--admin-token",
+        "secret",
+        "reencode",
+    ])
+    .unwrap();
+    assert_eq!(cli.overrides.port, Some(4000));
+    assert_eq!(cli.overrides.admin_token.as_deref(), Some("secret"));
+    assert_eq!(
+        cli.overrides.bind_address,
+        Some(IpAddr::from([127, 0, 0, 1]))
+    );
+    assert_eq!(cli.command, Some(Command::Reencode));
+}
+
+#[test]
+pub fn validation_normalizes_settings() {
+    let mut config = valid();
+    config.host_name = "https://example.com/".to_string();
+    config.sqlite.synchronous = "full".to_string();
+    config.log_format = "JSON".to_string();
+    let config = config.validate().unwrap();
+    assert_eq!(config.host_name, "https://example.com");
+    assert_eq!(config.sqlite.synchronous, "FULL");
+    assert_eq!(config.log_format, "json");
+}
+
+#[test]
+pub fn validation_rejects_invalid_settings() {
+    assert!(
+        Config::default().validate().is_err(),
+        "database_url is required"
+    );
+
+    let mut config = valid();
+    config.sqlite.journal_mode = "WAL; DROP TABLE games".to_string();
+    assert!(config.validate().is_err());
+
+    let mut config = valid();
+    config.pool_size = 0;
+    assert!(config.validate().is_err());
+
+    let mut config = valid();
+    config.host_name = "stourney.com".to_string();
+    assert!(config.validate().is_err());
+
+    let mut config = valid();
+    config.log_rotation = "weekly".to_string();
+    assert!(config.validate().is_err());
+
+    let mut config = valid();
+    config.runner.move_time_ms = 0;
+    assert!(config.validate().is_err());
+
+    let mut config = valid();
+    config.lobby.connect_time_secs = 0;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+pub fn database_file_is_read_from_the_url() {
+    let file = |url: &str| {
+        Config {
+            database_url: url.to_string(),
+            ..Config::default()
+        }
+        .database_file()
+    };
+    assert_eq!(file("sqlite://stourney.db"), Some("stourney.db".into()));
+    assert_eq!(
+        file("sqlite:///var/lib/stourney/games.db?mode=rwc"),
+        Some("/var/lib/stourney/games.db".into())
+    );
+    assert_eq!(file("sqlite:games.db"), Some("games.db".into()));
+    assert_eq!(file("sqlite::memory:"), None);
+}
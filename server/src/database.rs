@@ -1,80 +1,244 @@
-use crate::slug_list::{ADJECTIVES, NOUNS};
-use log::{debug, info, trace};
-use rand::Rng;
+#[cfg(test)]
+pub mod tests;
+
+use crate::config::{Config, SqliteConfig};
+use crate::encoding::{self, Encoding};
+use crate::ratings::{self, RatingChange};
+use crate::runner::MatchFailure;
+use crate::slugs;
 use splendor_arena::models::GameUpdate;
-use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+use splendor_arena::SmallClientInfo;
+use sqlx::sqlite::{
+    Sqlite, SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions,
+    SqliteSynchronous,
+};
 use sqlx::Row;
+use sqlx::Transaction;
+use std::collections::HashSet;
+use std::path::Path;
+use std::str::FromStr;
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-/// Connects to the database and returns a pool
-/// Requires the DATABASE_URL environment variable is set
-pub async fn connect() -> Result<SqlitePool, sqlx::Error> {
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-
+/// Connects to the database in the configuration and returns a pool
+pub async fn connect(config: &Config) -> Result<SqlitePool, sqlx::Error> {
     // We are using Sqlite, so it is recommended to have only one connection
     // TODO: conventional wisdom may fail in this instance, so perhaps tweak later
     let pool = SqlitePoolOptions::new()
-        .max_connections(1)
-        .connect(&database_url)
+        .max_connections(config.pool_size)
+        .connect_with(connect_options(&config.database_url, &config.sqlite)?)
         .await?;
     info!("Connected to database!");
-    init_schema(&pool).await;
-    sqlite_startup(&pool).await;
+    if let Some(file) = config.database_file() {
+        make_private(&file);
+    }
+    migrate(&pool).await?;
     Ok(pool)
 }
 
-/// The function that is called when the database is started
-/// responsible for setting up the database to get high performance
-/// speeds on sqlite
-pub async fn sqlite_startup(pool: &SqlitePool) {
-    // Set option to use write ahead logging
-    // which means multiple concurrent readers even
-    // during open write transactions
-    sqlx::query("PRAGMA journal_mode = WAL")
-        .execute(pool)
-        .await
-        .expect("Failed to set journal mode to WAL");
+/// Lets only the server read the database and its journals, bots run by
+/// the runner have them hidden as well, see runner::sandbox
+fn make_private(file: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    for suffix in ["", "-wal", "-shm"] {
+        let mut path = file.as_os_str().to_owned();
+        path.push(suffix);
+        let private = std::fs::Permissions::from_mode(0o600);
+        match std::fs::set_permissions(&path, private) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                warn!("[-] Cannot make {:?} private: {}", path, error)
+            }
+            _ => {}
+        }
+    }
+}
 
-    // Since we are using WAL mode, we
-    // don't need synchronous writes as WAL guarantees
-    // consistency in synchronous = normal mode
-    sqlx::query("PRAGMA synchronous = NORMAL")
-        .execute(pool)
-        .await
-        .expect("Failed to set synchronous mode to NORMAL");
+/// The options every connection of the pool is opened with, set up to get
+/// high performance speeds on sqlite. Pragmas are per connection, so they
+/// are set here rather than run once on the pool. The pragma values are
+/// checked by Config::validate
+pub fn connect_options(
+    url: &str,
+    config: &SqliteConfig,
+) -> Result<SqliteConnectOptions, sqlx::Error> {
+    Ok(SqliteConnectOptions::from_str(url)?
+        // Set option to use write ahead logging (WAL by default)
+        // which means multiple concurrent readers even
+        // during open write transactions
+        .journal_mode(SqliteJournalMode::from_str(&config.journal_mode)?)
+        // Since we are using WAL mode, we
+        // don't need synchronous writes as WAL guarantees
+        // consistency in synchronous = normal mode
+        .synchronous(SqliteSynchronous::from_str(&config.synchronous)?)
+        // Store temporary tables and files in memory,
+        // we will lose data if the database is closed but
+        // this is a trade off for speed
+        .pragma("temp_store", config.temp_store.clone())
+        // Use memory mapped I/O for reading and writing
+        // as it can be faster than normal I/O
+        // note this has implications for I/O errors on sqlite
+        .pragma("mmap_size", config.mmap_size.to_string())
+        // Must turn on support for foreign keys constraint
+        // as it is off by default on sqlite
+        .foreign_keys(true))
+}
 
-    // Store temporary tables and files in memory,
-    // we will lose data if the database is closed but
-    // this is a trade off for speed
-    sqlx::query("PRAGMA temp_store = MEMORY")
-        .execute(pool)
-        .await
-        .expect("Failed to set temp store to MEMORY");
+/// Every migration of the schema in the order it must be applied as
+/// (version, name, sql), the files live in src/migrations.
+///
+/// Migrations are forward only: never edit one that has been released,
+/// add a new one with the next version instead. scripts/migrate.sh applies
+/// the same files before the sqlx compile time checks run
+const MIGRATIONS: &[(i64, &str, &str)] = &[
+    (1, "initial", include_str!("migrations/0001_initial.sql")),
+    (
+        2,
+        "game_results",
+        include_str!("migrations/0002_game_results.sql"),
+    ),
+    (
+        3,
+        "game_listing",
+        include_str!("migrations/0003_game_listing.sql"),
+    ),
+    (4, "api_keys", include_str!("migrations/0004_api_keys.sql")),
+    (5, "accounts", include_str!("migrations/0005_accounts.sql")),
+    (6, "ratings", include_str!("migrations/0006_ratings.sql")),
+    (
+        7,
+        "seat_metadata",
+        include_str!("migrations/0007_seat_metadata.sql"),
+    ),
+    (
+        8,
+        "turn_tables",
+        include_str!("migrations/0008_turn_tables.sql"),
+    ),
+    (
+        9,
+        "update_encoding",
+        include_str!("migrations/0009_update_encoding.sql"),
+    ),
+    (
+        10,
+        "slug_aliases",
+        include_str!("migrations/0010_slug_aliases.sql"),
+    ),
+    (
+        11,
+        "bot_versions",
+        include_str!("migrations/0011_bot_versions.sql"),
+    ),
+    (12, "forfeits", include_str!("migrations/0012_forfeits.sql")),
+    (
+        13,
+        "tournaments",
+        include_str!("migrations/0013_tournaments.sql"),
+    ),
+    (
+        14,
+        "game_keys",
+        include_str!("migrations/0014_game_keys.sql"),
+    ),
+];
 
-    // Use memory mapped I/O for reading and writing
-    // as it can be faster than normal I/O
-    // note this has implications for I/O errors on sqlite
-    sqlx::query("PRAGMA mmap_size = 30000000000")
-        .execute(pool)
-        .await
-        .expect("Failed to set mmap size to 256MB");
+/// The version of the schema this build of the server expects
+pub const SCHEMA_VERSION: i64 = MIGRATIONS[MIGRATIONS.len() - 1].0;
 
-    // Must turn on support for foreign keys constraint
-    // as it is off by default on sqlite
-    sqlx::query("PRAGMA foreign_keys = ON")
-        .execute(pool)
-        .await
-        .expect("Failed to turn on foreign keys");
+/// What each schema version left behind in databases created before
+/// versioned migrations existed, when the schema was re-run on every boot.
+/// scripts/migrate.sh checks the same markers
+const LEGACY_MARKERS: &[(i64, &str)] = &[
+    (
+        1,
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'games'",
+    ),
+    (
+        2,
+        "SELECT COUNT(*) FROM pragma_table_info('games') WHERE name = 'finished_at'",
+    ),
+];
+
+/// Works out the version of a database created before versioned
+/// migrations existed, the last version whose marker it has
+async fn legacy_schema_version(pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+    let mut version = 0;
+    for (marker_version, marker) in LEGACY_MARKERS {
+        let found: i64 = sqlx::query_scalar(marker).fetch_one(pool).await?;
+        if found == 0 {
+            break;
+        }
+        version = *marker_version;
+    }
+    Ok(version)
 }
 
-/// Initializes the schema for the database, be sure that
-/// the schema.sql file is in the same directory as src
-pub async fn init_schema(pool: &SqlitePool) {
-    let schema = include_str!("schema.sql");
-    sqlx::query(&schema)
-        .execute(pool)
-        .await
-        .expect("Failed to create schema");
+/// Brings the schema up to date by applying every migration newer than the
+/// version recorded in the schema_version table, each in its own transaction.
+/// Refuses to touch a database that was migrated by a newer build
+pub async fn migrate(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::raw_sql(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    let current: Option<i64> = sqlx::query_scalar("SELECT MAX(version) FROM schema_version")
+        .fetch_one(pool)
+        .await?;
+
+    let current = match current {
+        Some(version) => version,
+        None => {
+            let version = legacy_schema_version(pool).await?;
+            let mut transaction = pool.begin().await?;
+            for (baseline, name, _) in MIGRATIONS.iter().filter(|(v, _, _)| *v <= version) {
+                sqlx::query("INSERT INTO schema_version (version, name) VALUES (?, ?)")
+                    .bind(baseline)
+                    .bind(name)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+            transaction.commit().await?;
+            if version > 0 {
+                info!(
+                    "[+] Adopted existing database at schema version {}",
+                    version
+                );
+            }
+            version
+        }
+    };
+
+    if current > SCHEMA_VERSION {
+        return Err(sqlx::Error::Configuration(
+            format!(
+                "database schema is at version {} but this server only knows up to version {}, \
+                 refusing to start against a newer schema",
+                current, SCHEMA_VERSION
+            )
+            .into(),
+        ));
+    }
+
+    for (version, name, sql) in MIGRATIONS.iter().filter(|(v, _, _)| *v > current) {
+        info!("[+] Applying migration {:04}_{}", version, name);
+        let mut transaction = pool.begin().await?;
+        sqlx::raw_sql(sql).execute(&mut *transaction).await?;
+        sqlx::query("INSERT INTO schema_version (version, name) VALUES (?, ?)")
+            .bind(version)
+            .bind(name)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+    }
+
+    debug!("[+] Database schema is at version {}", SCHEMA_VERSION);
+    Ok(())
 }
 
 /// Generates a new unique game id and saves it to the database
@@ -90,103 +254,869 @@ pub async fn generate_new_id(pool: &SqlitePool) -> Uuid {
     uuid
 }
 
-/// Serializes the game update and saves it to the database,
-/// "simple" because this doesn't make the info very queryable,
-/// but is good enough for storing data
-pub async fn simple_save_game_update(pool: &SqlitePool, game_update: GameUpdate, uuid: Uuid) {
-    debug!("[+] Saving game update...");
+/// Saves a batch of game updates in order, in a single transaction. The
+/// update is serialized, and as the encoded update itself isn't queryable
+/// it is also written out to the turn tables. If any of the updates fails
+/// to save the transaction is rolled back and none of them are
+pub async fn save_game_updates(
+    pool: &SqlitePool,
+    updates: &[(Uuid, GameUpdate)],
+) -> Result<(), sqlx::Error> {
+    debug!("[+] Saving {} game updates...", updates.len());
+    let mut tx = pool.begin().await?;
+    for (uuid, game_update) in updates {
+        save_game_update(&mut tx, *uuid, game_update).await?;
+    }
+    tx.commit().await
+}
+
+async fn save_game_update(
+    tx: &mut Transaction<'_, Sqlite>,
+    uuid: Uuid,
+    game_update: &GameUpdate,
+) -> Result<(), sqlx::Error> {
     let uuid = uuid.to_string();
     let turnid = game_update.update_num as i32;
+    let num_players = game_update.info.players.len() as i64;
+    let (encoding, payload) = encode_for_storage(tx, &uuid, game_update).await?;
+    let encoding = encoding as i64;
+    sqlx::query!(
+        "INSERT INTO game_updates (update_uuid, turn_id, encoding, payload) VALUES (?, ?, ?, ?)
+         ON CONFLICT (update_uuid, turn_id) DO UPDATE
+         SET game_update = NULL, encoding = excluded.encoding, payload = excluded.payload",
+        uuid,
+        turnid,
+        encoding,
+        payload
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    save_turn_tables(tx, &uuid, turnid, &game_update.info).await?;
+
+    // Keep the summary used for listing games up to date
+    sqlx::query!(
+        "UPDATE games SET last_updated = CURRENT_TIMESTAMP, num_players = ?,
+         latest_turn = MAX(IFNULL(latest_turn, 0), ?)
+         WHERE game_uuid = ?",
+        num_players,
+        turnid,
+        uuid
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
 
-    let game_exists = sqlx::query!(
-        "SELECT update_uuid FROM game_updates WHERE update_uuid = ? AND turn_id = ?",
+/// Writes one turn out to the turn_boards, turn_players, turn_cards and
+/// turn_nobles tables, replacing whatever was saved for that turn before.
+/// Rows are upserted, and only rows the turn no longer has are deleted
+async fn save_turn_tables(
+    tx: &mut Transaction<'_, Sqlite>,
+    uuid: &str,
+    turnid: i32,
+    info: &SmallClientInfo,
+) -> Result<(), sqlx::Error> {
+    let board = &info.board;
+    let current_player = info.current_player_num as i64;
+    let decks = board.deck_counts.map(|count| count as i64);
+    sqlx::query!(
+        r#"INSERT INTO turn_boards (game_uuid, turn_id, current_player,
+           deck_tier1, deck_tier2, deck_tier3, bank_onyx, bank_sapphire,
+           bank_emerald, bank_ruby, bank_diamond, bank_gold)
+           VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+           ON CONFLICT (game_uuid, turn_id) DO UPDATE SET
+           current_player = excluded.current_player, deck_tier1 = excluded.deck_tier1,
+           deck_tier2 = excluded.deck_tier2, deck_tier3 = excluded.deck_tier3,
+           bank_onyx = excluded.bank_onyx, bank_sapphire = excluded.bank_sapphire,
+           bank_emerald = excluded.bank_emerald, bank_ruby = excluded.bank_ruby,
+           bank_diamond = excluded.bank_diamond, bank_gold = excluded.bank_gold"#,
         uuid,
-        turnid
+        turnid,
+        current_player,
+        decks[0],
+        decks[1],
+        decks[2],
+        board.gems.onyx,
+        board.gems.sapphire,
+        board.gems.emerald,
+        board.gems.ruby,
+        board.gems.diamond,
+        board.gems.gold
     )
-    .fetch_all(pool)
-    .await
-    .expect("Failed to query database");
+    .execute(&mut **tx)
+    .await?;
 
-    let game_update = serde_json::to_string(&game_update).unwrap();
-    if game_exists.len() == 0 {
+    for (seat, player) in info.players.iter().enumerate() {
+        let seat = seat as i64;
+        let num_reserved = player.num_reserved as i64;
         sqlx::query!(
-            "INSERT INTO game_updates (update_uuid, turn_id, game_update) VALUES (?, ?, ?)",
+            r#"INSERT INTO turn_players (game_uuid, turn_id, seat, points, num_reserved,
+               gems_onyx, gems_sapphire, gems_emerald, gems_ruby, gems_diamond, gems_gold,
+               developments_onyx, developments_sapphire, developments_emerald,
+               developments_ruby, developments_diamond)
+               VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+               ON CONFLICT (game_uuid, turn_id, seat) DO UPDATE SET
+               points = excluded.points, num_reserved = excluded.num_reserved,
+               gems_onyx = excluded.gems_onyx, gems_sapphire = excluded.gems_sapphire,
+               gems_emerald = excluded.gems_emerald, gems_ruby = excluded.gems_ruby,
+               gems_diamond = excluded.gems_diamond, gems_gold = excluded.gems_gold,
+               developments_onyx = excluded.developments_onyx,
+               developments_sapphire = excluded.developments_sapphire,
+               developments_emerald = excluded.developments_emerald,
+               developments_ruby = excluded.developments_ruby,
+               developments_diamond = excluded.developments_diamond"#,
             uuid,
             turnid,
-            game_update
+            seat,
+            player.points,
+            num_reserved,
+            player.gems.onyx,
+            player.gems.sapphire,
+            player.gems.emerald,
+            player.gems.ruby,
+            player.gems.diamond,
+            player.gems.gold,
+            player.developments.onyx,
+            player.developments.sapphire,
+            player.developments.emerald,
+            player.developments.ruby,
+            player.developments.diamond
         )
-        .execute(pool)
-        .await
-        .expect("Failed to insert game update");
-    } else {
-        trace!("[+] Updating existing game update {}", uuid);
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    for (tier, cards) in board.available_cards.iter().enumerate() {
+        for (position, &card_id) in cards.iter().enumerate() {
+            let (tier, position) = (tier as i64, position as i64);
+            sqlx::query!(
+                r#"INSERT INTO turn_cards (game_uuid, turn_id, tier, position, card_id)
+                   VALUES (?, ?, ?, ?, ?)
+                   ON CONFLICT (game_uuid, turn_id, tier, position) DO UPDATE
+                   SET card_id = excluded.card_id"#,
+                uuid,
+                turnid,
+                tier,
+                position,
+                card_id
+            )
+            .execute(&mut **tx)
+            .await?;
+        }
+    }
+
+    for (position, &noble_id) in board.nobles.iter().enumerate() {
+        let position = position as i64;
         sqlx::query!(
-            "UPDATE game_updates SET game_update = ? WHERE update_uuid = ? AND turn_id = ?",
-            game_update,
+            r#"INSERT INTO turn_nobles (game_uuid, turn_id, position, noble_id)
+               VALUES (?, ?, ?, ?)
+               ON CONFLICT (game_uuid, turn_id, position) DO UPDATE
+               SET noble_id = excluded.noble_id"#,
             uuid,
-            turnid
+            turnid,
+            position,
+            noble_id
         )
-        .execute(pool)
-        .await
-        .expect("Failed to update game update");
+        .execute(&mut **tx)
+        .await?;
     }
+
+    // A turn saved again with fewer players, cards or nobles than before
+    let num_players = info.players.len() as i64;
+    let num_nobles = board.nobles.len() as i64;
+    let cards_per_tier = serde_json::to_string(
+        &board
+            .available_cards
+            .iter()
+            .map(Vec::len)
+            .collect::<Vec<_>>(),
+    )
+    .expect("Failed to serialize");
+    sqlx::query!(
+        "DELETE FROM turn_players WHERE game_uuid = ? AND turn_id = ? AND seat >= ?",
+        uuid,
+        turnid,
+        num_players
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM turn_cards WHERE game_uuid = ?1 AND turn_id = ?2
+           AND position >= IFNULL(json_extract(?3, '$[' || tier || ']'), 0)"#,
+        uuid,
+        turnid,
+        cards_per_tier
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM turn_nobles WHERE game_uuid = ? AND turn_id = ? AND position >= ?",
+        uuid,
+        turnid,
+        num_nobles
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
 }
 
-/// Loads the game update from the database
-pub async fn load_game_update(pool: &SqlitePool, uuid: Uuid, turnid: i32) -> Option<GameUpdate> {
-    let uuid = uuid.to_string();
-    let game_update = sqlx::query!(
-        "SELECT game_update FROM game_updates WHERE update_uuid = ? AND turn_id = ?",
+/// A row of game_updates, in whichever encoding it was saved with
+struct StoredUpdate {
+    turn_id: i64,
+    encoding: i64,
+    game_update: Option<String>,
+    payload: Option<Vec<u8>>,
+}
+
+/// Loads the serialized keyframe that the deltas of a turn are encoded
+/// against, None if that turn is missing or is not a keyframe. Deltas are
+/// compressed with the serialized keyframe as their zstd dictionary, so
+/// they can only be decoded with these exact bytes
+async fn load_keyframe<'e, E>(
+    executor: E,
+    uuid: &str,
+    turnid: i64,
+) -> Result<Option<Vec<u8>>, String>
+where
+    E: sqlx::SqliteExecutor<'e>,
+{
+    let keyframe_turn = encoding::keyframe_turn(turnid as usize) as i64;
+    let keyframe = Encoding::Keyframe as i64;
+    let row = sqlx::query!(
+        "SELECT payload FROM game_updates WHERE update_uuid = ? AND turn_id = ? AND encoding = ?",
         uuid,
-        turnid
+        keyframe_turn,
+        keyframe
     )
-    .fetch_one(pool)
-    .await;
-    if let Ok(game_update) = game_update {
-        let game_update: Option<String> = game_update.game_update;
-        game_update.map(|game_update| {
-            serde_json::from_str(&game_update).expect("could not deserialize game update")
-        })
+    .fetch_optional(executor)
+    .await
+    .expect("Failed to query database");
+
+    let Some(payload) = row.and_then(|row| row.payload) else {
+        return Ok(None);
+    };
+    encoding::decompress_keyframe(&payload)
+        .map(Some)
+        .map_err(|error| format!("corrupt keyframe of turn {}: {}", keyframe_turn, error))
+}
+
+/// Decodes a stored row, given the serialized keyframe when it is a delta.
+/// None if it is a JSON row without an update, an error if it is corrupt
+/// or is a delta whose keyframe is missing
+fn decode_stored(
+    row: &StoredUpdate,
+    keyframe: Option<&[u8]>,
+) -> Result<Option<GameUpdate>, String> {
+    let corrupt = |error: String| format!("corrupt update of turn {}: {}", row.turn_id, error);
+    let Some(encoding) = Encoding::from_i64(row.encoding) else {
+        return Err(corrupt(format!("unknown encoding {}", row.encoding)));
+    };
+    let decoded = match (encoding, &row.game_update, &row.payload, keyframe) {
+        (Encoding::Json, None, _, _) => return Ok(None),
+        (Encoding::Json, Some(game_update), _, _) => {
+            serde_json::from_str(game_update).map_err(|error| error.to_string())
+        }
+        (Encoding::Keyframe, _, Some(payload), _) => {
+            encoding::decode(payload, None).map_err(|error| error.to_string())
+        }
+        (Encoding::Delta, _, Some(payload), Some(keyframe)) => {
+            encoding::decode(payload, Some(keyframe)).map_err(|error| error.to_string())
+        }
+        (Encoding::Delta, _, Some(_), None) => Err(format!(
+            "missing keyframe of turn {}",
+            encoding::keyframe_turn(row.turn_id as usize)
+        )),
+        (_, _, None, _) => Err("missing payload".to_string()),
+    };
+    decoded.map(Some).map_err(corrupt)
+}
+
+/// Decodes a single stored row, loading its keyframe if it needs one
+async fn decode_with_keyframe<'e, E>(
+    executor: E,
+    uuid: &str,
+    row: &StoredUpdate,
+) -> Result<Option<GameUpdate>, String>
+where
+    E: sqlx::SqliteExecutor<'e>,
+{
+    let keyframe = if row.encoding == Encoding::Delta as i64 {
+        load_keyframe(executor, uuid, row.turn_id).await?
     } else {
         None
+    };
+    decode_stored(row, keyframe.as_deref())
+}
+
+/// Picks the smallest encoding a new update can be stored in: a delta if
+/// the keyframe of its turn is saved, otherwise a keyframe. Overwriting a
+/// keyframe re-encodes the deltas saved against it first
+async fn encode_for_storage(
+    tx: &mut Transaction<'_, Sqlite>,
+    uuid: &str,
+    game_update: &GameUpdate,
+) -> Result<(Encoding, Vec<u8>), sqlx::Error> {
+    let turnid = game_update.update_num;
+    // A corrupt keyframe is overwritten, or left without new deltas
+    if encoding::keyframe_turn(turnid) != turnid {
+        if let Ok(Some(keyframe)) = load_keyframe(&mut **tx, uuid, turnid as i64).await {
+            return Ok((
+                Encoding::Delta,
+                encoding::encode_delta(game_update, &keyframe),
+            ));
+        }
+        return Ok((Encoding::Keyframe, encoding::encode_keyframe(game_update)));
+    }
+
+    let new_keyframe = encoding::serialize(game_update);
+    if let Ok(Some(old_keyframe)) = load_keyframe(&mut **tx, uuid, turnid as i64).await {
+        let first = turnid as i64 + 1;
+        let last = (turnid + encoding::KEYFRAME_INTERVAL) as i64 - 1;
+        let delta = Encoding::Delta as i64;
+        let dependents = sqlx::query_as!(
+            StoredUpdate,
+            r#"SELECT turn_id AS "turn_id!", encoding, game_update, payload FROM game_updates
+               WHERE update_uuid = ? AND turn_id BETWEEN ? AND ? AND encoding = ?"#,
+            uuid,
+            first,
+            last,
+            delta
+        )
+        .fetch_all(&mut **tx)
+        .await?;
+
+        for row in dependents {
+            let Ok(Some(update)) = decode_stored(&row, Some(&old_keyframe)) else {
+                continue;
+            };
+            let payload = encoding::encode_delta(&update, &new_keyframe);
+            sqlx::query!(
+                "UPDATE game_updates SET payload = ? WHERE update_uuid = ? AND turn_id = ?",
+                payload,
+                uuid,
+                row.turn_id
+            )
+            .execute(&mut **tx)
+            .await?;
+        }
+    }
+    Ok((Encoding::Keyframe, encoding::encode_keyframe(game_update)))
+}
+
+/// Loads the game update from the database, an error if it is corrupt
+pub async fn load_game_update(
+    pool: &SqlitePool,
+    uuid: Uuid,
+    turnid: i32,
+) -> Result<Option<GameUpdate>, String> {
+    let uuid = uuid.to_string();
+    let row = sqlx::query_as!(
+        StoredUpdate,
+        r#"SELECT turn_id AS "turn_id!", encoding, game_update, payload FROM game_updates
+           WHERE update_uuid = ? AND turn_id = ?"#,
+        uuid,
+        turnid
+    )
+    .fetch_optional(pool)
+    .await
+    .expect("Failed to query database");
+
+    match row {
+        Some(row) => decode_with_keyframe(pool, &uuid, &row).await,
+        None => Ok(None),
+    }
+}
+
+/// Loads every game update saved for a game ordered by turn id,
+/// optionally restricted to the turns between `from` and `to` (inclusive),
+/// an error if any of them is corrupt
+pub async fn load_game_updates(
+    pool: &SqlitePool,
+    uuid: Uuid,
+    from: Option<i32>,
+    to: Option<i32>,
+) -> Result<Vec<GameUpdate>, String> {
+    let uuid = uuid.to_string();
+    // Start from the keyframe of the first turn so its deltas can be decoded
+    let first = from.map(|from| encoding::keyframe_turn(from.max(0) as usize) as i32);
+    let rows = sqlx::query_as!(
+        StoredUpdate,
+        r#"SELECT turn_id AS "turn_id!", encoding, game_update, payload FROM game_updates
+           WHERE update_uuid = ?
+           AND (? IS NULL OR turn_id >= ?)
+           AND (? IS NULL OR turn_id <= ?)
+           ORDER BY turn_id ASC"#,
+        uuid,
+        first,
+        first,
+        to,
+        to
+    )
+    .fetch_all(pool)
+    .await
+    .expect("Failed to query database");
+
+    let mut keyframe: Option<(i64, Vec<u8>)> = None;
+    let mut game_updates = vec![];
+    for row in rows {
+        let keyframe_turn = encoding::keyframe_turn(row.turn_id as usize) as i64;
+        // Fallback keyframes saved at other turns are not what deltas use
+        if row.encoding == Encoding::Keyframe as i64 && row.turn_id == keyframe_turn {
+            if let Some(payload) = &row.payload {
+                let bytes = encoding::decompress_keyframe(payload).map_err(|error| {
+                    format!("corrupt keyframe of turn {}: {}", row.turn_id, error)
+                })?;
+                keyframe = Some((row.turn_id, bytes));
+            }
+        }
+        if from.is_some_and(|from| row.turn_id < from as i64) {
+            continue;
+        }
+
+        let keyframe = keyframe
+            .as_ref()
+            .filter(|(turn, _)| *turn == keyframe_turn)
+            .map(|(_, bytes)| bytes.as_slice());
+        if let Some(game_update) = decode_stored(&row, keyframe)? {
+            game_updates.push(game_update);
+        }
+    }
+    Ok(game_updates)
+}
+
+/// Loads the game update with the highest turn id saved for a game, an
+/// error if it is corrupt
+pub async fn load_latest_game_update(
+    pool: &SqlitePool,
+    uuid: Uuid,
+) -> Result<Option<GameUpdate>, String> {
+    let uuid = uuid.to_string();
+    let row = sqlx::query_as!(
+        StoredUpdate,
+        r#"SELECT turn_id AS "turn_id!", encoding, game_update, payload FROM game_updates
+           WHERE update_uuid = ? ORDER BY turn_id DESC LIMIT 1"#,
+        uuid
+    )
+    .fetch_optional(pool)
+    .await
+    .expect("Failed to query database");
+
+    match row {
+        Some(row) => decode_with_keyframe(pool, &uuid, &row).await,
+        None => Ok(None),
+    }
+}
+
+/// Re-encodes every stored game update with the current encoding, so rows
+/// saved as JSON or against a different keyframe interval shrink.
+/// Returns the number of games and updates that were re-encoded
+pub async fn reencode_game_updates(pool: &SqlitePool) -> (usize, usize) {
+    let games: Vec<String> = sqlx::query_scalar!(r#"SELECT game_uuid AS "game_uuid!" FROM games"#)
+        .fetch_all(pool)
+        .await
+        .expect("Failed to query games");
+
+    let (mut num_games, mut num_updates) = (0, 0);
+    for uuid in games {
+        let Ok(id) = Uuid::parse_str(&uuid) else {
+            continue;
+        };
+        let updates = match load_game_updates(pool, id, None, None).await {
+            Ok(updates) if !updates.is_empty() => updates,
+            Ok(_) => continue,
+            Err(error) => {
+                error!("[!] Not re-encoding game {}: {}", id, error);
+                continue;
+            }
+        };
+
+        let mut tx = pool.begin().await.expect("Failed to start transaction");
+        let mut keyframe: Option<(usize, Vec<u8>)> = None;
+        for update in &updates {
+            let turn = update.update_num;
+            let keyframe_turn = encoding::keyframe_turn(turn);
+            let (encoding, payload) = if turn == keyframe_turn {
+                keyframe = Some((turn, encoding::serialize(update)));
+                (Encoding::Keyframe, encoding::encode_keyframe(update))
+            } else {
+                match &keyframe {
+                    Some((kept, bytes)) if *kept == keyframe_turn => {
+                        (Encoding::Delta, encoding::encode_delta(update, bytes))
+                    }
+                    _ => (Encoding::Keyframe, encoding::encode_keyframe(update)),
+                }
+            };
+
+            let (encoding, turnid) = (encoding as i64, turn as i64);
+            sqlx::query!(
+                "UPDATE game_updates SET game_update = NULL, encoding = ?, payload = ?
+                 WHERE update_uuid = ? AND turn_id = ?",
+                encoding,
+                payload,
+                uuid,
+                turnid
+            )
+            .execute(&mut *tx)
+            .await
+            .expect("Failed to re-encode game update");
+        }
+        tx.commit().await.expect("Failed to commit re-encoded game");
+
+        num_games += 1;
+        num_updates += updates.len();
+        debug!("[+] Re-encoded {} updates of game {}", updates.len(), uuid);
+    }
+    (num_games, num_updates)
+}
+
+/// The final outcome of a game, as recorded on the games table
+#[derive(Debug, Clone)]
+pub struct GameResult {
+    pub final_turn: usize,
+    /// None when the game ended in a tie that the rules cannot break
+    pub winner: Option<usize>,
+    pub final_scores: Vec<usize>,
+}
+
+/// Finds the winning seat of a finished game, None if the tie for first
+/// place cannot be broken by the rules
+fn determine_winner(info: &SmallClientInfo, forfeit: Option<usize>) -> Option<usize> {
+    let places = ratings::final_placements(info, forfeit);
+    let mut winners = places.iter().enumerate().filter(|(_, &place)| place == 0);
+
+    match (winners.next(), winners.next()) {
+        (Some((seat, _)), None) => Some(seat),
+        _ => None,
+    }
+}
+
+/// An unfinished game that can still be reconnected to
+#[derive(Debug, Clone)]
+pub struct ResumableGame {
+    pub id: Uuid,
+    /// The account the game is attributed to
+    pub owner_id: Option<i64>,
+    /// The api key of the arena that started the game
+    pub key_id: Option<i64>,
+}
+
+/// The unfinished games updated within the last `hours` whose latest
+/// update can be loaded
+pub async fn load_resumable_games(pool: &SqlitePool, hours: i64) -> Vec<ResumableGame> {
+    let since = format!("-{} hours", hours);
+    let rows = sqlx::query!(
+        r#"SELECT game_uuid AS "game_uuid!", owner_id, key_id FROM games
+           WHERE finished_at IS NULL AND last_updated >= datetime('now', ?)"#,
+        since
+    )
+    .fetch_all(pool)
+    .await
+    .expect("Failed to query games");
+
+    let mut games = vec![];
+    for row in rows {
+        let Ok(id) = Uuid::parse_str(&row.game_uuid) else {
+            continue;
+        };
+        match load_latest_game_update(pool, id).await {
+            Ok(Some(_)) => games.push(ResumableGame {
+                id,
+                owner_id: row.owner_id,
+                key_id: row.key_id,
+            }),
+            Ok(None) => {}
+            Err(error) => error!("[!] Game {} cannot be resumed: {}", id, error),
+        }
     }
+    games
 }
-/// Generates a unique slug for a url
-/// TODO: this is slow in the case of lots of collisions
-pub async fn generate_unique_slug(pool: &SqlitePool) -> String {
-    loop {
-        let first: usize;
-        let second: usize;
-        let third: i32;
-        {
-            let mut rng = rand::thread_rng();
-            first = rng.gen_range(0..ADJECTIVES.len());
-            second = rng.gen_range(0..NOUNS.len());
-            third = rng.gen_range(0..1000);
+
+/// Marks a game as finished using the last saved update as the final state,
+/// recording the end time, the final turn, the winner and the final scores.
+/// Returns None if no updates were ever saved for the game, or the last
+/// one is corrupt
+pub async fn save_game_over(pool: &SqlitePool, uuid: Uuid) -> Option<GameResult> {
+    let last_update = match load_latest_game_update(pool, uuid).await {
+        Ok(update) => update?,
+        Err(error) => {
+            error!("[!] Cannot record the result of game {}: {}", uuid, error);
+            return None;
         }
-        let slug = format!("{}_{}{:04}", ADJECTIVES[first], NOUNS[second], third);
-        let slug = slug.to_string();
+    };
+    let forfeit = load_forfeit(pool, uuid).await.map(|(seat, _)| seat);
+
+    let result = GameResult {
+        final_turn: last_update.update_num,
+        winner: determine_winner(&last_update.info, forfeit),
+        final_scores: last_update
+            .info
+            .players
+            .iter()
+            .map(|player| player.points as usize)
+            .collect(),
+    };
+
+    let uuid = uuid.to_string();
+    let final_turn = result.final_turn as i64;
+    let winner = result.winner.map(|winner| winner as i64);
+    let final_scores = serde_json::to_string(&result.final_scores).unwrap();
+    sqlx::query!(
+        "UPDATE games SET finished_at = CURRENT_TIMESTAMP, final_turn = ?, winner = ?, final_scores = ?
+         WHERE game_uuid = ?",
+        final_turn,
+        winner,
+        final_scores,
+        uuid
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to record game over");
+
+    Some(result)
+}
+
+/// Records that a seat forfeited a game by breaking a limit of the match
+pub async fn save_forfeit(pool: &SqlitePool, uuid: Uuid, seat: usize, reason: &str) {
+    let uuid = uuid.to_string();
+    let seat = seat as i64;
+    sqlx::query!(
+        "UPDATE games SET forfeit_seat = ?, forfeit_reason = ? WHERE game_uuid = ?",
+        seat,
+        reason,
+        uuid
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to record forfeit");
+}
+
+/// The seat that forfeited a game and why, None if no seat did
+pub async fn load_forfeit(pool: &SqlitePool, uuid: Uuid) -> Option<(usize, String)> {
+    let uuid = uuid.to_string();
+    let forfeit = sqlx::query!(
+        r#"SELECT forfeit_seat AS "seat!", forfeit_reason AS "reason!" FROM games
+           WHERE game_uuid = ? AND forfeit_seat IS NOT NULL"#,
+        uuid
+    )
+    .fetch_optional(pool)
+    .await
+    .expect("Failed to load forfeit")?;
+    Some((forfeit.seat as usize, forfeit.reason))
+}
+
+/// Which games to list, every filter is optional
+#[derive(Debug, Clone, Default)]
+pub struct GameFilter {
+    /// Only games last updated at or after this timestamp
+    pub since: Option<String>,
+    /// Only games last updated at or before this timestamp
+    pub until: Option<String>,
+    /// Only finished games when true, only games in progress when false
+    pub finished: Option<bool>,
+    pub num_players: Option<i64>,
+    pub min_turns: Option<i64>,
+    /// Only games uploaded by this user
+    pub owner_id: Option<i64>,
+    /// Only games that sort after this (last_updated, game_uuid) position
+    pub after: Option<(String, String)>,
+    pub limit: i64,
+}
+
+/// The summary of a game shown when listing games
+#[derive(Debug, Clone)]
+pub struct GameListing {
+    pub uuid: String,
+    pub slug: Option<String>,
+    pub last_updated: String,
+    pub finished_at: Option<String>,
+    pub num_players: Option<i64>,
+    pub latest_turn: Option<i64>,
+    /// Username of the account that uploaded the game
+    pub owner: Option<String>,
+    /// The seat that forfeited the game by breaking a limit
+    pub forfeit_seat: Option<i64>,
+    pub forfeit_reason: Option<String>,
+}
+
+/// Lists games matching the filter, most recently updated first
+pub async fn list_games(pool: &SqlitePool, filter: &GameFilter) -> Vec<GameListing> {
+    let finished = filter.finished.map(|finished| finished as i64);
+    let (after_updated, after_uuid) = filter.after.clone().unzip();
+    let games = sqlx::query!(
+        r#"SELECT g.game_uuid AS "game_uuid!", s.slug AS "slug?",
+           g.last_updated AS "last_updated!: String", g.finished_at AS "finished_at?: String",
+           g.num_players, g.latest_turn, u.username AS "owner?",
+           g.forfeit_seat, g.forfeit_reason
+           FROM games g LEFT JOIN slugs s ON s.slug_id = g.game_uuid AND s.is_alias = 0
+           LEFT JOIN users u ON u.user_id = g.owner_id
+           WHERE (?1 IS NULL OR g.last_updated >= ?1)
+           AND (?2 IS NULL OR g.last_updated <= ?2)
+           AND (?3 IS NULL OR (?3 = 1) = (g.finished_at IS NOT NULL))
+           AND (?4 IS NULL OR g.num_players = ?4)
+           AND (?5 IS NULL OR g.latest_turn >= ?5)
+           AND (?6 IS NULL OR g.owner_id = ?6)
+           AND (?7 IS NULL OR (g.last_updated, g.game_uuid) < (?7, ?8))
+           ORDER BY g.last_updated DESC, g.game_uuid DESC
+           LIMIT ?9"#,
+        filter.since,
+        filter.until,
+        finished,
+        filter.num_players,
+        filter.min_turns,
+        filter.owner_id,
+        after_updated,
+        after_uuid,
+        filter.limit
+    )
+    .fetch_all(pool)
+    .await
+    .expect("Failed to list games");
+
+    games
+        .into_iter()
+        .map(|game| GameListing {
+            uuid: game.game_uuid,
+            slug: game.slug,
+            last_updated: game.last_updated,
+            finished_at: game.finished_at,
+            num_players: game.num_players,
+            latest_turn: game.latest_turn,
+            owner: game.owner,
+            forfeit_seat: game.forfeit_seat,
+            forfeit_reason: game.forfeit_reason,
+        })
+        .collect()
+}
 
-        let slug_exists = sqlx::query!("SELECT slug FROM slugs WHERE slug = ?", slug)
-            .fetch_one(pool)
-            .await;
-        if slug_exists.is_err() {
-            return slug;
+/// Generates a unique slug for a url from a random adjective and noun
+/// that still have a free number, fails once MAX_PREFIX_ATTEMPTS pairs
+/// have none left
+pub async fn generate_unique_slug(pool: &SqlitePool) -> Result<String, String> {
+    for _ in 0..slugs::MAX_PREFIX_ATTEMPTS {
+        let prefix = slugs::random_prefix(&mut rand::thread_rng());
+        let pattern = format!("{}[0-9][0-9][0-9][0-9]", prefix);
+        let taken: HashSet<u32> =
+            sqlx::query_scalar!("SELECT slug FROM slugs WHERE slug GLOB ?", pattern)
+                .fetch_all(pool)
+                .await
+                .expect("Failed to query database")
+                .iter()
+                .filter_map(|slug| slugs::number_of(&prefix, slug))
+                .collect();
+        if let Some(number) = slugs::free_number(&taken, &mut rand::thread_rng()) {
+            return Ok(slugs::with_number(&prefix, number));
         }
+        warn!("[-] Every slug starting with {} is taken", prefix);
     }
+    Err(format!(
+        "slugs are exhausted, {} adjective and noun pairs had no free number",
+        slugs::MAX_PREFIX_ATTEMPTS
+    ))
 }
 
-/// Saves a slug to the database
-pub async fn save_slug(pool: &SqlitePool, uuid: Uuid, slug: &str) {
+/// Saves a slug to the database, returns false if it is already taken
+pub async fn save_slug(pool: &SqlitePool, uuid: Uuid, slug: &str) -> bool {
     let uuid = uuid.to_string();
     sqlx::query!(
-        "INSERT INTO slugs (slug_id, slug) VALUES (?, ?)",
+        "INSERT INTO slugs (slug_id, slug) VALUES (?, ?) ON CONFLICT (slug) DO NOTHING",
         uuid,
         slug
     )
     .execute(pool)
     .await
+    .expect("Failed to insert slug")
+    .rows_affected()
+        == 1
+}
+
+/// Replaces the slug of a game with a custom one, keeping its current
+/// slug as an alias. Only the newest MAX_ALIASES aliases are kept, older
+/// ones are released. Fails if another game has the slug
+pub async fn save_custom_slug(pool: &SqlitePool, uuid: Uuid, slug: &str) -> Result<(), String> {
+    let uuid = uuid.to_string();
+    let mut tx = pool.begin().await.expect("Failed to start transaction");
+    let existing = sqlx::query!("SELECT slug_id, is_alias FROM slugs WHERE slug = ?", slug)
+        .fetch_optional(&mut *tx)
+        .await
+        .expect("Failed to query database");
+    match &existing {
+        Some(row) if row.slug_id.as_deref() != Some(uuid.as_str()) => {
+            return Err(format!("the slug {} is already taken", slug));
+        }
+        Some(row) if row.is_alias == 0 => return Ok(()),
+        _ => {}
+    }
+
+    sqlx::query!(
+        "UPDATE slugs SET is_alias = 1, last_updated = CURRENT_TIMESTAMP
+         WHERE slug_id = ? AND is_alias = 0",
+        uuid
+    )
+    .execute(&mut *tx)
+    .await
+    .expect("Failed to update slug");
+    // An alias of the game is taken back as its slug
+    sqlx::query!(
+        "INSERT INTO slugs (slug_id, slug) VALUES (?, ?)
+         ON CONFLICT (slug) DO UPDATE SET is_alias = 0, last_updated = CURRENT_TIMESTAMP",
+        uuid,
+        slug
+    )
+    .execute(&mut *tx)
+    .await
     .expect("Failed to insert slug");
+    sqlx::query!(
+        "DELETE FROM slugs WHERE slug_id = ? AND is_alias = 1 AND rowid NOT IN (
+             SELECT rowid FROM slugs WHERE slug_id = ? AND is_alias = 1
+             ORDER BY last_updated DESC, rowid DESC LIMIT ?)",
+        uuid,
+        uuid,
+        slugs::MAX_ALIASES
+    )
+    .execute(&mut *tx)
+    .await
+    .expect("Failed to release old slugs");
+    tx.commit().await.expect("Failed to commit transaction");
+    Ok(())
+}
+
+/// Loads the old slugs of a game that still lead to it, newest first
+pub async fn load_slug_aliases(pool: &SqlitePool, uuid: Uuid) -> Vec<String> {
+    let uuid = uuid.to_string();
+    sqlx::query_scalar!(
+        "SELECT slug FROM slugs WHERE slug_id = ? AND is_alias = 1
+         ORDER BY last_updated DESC, rowid DESC",
+        uuid
+    )
+    .fetch_all(pool)
+    .await
+    .expect("Failed to query database")
+}
+
+/// Loads the current slug of the game a slug or alias leads to
+pub async fn load_canonical_slug(pool: &SqlitePool, slug: &str) -> Option<String> {
+    sqlx::query_scalar!(
+        "SELECT c.slug FROM slugs a JOIN slugs c ON c.slug_id = a.slug_id AND c.is_alias = 0
+         WHERE a.slug = ?",
+        slug
+    )
+    .fetch_optional(pool)
+    .await
+    .expect("Failed to query database")
+}
+
+/// Loads the uuid of the game a slug names, only if it is the current
+/// slug of the game and not one of its aliases
+pub async fn load_uuid_from_canonical_slug(pool: &SqlitePool, slug: &str) -> Option<Uuid> {
+    let uuid = sqlx::query_scalar!(
+        r#"SELECT slug_id AS "slug_id!" FROM slugs WHERE slug = ? AND is_alias = 0"#,
+        slug
+    )
+    .fetch_optional(pool)
+    .await
+    .expect("Failed to query database")?;
+    Uuid::parse_str(&uuid).ok()
 }
 
 /// Loads a uuid given a slug form the database
@@ -208,7 +1138,7 @@ pub async fn load_uuid_from_slug(pool: &SqlitePool, slug: &str) -> Result<Uuid,
 /// Loads a slug from the database if it is present,
 pub async fn load_slug(pool: &SqlitePool, uuid: Uuid) -> Option<String> {
     let uuid = uuid.to_string();
-    let slug = sqlx::query("SELECT slug FROM slugs WHERE slug_id = ?")
+    let slug = sqlx::query("SELECT slug FROM slugs WHERE slug_id = ? AND is_alias = 0")
         .bind(uuid)
         .fetch_one(pool)
         .await;
@@ -223,15 +1153,1071 @@ pub async fn load_slug(pool: &SqlitePool, uuid: Uuid) -> Option<String> {
 
 /// Loads a slug from the database if it is present,
 /// otherwise returns a human readable string to be used as a slug
-/// and saves it to the database
+/// and saves it to the database. If no slug can be generated the
+/// game is named by its uuid
 pub async fn load_slug_default(pool: &SqlitePool, uuid: Uuid) -> String {
-    let slug = load_slug(pool, uuid).await;
-    match slug {
-        Some(slug) => slug,
-        None => {
-            let slug = generate_unique_slug(pool).await;
-            save_slug(pool, uuid, &slug).await;
-            slug
+    if let Some(slug) = load_slug(pool, uuid).await {
+        return slug;
+    }
+    // The slug can be taken between generating and saving it
+    for _ in 0..3 {
+        match generate_unique_slug(pool).await {
+            Ok(slug) if save_slug(pool, uuid, &slug).await => return slug,
+            Ok(_) => continue,
+            Err(reason) => {
+                error!("[-] Failed to generate a slug for {}: {}", uuid, reason);
+                break;
+            }
         }
     }
+    let slug = uuid.to_string();
+    save_slug(pool, uuid, &slug).await;
+    slug
+}
+
+/// An api key as stored in the database, without the key itself
+#[derive(Debug, Clone)]
+pub struct ApiKeyRecord {
+    pub key_id: i64,
+    pub owner: String,
+    /// The account the key belongs to, None for keys minted by an admin
+    /// for someone without an account
+    pub user_id: Option<i64>,
+    pub label: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub revoked: bool,
+}
+
+/// Saves the hash of a new api key and returns its id
+pub async fn save_api_key(
+    pool: &SqlitePool,
+    key_hash: &str,
+    owner: &str,
+    user_id: Option<i64>,
+    label: &str,
+) -> i64 {
+    sqlx::query!(
+        "INSERT INTO api_keys (key_hash, owner, user_id, label) VALUES (?, ?, ?, ?)",
+        key_hash,
+        owner,
+        user_id,
+        label
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to insert api key")
+    .last_insert_rowid()
+}
+
+/// Loads an api key by the hash of the key
+pub async fn load_api_key(pool: &SqlitePool, key_hash: &str) -> Option<ApiKeyRecord> {
+    sqlx::query!(
+        r#"SELECT key_id AS "key_id!", owner, user_id, label, created_at AS "created_at!: String",
+           last_used_at AS "last_used_at?: String", revoked
+           FROM api_keys WHERE key_hash = ?"#,
+        key_hash
+    )
+    .fetch_optional(pool)
+    .await
+    .expect("Failed to query api keys")
+    .map(|key| ApiKeyRecord {
+        key_id: key.key_id,
+        owner: key.owner,
+        user_id: key.user_id,
+        label: key.label,
+        created_at: key.created_at,
+        last_used_at: key.last_used_at,
+        revoked: key.revoked != 0,
+    })
+}
+
+/// Lists every api key, newest first
+pub async fn list_api_keys(pool: &SqlitePool) -> Vec<ApiKeyRecord> {
+    sqlx::query!(
+        r#"SELECT key_id AS "key_id!", owner, user_id, label, created_at AS "created_at!: String",
+           last_used_at AS "last_used_at?: String", revoked
+           FROM api_keys ORDER BY key_id DESC"#
+    )
+    .fetch_all(pool)
+    .await
+    .expect("Failed to query api keys")
+    .into_iter()
+    .map(|key| ApiKeyRecord {
+        key_id: key.key_id,
+        owner: key.owner,
+        user_id: key.user_id,
+        label: key.label,
+        created_at: key.created_at,
+        last_used_at: key.last_used_at,
+        revoked: key.revoked != 0,
+    })
+    .collect()
+}
+
+/// Records that an api key was just used to authenticate
+pub async fn touch_api_key(pool: &SqlitePool, key_id: i64) {
+    sqlx::query!(
+        "UPDATE api_keys SET last_used_at = CURRENT_TIMESTAMP WHERE key_id = ?",
+        key_id
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to update api key");
+}
+
+/// Revokes an api key, returns false if there is no such key
+pub async fn revoke_api_key(pool: &SqlitePool, key_id: i64) -> bool {
+    sqlx::query!("UPDATE api_keys SET revoked = 1 WHERE key_id = ?", key_id)
+        .execute(pool)
+        .await
+        .expect("Failed to revoke api key")
+        .rows_affected()
+        > 0
+}
+
+/// Revokes an api key of a user, returns false if the user has no key
+/// with this id
+pub async fn revoke_user_api_key(pool: &SqlitePool, key_id: i64, user_id: i64) -> bool {
+    sqlx::query!(
+        "UPDATE api_keys SET revoked = 1 WHERE key_id = ? AND user_id = ?",
+        key_id,
+        user_id
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to revoke api key")
+    .rows_affected()
+        > 0
+}
+
+/// Records which account uploaded a game
+pub async fn save_game_owner(pool: &SqlitePool, uuid: Uuid, user_id: i64) {
+    let uuid_str = uuid.to_string();
+    sqlx::query!(
+        "UPDATE games SET owner_id = ? WHERE game_uuid = ?",
+        user_id,
+        uuid_str
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to update game owner");
+}
+
+/// Records which api key the arena that started a game authenticated with
+pub async fn save_game_key(pool: &SqlitePool, uuid: Uuid, key_id: i64) {
+    let uuid_str = uuid.to_string();
+    sqlx::query!(
+        "UPDATE games SET key_id = ? WHERE game_uuid = ?",
+        key_id,
+        uuid_str
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to update game key");
+}
+
+/// An account as stored in the database
+#[derive(Debug, Clone)]
+pub struct UserRecord {
+    pub user_id: i64,
+    pub username: String,
+    pub password_hash: String,
+    pub created_at: String,
+}
+
+/// Creates an account and returns its id, None if the username is taken
+pub async fn save_user(pool: &SqlitePool, username: &str, password_hash: &str) -> Option<i64> {
+    let result = sqlx::query!(
+        "INSERT INTO users (username, password_hash) VALUES (?, ?)",
+        username,
+        password_hash
+    )
+    .execute(pool)
+    .await;
+
+    match result {
+        Ok(result) => Some(result.last_insert_rowid()),
+        Err(sqlx::Error::Database(error)) if error.is_unique_violation() => None,
+        Err(error) => panic!("Failed to insert user: {}", error),
+    }
+}
+
+/// Loads an account by username, ignoring case
+pub async fn load_user_by_name(pool: &SqlitePool, username: &str) -> Option<UserRecord> {
+    sqlx::query_as!(
+        UserRecord,
+        r#"SELECT user_id AS "user_id!", username, password_hash,
+           created_at AS "created_at!: String"
+           FROM users WHERE username = ?"#,
+        username
+    )
+    .fetch_optional(pool)
+    .await
+    .expect("Failed to query users")
+}
+
+/// Starts a session for an account that lasts for the given number of days
+pub async fn save_session(pool: &SqlitePool, token_hash: &str, user_id: i64, days: i64) {
+    let lifetime = format!("+{} days", days);
+    sqlx::query!(
+        r#"INSERT INTO sessions (token_hash, user_id, expires_at)
+           VALUES (?, ?, datetime('now', ?))"#,
+        token_hash,
+        user_id,
+        lifetime
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to insert session");
+}
+
+/// Loads the account of a session, None if the session is unknown or expired
+pub async fn load_session_user(pool: &SqlitePool, token_hash: &str) -> Option<UserRecord> {
+    sqlx::query_as!(
+        UserRecord,
+        r#"SELECT u.user_id AS "user_id!", u.username, u.password_hash,
+           u.created_at AS "created_at!: String"
+           FROM sessions s JOIN users u ON u.user_id = s.user_id
+           WHERE s.token_hash = ? AND s.expires_at > datetime('now')"#,
+        token_hash
+    )
+    .fetch_optional(pool)
+    .await
+    .expect("Failed to query sessions")
+}
+
+/// Ends a session, along with any other session of anyone that has expired
+pub async fn delete_session(pool: &SqlitePool, token_hash: &str) {
+    sqlx::query!(
+        "DELETE FROM sessions WHERE token_hash = ? OR expires_at <= datetime('now')",
+        token_hash
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to delete session");
+}
+
+/// The bot sitting in a seat, as reported by whoever uploaded the game
+#[derive(Debug, Clone)]
+pub struct SeatRecord {
+    pub name: String,
+    /// The build of the bot, e.g. a version number or commit
+    pub version: Option<String>,
+    /// Who wrote the bot, as reported by the arena
+    pub owner: Option<String>,
+}
+
+/// A seat in a game and the bot sitting in it
+#[derive(Debug, Clone)]
+pub struct GamePlayer {
+    pub seat: i64,
+    pub bot_id: i64,
+    pub name: String,
+    pub version: Option<String>,
+    pub owner: Option<String>,
+    /// The rating of the bot now, None if it has never been rated
+    pub rating: Option<f64>,
+}
+
+/// Records which bot sat in each seat of a game, creating the bots of
+/// the account that are new, and replacing any seats recorded before
+pub async fn save_game_players(
+    pool: &SqlitePool,
+    uuid: Uuid,
+    owner_id: Option<i64>,
+    seats: &[SeatRecord],
+) -> Vec<GamePlayer> {
+    let uuid_str = uuid.to_string();
+    let mut tx = pool.begin().await.expect("Failed to start transaction");
+
+    sqlx::query!("DELETE FROM game_players WHERE game_uuid = ?", uuid_str)
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to clear game players");
+
+    for (seat, record) in seats.iter().enumerate() {
+        let seat = seat as i64;
+        let name = &record.name;
+        sqlx::query!(
+            r#"INSERT INTO bots (name, owner_id) SELECT ?1, ?2
+               WHERE NOT EXISTS (SELECT 1 FROM bots WHERE name = ?1 AND owner_id IS ?2)"#,
+            name,
+            owner_id
+        )
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to insert bot");
+        sqlx::query!(
+            r#"INSERT INTO game_players (game_uuid, seat, bot_id, version, owner)
+               SELECT ?, ?, bot_id, ?, ? FROM bots WHERE name = ? AND owner_id IS ?"#,
+            uuid_str,
+            seat,
+            record.version,
+            record.owner,
+            name,
+            owner_id
+        )
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to insert game player");
+    }
+
+    tx.commit().await.expect("Failed to commit game players");
+    load_game_players(pool, uuid).await
+}
+
+/// Records the uploaded bot versions that sat in a hosted game, by seat.
+/// Unlike save_game_players the bots are known, so each keeps its owner
+pub async fn save_game_bots(
+    pool: &SqlitePool,
+    uuid: Uuid,
+    versions: &[BotVersion],
+) -> Vec<GamePlayer> {
+    let uuid_str = uuid.to_string();
+    let mut tx = pool.begin().await.expect("Failed to start transaction");
+
+    sqlx::query!("DELETE FROM game_players WHERE game_uuid = ?", uuid_str)
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to clear game players");
+
+    for (seat, version) in versions.iter().enumerate() {
+        let seat = seat as i64;
+        let number = version.version.to_string();
+        sqlx::query!(
+            r#"INSERT INTO game_players (game_uuid, seat, bot_id, version, owner)
+               SELECT ?, ?, b.bot_id, ?, u.username
+               FROM bots b LEFT JOIN users u ON u.user_id = b.owner_id
+               WHERE b.bot_id = ?"#,
+            uuid_str,
+            seat,
+            number,
+            version.bot_id
+        )
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to insert game player");
+    }
+
+    tx.commit().await.expect("Failed to commit game players");
+    load_game_players(pool, uuid).await
+}
+
+/// Records the bots that sat in a game played in the lobby, by seat. The
+/// bots are known, and each keeps its owner
+pub async fn save_game_seats(pool: &SqlitePool, uuid: Uuid, bot_ids: &[i64]) -> Vec<GamePlayer> {
+    let uuid_str = uuid.to_string();
+    let mut tx = pool.begin().await.expect("Failed to start transaction");
+
+    sqlx::query!("DELETE FROM game_players WHERE game_uuid = ?", uuid_str)
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to clear game players");
+
+    for (seat, bot_id) in bot_ids.iter().enumerate() {
+        let seat = seat as i64;
+        sqlx::query!(
+            r#"INSERT INTO game_players (game_uuid, seat, bot_id, owner)
+               SELECT ?, ?, b.bot_id, u.username
+               FROM bots b LEFT JOIN users u ON u.user_id = b.owner_id
+               WHERE b.bot_id = ?"#,
+            uuid_str,
+            seat,
+            bot_id
+        )
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to insert game player");
+    }
+
+    tx.commit().await.expect("Failed to commit game players");
+    load_game_players(pool, uuid).await
+}
+
+/// A bot of an account entering the lobby, with its rating
+#[derive(Debug, Clone, PartialEq)]
+pub struct LobbyBot {
+    pub bot_id: i64,
+    pub name: String,
+    /// The username of the account
+    pub owner: String,
+    /// None if it has never been rated
+    pub rating: Option<f64>,
+}
+
+/// Finds a bot of an account by name, creating it if it is new
+pub async fn load_or_create_bot(pool: &SqlitePool, owner_id: i64, name: &str) -> LobbyBot {
+    sqlx::query!(
+        r#"INSERT INTO bots (name, owner_id) SELECT ?1, ?2
+           WHERE NOT EXISTS (SELECT 1 FROM bots WHERE name = ?1 AND owner_id = ?2)"#,
+        name,
+        owner_id
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to insert bot");
+    sqlx::query_as!(
+        LobbyBot,
+        r#"SELECT b.bot_id AS "bot_id!", b.name, u.username AS owner, r.rating AS "rating?"
+           FROM bots b JOIN users u ON u.user_id = b.owner_id
+           LEFT JOIN bot_ratings r ON r.bot_id = b.bot_id
+           WHERE b.name = ? AND b.owner_id = ?"#,
+        name,
+        owner_id
+    )
+    .fetch_one(pool)
+    .await
+    .expect("Failed to query bot")
+}
+
+/// Loads the bots that sat in a game, ordered by seat
+pub async fn load_game_players(pool: &SqlitePool, uuid: Uuid) -> Vec<GamePlayer> {
+    let uuid_str = uuid.to_string();
+    sqlx::query_as!(
+        GamePlayer,
+        r#"SELECT p.seat, p.bot_id, b.name, p.version, p.owner, r.rating AS "rating?"
+           FROM game_players p JOIN bots b ON b.bot_id = p.bot_id
+           LEFT JOIN bot_ratings r ON r.bot_id = p.bot_id
+           WHERE p.game_uuid = ? ORDER BY p.seat"#,
+        uuid_str
+    )
+    .fetch_all(pool)
+    .await
+    .expect("Failed to query game players")
+}
+
+/// Rates a finished game and saves the rating changes, all at once, given
+/// the bot in each seat and the place each seat finished in. The ratings
+/// the changes start from are read inside the transaction that marks the
+/// game rated, once it holds the write lock, so two games rated at the
+/// same time for a bot do not both start from the same rating. Returns
+/// None, saving nothing, if the game is unfinished or was already rated,
+/// so a game can never be rated twice
+pub async fn save_game_rating(
+    pool: &SqlitePool,
+    uuid: Uuid,
+    bot_ids: &[i64],
+    places: &[usize],
+) -> Option<Vec<RatingChange>> {
+    let uuid_str = uuid.to_string();
+    let mut tx = pool.begin().await.expect("Failed to start transaction");
+
+    let claimed = sqlx::query!(
+        r#"UPDATE games SET rated_at = CURRENT_TIMESTAMP
+           WHERE game_uuid = ? AND finished_at IS NOT NULL AND rated_at IS NULL"#,
+        uuid_str
+    )
+    .execute(&mut *tx)
+    .await
+    .expect("Failed to mark game as rated")
+    .rows_affected();
+    if claimed == 0 {
+        return None;
+    }
+
+    let mut seats = vec![];
+    for &bot_id in bot_ids {
+        let rating = sqlx::query_scalar!("SELECT rating FROM bot_ratings WHERE bot_id = ?", bot_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .expect("Failed to query rating");
+        seats.push((bot_id, rating.unwrap_or(ratings::INITIAL_RATING)));
+    }
+    let changes = ratings::rate(&seats, places);
+    for change in &changes {
+        sqlx::query!(
+            r#"INSERT INTO bot_ratings (bot_id, rating, games_played) VALUES (?, ?, 1)
+               ON CONFLICT(bot_id) DO UPDATE SET rating = excluded.rating,
+               games_played = games_played + 1, updated_at = CURRENT_TIMESTAMP"#,
+            change.bot_id,
+            change.rating_after
+        )
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to save rating");
+        sqlx::query!(
+            r#"INSERT INTO rating_history (bot_id, game_uuid, rating_before, rating_after)
+               VALUES (?, ?, ?, ?)"#,
+            change.bot_id,
+            uuid_str,
+            change.rating_before,
+            change.rating_after
+        )
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to save rating history");
+    }
+
+    tx.commit().await.expect("Failed to commit ratings");
+    Some(changes)
+}
+
+/// A rated bot as shown on the leaderboard
+#[derive(Debug, Clone)]
+pub struct LeaderboardEntry {
+    pub bot_id: i64,
+    pub name: String,
+    /// Username of the account the bot belongs to
+    pub owner: Option<String>,
+    pub rating: f64,
+    pub games_played: i64,
+}
+
+/// Loads the highest rated bots, best first
+pub async fn load_leaderboard(pool: &SqlitePool, limit: i64) -> Vec<LeaderboardEntry> {
+    sqlx::query_as!(
+        LeaderboardEntry,
+        r#"SELECT r.bot_id AS "bot_id!", b.name, u.username AS "owner?", r.rating, r.games_played
+           FROM bot_ratings r JOIN bots b ON b.bot_id = r.bot_id
+           LEFT JOIN users u ON u.user_id = b.owner_id
+           ORDER BY r.rating DESC, r.bot_id
+           LIMIT ?"#,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+    .expect("Failed to query leaderboard")
+}
+
+/// One game's effect on the rating of a bot
+#[derive(Debug, Clone)]
+pub struct RatingHistoryEntry {
+    pub slug: Option<String>,
+    pub rating_before: f64,
+    pub rating_after: f64,
+    pub rated_at: String,
+}
+
+/// Loads every rating change of a bot, oldest first,
+/// None if there is no such bot
+pub async fn load_rating_history(
+    pool: &SqlitePool,
+    bot_id: i64,
+) -> Option<Vec<RatingHistoryEntry>> {
+    sqlx::query!("SELECT bot_id FROM bots WHERE bot_id = ?", bot_id)
+        .fetch_optional(pool)
+        .await
+        .expect("Failed to query bots")?;
+
+    let history = sqlx::query_as!(
+        RatingHistoryEntry,
+        r#"SELECT s.slug AS "slug?", h.rating_before, h.rating_after,
+           h.rated_at AS "rated_at!: String"
+           FROM rating_history h LEFT JOIN slugs s ON s.slug_id = h.game_uuid AND s.is_alias = 0
+           WHERE h.bot_id = ? ORDER BY h.history_id"#,
+        bot_id
+    )
+    .fetch_all(pool)
+    .await
+    .expect("Failed to query rating history");
+    Some(history)
+}
+
+/// Loads the account that uploaded a game, None if the game is unknown
+/// or was uploaded without an account
+pub async fn load_game_owner(pool: &SqlitePool, uuid: Uuid) -> Option<i64> {
+    let uuid_str = uuid.to_string();
+    sqlx::query!("SELECT owner_id FROM games WHERE game_uuid = ?", uuid_str)
+        .fetch_optional(pool)
+        .await
+        .expect("Failed to query game owner")
+        .and_then(|game| game.owner_id)
+}
+
+/// An uploaded build of a bot
+#[derive(Debug, Clone)]
+pub struct BotVersion {
+    pub bot_id: i64,
+    pub name: String,
+    /// Counts up from 1 for every upload of the bot
+    pub version: i64,
+    pub kind: String,
+    pub filename: String,
+    pub sha256: String,
+    pub size: i64,
+    pub uploaded_at: String,
+}
+
+/// Records an upload as the next version of a bot of the account,
+/// creating the bot if it is new
+pub async fn save_bot_version(
+    pool: &SqlitePool,
+    owner_id: i64,
+    name: &str,
+    kind: &str,
+    filename: &str,
+    sha256: &str,
+    size: i64,
+) -> BotVersion {
+    let mut tx = pool.begin().await.expect("Failed to start transaction");
+    sqlx::query!(
+        r#"INSERT INTO bots (name, owner_id) SELECT ?1, ?2
+           WHERE NOT EXISTS (SELECT 1 FROM bots WHERE name = ?1 AND owner_id IS ?2)"#,
+        name,
+        owner_id
+    )
+    .execute(&mut *tx)
+    .await
+    .expect("Failed to insert bot");
+    let bot_id = sqlx::query_scalar!(
+        r#"SELECT bot_id AS "bot_id!" FROM bots WHERE name = ? AND owner_id IS ?"#,
+        name,
+        owner_id
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .expect("Failed to query bot");
+    // Deleted versions keep their number, so it is never reused
+    let version = sqlx::query_scalar!(
+        r#"SELECT COALESCE(MAX(version), 0) + 1 AS "version!: i64" FROM bot_versions
+           WHERE bot_id = ?"#,
+        bot_id
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .expect("Failed to query bot versions");
+    sqlx::query!(
+        r#"INSERT INTO bot_versions (bot_id, version, kind, filename, sha256, size)
+           VALUES (?, ?, ?, ?, ?, ?)"#,
+        bot_id,
+        version,
+        kind,
+        filename,
+        sha256,
+        size
+    )
+    .execute(&mut *tx)
+    .await
+    .expect("Failed to insert bot version");
+    tx.commit().await.expect("Failed to commit bot version");
+
+    load_bot_version(pool, bot_id, version)
+        .await
+        .expect("Bot version was just saved")
+}
+
+/// Loads the account a bot belongs to, None if the bot does not exist
+/// or belongs to no account
+pub async fn load_bot_owner(pool: &SqlitePool, bot_id: i64) -> Option<i64> {
+    sqlx::query_scalar!("SELECT owner_id FROM bots WHERE bot_id = ?", bot_id)
+        .fetch_optional(pool)
+        .await
+        .expect("Failed to query bot")
+        .flatten()
+}
+
+/// Loads the versions of a bot that are not deleted, newest first
+pub async fn load_bot_versions(pool: &SqlitePool, bot_id: i64) -> Vec<BotVersion> {
+    sqlx::query_as!(
+        BotVersion,
+        r#"SELECT v.bot_id, b.name, v.version, v.kind, v.filename, v.sha256, v.size,
+                  v.uploaded_at AS "uploaded_at!: String"
+           FROM bot_versions v JOIN bots b ON b.bot_id = v.bot_id
+           WHERE v.bot_id = ? AND v.deleted_at IS NULL ORDER BY v.version DESC"#,
+        bot_id
+    )
+    .fetch_all(pool)
+    .await
+    .expect("Failed to query bot versions")
+}
+
+/// Loads a version of a bot, None if it does not exist or was deleted
+pub async fn load_bot_version(pool: &SqlitePool, bot_id: i64, version: i64) -> Option<BotVersion> {
+    sqlx::query_as!(
+        BotVersion,
+        r#"SELECT v.bot_id, b.name, v.version, v.kind, v.filename, v.sha256, v.size,
+                  v.uploaded_at AS "uploaded_at!: String"
+           FROM bot_versions v JOIN bots b ON b.bot_id = v.bot_id
+           WHERE v.bot_id = ? AND v.version = ? AND v.deleted_at IS NULL"#,
+        bot_id,
+        version
+    )
+    .fetch_optional(pool)
+    .await
+    .expect("Failed to query bot version")
+}
+
+/// Marks a version of a bot deleted, returns false if it does not exist
+/// or already was
+pub async fn delete_bot_version(pool: &SqlitePool, bot_id: i64, version: i64) -> bool {
+    sqlx::query!(
+        r#"UPDATE bot_versions SET deleted_at = CURRENT_TIMESTAMP
+           WHERE bot_id = ? AND version = ? AND deleted_at IS NULL"#,
+        bot_id,
+        version
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to delete bot version")
+    .rows_affected()
+        == 1
+}
+
+/// Whether any version that is not deleted is stored as the artifact
+pub async fn is_artifact_used(pool: &SqlitePool, sha256: &str) -> bool {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM bot_versions WHERE sha256 = ? AND deleted_at IS NULL)
+           AS "used!: bool""#,
+        sha256
+    )
+    .fetch_one(pool)
+    .await
+    .expect("Failed to query bot versions")
+}
+
+/// A tournament as it is saved, see tournaments::schedule for what the
+/// settings mean
+#[derive(Debug, Clone, PartialEq)]
+pub struct TournamentRecord {
+    pub tournament_id: i64,
+    pub name: String,
+    pub format: String,
+    pub seats: i64,
+    pub games_per_pairing: i64,
+    pub rotation: String,
+    pub rounds: i64,
+    pub created_at: String,
+    pub finished_at: Option<String>,
+}
+
+/// Participants drawn to play each other in a round of a tournament
+#[derive(Debug, Clone, PartialEq)]
+pub struct TournamentPairing {
+    pub round: usize,
+    pub pairing: usize,
+    pub participants: Vec<usize>,
+    /// How many times its games were played again
+    pub replays: usize,
+}
+
+/// A game of a pairing of a tournament
+#[derive(Debug, Clone, PartialEq)]
+pub struct TournamentGame {
+    pub round: usize,
+    pub pairing: usize,
+    pub game: usize,
+    /// The participant in each seat
+    pub seating: Vec<usize>,
+    /// The match of the runner playing the game
+    pub match_number: Option<u64>,
+    pub game_uuid: Option<String>,
+    pub slug: Option<String>,
+    /// The place of each seat once the game is finished
+    pub places: Option<Vec<usize>>,
+    /// Why the game could not be played
+    pub reason: Option<String>,
+    /// The seat whose bot made the game fail, if it was one bot
+    pub failed_seat: Option<usize>,
+}
+
+/// Saves a new tournament between bot versions, in seed order, and
+/// returns its id
+pub async fn save_tournament(
+    pool: &SqlitePool,
+    tournament: &TournamentRecord,
+    participants: &[BotVersion],
+) -> i64 {
+    let mut tx = pool.begin().await.expect("Failed to start transaction");
+    let tournament_id = sqlx::query!(
+        r#"INSERT INTO tournaments (name, format, seats, games_per_pairing, rotation, rounds)
+           VALUES (?, ?, ?, ?, ?, ?)"#,
+        tournament.name,
+        tournament.format,
+        tournament.seats,
+        tournament.games_per_pairing,
+        tournament.rotation,
+        tournament.rounds
+    )
+    .execute(&mut *tx)
+    .await
+    .expect("Failed to insert tournament")
+    .last_insert_rowid();
+
+    for (participant, version) in participants.iter().enumerate() {
+        let participant = participant as i64;
+        sqlx::query!(
+            r#"INSERT INTO tournament_participants (tournament_id, participant, bot_id, version)
+               VALUES (?, ?, ?, ?)"#,
+            tournament_id,
+            participant,
+            version.bot_id,
+            version.version
+        )
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to insert tournament participant");
+    }
+    tx.commit().await.expect("Failed to commit tournament");
+    tournament_id
+}
+
+/// Loads a tournament, None if there is no such tournament
+pub async fn load_tournament(pool: &SqlitePool, tournament_id: i64) -> Option<TournamentRecord> {
+    sqlx::query_as!(
+        TournamentRecord,
+        r#"SELECT tournament_id AS "tournament_id!", name, format, seats, games_per_pairing,
+                  rotation, rounds, created_at AS "created_at!: String",
+                  finished_at AS "finished_at?: String"
+           FROM tournaments WHERE tournament_id = ?"#,
+        tournament_id
+    )
+    .fetch_optional(pool)
+    .await
+    .expect("Failed to query tournament")
+}
+
+/// The ids of the tournaments that are not finished
+pub async fn load_unfinished_tournaments(pool: &SqlitePool) -> Vec<i64> {
+    sqlx::query_scalar!(
+        r#"SELECT tournament_id AS "tournament_id!" FROM tournaments
+           WHERE finished_at IS NULL ORDER BY tournament_id"#
+    )
+    .fetch_all(pool)
+    .await
+    .expect("Failed to query tournaments")
+}
+
+/// Loads the bot versions of a tournament in seed order, including the
+/// versions deleted since it started
+pub async fn load_tournament_participants(
+    pool: &SqlitePool,
+    tournament_id: i64,
+) -> Vec<BotVersion> {
+    sqlx::query_as!(
+        BotVersion,
+        r#"SELECT v.bot_id, b.name, v.version, v.kind, v.filename, v.sha256, v.size,
+                  v.uploaded_at AS "uploaded_at!: String"
+           FROM tournament_participants p
+           JOIN bot_versions v ON v.bot_id = p.bot_id AND v.version = p.version
+           JOIN bots b ON b.bot_id = v.bot_id
+           WHERE p.tournament_id = ? ORDER BY p.participant"#,
+        tournament_id
+    )
+    .fetch_all(pool)
+    .await
+    .expect("Failed to query tournament participants")
+}
+
+/// Saves the pairings of a round of a tournament, with the seating of
+/// each of their games
+pub async fn save_tournament_round(
+    pool: &SqlitePool,
+    tournament_id: i64,
+    round: usize,
+    pairings: &[(Vec<usize>, Vec<Vec<usize>>)],
+) {
+    let round = round as i64;
+    let mut tx = pool.begin().await.expect("Failed to start transaction");
+    for (pairing, (participants, seatings)) in pairings.iter().enumerate() {
+        let pairing = pairing as i64;
+        let participants = serde_json::to_string(participants).unwrap();
+        sqlx::query!(
+            r#"INSERT INTO tournament_pairings (tournament_id, round, pairing, participants)
+               VALUES (?, ?, ?, ?)"#,
+            tournament_id,
+            round,
+            pairing,
+            participants
+        )
+        .execute(&mut *tx)
+        .await
+        .expect("Failed to insert tournament pairing");
+
+        for (game, seating) in seatings.iter().enumerate() {
+            let game = game as i64;
+            let seating = serde_json::to_string(seating).unwrap();
+            sqlx::query!(
+                r#"INSERT INTO tournament_games (tournament_id, round, pairing, game, seating)
+                   VALUES (?, ?, ?, ?, ?)"#,
+                tournament_id,
+                round,
+                pairing,
+                game,
+                seating
+            )
+            .execute(&mut *tx)
+            .await
+            .expect("Failed to insert tournament game");
+        }
+    }
+    tx.commit()
+        .await
+        .expect("Failed to commit tournament round");
+}
+
+/// Loads the pairings of every round of a tournament, in order
+pub async fn load_tournament_pairings(
+    pool: &SqlitePool,
+    tournament_id: i64,
+) -> Vec<TournamentPairing> {
+    sqlx::query!(
+        r#"SELECT round, pairing, participants, replays FROM tournament_pairings
+           WHERE tournament_id = ? ORDER BY round, pairing"#,
+        tournament_id
+    )
+    .fetch_all(pool)
+    .await
+    .expect("Failed to query tournament pairings")
+    .into_iter()
+    .map(|row| TournamentPairing {
+        round: row.round as usize,
+        pairing: row.pairing as usize,
+        participants: serde_json::from_str(&row.participants).unwrap_or_default(),
+        replays: row.replays as usize,
+    })
+    .collect()
+}
+
+/// Loads the games of every round of a tournament, in order
+pub async fn load_tournament_games(pool: &SqlitePool, tournament_id: i64) -> Vec<TournamentGame> {
+    sqlx::query!(
+        r#"SELECT t.round, t.pairing, t.game, t.seating, t.match_number, t.game_uuid,
+                  s.slug AS "slug?", t.places, t.reason, t.failed_seat
+           FROM tournament_games t
+           LEFT JOIN slugs s ON s.slug_id = t.game_uuid AND s.is_alias = 0
+           WHERE t.tournament_id = ? ORDER BY t.round, t.pairing, t.game"#,
+        tournament_id
+    )
+    .fetch_all(pool)
+    .await
+    .expect("Failed to query tournament games")
+    .into_iter()
+    .map(|row| TournamentGame {
+        round: row.round as usize,
+        pairing: row.pairing as usize,
+        game: row.game as usize,
+        seating: serde_json::from_str(&row.seating).unwrap_or_default(),
+        match_number: row.match_number.map(|number| number as u64),
+        game_uuid: row.game_uuid,
+        slug: row.slug,
+        places: row
+            .places
+            .and_then(|places| serde_json::from_str(&places).ok()),
+        reason: row.reason,
+        failed_seat: row.failed_seat.map(|seat| seat as usize),
+    })
+    .collect()
+}
+
+/// Records the match of the runner playing a game of a tournament
+pub async fn save_tournament_game_match(
+    pool: &SqlitePool,
+    tournament_id: i64,
+    game: &TournamentGame,
+    match_number: u64,
+) {
+    let (round, pairing, number) = (game.round as i64, game.pairing as i64, game.game as i64);
+    let match_number = match_number as i64;
+    sqlx::query!(
+        r#"UPDATE tournament_games SET match_number = ?
+           WHERE tournament_id = ? AND round = ? AND pairing = ? AND game = ?"#,
+        match_number,
+        tournament_id,
+        round,
+        pairing,
+        number
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to record tournament game match");
+}
+
+/// Records how a game of a tournament ended, the game it was saved as and
+/// the places by seat if it finished, or why it failed
+pub async fn save_tournament_game_result(
+    pool: &SqlitePool,
+    tournament_id: i64,
+    game: &TournamentGame,
+    result: Result<(Uuid, &[usize]), &MatchFailure>,
+) {
+    let (round, pairing, number) = (game.round as i64, game.pairing as i64, game.game as i64);
+    let (game_uuid, places, reason, failed_seat) = match result {
+        Ok((uuid, places)) => (
+            Some(uuid.to_string()),
+            Some(serde_json::to_string(places).unwrap()),
+            None,
+            None,
+        ),
+        Err(failure) => (
+            None,
+            None,
+            Some(failure.reason.as_str()),
+            failure.seat.map(|seat| seat as i64),
+        ),
+    };
+    sqlx::query!(
+        r#"UPDATE tournament_games SET game_uuid = ?, places = ?, reason = ?, failed_seat = ?
+           WHERE tournament_id = ? AND round = ? AND pairing = ? AND game = ?"#,
+        game_uuid,
+        places,
+        reason,
+        failed_seat,
+        tournament_id,
+        round,
+        pairing,
+        number
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to record tournament game result");
+}
+
+/// Forgets the matches of the games of a tournament that were still
+/// being played when the server stopped, so they are played again
+pub async fn reset_tournament_matches(pool: &SqlitePool, tournament_id: i64) {
+    sqlx::query!(
+        r#"UPDATE tournament_games SET match_number = NULL
+           WHERE tournament_id = ? AND places IS NULL AND reason IS NULL"#,
+        tournament_id
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to reset tournament games");
+}
+
+/// Forgets how the games of a pairing of a tournament ended, so they are
+/// played again, and counts the replay
+pub async fn replay_tournament_pairing(
+    pool: &SqlitePool,
+    tournament_id: i64,
+    round: usize,
+    pairing: usize,
+) {
+    let (round, pairing) = (round as i64, pairing as i64);
+    let mut tx = pool.begin().await.expect("Failed to start transaction");
+    sqlx::query!(
+        r#"UPDATE tournament_games
+           SET match_number = NULL, game_uuid = NULL, places = NULL, reason = NULL,
+               failed_seat = NULL
+           WHERE tournament_id = ? AND round = ? AND pairing = ?"#,
+        tournament_id,
+        round,
+        pairing
+    )
+    .execute(&mut *tx)
+    .await
+    .expect("Failed to reset tournament games");
+    sqlx::query!(
+        r#"UPDATE tournament_pairings SET replays = replays + 1
+           WHERE tournament_id = ? AND round = ? AND pairing = ?"#,
+        tournament_id,
+        round,
+        pairing
+    )
+    .execute(&mut *tx)
+    .await
+    .expect("Failed to count tournament replay");
+    tx.commit().await.expect("Failed to commit transaction");
+}
+
+/// Marks a tournament finished
+pub async fn save_tournament_finished(pool: &SqlitePool, tournament_id: i64) {
+    sqlx::query!(
+        "UPDATE tournaments SET finished_at = CURRENT_TIMESTAMP WHERE tournament_id = ?",
+        tournament_id
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to finish tournament");
 }
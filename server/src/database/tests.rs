@@ -0,0 +1,400 @@
+use super::*;
+use splendor_arena::*;
+
+/// A fresh in-memory database with every migration applied
+pub async fn create_test_db() -> sqlx::SqlitePool {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("could not open in-memory database");
+    migrate(&pool)
+        .await
+        .expect("could not migrate in-memory database");
+    pool
+}
+
+/// Saves a single game update, failing the test if it cannot be saved
+pub async fn simple_save_game_update(pool: &SqlitePool, game_update: GameUpdate, uuid: Uuid) {
+    save_game_updates(pool, &[(uuid, game_update)])
+        .await
+        .expect("could not save game update");
+}
+
+/// The first update of a game between three players
+pub fn default_game_update() -> GameUpdate {
+    GameUpdate {
+        update_num: 0,
+        info: SmallClientInfo {
+            board: Board {
+                deck_counts: [16, 26, 36],
+                available_cards: vec![vec![0, 1, 2, 3], vec![5, 6, 7, 8], vec![10, 11, 12]],
+                nobles: vec![0, 1, 2, 3, 4],
+                gems: Gems::empty(),
+            },
+            players: vec![
+                PlayerPublicInfo {
+                    gems: Gems::empty(),
+                    developments: Cost::from_gems(&Gems::empty()),
+                    num_reserved: 0,
+                    points: 0,
+                },
+                PlayerPublicInfo {
+                    gems: Gems::empty(),
+                    developments: Cost::from_gems(&Gems::empty()),
+                    num_reserved: 0,
+                    points: 0,
+                },
+                PlayerPublicInfo {
+                    gems: Gems::empty(),
+                    developments: Cost::from_gems(&Gems::empty()),
+                    num_reserved: 0,
+                    points: 0,
+                },
+            ],
+            current_player_num: 0,
+        },
+    }
+}
+
+/// An in-memory database without any schema
+async fn empty_db() -> sqlx::SqlitePool {
+    sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("could not open in-memory database")
+}
+
+#[tokio::test]
+async fn every_pooled_connection_gets_the_pragmas() {
+    let file = std::env::temp_dir().join(format!("stourney-pool-{}.db", Uuid::new_v4()));
+    let config = Config {
+        database_url: format!("sqlite://{}?mode=rwc", file.display()),
+        pool_size: 3,
+        ..Config::default()
+    };
+    let pool = connect(&config).await.unwrap();
+    let mut connections = vec![];
+    for _ in 0..config.pool_size {
+        connections.push(pool.acquire().await.unwrap());
+    }
+    // NORMAL synchronous is 1 and MEMORY temp_store is 2
+    let expected = [("foreign_keys", 1), ("synchronous", 1), ("temp_store", 2)];
+    for connection in &mut connections {
+        for (name, value) in expected {
+            let set: i64 = sqlx::query_scalar(&format!("PRAGMA {}", name))
+                .fetch_one(&mut **connection)
+                .await
+                .unwrap();
+            assert_eq!(set, value, "PRAGMA {}", name);
+        }
+    }
+    drop(connections);
+    pool.close().await;
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", file.display(), suffix));
+    }
+}
+
+/// The versions recorded in the schema_version table, in order
+async fn applied_versions(db: &sqlx::SqlitePool) -> Vec<i64> {
+    sqlx::query_scalar("SELECT version FROM schema_version ORDER BY version")
+        .fetch_all(db)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+pub async fn fresh_database_is_migrated_to_the_latest_version() {
+    let db = empty_db().await;
+    migrate(&db).await.unwrap();
+    let all: Vec<i64> = MIGRATIONS.iter().map(|(version, _, _)| *version).collect();
+    assert_eq!(applied_versions(&db).await, all);
+
+    // Nothing is left to apply the second time
+    migrate(&db).await.unwrap();
+    assert_eq!(applied_versions(&db).await, all);
+}
+
+#[tokio::test]
+pub async fn legacy_database_is_adopted_at_the_version_it_was_left_at() {
+    for legacy_version in [1, 2] {
+        let db = empty_db().await;
+        for (_, _, sql) in &MIGRATIONS[..legacy_version] {
+            sqlx::raw_sql(sql).execute(&db).await.unwrap();
+        }
+        let id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO games (game_uuid) VALUES (?)")
+            .bind(&id)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            legacy_schema_version(&db).await.unwrap(),
+            legacy_version as i64
+        );
+        migrate(&db).await.unwrap();
+        let all: Vec<i64> = MIGRATIONS.iter().map(|(version, _, _)| *version).collect();
+        assert_eq!(applied_versions(&db).await, all);
+        let games: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM games WHERE game_uuid = ?")
+            .bind(&id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(games, 1, "the games of a legacy database are kept");
+    }
+}
+
+/// A database migrated up to the version before the named migration
+async fn migrated_until(migration: &str) -> sqlx::SqlitePool {
+    let db = empty_db().await;
+    sqlx::raw_sql("CREATE TABLE schema_version (version INTEGER PRIMARY KEY, name TEXT NOT NULL)")
+        .execute(&db)
+        .await
+        .unwrap();
+    let until = MIGRATIONS
+        .iter()
+        .position(|(_, name, _)| *name == migration)
+        .unwrap();
+    for (version, name, sql) in &MIGRATIONS[..until] {
+        sqlx::raw_sql(sql).execute(&db).await.unwrap();
+        sqlx::query("INSERT INTO schema_version (version, name) VALUES (?, ?)")
+            .bind(version)
+            .bind(name)
+            .execute(&db)
+            .await
+            .unwrap();
+    }
+    db
+}
+
+#[tokio::test]
+pub async fn turn_tables_are_backfilled_from_the_updates_that_were_saved() {
+    let db = migrated_until("turn_tables").await;
+    let id = Uuid::new_v4().to_string();
+    let saved = serde_json::to_string(&default_game_update()).unwrap();
+    sqlx::query(
+        "INSERT INTO games (game_uuid) VALUES (?1);
+         INSERT INTO game_updates (update_uuid, turn_id, game_update) VALUES (?1, 0, ?2), (?1, 1, NULL)",
+    )
+    .bind(&id)
+    .bind(saved)
+    .execute(&db)
+    .await
+    .unwrap();
+
+    // An update that was never saved is skipped instead of failing the migration
+    migrate(&db).await.unwrap();
+    let turns: Vec<i64> = sqlx::query_scalar("SELECT turn_id FROM turn_boards WHERE game_uuid = ?")
+        .bind(&id)
+        .fetch_all(&db)
+        .await
+        .unwrap();
+    assert_eq!(turns, vec![0]);
+}
+
+#[tokio::test]
+pub async fn duplicate_slugs_stay_with_their_first_game() {
+    let db = migrated_until("slug_aliases").await;
+    let (first, second) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+    sqlx::query(
+        "INSERT INTO games (game_uuid) VALUES (?1), (?2);
+         INSERT INTO slugs (slug, slug_id) VALUES ('brave_fox0042', ?1), ('brave_fox0042', ?2)",
+    )
+    .bind(&first)
+    .bind(&second)
+    .execute(&db)
+    .await
+    .unwrap();
+
+    migrate(&db).await.unwrap();
+    let games: Vec<String> = sqlx::query_scalar("SELECT slug_id FROM slugs WHERE slug = ?")
+        .bind("brave_fox0042")
+        .fetch_all(&db)
+        .await
+        .unwrap();
+    assert_eq!(games, vec![first.clone()]);
+
+    let dropped: Vec<(String, String, String)> =
+        sqlx::query_as("SELECT slug, slug_id, kept_slug_id FROM dropped_slugs")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+    assert_eq!(
+        dropped,
+        vec![("brave_fox0042".to_string(), second, first)],
+        "expected the dropped slug to be recorded"
+    );
+}
+
+#[tokio::test]
+pub async fn database_migrated_by_a_newer_server_is_refused() {
+    let db = create_test_db().await;
+    sqlx::query("INSERT INTO schema_version (version, name) VALUES (?, 'from the future')")
+        .bind(SCHEMA_VERSION + 1)
+        .execute(&db)
+        .await
+        .unwrap();
+    assert!(migrate(&db).await.is_err());
+}
+
+#[tokio::test]
+pub async fn saved_game_update_is_written_to_the_turn_tables() {
+    let db = create_test_db().await;
+    let id = generate_new_id(&db).await;
+    let mut update = default_game_update();
+    simple_save_game_update(&db, update.clone(), id).await;
+
+    // Saving the same turn again replaces its rows
+    update.info.players[1].points = 3;
+    update.info.board.nobles = vec![4, 5];
+    update.info.board.available_cards[2].pop();
+    simple_save_game_update(&db, update.clone(), id).await;
+
+    let uuid = id.to_string();
+    let turn = update.update_num as i64;
+    let points: Vec<i64> = sqlx::query_scalar(
+        "SELECT points FROM turn_players WHERE game_uuid = ? AND turn_id = ? ORDER BY seat",
+    )
+    .bind(&uuid)
+    .bind(turn)
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(points, vec![0, 3, 0]);
+
+    let nobles: Vec<i64> = sqlx::query_scalar(
+        "SELECT noble_id FROM turn_nobles WHERE game_uuid = ? ORDER BY position",
+    )
+    .bind(&uuid)
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(nobles, vec![4, 5]);
+
+    let cards: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM turn_cards WHERE game_uuid = ?")
+        .bind(&uuid)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    let expected: usize = update.info.board.available_cards.iter().map(Vec::len).sum();
+    assert_eq!(cards, expected as i64);
+}
+
+#[tokio::test]
+pub async fn updates_saved_as_deltas_load_after_their_keyframe_is_overwritten() {
+    let db = create_test_db().await;
+    let id = generate_new_id(&db).await;
+    let turn = |n: usize| {
+        let mut update = default_game_update();
+        update.update_num = n;
+        update.info.players[0].points = n as u8;
+        update
+    };
+    for n in 0..20 {
+        simple_save_game_update(&db, turn(n), id).await;
+    }
+
+    // The arena resends the keyframe with different contents
+    let mut keyframe = turn(16);
+    keyframe.info.players[1].points = 9;
+    simple_save_game_update(&db, keyframe, id).await;
+
+    let updates = load_game_updates(&db, id, Some(17), None).await.unwrap();
+    let points: Vec<u8> = updates
+        .iter()
+        .map(|update| update.info.players[0].points)
+        .collect();
+    assert_eq!(points, vec![17, 18, 19]);
+
+    let update = load_game_update(&db, id, 16).await.unwrap().unwrap();
+    assert_eq!(update.info.players[1].points, 9);
+    let latest = load_latest_game_update(&db, id).await.unwrap().unwrap();
+    assert_eq!(latest.update_num, 19);
+    assert_eq!(
+        load_game_updates(&db, id, None, None).await.unwrap().len(),
+        20
+    );
+}
+
+#[tokio::test]
+pub async fn updates_saved_before_their_keyframe_do_not_hide_later_deltas() {
+    let db = create_test_db().await;
+    let id = generate_new_id(&db).await;
+    let turn = |n: usize| {
+        let mut update = default_game_update();
+        update.update_num = n;
+        update.info.players[0].points = n as u8;
+        update
+    };
+    for n in 0..16 {
+        simple_save_game_update(&db, turn(n), id).await;
+    }
+    // Turn 17 arrives before its keyframe, so it is saved as a keyframe itself
+    for n in [17, 16, 18, 19] {
+        simple_save_game_update(&db, turn(n), id).await;
+    }
+
+    let updates = load_game_updates(&db, id, None, None).await.unwrap();
+    let points: Vec<u8> = updates
+        .iter()
+        .map(|update| update.info.players[0].points)
+        .collect();
+    assert_eq!(points, (0..20).collect::<Vec<u8>>());
+}
+
+#[test]
+pub fn winner_is_the_only_seat_in_first_place() {
+    let mut update = default_game_update();
+    assert_eq!(
+        determine_winner(&update.info, None),
+        None,
+        "expected an even game to have no winner"
+    );
+
+    update.info.players[1].points = 15;
+    update.info.players[2].points = 9;
+    assert_eq!(determine_winner(&update.info, None), Some(1));
+
+    // A seat that forfeited places last whatever its points
+    assert_eq!(determine_winner(&update.info, Some(1)), Some(2));
+}
+
+#[tokio::test]
+pub async fn game_over_records_the_final_state_of_the_game() {
+    let db = create_test_db().await;
+    let empty = generate_new_id(&db).await;
+    assert!(
+        save_game_over(&db, empty).await.is_none(),
+        "expected no result for a game without updates"
+    );
+
+    let id = generate_new_id(&db).await;
+    let mut update = default_game_update();
+    simple_save_game_update(&db, update.clone(), id).await;
+    update.update_num = 1;
+    update.info.players[0].points = 16;
+    update.info.players[2].points = 4;
+    simple_save_game_update(&db, update, id).await;
+
+    let result = save_game_over(&db, id)
+        .await
+        .expect("expected a result for a game with updates");
+    assert_eq!(result.final_turn, 1);
+    assert_eq!(result.winner, Some(0));
+    assert_eq!(result.final_scores, vec![16, 0, 4]);
+
+    let (finished, final_turn, winner, final_scores): (Option<String>, i64, i64, String) =
+        sqlx::query_as(
+            "SELECT finished_at, final_turn, winner, final_scores FROM games WHERE game_uuid = ?",
+        )
+        .bind(id.to_string())
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert!(finished.is_some(), "expected the game to be finished");
+    assert_eq!((final_turn, winner), (1, 0));
+    assert_eq!(final_scores, "[16,0,4]");
+}
@@ -0,0 +1,193 @@
+// Infers what happened between two consecutive game updates.
+//
+// The arena only ever sends full snapshots of the game state, so the
+// action a player took has to be worked out by comparing the snapshot
+// before their turn with the snapshot after it:
+//
+//      - a new development card means a card was bought, either from the
+//        board (the card disappears from the available cards) or from the
+//        player's reserved hand (their reserve count goes down)
+//      - a larger reserve count means a card was reserved, either face up
+//        from the board or blind from the top of a deck
+//      - otherwise, gaining tokens means gems were taken
+//
+// Tokens moving from the player back to the bank (paying for a card or
+// discarding down to the token limit) are reported separately from the
+// action as the gems returned that turn.
+
+#[cfg(test)]
+pub mod tests;
+
+use serde::{Deserialize, Serialize};
+use splendor_arena::models::GameUpdate;
+use splendor_arena::*;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Action {
+    #[serde(rename = "takeGems")]
+    TakeGems,
+    #[serde(rename = "reserveCard")]
+    ReserveCard {
+        /// None when the card was reserved blind from the top of a deck
+        #[serde(rename = "cardId")]
+        card_id: Option<CardId>,
+        /// Index of the row of available cards (and deck) it came from
+        tier: Option<usize>,
+    },
+    #[serde(rename = "buyCard")]
+    BuyCard {
+        /// None when a reserved card was bought, as reserved cards are
+        /// hidden from the snapshots
+        #[serde(rename = "cardId")]
+        card_id: Option<CardId>,
+        #[serde(rename = "fromReserve")]
+        from_reserve: bool,
+    },
+    #[serde(rename = "pass")]
+    Pass,
+    /// The two snapshots do not describe any single legal action
+    #[serde(rename = "unknown")]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TurnDelta {
+    #[serde(rename = "turnNumber")]
+    pub turn_number: usize,
+    /// The seat of the player who acted
+    pub player: usize,
+    pub action: Action,
+    /// Tokens moved from the bank to the player
+    #[serde(rename = "gemsTaken")]
+    pub gems_taken: Gems,
+    /// Tokens moved from the player back to the bank
+    #[serde(rename = "gemsReturned")]
+    pub gems_returned: Gems,
+    #[serde(rename = "nobleAcquired")]
+    pub noble_acquired: Option<NobleId>,
+    /// Cards dealt from the decks to refill the board
+    #[serde(rename = "cardsDealt")]
+    pub cards_dealt: Vec<CardId>,
+    #[serde(rename = "pointsGained")]
+    pub points_gained: usize,
+}
+
+fn development_count(developments: &Cost) -> i32 {
+    Gem::all_expect_gold()
+        .into_iter()
+        .map(|gem| developments[gem] as i32)
+        .sum()
+}
+
+/// The position of a card on the board as (row, column)
+fn find_card(board: &Board, card_id: CardId) -> Option<(usize, usize)> {
+    board
+        .available_cards
+        .iter()
+        .enumerate()
+        .find_map(|(row, cards)| {
+            cards
+                .iter()
+                .position(|&id| id == card_id)
+                .map(|column| (row, column))
+        })
+}
+
+/// Cards on the board of `before` that are no longer on the board of `after`
+fn removed_cards(before: &Board, after: &Board) -> Vec<CardId> {
+    before
+        .available_cards
+        .iter()
+        .flatten()
+        .filter(|&&id| find_card(after, id).is_none())
+        .copied()
+        .collect()
+}
+
+/// Infer what the current player of `previous` did to reach `next`,
+/// None if that player has no seat in either of them, or if their gem
+/// counts are too far apart to be a turn of the game
+pub fn infer(previous: &GameUpdate, next: &GameUpdate) -> Option<TurnDelta> {
+    let player = previous.info.current_player_num;
+    let before = previous.info.players.get(player)?;
+    let after = next.info.players.get(player)?;
+    let board_before = &previous.info.board;
+    let board_after = &next.info.board;
+
+    let mut gems_taken = Gems::empty();
+    let mut gems_returned = Gems::empty();
+    for gem in Gem::all() {
+        // Counts come from the arena, so they are not trusted to be in range
+        let change = after.gems[gem].checked_sub(before.gems[gem])?;
+        if change > 0 {
+            gems_taken[gem] = change;
+        } else {
+            gems_returned[gem] = change.checked_neg()?;
+        }
+    }
+
+    let removed = removed_cards(board_before, board_after);
+    let cards_dealt = removed_cards(board_after, board_before);
+    let noble_acquired = board_before
+        .nobles
+        .iter()
+        .find(|noble| !board_after.nobles.contains(noble))
+        .copied();
+
+    let developments_gained =
+        development_count(&after.developments) - development_count(&before.developments);
+    let reserved_gained = after.num_reserved as i32 - before.num_reserved as i32;
+
+    let all_cards = Card::all_const();
+    let action = match (developments_gained, reserved_gained) {
+        (1, 0) => {
+            // A card bought from the board must match the development gained
+            let card_id = removed.iter().copied().find(|&id| {
+                all_cards.get(id as usize).is_some_and(|card| {
+                    let gem = card.gem();
+                    after.developments[gem] > before.developments[gem]
+                })
+            });
+            match card_id {
+                Some(card_id) => Action::BuyCard {
+                    card_id: Some(card_id),
+                    from_reserve: false,
+                },
+                None => Action::Unknown,
+            }
+        }
+        (1, -1) => Action::BuyCard {
+            card_id: None,
+            from_reserve: true,
+        },
+        (0, 1) => match removed.first() {
+            Some(&card_id) => Action::ReserveCard {
+                card_id: Some(card_id),
+                tier: find_card(board_before, card_id).map(|(row, _)| row),
+            },
+            None => {
+                // Reserved from the top of the deck that shrank
+                let tier = (0..board_before.deck_counts.len())
+                    .find(|&tier| board_after.deck_counts[tier] < board_before.deck_counts[tier]);
+                Action::ReserveCard {
+                    card_id: None,
+                    tier,
+                }
+            }
+        },
+        (0, 0) if gems_taken.total() > 0 => Action::TakeGems,
+        (0, 0) if gems_returned.total() == 0 && removed.is_empty() => Action::Pass,
+        _ => Action::Unknown,
+    };
+
+    Some(TurnDelta {
+        turn_number: next.update_num,
+        player,
+        action,
+        gems_taken,
+        gems_returned,
+        noble_acquired,
+        cards_dealt,
+        points_gained: after.points.saturating_sub(before.points) as usize,
+    })
+}
@@ -0,0 +1,139 @@
+use super::*;
+use crate::database::tests::default_game_update;
+
+fn next_turn(previous: &GameUpdate) -> GameUpdate {
+    let mut next = previous.clone();
+    next.update_num += 1;
+    next.info.current_player_num =
+        (previous.info.current_player_num + 1) % previous.info.players.len();
+    next
+}
+
+#[test]
+pub fn taking_gems_is_inferred() {
+    let previous = default_game_update();
+    let mut next = next_turn(&previous);
+    next.info.players[0].gems.ruby += 1;
+    next.info.players[0].gems.onyx += 1;
+    next.info.players[0].gems.diamond += 1;
+    next.info.board.gems.ruby -= 1;
+    next.info.board.gems.onyx -= 1;
+    next.info.board.gems.diamond -= 1;
+
+    let delta = infer(&previous, &next).unwrap();
+    assert_eq!(delta.turn_number, 1);
+    assert_eq!(delta.player, 0);
+    assert_eq!(delta.action, Action::TakeGems);
+    assert_eq!(delta.gems_taken.total(), 3);
+    assert_eq!(delta.gems_returned.total(), 0);
+    assert!(delta.cards_dealt.is_empty());
+}
+
+#[test]
+pub fn buying_from_the_board_reports_card_payment_and_refill() {
+    let mut previous = default_game_update();
+    previous.info.current_player_num = 1;
+    previous.info.players[1].gems = Gems {
+        onyx: 0,
+        sapphire: 1,
+        emerald: 1,
+        ruby: 1,
+        diamond: 1,
+        gold: 0,
+    };
+
+    // Card 0 is an onyx development costing one of every other color
+    let mut next = next_turn(&previous);
+    next.info.players[1].gems = Gems::empty();
+    next.info.players[1].developments.onyx = 1;
+    next.info.board.available_cards[0] = vec![4, 1, 2, 3];
+    next.info.board.deck_counts[0] -= 1;
+
+    let delta = infer(&previous, &next).unwrap();
+    assert_eq!(delta.player, 1);
+    assert_eq!(
+        delta.action,
+        Action::BuyCard {
+            card_id: Some(0),
+            from_reserve: false
+        }
+    );
+    assert_eq!(delta.gems_returned.total(), 4);
+    assert_eq!(delta.cards_dealt, vec![4]);
+}
+
+#[test]
+pub fn blind_reserve_reports_the_deck_it_came_from() {
+    let previous = default_game_update();
+    let mut next = next_turn(&previous);
+    next.info.players[0].num_reserved = 1;
+    next.info.players[0].gems.gold = 1;
+    next.info.board.gems.gold -= 1;
+    next.info.board.deck_counts[2] -= 1;
+
+    let delta = infer(&previous, &next).unwrap();
+    assert_eq!(
+        delta.action,
+        Action::ReserveCard {
+            card_id: None,
+            tier: Some(2)
+        }
+    );
+    assert_eq!(delta.gems_taken.gold, 1);
+}
+
+#[test]
+pub fn noble_visit_is_reported_with_the_purchase() {
+    let mut previous = default_game_update();
+    previous.info.players[0].num_reserved = 1;
+    let mut next = next_turn(&previous);
+    next.info.players[0].num_reserved = 0;
+    next.info.players[0].developments.ruby = 1;
+    next.info.players[0].points = 3;
+    next.info.board.nobles = vec![0, 2, 3, 4];
+
+    let delta = infer(&previous, &next).unwrap();
+    assert_eq!(
+        delta.action,
+        Action::BuyCard {
+            card_id: None,
+            from_reserve: true
+        }
+    );
+    assert_eq!(delta.noble_acquired, Some(1));
+    assert_eq!(delta.points_gained, 3);
+}
+
+#[test]
+pub fn unchanged_state_is_a_pass() {
+    let previous = default_game_update();
+    let next = next_turn(&previous);
+    assert_eq!(infer(&previous, &next).unwrap().action, Action::Pass);
+}
+
+#[test]
+pub fn current_player_without_a_seat_infers_nothing() {
+    let mut previous = default_game_update();
+    previous.info.current_player_num = previous.info.players.len();
+    let next = next_turn(&previous);
+    assert!(infer(&previous, &next).is_none());
+
+    let previous = default_game_update();
+    let mut next = next_turn(&previous);
+    next.info.players.clear();
+    assert!(infer(&previous, &next).is_none());
+}
+
+#[test]
+pub fn out_of_range_gem_counts_infer_nothing() {
+    let mut previous = default_game_update();
+    previous.info.players[0].gems.ruby = -128;
+    let mut next = next_turn(&previous);
+    next.info.players[0].gems.ruby = 100;
+    assert!(infer(&previous, &next).is_none());
+
+    let previous = default_game_update();
+    let mut next = next_turn(&previous);
+    next.info.players[0].gems.onyx = -128;
+    assert!(infer(&previous, &next).is_none());
+}
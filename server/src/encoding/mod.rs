@@ -0,0 +1,94 @@
+// Compact binary encoding for the game updates stored in game_updates.
+//
+// Updates are serialized with bincode and compressed with zstd. Every
+// KEYFRAME_INTERVAL turns the update is stored on its own as a keyframe,
+// and the turns in between are compressed using their keyframe as a zstd
+// dictionary, so only what changed since the keyframe takes up space:
+//
+//      turn:      0    1    2  ...  15   16   17  ...
+//      encoding:  K    D    D  ...  D    K    D   ...
+//                 ^----'----'-------'    ^----'
+//
+// Deltas are taken against the keyframe rather than the previous turn so
+// any turn can be decoded from two rows, instead of replaying every turn
+// since the keyframe. Rows saved before this encoding existed are JSON
+// and are still decoded as such.
+
+#[cfg(test)]
+pub mod tests;
+
+use splendor_arena::models::GameUpdate;
+use std::io::{self, Read};
+
+/// How often a turn is stored as a keyframe
+pub const KEYFRAME_INTERVAL: usize = 16;
+
+/// The zstd compression level, higher is smaller but slower
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// How a row of game_updates is stored, saved as an integer alongside it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// serde_json text in the game_update column
+    Json = 0,
+    /// Compressed bincode in the payload column
+    Keyframe = 1,
+    /// Compressed bincode in the payload column, using the raw bytes of
+    /// the keyframe of the turn as the compression dictionary
+    Delta = 2,
+}
+
+impl Encoding {
+    pub fn from_i64(value: i64) -> Option<Self> {
+        match value {
+            0 => Some(Encoding::Json),
+            1 => Some(Encoding::Keyframe),
+            2 => Some(Encoding::Delta),
+            _ => None,
+        }
+    }
+}
+
+/// The turn of the keyframe a turn is encoded against
+pub fn keyframe_turn(turn: usize) -> usize {
+    turn - turn % KEYFRAME_INTERVAL
+}
+
+/// The uncompressed bytes of an update, also used as the dictionary
+/// for the deltas of a keyframe
+pub fn serialize(update: &GameUpdate) -> Vec<u8> {
+    bincode::serialize(update).expect("Failed to serialize game update")
+}
+
+/// Encodes an update to be stored as a keyframe
+pub fn encode_keyframe(update: &GameUpdate) -> Vec<u8> {
+    zstd::bulk::compress(&serialize(update), COMPRESSION_LEVEL)
+        .expect("Failed to compress game update")
+}
+
+/// Encodes an update to be stored as a delta against a keyframe, given
+/// the serialized keyframe
+pub fn encode_delta(update: &GameUpdate, keyframe: &[u8]) -> Vec<u8> {
+    zstd::bulk::Compressor::with_dictionary(COMPRESSION_LEVEL, keyframe)
+        .and_then(|mut compressor| compressor.compress(&serialize(update)))
+        .expect("Failed to compress game update")
+}
+
+/// The serialized update of a stored keyframe, to decode its deltas with
+pub fn decompress_keyframe(payload: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::stream::decode_all(payload)
+}
+
+/// Decodes a keyframe, or a delta given the serialized keyframe
+pub fn decode(payload: &[u8], keyframe: Option<&[u8]>) -> io::Result<GameUpdate> {
+    let bytes = match keyframe {
+        Some(keyframe) => {
+            let mut bytes = vec![];
+            zstd::stream::read::Decoder::with_dictionary(payload, keyframe)?
+                .read_to_end(&mut bytes)?;
+            bytes
+        }
+        None => decompress_keyframe(payload)?,
+    };
+    bincode::deserialize(&bytes).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
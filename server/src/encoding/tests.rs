@@ -0,0 +1,54 @@
+use super::*;
+use crate::database::tests::default_game_update;
+
+#[test]
+pub fn keyframes_round_trip() {
+    let update = GameUpdate {
+        update_num: 16,
+        ..default_game_update()
+    };
+    let decoded = decode(&encode_keyframe(&update), None).unwrap();
+    assert_eq!(serialize(&decoded), serialize(&update));
+}
+
+#[test]
+pub fn deltas_round_trip_and_are_smaller_than_keyframes() {
+    let mut update = default_game_update();
+    update.update_num = 16;
+    let keyframe = serialize(&update);
+    // Five turns on, a couple of gems and points have changed hands
+    update.update_num = 21;
+    update.info.current_player_num = 2;
+    update.info.players[0].gems.ruby = 1;
+    update.info.players[1].points = 2;
+
+    let delta = encode_delta(&update, &keyframe);
+    let decoded = decode(&delta, Some(&keyframe)).unwrap();
+    assert_eq!(serialize(&decoded), serialize(&update));
+    assert!(
+        delta.len() < encode_keyframe(&update).len(),
+        "delta of {} bytes is no smaller than a keyframe",
+        delta.len()
+    );
+}
+
+#[test]
+pub fn encoded_update_is_smaller_than_json() {
+    let update = GameUpdate {
+        update_num: 3,
+        ..default_game_update()
+    };
+    let json = serde_json::to_string(&update).unwrap();
+    assert!(encode_keyframe(&update).len() < json.len() / 2);
+}
+
+#[test]
+pub fn keyframe_turns_are_every_interval() {
+    assert_eq!(keyframe_turn(0), 0);
+    assert_eq!(keyframe_turn(KEYFRAME_INTERVAL - 1), 0);
+    assert_eq!(keyframe_turn(KEYFRAME_INTERVAL), KEYFRAME_INTERVAL);
+    assert_eq!(
+        keyframe_turn(3 * KEYFRAME_INTERVAL + 5),
+        3 * KEYFRAME_INTERVAL
+    );
+}
@@ -0,0 +1,91 @@
+// Picking tables out of the matchmaking queue.
+//
+// Every ticket asks for a game of a number of players. The ticket that has
+// waited longest is seated first, with the tickets for the same number of
+// players closest to it in rating. Two tickets can only sit together when
+// their ratings are within the spread of both of them, and the spread of a
+// ticket widens the longer it waits:
+//
+//      spread = rating_spread * (1 + waited / WIDEN_EVERY)
+//
+// so a player with no one close in rating still finds a game eventually.
+// An account never sits at a table twice.
+
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long a ticket waits before its spread has widened by rating_spread
+pub const WIDEN_EVERY: Duration = Duration::from_secs(30);
+
+/// A player waiting in the queue for a game
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ticket {
+    pub token: String,
+    /// How many players the game is for, 2 to 4
+    pub players: usize,
+    pub user_id: i64,
+    pub rating: f64,
+    pub since: Instant,
+}
+
+/// How far from its rating a ticket accepts opponents after waiting
+pub fn spread(rating_spread: f64, waited: Duration) -> f64 {
+    rating_spread * (1.0 + waited.as_secs_f64() / WIDEN_EVERY.as_secs_f64())
+}
+
+/// The tables that can be seated now, as indices into the tickets, in
+/// seat order from the ticket that waited longest
+pub fn find_tables(tickets: &[Ticket], rating_spread: f64, now: Instant) -> Vec<Vec<usize>> {
+    let reach =
+        |ticket: &Ticket| spread(rating_spread, now.saturating_duration_since(ticket.since));
+    let mut waiting: Vec<usize> = (0..tickets.len()).collect();
+    waiting.sort_by_key(|&index| tickets[index].since);
+
+    let mut tables = vec![];
+    let mut seated = vec![false; tickets.len()];
+    for &first in &waiting {
+        if seated[first] {
+            continue;
+        }
+        let anchor = &tickets[first];
+        let mut candidates: Vec<usize> = waiting
+            .iter()
+            .copied()
+            .filter(|&other| {
+                let ticket = &tickets[other];
+                let apart = (ticket.rating - anchor.rating).abs();
+                other != first
+                    && !seated[other]
+                    && ticket.players == anchor.players
+                    && apart <= reach(anchor).min(reach(ticket))
+            })
+            .collect();
+        candidates.sort_by(|&a, &b| {
+            let apart = |index: usize| (tickets[index].rating - anchor.rating).abs();
+            apart(a).total_cmp(&apart(b))
+        });
+
+        let mut table = vec![first];
+        for other in candidates {
+            if table.len() == anchor.players {
+                break;
+            }
+            let ticket = &tickets[other];
+            let fits = table.iter().all(|&seat| {
+                let sitting = &tickets[seat];
+                sitting.user_id != ticket.user_id
+                    && (sitting.rating - ticket.rating).abs() <= reach(sitting).min(reach(ticket))
+            });
+            if fits {
+                table.push(other);
+            }
+        }
+        if table.len() == anchor.players {
+            for &seat in &table {
+                seated[seat] = true;
+            }
+            tables.push(table);
+        }
+    }
+    tables
+}
@@ -0,0 +1,957 @@
+// Online games between players gathered in the lobby.
+//
+// Players are logged in accounts, people on the website or bots, each
+// playing as one of the bots of their account. They sit at a table for 2
+// to 4 players, either one they open or join themselves, or one the
+// matchmaking queue seats them at with players of a similar rating, see
+// matchmaking.rs. Every seat taken is handed a ticket, and the player
+// plays through a websocket of their own opened with it:
+//
+//      POST /api/lobby/tables           \
+//      POST /api/lobby/tables/{id}/join  -> ticket
+//      POST /api/lobby/queue            /
+//
+//      GET /api/lobby/play/{ticket} -> Seated -> Started -> turns -> Finished
+//
+// Once a table is full the server plays its game the way the runner plays
+// hosted matches: each turn every seat is sent a Broadcast of the board,
+// and the player to move a PlayerActionRequest, answered with an Action
+// as in the protocol of splendor_arena. A player that is too slow, or
+// whose websocket is gone, has the first legal action played for them,
+// and can reconnect with the same ticket until the game ends. A player
+// still gone when their turn comes has the time of a move to reconnect,
+// or forfeits and places last. Games are saved through the queue and rated
+// like any other, with the bot of each player in its seat.
+//
+// An account sits at most once at a table or in the queue, and has at
+// most max_tables_per_user tables open or playing. Tables nobody filled
+// within open_table_secs are abandoned.
+//
+// Tables and tickets are kept in memory, and are lost when the server
+// restarts, but the games played at them are not.
+
+pub mod matchmaking;
+#[cfg(test)]
+pub mod tests;
+
+use futures::{SinkExt, StreamExt};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use splendor_arena::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, info, warn, Instrument};
+use uuid::Uuid;
+use warp::ws::{Message, WebSocket};
+use warp::Filter;
+
+use crate::api::Response;
+use crate::auth;
+use crate::config;
+use crate::logging;
+use crate::queue::{self, AsyncQueue};
+use crate::ratings;
+use crate::runner::{self, Deadline, Fault, MAX_ACTIONS};
+use crate::shutdown;
+use crate::websocket::game_url;
+use matchmaking::Ticket;
+
+/// How many finished tables are remembered
+const MAX_FINISHED: usize = 256;
+
+/// How often the matchmaking queue is looked at while players wait in it
+const MATCHMAKING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Someone sitting in the lobby, as the bot of their account they play as
+#[derive(Debug, Clone, PartialEq)]
+pub struct Player {
+    pub user_id: i64,
+    pub bot_id: i64,
+    pub name: String,
+    /// The username of the account
+    pub owner: String,
+    pub rating: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableState {
+    /// Waiting for players to join
+    Open,
+    /// Full, waiting for its players to connect
+    Starting,
+    Playing {
+        id: Uuid,
+        slug: String,
+    },
+    Finished {
+        slug: String,
+        /// The place each seat finished in, 0 for the winner
+        places: Vec<usize>,
+    },
+    /// The game could not be played
+    Abandoned(String),
+}
+
+impl TableState {
+    pub fn is_over(&self) -> bool {
+        matches!(self, TableState::Finished { .. } | TableState::Abandoned(_))
+    }
+}
+
+/// A table as shown in the lobby
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableStatus {
+    pub id: u64,
+    /// How many players the game is for
+    pub players: usize,
+    /// The players sitting, in seat order
+    pub seats: Vec<Player>,
+    pub state: TableState,
+    /// Whether the matchmaking queue seated the players
+    pub matched: bool,
+}
+
+/// Where a ticket sits, watched by the websocket of its player
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Seating {
+    pub table: u64,
+    pub seat: usize,
+    /// Whether the table is full and its game is being played
+    pub started: bool,
+}
+
+/// A seat taken in the lobby, None while the ticket waits in the queue
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entered {
+    pub ticket: String,
+    pub seating: Option<Seating>,
+}
+
+/// Why the lobby turned a player away
+#[derive(Debug, Clone, PartialEq)]
+pub enum Refused {
+    NotFound(String),
+    Conflict(String),
+}
+
+/// A table that just filled up, with the channel each of its seats
+/// receives the websockets of its player on
+pub struct Start {
+    pub table: u64,
+    pub players: Vec<Player>,
+    pub sockets: Vec<UnboundedReceiver<WebSocket>>,
+}
+
+/// What the websocket of a ticket is told about its table, on top of the
+/// messages of splendor_arena sent while the game is played
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LobbyEvent {
+    /// The ticket sits at a table, sent again if its seat changes
+    Seated {
+        table: u64,
+        seat: usize,
+        players: usize,
+    },
+    /// The game of the table started, and can be watched at the url
+    Started {
+        seat: usize,
+        slug: String,
+        url: String,
+        names: Vec<String>,
+    },
+    Finished {
+        places: Vec<usize>,
+    },
+    Abandoned {
+        reason: String,
+    },
+}
+
+/// How long players have to connect and play
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub move_time: Duration,
+    pub connect_time: Duration,
+    pub max_actions: usize,
+}
+
+impl Limits {
+    pub fn configured() -> Self {
+        let lobby = &config::get().lobby;
+        Limits {
+            move_time: Duration::from_secs(lobby.move_time_secs),
+            connect_time: Duration::from_secs(lobby.connect_time_secs),
+            max_actions: MAX_ACTIONS,
+        }
+    }
+}
+
+struct Table {
+    players: usize,
+    seats: Vec<Player>,
+    tickets: Vec<String>,
+    sockets: Vec<UnboundedSender<WebSocket>>,
+    /// The other ends of the sockets, handed to the game once it starts
+    waiting: Vec<UnboundedReceiver<WebSocket>>,
+    state: TableState,
+    matched: bool,
+    opened: Instant,
+}
+
+impl Table {
+    fn status(&self, id: u64) -> TableStatus {
+        TableStatus {
+            id,
+            players: self.players,
+            seats: self.seats.clone(),
+            state: self.state.clone(),
+            matched: self.matched,
+        }
+    }
+}
+
+/// The tables, the matchmaking queue and the tickets of their players
+#[derive(Default)]
+pub struct Lobby {
+    tables: BTreeMap<u64, Table>,
+    queue: Vec<Ticket>,
+    /// The players of the tickets waiting in the queue
+    queued: HashMap<String, Player>,
+    tickets: HashMap<String, watch::Sender<Option<Seating>>>,
+    last_table: u64,
+}
+
+impl Lobby {
+    fn new_ticket(&mut self) -> String {
+        let ticket = auth::generate_session_token();
+        self.tickets.insert(ticket.clone(), watch::channel(None).0);
+        ticket
+    }
+
+    fn entered(&self, ticket: String) -> Entered {
+        let seating = self
+            .tickets
+            .get(&ticket)
+            .and_then(|seating| *seating.borrow());
+        Entered { ticket, seating }
+    }
+
+    fn active_tables(&self) -> usize {
+        self.tables
+            .values()
+            .filter(|table| !table.state.is_over())
+            .count()
+    }
+
+    /// How many tables open or playing the account of a player sits at
+    fn tables_of(&self, user_id: i64) -> usize {
+        self.tables
+            .values()
+            .filter(|table| !table.state.is_over())
+            .filter(|table| table.seats.iter().any(|seated| seated.user_id == user_id))
+            .count()
+    }
+
+    /// Refuses a player whose account already sits at max_tables_per_user
+    /// tables open or playing
+    fn check_tables_of(&self, player: &Player, max_tables_per_user: usize) -> Result<(), Refused> {
+        if self.tables_of(player.user_id) >= max_tables_per_user {
+            return Err(Refused::Conflict(format!(
+                "{} already sits at {} tables",
+                player.owner, max_tables_per_user
+            )));
+        }
+        Ok(())
+    }
+
+    fn add_table(&mut self, players: usize, matched: bool, now: Instant) -> u64 {
+        self.last_table += 1;
+        let table = Table {
+            players,
+            seats: vec![],
+            tickets: vec![],
+            sockets: vec![],
+            waiting: vec![],
+            state: TableState::Open,
+            matched,
+            opened: now,
+        };
+        self.tables.insert(self.last_table, table);
+        self.last_table
+    }
+
+    /// Tells the ticket of every seat of a table where it sits
+    fn announce(&self, id: u64) {
+        let Some(table) = self.tables.get(&id) else {
+            return;
+        };
+        let started = table.state != TableState::Open;
+        for (seat, ticket) in table.tickets.iter().enumerate() {
+            if let Some(seating) = self.tickets.get(ticket) {
+                seating.send_replace(Some(Seating {
+                    table: id,
+                    seat,
+                    started,
+                }));
+            }
+        }
+    }
+
+    /// Seats a player at the next seat of a table, returns the table to
+    /// play once it is full
+    fn sit(&mut self, id: u64, player: Player, ticket: String) -> Option<Start> {
+        let table = self
+            .tables
+            .get_mut(&id)
+            .expect("Players sit at tables that exist");
+        let (socket, waiting) = mpsc::unbounded_channel();
+        table.seats.push(player);
+        table.tickets.push(ticket);
+        table.sockets.push(socket);
+        table.waiting.push(waiting);
+        let start = (table.seats.len() == table.players).then(|| {
+            table.state = TableState::Starting;
+            Start {
+                table: id,
+                players: table.seats.clone(),
+                sockets: std::mem::take(&mut table.waiting),
+            }
+        });
+        self.announce(id);
+        start
+    }
+
+    /// Opens a table for a number of players with the player in its first
+    /// seat, if the lobby and the account of the player have room for it
+    pub fn open_table(
+        &mut self,
+        players: usize,
+        player: Player,
+        max_tables: usize,
+        max_tables_per_user: usize,
+        now: Instant,
+    ) -> Result<Entered, Refused> {
+        if self.active_tables() >= max_tables {
+            return Err(Refused::Conflict(
+                "the lobby has no room for another table".to_string(),
+            ));
+        }
+        self.check_tables_of(&player, max_tables_per_user)?;
+        let id = self.add_table(players, false, now);
+        let ticket = self.new_ticket();
+        self.sit(id, player, ticket.clone());
+        Ok(self.entered(ticket))
+    }
+
+    /// Sits a player at the next free seat of an open table, if their
+    /// account has room for it, along with the table to play if they
+    /// filled it
+    pub fn join_table(
+        &mut self,
+        id: u64,
+        player: Player,
+        max_tables_per_user: usize,
+    ) -> Result<(Entered, Option<Start>), Refused> {
+        let table = self
+            .tables
+            .get(&id)
+            .ok_or_else(|| Refused::NotFound(format!("no table {}", id)))?;
+        if table.state != TableState::Open {
+            return Err(Refused::Conflict(format!("table {} is full", id)));
+        }
+        if table
+            .seats
+            .iter()
+            .any(|seated| seated.user_id == player.user_id)
+        {
+            return Err(Refused::Conflict(format!(
+                "{} already sits at table {}",
+                player.owner, id
+            )));
+        }
+        self.check_tables_of(&player, max_tables_per_user)?;
+        let ticket = self.new_ticket();
+        let start = self.sit(id, player, ticket.clone());
+        Ok((self.entered(ticket), start))
+    }
+
+    /// Puts a player in the matchmaking queue for a game of a number of
+    /// players, if their account has room for another table, they are
+    /// seated by matchmake()
+    pub fn enqueue(
+        &mut self,
+        players: usize,
+        player: Player,
+        max_tables_per_user: usize,
+        now: Instant,
+    ) -> Result<Entered, Refused> {
+        if self
+            .queue
+            .iter()
+            .any(|ticket| ticket.user_id == player.user_id)
+        {
+            return Err(Refused::Conflict(format!(
+                "{} is already waiting in the queue",
+                player.owner
+            )));
+        }
+        self.check_tables_of(&player, max_tables_per_user)?;
+        let ticket = self.new_ticket();
+        self.queue.push(Ticket {
+            token: ticket.clone(),
+            players,
+            user_id: player.user_id,
+            rating: player.rating,
+            since: now,
+        });
+        self.queued.insert(ticket.clone(), player);
+        Ok(self.entered(ticket))
+    }
+
+    /// Seats the players of the queue that can play each other now at
+    /// tables of their own, and returns the tables to play. Players whose
+    /// account sits at max_tables_per_user tables keep waiting
+    pub fn matchmake(
+        &mut self,
+        rating_spread: f64,
+        max_tables: usize,
+        max_tables_per_user: usize,
+        now: Instant,
+    ) -> Vec<Start> {
+        let eligible: Vec<usize> = (0..self.queue.len())
+            .filter(|&index| self.tables_of(self.queue[index].user_id) < max_tables_per_user)
+            .collect();
+        let tickets: Vec<Ticket> = eligible
+            .iter()
+            .map(|&index| self.queue[index].clone())
+            .collect();
+        let mut starts = vec![];
+        let mut seated = vec![];
+        for found in matchmaking::find_tables(&tickets, rating_spread, now) {
+            if self.active_tables() >= max_tables {
+                break;
+            }
+            let id = self.add_table(found.len(), true, now);
+            for index in found.into_iter().map(|found| eligible[found]) {
+                let ticket = self.queue[index].token.clone();
+                let player = self
+                    .queued
+                    .remove(&ticket)
+                    .expect("Every queued ticket has a player");
+                seated.push(index);
+                starts.extend(self.sit(id, player, ticket));
+            }
+        }
+        seated.sort();
+        for index in seated.into_iter().rev() {
+            self.queue.remove(index);
+        }
+        starts
+    }
+
+    /// Gives up a ticket, leaving the queue or an open table
+    pub fn leave(&mut self, ticket: &str) -> Result<(), Refused> {
+        if !self.tickets.contains_key(ticket) {
+            return Err(Refused::NotFound("unknown ticket".to_string()));
+        }
+        if let Some(index) = self.queue.iter().position(|queued| queued.token == ticket) {
+            self.queue.remove(index);
+            self.queued.remove(ticket);
+            self.tickets.remove(ticket);
+            return Ok(());
+        }
+
+        let (&id, table) = self
+            .tables
+            .iter_mut()
+            .find(|(_, table)| table.tickets.iter().any(|seated| seated == ticket))
+            .expect("Every ticket is queued or seated");
+        if table.state != TableState::Open {
+            return Err(Refused::Conflict("the game has started".to_string()));
+        }
+        let seat = table
+            .tickets
+            .iter()
+            .position(|seated| seated == ticket)
+            .expect("The ticket is seated at the table");
+        table.seats.remove(seat);
+        table.tickets.remove(seat);
+        table.sockets.remove(seat);
+        table.waiting.remove(seat);
+        if table.seats.is_empty() {
+            self.tables.remove(&id);
+        }
+        self.tickets.remove(ticket);
+        self.announce(id);
+        Ok(())
+    }
+
+    /// Abandons the open tables that were not filled within `open_for`,
+    /// returns how many there were
+    pub fn expire(&mut self, now: Instant, open_for: Duration) -> usize {
+        let expired: Vec<u64> = self
+            .tables
+            .iter()
+            .filter(|(_, table)| table.state == TableState::Open)
+            .filter(|(_, table)| now.saturating_duration_since(table.opened) >= open_for)
+            .map(|(&id, _)| id)
+            .collect();
+        for &id in &expired {
+            let reason = format!("no one joined within {} seconds", open_for.as_secs());
+            self.set_state(id, TableState::Abandoned(reason));
+        }
+        expired.len()
+    }
+
+    /// Watches where a ticket sits, None if the ticket is unknown
+    pub fn seating(&self, ticket: &str) -> Option<watch::Receiver<Option<Seating>>> {
+        self.tickets.get(ticket).map(watch::Sender::subscribe)
+    }
+
+    /// Where to send the websocket of the player of a seat
+    fn socket(&self, seating: Seating) -> Option<UnboundedSender<WebSocket>> {
+        let table = self.tables.get(&seating.table)?;
+        table.sockets.get(seating.seat).cloned()
+    }
+
+    /// Records how the game of a table is going. Once it is over the
+    /// tickets of its players are given up, and the oldest tables over
+    /// are forgotten past MAX_FINISHED
+    pub fn set_state(&mut self, id: u64, state: TableState) {
+        let Some(table) = self.tables.get_mut(&id) else {
+            return;
+        };
+        table.state = state;
+        if !table.state.is_over() {
+            return;
+        }
+        for ticket in table.tickets.drain(..) {
+            self.tickets.remove(&ticket);
+        }
+        table.sockets.clear();
+
+        let over: Vec<u64> = self
+            .tables
+            .iter()
+            .filter(|(_, table)| table.state.is_over())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in over.iter().take(over.len().saturating_sub(MAX_FINISHED)) {
+            self.tables.remove(id);
+        }
+    }
+
+    /// The tables that are open or playing
+    pub fn tables(&self) -> Vec<TableStatus> {
+        self.tables
+            .iter()
+            .filter(|(_, table)| !table.state.is_over())
+            .map(|(&id, table)| table.status(id))
+            .collect()
+    }
+
+    pub fn table(&self, id: u64) -> Option<TableStatus> {
+        self.tables.get(&id).map(|table| table.status(id))
+    }
+
+    /// How many players wait in the queue for a game of 2, 3 and 4 players
+    pub fn queued(&self) -> Vec<usize> {
+        (2..=4)
+            .map(|players| {
+                self.queue
+                    .iter()
+                    .filter(|ticket| ticket.players == players)
+                    .count()
+            })
+            .collect()
+    }
+}
+
+lazy_static! {
+    static ref LOBBY: Mutex<Lobby> = Mutex::new(Lobby::default());
+}
+
+/// Whether a task is matching the players waiting in the queue
+static MATCHMAKING: AtomicBool = AtomicBool::new(false);
+
+/// The lobby of the server
+pub fn shared() -> &'static Mutex<Lobby> {
+    &LOBBY
+}
+
+/// Abandons the tables of the lobby left open for longer than
+/// lobby.open_table_secs, see Lobby::expire
+pub fn expire_open_tables(lobby: &mut Lobby) {
+    let open_for = Duration::from_secs(config::get().lobby.open_table_secs);
+    let expired = lobby.expire(Instant::now(), open_for);
+    if expired > 0 {
+        info!("[-] Abandoned {} tables nobody filled", expired);
+    }
+}
+
+/// Opens a table in the lobby, see Lobby::open_table
+pub fn open_table(players: usize, player: Player) -> Result<Entered, Refused> {
+    let config = &config::get().lobby;
+    let entered = {
+        let mut lobby = LOBBY.lock().unwrap();
+        expire_open_tables(&mut lobby);
+        lobby.open_table(
+            players,
+            player,
+            config.max_tables,
+            config.max_tables_per_user,
+            Instant::now(),
+        )?
+    };
+    info!("[+] Opened a table for {} players", players);
+    Ok(entered)
+}
+
+/// Joins a table of the lobby, and starts its game if it is full
+pub fn join_table(id: u64, player: Player) -> Result<Entered, Refused> {
+    let (entered, start) = {
+        let mut lobby = LOBBY.lock().unwrap();
+        expire_open_tables(&mut lobby);
+        lobby.join_table(id, player, config::get().lobby.max_tables_per_user)?
+    };
+    if let Some(start) = start {
+        spawn_game(&LOBBY, start);
+    }
+    Ok(entered)
+}
+
+/// Puts a player in the matchmaking queue, seating them at once if there
+/// are players for them to play
+pub fn enqueue(players: usize, player: Player) -> Result<Entered, Refused> {
+    let mut lobby = LOBBY.lock().unwrap();
+    let max_tables_per_user = config::get().lobby.max_tables_per_user;
+    let entered = lobby.enqueue(players, player, max_tables_per_user, Instant::now())?;
+    let starts = matchmake(&mut lobby);
+    let entered = lobby.entered(entered.ticket);
+    if !lobby.queue.is_empty() && !MATCHMAKING.swap(true, Ordering::SeqCst) {
+        tokio::spawn(keep_matchmaking());
+    }
+    drop(lobby);
+    for start in starts {
+        spawn_game(&LOBBY, start);
+    }
+    Ok(entered)
+}
+
+/// Gives up a ticket of the lobby
+pub fn leave(ticket: &str) -> Result<(), Refused> {
+    LOBBY.lock().unwrap().leave(ticket)
+}
+
+fn matchmake(lobby: &mut Lobby) -> Vec<Start> {
+    let config = &config::get().lobby;
+    expire_open_tables(lobby);
+    lobby.matchmake(
+        config.rating_spread as f64,
+        config.max_tables,
+        config.max_tables_per_user,
+        Instant::now(),
+    )
+}
+
+/// Matches the players in the queue every MATCHMAKING_INTERVAL as their
+/// rating spread widens, until the queue is empty
+async fn keep_matchmaking() {
+    loop {
+        tokio::time::sleep(MATCHMAKING_INTERVAL).await;
+        let (starts, empty) = {
+            let mut lobby = LOBBY.lock().unwrap();
+            let starts = matchmake(&mut lobby);
+            let empty = lobby.queue.is_empty();
+            if empty {
+                MATCHMAKING.store(false, Ordering::SeqCst);
+            }
+            (starts, empty)
+        };
+        for start in starts {
+            spawn_game(&LOBBY, start);
+        }
+        if empty {
+            return;
+        }
+    }
+}
+
+/// Plays the game of a full table in the background
+fn spawn_game(lobby: &'static Mutex<Lobby>, start: Start) {
+    let table = start.table;
+    tokio::spawn(
+        async move {
+            let state = match queue::running() {
+                Some(sender) => tokio::select! {
+                    result = play(lobby, start, Limits::configured(), sender) => result,
+                    _ = shutdown::triggered() => Err("the server is shutting down".to_string()),
+                },
+                None => Err("the queue is not running".to_string()),
+            };
+            let state = state.unwrap_or_else(|reason| {
+                warn!("[-] Table abandoned: {}", reason);
+                TableState::Abandoned(reason)
+            });
+            lobby.lock().unwrap().set_state(table, state);
+        }
+        .instrument(logging::table_span(table)),
+    );
+}
+
+/// Tells the connected players their table was abandoned, and closes
+/// their websockets
+async fn abandon(sockets: &mut [Option<WebSocket>], reason: &str) {
+    let abandoned = to_message(&LobbyEvent::Abandoned {
+        reason: reason.to_string(),
+    });
+    for socket in sockets.iter_mut().flatten() {
+        let _ = socket.send(abandoned.clone()).await;
+        let _ = socket.close().await;
+    }
+}
+
+fn to_message<T: Serialize>(message: &T) -> Message {
+    Message::text(serde_json::to_string(message).expect("Failed to serialize"))
+}
+
+/// Takes the websockets a player opened since last time, keeping the newest,
+/// which has no requests left to answer
+fn reconnect(
+    channel: &mut UnboundedReceiver<WebSocket>,
+    socket: &mut Option<WebSocket>,
+    stale: &mut usize,
+) {
+    while let Ok(reconnected) = channel.try_recv() {
+        *socket = Some(reconnected);
+        *stale = 0;
+    }
+}
+
+/// Plays the game of a full table once every player has connected, and
+/// returns how it finished
+pub async fn play(
+    lobby: &Mutex<Lobby>,
+    start: Start,
+    limits: Limits,
+    mut sender: AsyncQueue,
+) -> Result<TableState, String> {
+    let Start {
+        table,
+        players,
+        sockets: mut channels,
+    } = start;
+    let count = players.len();
+    let mut sockets: Vec<Option<WebSocket>> = (0..count).map(|_| None).collect();
+    let connect_by = Instant::now() + limits.connect_time;
+    for (channel, socket) in channels.iter_mut().zip(&mut sockets) {
+        if let Ok(Some(connected)) = timeout_at(connect_by, channel.recv()).await {
+            *socket = Some(connected);
+        }
+    }
+    let missing: Vec<usize> = (0..count).filter(|&seat| sockets[seat].is_none()).collect();
+    if !missing.is_empty() {
+        let reason = format!(
+            "seats {:?} did not connect within {} seconds",
+            missing,
+            limits.connect_time.as_secs()
+        );
+        abandon(&mut sockets, &reason).await;
+        return Err(reason);
+    }
+
+    let ids = match queue::create_id(&sender).await {
+        Some(id) => queue::get_slug(id, &sender).await.map(|slug| (id, slug)),
+        None => None,
+    };
+    let Some((id, slug)) = ids else {
+        let reason = "the queue is closed".to_string();
+        abandon(&mut sockets, &reason).await;
+        return Err(reason);
+    };
+    queue::set_game_seats(id, players.iter().map(|p| p.bot_id).collect(), &sender).await;
+    logging::record_game(id);
+    logging::record_slug(&slug);
+    lobby.lock().unwrap().set_state(
+        table,
+        TableState::Playing {
+            id,
+            slug: slug.clone(),
+        },
+    );
+    info!("[+] Playing a table of {} players", count);
+    let names: Vec<String> = players.iter().map(|p| p.name.clone()).collect();
+    for (seat, socket) in sockets.iter_mut().enumerate() {
+        let started = LobbyEvent::Started {
+            seat,
+            slug: slug.clone(),
+            url: game_url(&slug),
+            names: names.clone(),
+        };
+        if let Some(open) = socket {
+            if open.send(to_message(&started)).await.is_err() {
+                *socket = None;
+            }
+        }
+    }
+
+    let mut arena = ArenaBuilder::new().num_players(count).build();
+    arena.start_game();
+    let deadline = Deadline::default();
+    let mut stale = vec![0; count];
+    let mut forfeit = None;
+    let mut actions = 0;
+    while !arena.is_game_over() {
+        if actions >= limits.max_actions {
+            queue::set_game_over(id, &sender).await;
+            return Err(format!("the game did not end after {} actions", actions));
+        }
+        for ((channel, socket), stale) in channels.iter_mut().zip(&mut sockets).zip(&mut stale) {
+            reconnect(channel, socket, stale);
+        }
+        runner::push(id, runner::game_update(&arena), &mut sender).await;
+        for seat in runner::broadcast(&mut sockets, &arena, limits.move_time).await {
+            warn!(
+                seat,
+                "[-] Player disconnected, they forfeit unless they are back by their turn"
+            );
+        }
+
+        let seat = arena.current_player_num().expect("The game is started");
+        let legal = arena.get_legal_actions().expect("The game is not over");
+        if sockets[seat].is_none() {
+            let back_by = Instant::now() + limits.move_time;
+            if let Ok(Some(reconnected)) = timeout_at(back_by, channels[seat].recv()).await {
+                sockets[seat] = Some(reconnected);
+                stale[seat] = 0;
+            }
+        }
+        let played = match &mut sockets[seat] {
+            Some(socket) => {
+                let stale = &mut stale[seat];
+                runner::request_action(socket, stale, &arena, &legal, limits.move_time, &deadline)
+                    .await
+            }
+            None => {
+                let reason = format!(
+                    "left the game for more than {} seconds",
+                    limits.move_time.as_secs()
+                );
+                warn!(seat, "[-] Player {}, forfeiting the game", reason);
+                forfeit = Some((seat, reason));
+                break;
+            }
+        };
+        let action = played.unwrap_or_else(|fault| {
+            debug!(seat, "[-] Player {}, playing the first legal action", fault);
+            if fault == Fault::Disconnected {
+                sockets[seat] = None;
+            }
+            legal[0].clone()
+        });
+        arena.play_action(action);
+        actions += 1;
+        logging::record_turn(actions);
+    }
+
+    let update = runner::game_update(&arena);
+    let seat = forfeit.as_ref().map(|(seat, _)| *seat);
+    let places = ratings::final_placements(&update.info, seat);
+    runner::push(id, update, &mut sender).await;
+    if let Some((seat, reason)) = forfeit {
+        queue::set_forfeit(id, seat, reason, &sender).await;
+    }
+    queue::set_game_over(id, &sender).await;
+    let finished = to_message(&LobbyEvent::Finished {
+        places: places.clone(),
+    });
+    for socket in sockets.iter_mut().flatten() {
+        let _ = socket.send(finished.clone()).await;
+        let _ = socket.close().await;
+    }
+    info!("[+] Table finished with places {:?}", places);
+    Ok(TableState::Finished { slug, places })
+}
+
+/// Tells the player of a ticket where they sit until their table starts,
+/// then hands their websocket to the game. A player that goes away before
+/// then gives up their ticket
+async fn connect(mut socket: WebSocket, ticket: String, lobby: &'static Mutex<Lobby>) {
+    let Some(mut seating) = lobby.lock().unwrap().seating(&ticket) else {
+        let reason = "unknown ticket".to_string();
+        let _ = socket.send(to_message(&Response::Failure { reason })).await;
+        let _ = socket.close().await;
+        return;
+    };
+
+    let mut announced = None;
+    let seated = loop {
+        let current = *seating.borrow_and_update();
+        if let Some(seated) = current {
+            if announced != Some((seated.table, seated.seat)) {
+                announced = Some((seated.table, seated.seat));
+                let players = lobby
+                    .lock()
+                    .unwrap()
+                    .table(seated.table)
+                    .map_or(0, |table| table.players);
+                let event = LobbyEvent::Seated {
+                    table: seated.table,
+                    seat: seated.seat,
+                    players,
+                };
+                if socket.send(to_message(&event)).await.is_err() {
+                    let _ = lobby.lock().unwrap().leave(&ticket);
+                    return;
+                }
+            }
+            if seated.started {
+                break seated;
+            }
+        }
+        tokio::select! {
+            changed = seating.changed() => if changed.is_err() {
+                // The ticket was given up
+                let _ = socket.close().await;
+                return;
+            },
+            message = socket.next() => match message {
+                Some(Ok(message)) if !message.is_close() => continue,
+                _ => {
+                    let _ = lobby.lock().unwrap().leave(&ticket);
+                    debug!("[-] A player left the lobby");
+                    return;
+                }
+            },
+        }
+    };
+
+    let socket_sender = lobby.lock().unwrap().socket(seated);
+    match socket_sender {
+        Some(sender) => {
+            if let Err(returned) = sender.send(socket) {
+                let _ = returned.0.close().await;
+            }
+        }
+        None => {
+            let _ = socket.close().await;
+        }
+    }
+}
+
+/// GET /api/lobby/play/{ticket}
+/// upgrade to the websocket a player plays the game of their ticket on
+pub fn route(
+    lobby: &'static Mutex<Lobby>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "lobby" / "play" / String)
+        .and(warp::ws())
+        .map(move |ticket: String, ws: warp::ws::Ws| {
+            ws.on_upgrade(move |socket| connect(socket, ticket, lobby))
+        })
+}
@@ -0,0 +1,560 @@
+use super::matchmaking::*;
+use super::*;
+use crate::database;
+use crate::database::tests::create_test_db;
+use splendor_arena::tungstenite;
+use sqlx::SqlitePool;
+use std::thread::JoinHandle;
+
+fn ticket(token: &str, players: usize, user_id: i64, rating: f64, since: Instant) -> Ticket {
+    Ticket {
+        token: token.to_string(),
+        players,
+        user_id,
+        rating,
+        since,
+    }
+}
+
+fn player(bot_id: i64, rating: f64) -> Player {
+    Player {
+        user_id: bot_id,
+        bot_id,
+        name: format!("bot{}", bot_id),
+        owner: format!("user{}", bot_id),
+        rating,
+    }
+}
+
+#[test]
+fn close_ratings_are_seated_together() {
+    let now = Instant::now();
+    let tickets = vec![
+        ticket("a", 2, 1, 1500.0, now),
+        ticket("b", 2, 2, 1900.0, now),
+        ticket("c", 2, 3, 1550.0, now),
+        ticket("d", 2, 4, 1850.0, now),
+    ];
+    let mut tables = find_tables(&tickets, 100.0, now);
+    tables.sort();
+    assert_eq!(tables, vec![vec![0, 2], vec![1, 3]]);
+}
+
+#[test]
+fn spreads_widen_while_tickets_wait() {
+    let now = Instant::now();
+    let tickets = vec![
+        ticket("a", 2, 1, 1500.0, now),
+        ticket("b", 2, 2, 1750.0, now),
+    ];
+    assert!(find_tables(&tickets, 100.0, now).is_empty());
+    // Both spreads reach 250 once the tickets waited 1.5 WIDEN_EVERY
+    let later = now + WIDEN_EVERY * 3 / 2;
+    assert_eq!(find_tables(&tickets, 100.0, later), vec![vec![0, 1]]);
+    assert_eq!(spread(100.0, WIDEN_EVERY), 200.0);
+}
+
+#[test]
+fn tables_need_distinct_accounts_and_the_same_player_count() {
+    let now = Instant::now();
+    let tickets = vec![
+        ticket("a", 3, 1, 1500.0, now),
+        ticket("b", 3, 1, 1500.0, now),
+        ticket("c", 2, 2, 1500.0, now),
+        ticket("d", 3, 3, 1500.0, now),
+    ];
+    assert!(find_tables(&tickets, 100.0, now).is_empty());
+
+    let mut tickets = tickets;
+    tickets.push(ticket("e", 3, 4, 1520.0, now));
+    assert_eq!(find_tables(&tickets, 100.0, now), vec![vec![0, 3, 4]]);
+}
+
+#[test]
+fn the_longest_waiting_ticket_is_seated_first() {
+    let now = Instant::now();
+    let tickets = vec![
+        ticket("new", 2, 1, 1500.0, now),
+        ticket("old", 2, 2, 1500.0, now - Duration::from_secs(10)),
+        ticket("other", 2, 3, 1510.0, now),
+    ];
+    assert_eq!(find_tables(&tickets, 100.0, now), vec![vec![1, 0]]);
+}
+
+#[tokio::test]
+async fn tables_start_once_they_are_full() {
+    let mut lobby = Lobby::default();
+    let first = lobby
+        .open_table(3, player(1, 1500.0), 8, 8, Instant::now())
+        .unwrap();
+    let seating = first.seating.unwrap();
+    assert_eq!((seating.seat, seating.started), (0, false));
+    let table = seating.table;
+
+    let (second, start) = lobby.join_table(table, player(2, 1500.0), 8).unwrap();
+    assert!(start.is_none());
+    assert_eq!(second.seating.unwrap().seat, 1);
+    assert!(matches!(
+        lobby.join_table(table, player(2, 1500.0), 8),
+        Err(Refused::Conflict(reason)) if reason.contains("already sits")
+    ));
+    // Another bot of the same account
+    let other_bot = Player {
+        bot_id: 9,
+        ..player(2, 1500.0)
+    };
+    assert!(matches!(
+        lobby.join_table(table, other_bot, 8),
+        Err(Refused::Conflict(reason)) if reason.contains("already sits")
+    ));
+
+    let watched = lobby.seating(&first.ticket).unwrap();
+    let (_, start) = lobby.join_table(table, player(3, 1500.0), 8).unwrap();
+    let start = start.expect("the table is full");
+    assert_eq!(start.players.len(), 3);
+    assert_eq!(start.sockets.len(), 3);
+    assert!(watched.borrow().unwrap().started);
+    assert_eq!(lobby.table(table).unwrap().state, TableState::Starting);
+
+    assert!(matches!(
+        lobby.join_table(table, player(4, 1500.0), 8),
+        Err(Refused::Conflict(_))
+    ));
+    assert!(matches!(
+        lobby.join_table(table + 1, player(4, 1500.0), 8),
+        Err(Refused::NotFound(_))
+    ));
+    assert!(matches!(
+        lobby.leave(&first.ticket),
+        Err(Refused::Conflict(_))
+    ));
+
+    lobby.set_state(table, TableState::Abandoned("gone".to_string()));
+    assert!(lobby.seating(&first.ticket).is_none());
+    assert!(lobby.tables().is_empty());
+}
+
+#[tokio::test]
+async fn leaving_reseats_the_players_after() {
+    let mut lobby = Lobby::default();
+    let first = lobby
+        .open_table(4, player(1, 1500.0), 8, 8, Instant::now())
+        .unwrap();
+    let table = first.seating.unwrap().table;
+    let (second, _) = lobby.join_table(table, player(2, 1500.0), 8).unwrap();
+    let watched = lobby.seating(&second.ticket).unwrap();
+
+    lobby.leave(&first.ticket).unwrap();
+    assert_eq!(watched.borrow().unwrap().seat, 0);
+    assert_eq!(lobby.table(table).unwrap().seats, vec![player(2, 1500.0)]);
+    assert!(matches!(
+        lobby.leave(&first.ticket),
+        Err(Refused::NotFound(_))
+    ));
+
+    lobby.leave(&second.ticket).unwrap();
+    assert!(lobby.table(table).is_none());
+    assert!(matches!(
+        lobby.open_table(2, player(3, 1500.0), 0, 8, Instant::now()),
+        Err(Refused::Conflict(_))
+    ));
+}
+
+#[tokio::test]
+async fn tables_are_capped_per_account_and_expire() {
+    let mut lobby = Lobby::default();
+    let now = Instant::now();
+    let open_for = Duration::from_secs(600);
+    let first = lobby.open_table(2, player(1, 1500.0), 8, 2, now).unwrap();
+    let later = now + Duration::from_secs(300);
+    lobby.open_table(3, player(1, 1500.0), 8, 2, later).unwrap();
+    assert!(matches!(
+        lobby.open_table(4, player(1, 1500.0), 8, 2, later),
+        Err(Refused::Conflict(reason)) if reason.contains("2 tables")
+    ));
+    lobby.open_table(2, player(2, 1500.0), 8, 2, later).unwrap();
+
+    assert_eq!(lobby.expire(now + Duration::from_secs(599), open_for), 0);
+    assert_eq!(lobby.expire(now + open_for, open_for), 1);
+    let table = first.seating.unwrap().table;
+    assert!(matches!(
+        lobby.table(table).unwrap().state,
+        TableState::Abandoned(reason) if reason.contains("no one joined")
+    ));
+    assert!(lobby.seating(&first.ticket).is_none());
+    assert_eq!(lobby.tables().len(), 2);
+    lobby
+        .open_table(4, player(1, 1500.0), 8, 2, now + open_for)
+        .unwrap();
+}
+
+#[tokio::test]
+async fn joining_and_the_queue_respect_the_cap_per_account() {
+    let mut lobby = Lobby::default();
+    let now = Instant::now();
+    let mut tables = vec![];
+    for user_id in 2..=4 {
+        let entered = lobby
+            .open_table(2, player(user_id, 1500.0), 8, 2, now)
+            .unwrap();
+        tables.push(entered.seating.unwrap().table);
+    }
+    lobby.join_table(tables[0], player(1, 1500.0), 2).unwrap();
+    lobby.join_table(tables[1], player(1, 1500.0), 2).unwrap();
+    assert!(matches!(
+        lobby.join_table(tables[2], player(1, 1500.0), 2),
+        Err(Refused::Conflict(reason)) if reason.contains("2 tables")
+    ));
+    assert!(matches!(
+        lobby.enqueue(2, player(1, 1500.0), 2, now),
+        Err(Refused::Conflict(reason)) if reason.contains("2 tables")
+    ));
+
+    // Queued before reaching the cap, so the queue leaves them waiting
+    lobby.enqueue(2, player(5, 1500.0), 2, now).unwrap();
+    lobby.enqueue(2, player(6, 1500.0), 2, now).unwrap();
+    lobby.open_table(2, player(5, 1500.0), 8, 2, now).unwrap();
+    lobby.open_table(3, player(5, 1500.0), 8, 2, now).unwrap();
+    assert!(lobby.matchmake(100.0, 8, 2, now).is_empty());
+    assert_eq!(lobby.queued(), vec![2, 0, 0]);
+    lobby.enqueue(2, player(7, 1500.0), 2, now).unwrap();
+    let starts = lobby.matchmake(100.0, 8, 2, now);
+    assert_eq!(starts.len(), 1);
+    let users: Vec<i64> = starts[0].players.iter().map(|p| p.user_id).collect();
+    assert_eq!(users, vec![6, 7]);
+    assert_eq!(lobby.queued(), vec![1, 0, 0]);
+}
+
+#[tokio::test]
+async fn the_queue_seats_players_of_similar_ratings() {
+    let mut lobby = Lobby::default();
+    let now = Instant::now();
+    let first = lobby.enqueue(2, player(1, 1500.0), 8, now).unwrap();
+    assert_eq!(first.seating, None);
+    lobby.enqueue(2, player(2, 2000.0), 8, now).unwrap();
+    let other_bot = Player {
+        bot_id: 9,
+        ..player(1, 1500.0)
+    };
+    assert!(matches!(
+        lobby.enqueue(2, other_bot, 8, now),
+        Err(Refused::Conflict(_))
+    ));
+    assert_eq!(lobby.queued(), vec![2, 0, 0]);
+    assert!(lobby.matchmake(100.0, 8, 8, now).is_empty());
+
+    lobby.enqueue(2, player(3, 1540.0), 8, now).unwrap();
+    let starts = lobby.matchmake(100.0, 8, 8, now);
+    assert_eq!(starts.len(), 1);
+    let bots: Vec<i64> = starts[0].players.iter().map(|p| p.bot_id).collect();
+    assert_eq!(bots, vec![1, 3]);
+    assert!(lobby.table(starts[0].table).unwrap().matched);
+    assert!(
+        lobby
+            .seating(&first.ticket)
+            .unwrap()
+            .borrow()
+            .unwrap()
+            .started
+    );
+    assert_eq!(lobby.queued(), vec![1, 0, 0]);
+}
+
+/// A player on a thread that plays the first legal action, returns the
+/// lobby events it was sent
+fn thread_player(port: u16, ticket: String) -> JoinHandle<Vec<LobbyEvent>> {
+    std::thread::spawn(move || {
+        let url = format!("ws://127.0.0.1:{}/api/lobby/play/{}", port, ticket);
+        let (mut socket, _) = tungstenite::connect(url).unwrap();
+        let mut events = vec![];
+        while let Ok(message) = socket.read() {
+            let Ok(text) = message.to_text() else { break };
+            if let Ok(event) = serde_json::from_str::<LobbyEvent>(text) {
+                events.push(event);
+                continue;
+            }
+            let Ok(ServerMessage::PlayerActionRequest(info)) = serde_json::from_str(text) else {
+                continue;
+            };
+            let action = ClientMessage::Action(info.legal_actions[0].clone());
+            let reply = serde_json::to_string(&action).unwrap();
+            if socket.send(tungstenite::Message::Text(reply)).is_err() {
+                break;
+            }
+        }
+        events
+    })
+}
+
+/// A player on a thread that goes away once its game started
+fn leaving_player(port: u16, ticket: String) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let url = format!("ws://127.0.0.1:{}/api/lobby/play/{}", port, ticket);
+        let (mut socket, _) = tungstenite::connect(url).unwrap();
+        while let Ok(message) = socket.read() {
+            let Ok(text) = message.to_text() else { break };
+            if let Ok(LobbyEvent::Started { .. }) = serde_json::from_str(text) {
+                break;
+            }
+        }
+        let _ = socket.close(None);
+        let _ = socket.flush();
+    })
+}
+
+/// The alpha and beta bots of the host and guest accounts
+async fn players(db: &SqlitePool) -> Vec<Player> {
+    let mut players = vec![];
+    for (owner, name) in [("host", "alpha"), ("guest", "beta")] {
+        let user_id = database::save_user(db, owner, "hash").await.unwrap();
+        let bot = database::load_or_create_bot(db, user_id, name).await;
+        players.push(Player {
+            user_id,
+            bot_id: bot.bot_id,
+            name: bot.name,
+            owner: bot.owner,
+            rating: 1500.0,
+        });
+    }
+    players
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn full_tables_are_played_over_the_websockets_of_their_seats() {
+    let db = create_test_db().await;
+    let lobby: &'static Mutex<Lobby> = Box::leak(Box::default());
+    let (address, server) = warp::serve(route(lobby)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    let (sender, receiver) = queue::with_capacity(64);
+    let processor = tokio::spawn(queue::queue_processer(db.clone(), receiver, None));
+
+    let players = players(&db).await;
+
+    let first = lobby
+        .lock()
+        .unwrap()
+        .open_table(2, players[0].clone(), 8, 8, Instant::now())
+        .unwrap();
+    let table = first.seating.unwrap().table;
+    let alpha = thread_player(address.port(), first.ticket);
+    let (second, start) = lobby
+        .lock()
+        .unwrap()
+        .join_table(table, players[1].clone(), 8)
+        .unwrap();
+    let beta = thread_player(address.port(), second.ticket);
+
+    let limits = Limits {
+        move_time: Duration::from_secs(5),
+        connect_time: Duration::from_secs(5),
+        max_actions: MAX_ACTIONS,
+    };
+    let state = play(lobby, start.unwrap(), limits, sender.clone())
+        .await
+        .unwrap();
+    let TableState::Finished { slug, places } = state else {
+        panic!("the table did not finish: {:?}", state);
+    };
+    assert!(places.contains(&0));
+    let events = alpha.join().unwrap();
+    assert_eq!(
+        events[0],
+        LobbyEvent::Seated {
+            table,
+            seat: 0,
+            players: 2
+        }
+    );
+    assert!(
+        matches!(&events[1], LobbyEvent::Started { seat: 0, slug: started, .. } if *started == slug)
+    );
+    assert_eq!(
+        events.last(),
+        Some(&LobbyEvent::Finished {
+            places: places.clone()
+        })
+    );
+    assert!(matches!(
+        beta.join().unwrap()[1],
+        LobbyEvent::Started { seat: 1, .. }
+    ));
+    drop(sender);
+    processor.await.unwrap();
+
+    let uuid = database::load_uuid_from_slug(&db, &slug).await.unwrap();
+    let seated = database::load_game_players(&db, uuid).await;
+    let names: Vec<&str> = seated.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, vec!["alpha", "beta"]);
+    assert_eq!(seated[0].owner.as_deref(), Some("host"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn players_that_do_not_come_back_forfeit() {
+    let db = create_test_db().await;
+    let lobby: &'static Mutex<Lobby> = Box::leak(Box::default());
+    let (address, server) = warp::serve(route(lobby)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    let (sender, receiver) = queue::with_capacity(64);
+    let processor = tokio::spawn(queue::queue_processer(db.clone(), receiver, None));
+    let players = players(&db).await;
+
+    let first = lobby
+        .lock()
+        .unwrap()
+        .open_table(2, players[0].clone(), 8, 8, Instant::now())
+        .unwrap();
+    let table = first.seating.unwrap().table;
+    let alpha = thread_player(address.port(), first.ticket);
+    let (second, start) = lobby
+        .lock()
+        .unwrap()
+        .join_table(table, players[1].clone(), 8)
+        .unwrap();
+    let beta = leaving_player(address.port(), second.ticket);
+
+    let limits = Limits {
+        move_time: Duration::from_millis(500),
+        connect_time: Duration::from_secs(5),
+        max_actions: MAX_ACTIONS,
+    };
+    let state = play(lobby, start.unwrap(), limits, sender.clone())
+        .await
+        .unwrap();
+    beta.join().unwrap();
+    let TableState::Finished { slug, places } = state else {
+        panic!("the table did not finish: {:?}", state);
+    };
+    assert_eq!(places, vec![0, 1]);
+    assert_eq!(
+        alpha.join().unwrap().last(),
+        Some(&LobbyEvent::Finished { places })
+    );
+    drop(sender);
+    processor.await.unwrap();
+
+    let uuid = database::load_uuid_from_slug(&db, &slug).await.unwrap();
+    let (seat, reason) = database::load_forfeit(&db, uuid).await.unwrap();
+    assert_eq!(seat, 1);
+    assert!(reason.contains("left the game"), "{}", reason);
+}
+
+#[tokio::test]
+async fn tables_are_abandoned_when_players_do_not_connect() {
+    let (sender, _receiver) = queue::with_capacity(16);
+    let lobby: &'static Mutex<Lobby> = Box::leak(Box::default());
+    let first = lobby
+        .lock()
+        .unwrap()
+        .open_table(2, player(1, 1500.0), 8, 8, Instant::now())
+        .unwrap();
+    let table = first.seating.unwrap().table;
+    let (_, start) = lobby
+        .lock()
+        .unwrap()
+        .join_table(table, player(2, 1500.0), 8)
+        .unwrap();
+    let limits = Limits {
+        move_time: Duration::from_secs(1),
+        connect_time: Duration::from_millis(100),
+        max_actions: MAX_ACTIONS,
+    };
+    let reason = play(lobby, start.unwrap(), limits, sender)
+        .await
+        .unwrap_err();
+    assert!(reason.contains("did not connect"), "{}", reason);
+}
+
+#[tokio::test]
+pub async fn lobby_tables_are_opened_and_joined_with_a_session() {
+    let db = create_test_db().await;
+    let host = crate::database::save_user(&db, "host", "hash")
+        .await
+        .unwrap();
+    let guest = crate::database::save_user(&db, "guest", "hash")
+        .await
+        .unwrap();
+    let routes = crate::api::routes(db.clone());
+
+    let response = warp::test::request()
+        .method("POST")
+        .path("/api/lobby/tables")
+        .json(&serde_json::json!({"players": 3}))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 401);
+
+    let token = crate::auth::start_session(&db, host).await;
+    let response = warp::test::request()
+        .method("POST")
+        .path("/api/lobby/tables")
+        .header("cookie", format!("session={}", token))
+        .json(&serde_json::json!({"players": 5}))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 400);
+
+    let response = warp::test::request()
+        .method("POST")
+        .path("/api/lobby/tables")
+        .header("cookie", format!("session={}", token))
+        .json(&serde_json::json!({"players": 3, "bot": "planner"}))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 201);
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    let ticket = &body["success"]["ticket"];
+    assert_eq!(ticket["seat"], 0);
+    let table = ticket["table"].as_u64().unwrap();
+    let host_ticket = ticket["ticket"].as_str().unwrap().to_string();
+    assert_eq!(
+        ticket["url"],
+        format!("/api/lobby/play/{}", host_ticket).as_str()
+    );
+
+    let token = crate::auth::start_session(&db, guest).await;
+    let response = warp::test::request()
+        .method("POST")
+        .path(&format!("/api/lobby/tables/{}/join", table))
+        .header("cookie", format!("session={}", token))
+        .json(&serde_json::json!({}))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(body["success"]["ticket"]["seat"], 1);
+
+    let response = warp::test::request()
+        .path(&format!("/api/lobby/tables/{}", table))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    let described = &body["success"]["table"];
+    assert_eq!(described["state"], "open");
+    assert_eq!(described["seats"][0]["name"], "planner");
+    assert_eq!(described["seats"][0]["owner"], "host");
+    assert_eq!(described["seats"][1]["name"], "guest");
+    assert_eq!(described["seats"][1]["rating"], 1500.0);
+
+    let response = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/api/lobby/tickets/{}", host_ticket))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 200);
+    let response = warp::test::request()
+        .path(&format!("/api/lobby/tables/{}", table))
+        .reply(&routes)
+        .await;
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(body["success"]["table"]["seats"][0]["name"], "guest");
+
+    let response = warp::test::request()
+        .method("POST")
+        .path("/api/lobby/tables/0/join")
+        .header("cookie", format!("session={}", token))
+        .json(&serde_json::json!({}))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 404);
+}
@@ -0,0 +1,149 @@
+// Structured logging of the server.
+//
+// Lines are written as text, or as one JSON object per line with
+// log_format = "json", to stderr or to files in log_dir that are rotated
+// hourly or daily. Every line logged while handling an arena connection is
+// in the span of the connection, which records the game once it is known:
+//
+//      connection{conn=12 game=<uuid> slug=<slug> turn=31}: ...
+//
+// Updates are queued along with the span they were sent from, so the lines
+// logged while the queue processes them carry the same fields, and the
+// lifecycle of a game can be grepped out of the log by its uuid or slug.
+// Matches the server plays itself log in a match span with the same fields.
+// Lines logged with the log crate are forwarded into the current span.
+
+#[cfg(test)]
+pub mod tests;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{field, info_span, Span, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+use uuid::Uuid;
+
+use crate::config::Config;
+
+/// Log files are named stourney_server.<date>.log
+const LOG_FILE_PREFIX: &str = "stourney_server";
+const LOG_FILE_SUFFIX: &str = "log";
+
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+/// Formats lines as text or json, to the given writer
+pub fn format_layer<S, W>(format: &str, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match format {
+        "json" => layer
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+        _ => layer.with_ansi(ansi).boxed(),
+    }
+}
+
+/// Starts logging as configured, the returned guard flushes the lines
+/// still buffered when it is dropped, so it must be held until exit
+pub fn init(config: &Config) -> Result<WorkerGuard, String> {
+    let filter = EnvFilter::try_new(&config.log_level)
+        .map_err(|error| format!("log_level {}: {}", config.log_level, error))?;
+
+    let (writer, guard, ansi) = match &config.log_dir {
+        Some(dir) => {
+            let rotation = match config.log_rotation.as_str() {
+                "hourly" => Rotation::HOURLY,
+                "daily" => Rotation::DAILY,
+                _ => Rotation::NEVER,
+            };
+            let mut builder = RollingFileAppender::builder()
+                .rotation(rotation)
+                .filename_prefix(LOG_FILE_PREFIX)
+                .filename_suffix(LOG_FILE_SUFFIX);
+            if config.log_max_files > 0 {
+                builder = builder.max_log_files(config.log_max_files);
+            }
+            let appender = builder
+                .build(dir)
+                .map_err(|error| format!("cannot log to {}: {}", dir.display(), error))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (writer, guard, false)
+        }
+        None => {
+            let (writer, guard) = tracing_appender::non_blocking(std::io::stderr());
+            (writer, guard, true)
+        }
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(format_layer(&config.log_format, writer, ansi))
+        .try_init()
+        .map_err(|error| error.to_string())?;
+    Ok(guard)
+}
+
+/// The span of a new arena connection, the game it plays is recorded
+/// once it is known
+pub fn connection_span() -> Span {
+    let conn = NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed);
+    info_span!(
+        "connection",
+        conn,
+        game = field::Empty,
+        slug = field::Empty,
+        turn = field::Empty
+    )
+}
+
+/// The span of a match played by the server between uploaded bots, with
+/// the same fields as a connection
+pub fn match_span(number: u64) -> Span {
+    info_span!(
+        "match",
+        number,
+        game = field::Empty,
+        slug = field::Empty,
+        turn = field::Empty
+    )
+}
+
+/// The span of a tournament, around the spans of the matches it plays
+pub fn tournament_span(tournament: i64) -> Span {
+    info_span!("tournament", tournament)
+}
+
+/// The span of a lobby table, around the game played at it
+pub fn table_span(table: u64) -> Span {
+    info_span!(
+        "table",
+        table,
+        game = field::Empty,
+        slug = field::Empty,
+        turn = field::Empty
+    )
+}
+
+/// Records the game of the connection being handled
+pub fn record_game(id: Uuid) {
+    Span::current().record("game", field::display(id));
+}
+
+/// Records the slug of the game of the connection being handled
+pub fn record_slug(slug: &str) {
+    Span::current().record("slug", slug);
+}
+
+/// Records the latest turn received by the connection being handled
+pub fn record_turn(turn: usize) {
+    Span::current().record("turn", turn);
+}
@@ -0,0 +1,131 @@
+use super::*;
+use crate::database::tests::default_game_update;
+use crate::queue::{self, Queued};
+use crate::websocket::server;
+use serde_json::Value;
+use splendor_arena::models::GameUpdate;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::Registry;
+
+/// Collects the lines written by a subscriber
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Captured {
+    type Writer = Captured;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+impl Captured {
+    fn json_lines(&self) -> Vec<Value> {
+        let text = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+        text.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+}
+
+/// Logs in json to the returned buffer while running `f`
+fn capture_json(f: impl FnOnce()) -> Vec<Value> {
+    let captured = Captured::default();
+    let subscriber =
+        Registry::default().with(format_layer::<Registry, _>("json", captured.clone(), false));
+    tracing::subscriber::with_default(subscriber, f);
+    captured.json_lines()
+}
+
+/// The value of a field in any of the spans of a json line
+fn span_field<'a>(line: &'a Value, name: &str) -> Option<&'a Value> {
+    line["spans"]
+        .as_array()?
+        .iter()
+        .find_map(|span| span.get(name))
+}
+
+#[test]
+pub fn lines_carry_the_game_of_the_connection() {
+    let id = Uuid::new_v4();
+    let lines = capture_json(|| {
+        connection_span().in_scope(|| {
+            record_game(id);
+            record_slug("brave-fox");
+            record_turn(12);
+            tracing::info!("playing");
+        })
+    });
+
+    assert_eq!(lines.len(), 1);
+    let line = &lines[0];
+    assert_eq!(line["fields"]["message"], "playing");
+    assert!(span_field(line, "conn").is_some());
+    assert_eq!(span_field(line, "game").unwrap(), &id.to_string());
+    assert_eq!(span_field(line, "slug").unwrap(), "brave-fox");
+    assert_eq!(span_field(line, "turn").unwrap(), 12);
+}
+
+#[test]
+pub fn queued_updates_keep_the_span_they_were_sent_from() {
+    let id = Uuid::new_v4();
+    let (mut queue, mut receiver) = queue::with_capacity(4);
+    let update = GameUpdate {
+        update_num: 7,
+        ..default_game_update()
+    };
+
+    let lines = capture_json(|| {
+        connection_span().in_scope(|| {
+            let updates = vec![update];
+            let push = queue::push_game_updates(id, &updates, &mut queue);
+            futures::executor::block_on(push).unwrap();
+        });
+        let Queued { span, .. } = receiver.try_recv().unwrap();
+        span.in_scope(|| tracing::info!("saving"));
+    });
+
+    assert_eq!(lines.len(), 1);
+    assert_eq!(span_field(&lines[0], "game").unwrap(), &id.to_string());
+    assert_eq!(span_field(&lines[0], "turn").unwrap(), 7);
+}
+
+#[test]
+pub fn arena_connections_are_logged_in_their_own_span() {
+    let lines = capture_json(|| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let db = crate::database::tests::create_test_db().await;
+            let (queue, _receiver) = queue::with_capacity(4);
+            let routes = server::routes(server::Connections::new(db, queue));
+            let mut arena = warp::test::ws()
+                .path("/ws")
+                .handshake(routes)
+                .await
+                .expect("expected the arena to connect");
+            arena.send_text("not a request").await;
+            arena.recv().await.expect("expected an answer");
+        });
+    });
+
+    let line = lines
+        .iter()
+        .find(|line| line["level"] == "WARN")
+        .expect("expected the invalid request to be logged");
+    assert!(span_field(line, "conn").is_some());
+}
@@ -1,10 +1,30 @@
+// The filters of the server nest deeper than the default limit allows
+#![recursion_limit = "256"]
+
 mod api;
+mod artifacts;
+mod auth;
+mod config;
 mod constants;
 mod database;
+mod delta;
+mod encoding;
+mod lobby;
+mod logging;
+mod metrics;
 mod queue;
+mod ratings;
+mod runner;
+mod shutdown;
 mod slug_list;
+mod slugs;
+mod spool;
+mod tournaments;
 mod websocket;
 
+use clap::Parser;
+use std::net::SocketAddr;
+
 /// Note: this uses sqlx compile time checker
 /// to ensure that the queries are correct
 /// be sure to run sqlx prepare if strange errors occur
@@ -12,8 +32,45 @@ mod websocket;
 
 #[tokio::main]
 async fn main() -> Result<(), sqlx::Error> {
-    env_logger::init();
-    let db = database::connect().await?;
-    websocket::serve(3031, db).await;
+    let mut cli = config::Cli::parse();
+    let command = cli.command.take().unwrap_or(config::Command::Serve);
+    let config = match config::load(cli) {
+        Ok(config) => config,
+        Err(reason) => {
+            eprintln!("Invalid configuration: {}", reason);
+            std::process::exit(2);
+        }
+    };
+    // Buffered lines are flushed when the guard is dropped on exit
+    let _log_guard = match logging::init(&config) {
+        Ok(guard) => guard,
+        Err(reason) => {
+            eprintln!("Failed to start logging: {}", reason);
+            std::process::exit(2);
+        }
+    };
+    config::init(config);
+    let config = config::get();
+    let db = database::connect(config).await?;
+
+    if command == config::Command::Reencode {
+        let (games, updates) = database::reencode_game_updates(&db).await;
+        tracing::info!("[+] Re-encoded {} updates across {} games", updates, games);
+        return Ok(());
+    }
+
+    // serve returns once a signal starts shutting down the server and it
+    // stops accepting connections
+    tokio::spawn(shutdown::listen_for_signals());
+    let queue = queue::start(&db).await;
+    tokio::spawn(tournaments::resume(db.clone()));
+    let address = SocketAddr::new(config.bind_address, config.port);
+    websocket::server::serve(address, db.clone(), queue).await;
+
+    if !shutdown::finished().await {
+        tracing::warn!("[-] Timed out waiting for the server to shut down");
+    }
+    db.close().await;
+    tracing::info!("[+] Server shut down");
     Ok(())
 }
@@ -0,0 +1,200 @@
+// Probes and Prometheus metrics for running the server in a container.
+//
+//      GET /healthz  200 while the process is serving requests at all
+//      GET /readyz   200 once the database answers and the queue processor
+//                    is running, 503 otherwise, with the failed checks
+//      GET /metrics  every metric below in the Prometheus text format
+//
+// Metrics are registered in the default Prometheus registry the first
+// time they are used. Rates, such as the updates persisted per second,
+// are left to Prometheus, e.g. rate(stourney_updates_persisted_total[1m]).
+
+#[cfg(test)]
+pub mod tests;
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramTimer, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use serde::Serialize;
+use sqlx::sqlite::SqlitePool;
+use std::convert::Infallible;
+use std::time::Duration;
+use tracing::error;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::queue;
+
+/// How long the database has to answer the readiness check
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    pub static ref CONNECTED_ARENAS: IntGauge = register_int_gauge!(
+        "stourney_connected_arenas",
+        "Arenas connected over the websocket"
+    )
+    .unwrap();
+    pub static ref AUTHENTICATED_ARENAS: IntGauge = register_int_gauge!(
+        "stourney_authenticated_arenas",
+        "Connected arenas that have authenticated"
+    )
+    .unwrap();
+    pub static ref QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "stourney_queue_depth",
+        "Updates waiting in the queue to be saved"
+    )
+    .unwrap();
+    pub static ref UPDATES_PERSISTED: IntCounter = register_int_counter!(
+        "stourney_updates_persisted_total",
+        "Game updates committed to the database"
+    )
+    .unwrap();
+    pub static ref UPDATES_REJECTED: IntCounter = register_int_counter!(
+        "stourney_updates_rejected_total",
+        "Game updates rejected because the queue stayed full"
+    )
+    .unwrap();
+    pub static ref DB_LATENCY: HistogramVec = register_histogram_vec!(
+        "stourney_db_duration_seconds",
+        "Time taken by database operations",
+        &["operation"]
+    )
+    .unwrap();
+    pub static ref LOAD_GAME_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "stourney_load_game_requests_total",
+        "Requests for a turn of a game, by whether it was found",
+        &["result"]
+    )
+    .unwrap();
+}
+
+/// Times a database operation until the timer is dropped
+pub fn time_db(operation: &str) -> HistogramTimer {
+    DB_LATENCY.with_label_values(&[operation]).start_timer()
+}
+
+/// Counts an arena as connected for as long as it is held
+pub struct ArenaConnection {
+    authenticated: bool,
+}
+
+impl ArenaConnection {
+    pub fn new() -> Self {
+        CONNECTED_ARENAS.inc();
+        ArenaConnection {
+            authenticated: false,
+        }
+    }
+
+    /// Counts the arena as authenticated until it disconnects
+    pub fn authenticated(&mut self) {
+        if !self.authenticated {
+            AUTHENTICATED_ARENAS.inc();
+            self.authenticated = true;
+        }
+    }
+}
+
+impl Drop for ArenaConnection {
+    fn drop(&mut self) {
+        CONNECTED_ARENAS.dec();
+        if self.authenticated {
+            AUTHENTICATED_ARENAS.dec();
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Readiness {
+    pub database: bool,
+    pub queue: bool,
+}
+
+/// Whether the database answers and the queue processor is running
+pub async fn check_readiness(db_pool: &SqlitePool) -> Readiness {
+    let ping = async {
+        let _timer = time_db("ping");
+        sqlx::query("SELECT 1").execute(db_pool).await
+    };
+    let database = match tokio::time::timeout(READY_TIMEOUT, ping).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(error)) => {
+            error!(
+                "[-] Readiness check failed to query the database: {}",
+                error
+            );
+            false
+        }
+        Err(_) => {
+            error!("[-] Readiness check timed out querying the database");
+            false
+        }
+    };
+    Readiness {
+        database,
+        queue: queue::is_running(),
+    }
+}
+
+/// GET /healthz
+pub async fn health() -> Result<impl Reply, Infallible> {
+    Ok("ok")
+}
+
+/// GET /readyz
+pub async fn readiness(db_pool: SqlitePool) -> Result<impl Reply, Infallible> {
+    let readiness = check_readiness(&db_pool).await;
+    let status = if readiness.database && readiness.queue {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&readiness),
+        status,
+    ))
+}
+
+/// Every metric in the Prometheus text format
+pub fn render() -> String {
+    // Metrics are registered on first use, list them all from the start
+    lazy_static::initialize(&CONNECTED_ARENAS);
+    lazy_static::initialize(&AUTHENTICATED_ARENAS);
+    lazy_static::initialize(&QUEUE_DEPTH);
+    lazy_static::initialize(&UPDATES_PERSISTED);
+    lazy_static::initialize(&UPDATES_REJECTED);
+    lazy_static::initialize(&DB_LATENCY);
+    lazy_static::initialize(&LOAD_GAME_REQUESTS);
+    QUEUE_DEPTH.set(queue::depth() as i64);
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("Failed to encode metrics");
+    String::from_utf8(buffer).expect("Metrics are not valid utf-8")
+}
+
+/// GET /metrics
+pub async fn metrics() -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::with_header(
+        render(),
+        "content-type",
+        TextEncoder::new().format_type(),
+    ))
+}
+
+pub fn routes(
+    db_pool: SqlitePool,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let health = warp::path!("healthz").and(warp::get()).and_then(health);
+
+    let ready = warp::path!("readyz")
+        .and(warp::get())
+        .and(warp::any().map(move || db_pool.clone()))
+        .and_then(readiness);
+
+    let metrics = warp::path!("metrics").and(warp::get()).and_then(metrics);
+
+    health.or(ready).or(metrics)
+}
@@ -0,0 +1,66 @@
+use super::*;
+use crate::database::tests::create_test_db;
+
+#[test]
+pub fn arena_connection_counts_until_dropped() {
+    let (connected, authenticated) = (CONNECTED_ARENAS.get(), AUTHENTICATED_ARENAS.get());
+    let mut connection = ArenaConnection::new();
+    assert_eq!(CONNECTED_ARENAS.get(), connected + 1);
+    assert_eq!(AUTHENTICATED_ARENAS.get(), authenticated);
+
+    connection.authenticated();
+    connection.authenticated();
+    assert_eq!(AUTHENTICATED_ARENAS.get(), authenticated + 1);
+
+    drop(connection);
+    assert_eq!(CONNECTED_ARENAS.get(), connected);
+    assert_eq!(AUTHENTICATED_ARENAS.get(), authenticated);
+}
+
+#[test]
+pub fn render_lists_every_metric() {
+    time_db("render_test").observe_duration();
+    LOAD_GAME_REQUESTS.with_label_values(&["found"]).inc_by(0);
+    UPDATES_PERSISTED.inc_by(0);
+    let text = render();
+    for name in [
+        "stourney_connected_arenas",
+        "stourney_authenticated_arenas",
+        "stourney_queue_depth",
+        "stourney_updates_persisted_total",
+        "stourney_updates_rejected_total",
+        "stourney_db_duration_seconds_bucket{operation=\"render_test\"",
+        "stourney_load_game_requests_total{result=\"found\"}",
+    ] {
+        assert!(text.contains(name), "{} missing from:\n{}", name, text);
+    }
+}
+
+#[tokio::test]
+pub async fn readiness_fails_once_the_database_is_closed() {
+    let db = create_test_db().await;
+    db.close().await;
+    assert!(!check_readiness(&db).await.database);
+}
+
+#[tokio::test]
+pub async fn health_and_metrics_are_served() {
+    let db = create_test_db().await;
+    let health = warp::test::request()
+        .path("/healthz")
+        .reply(&routes(db.clone()))
+        .await;
+    assert_eq!(health.status(), StatusCode::OK);
+    assert_eq!(health.body(), "ok");
+
+    let response = warp::test::request()
+        .path("/metrics")
+        .reply(&routes(db))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    assert!(String::from_utf8_lossy(response.body()).contains("stourney_queue_depth"));
+}
@@ -0,0 +1,4 @@
+ALTER TABLE games ADD COLUMN finished_at TIMESTAMP;
+ALTER TABLE games ADD COLUMN final_turn INTEGER;
+ALTER TABLE games ADD COLUMN winner INTEGER;
+ALTER TABLE games ADD COLUMN final_scores TEXT;
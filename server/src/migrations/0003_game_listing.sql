@@ -0,0 +1,15 @@
+ALTER TABLE games ADD COLUMN num_players INTEGER;
+ALTER TABLE games ADD COLUMN latest_turn INTEGER;
+
+UPDATE games SET
+  latest_turn = (
+    SELECT MAX(turn_id) FROM game_updates WHERE update_uuid = games.game_uuid
+  ),
+  num_players = (
+    SELECT json_array_length(game_update, '$.info.players') FROM game_updates
+    WHERE update_uuid = games.game_uuid
+    ORDER BY turn_id DESC LIMIT 1
+  ),
+  last_updated = IFNULL(last_updated, CURRENT_TIMESTAMP);
+
+CREATE INDEX IF NOT EXISTS games_by_last_updated ON games(last_updated, game_uuid);
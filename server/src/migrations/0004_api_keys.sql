@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS api_keys (
+  key_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  key_hash TEXT NOT NULL UNIQUE,
+  owner TEXT NOT NULL,
+  label TEXT NOT NULL DEFAULT '',
+  created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+  last_used_at TIMESTAMP,
+  revoked INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS users (
+  user_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+  password_hash TEXT NOT NULL,
+  created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+  token_hash TEXT PRIMARY KEY,
+  user_id INTEGER NOT NULL,
+  created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMP NOT NULL,
+  FOREIGN KEY(user_id) REFERENCES users(user_id)
+);
+
+ALTER TABLE api_keys ADD COLUMN user_id INTEGER REFERENCES users(user_id);
+ALTER TABLE games ADD COLUMN owner_id INTEGER REFERENCES users(user_id);
+
+CREATE INDEX IF NOT EXISTS games_by_owner ON games(owner_id);
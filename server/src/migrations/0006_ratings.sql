@@ -0,0 +1,40 @@
+CREATE TABLE IF NOT EXISTS bots (
+  bot_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  name TEXT NOT NULL,
+  owner_id INTEGER REFERENCES users(user_id),
+  created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE(owner_id, name)
+);
+
+CREATE TABLE IF NOT EXISTS game_players (
+  game_uuid TEXT NOT NULL,
+  seat INTEGER NOT NULL,
+  bot_id INTEGER NOT NULL,
+  PRIMARY KEY(game_uuid, seat),
+  FOREIGN KEY(game_uuid) REFERENCES games(game_uuid),
+  FOREIGN KEY(bot_id) REFERENCES bots(bot_id)
+);
+
+CREATE TABLE IF NOT EXISTS bot_ratings (
+  bot_id INTEGER PRIMARY KEY,
+  rating REAL NOT NULL,
+  games_played INTEGER NOT NULL DEFAULT 0,
+  updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+  FOREIGN KEY(bot_id) REFERENCES bots(bot_id)
+);
+
+CREATE TABLE IF NOT EXISTS rating_history (
+  history_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  bot_id INTEGER NOT NULL,
+  game_uuid TEXT NOT NULL,
+  rating_before REAL NOT NULL,
+  rating_after REAL NOT NULL,
+  rated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+  FOREIGN KEY(bot_id) REFERENCES bots(bot_id),
+  FOREIGN KEY(game_uuid) REFERENCES games(game_uuid)
+);
+
+ALTER TABLE games ADD COLUMN rated_at TIMESTAMP;
+
+CREATE INDEX IF NOT EXISTS bot_ratings_by_rating ON bot_ratings(rating DESC);
+CREATE INDEX IF NOT EXISTS rating_history_by_bot ON rating_history(bot_id, history_id);
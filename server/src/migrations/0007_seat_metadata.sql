@@ -0,0 +1,2 @@
+ALTER TABLE game_players ADD COLUMN version TEXT;
+ALTER TABLE game_players ADD COLUMN owner TEXT;
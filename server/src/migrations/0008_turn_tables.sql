@@ -0,0 +1,109 @@
+-- Normalized copies of each saved game update, one row per player,
+-- per available card and per noble, so turns can be queried in SQL
+-- without deserializing game_updates.game_update
+
+CREATE TABLE IF NOT EXISTS turn_boards (
+  game_uuid TEXT NOT NULL,
+  turn_id INTEGER NOT NULL,
+  current_player INTEGER NOT NULL,
+  deck_tier1 INTEGER NOT NULL,
+  deck_tier2 INTEGER NOT NULL,
+  deck_tier3 INTEGER NOT NULL,
+  bank_onyx INTEGER NOT NULL,
+  bank_sapphire INTEGER NOT NULL,
+  bank_emerald INTEGER NOT NULL,
+  bank_ruby INTEGER NOT NULL,
+  bank_diamond INTEGER NOT NULL,
+  bank_gold INTEGER NOT NULL,
+  PRIMARY KEY(game_uuid, turn_id),
+  FOREIGN KEY(game_uuid) REFERENCES games(game_uuid)
+);
+
+CREATE TABLE IF NOT EXISTS turn_players (
+  game_uuid TEXT NOT NULL,
+  turn_id INTEGER NOT NULL,
+  seat INTEGER NOT NULL,
+  points INTEGER NOT NULL,
+  num_reserved INTEGER NOT NULL,
+  gems_onyx INTEGER NOT NULL,
+  gems_sapphire INTEGER NOT NULL,
+  gems_emerald INTEGER NOT NULL,
+  gems_ruby INTEGER NOT NULL,
+  gems_diamond INTEGER NOT NULL,
+  gems_gold INTEGER NOT NULL,
+  developments_onyx INTEGER NOT NULL,
+  developments_sapphire INTEGER NOT NULL,
+  developments_emerald INTEGER NOT NULL,
+  developments_ruby INTEGER NOT NULL,
+  developments_diamond INTEGER NOT NULL,
+  PRIMARY KEY(game_uuid, turn_id, seat),
+  FOREIGN KEY(game_uuid) REFERENCES games(game_uuid)
+);
+
+CREATE TABLE IF NOT EXISTS turn_cards (
+  game_uuid TEXT NOT NULL,
+  turn_id INTEGER NOT NULL,
+  tier INTEGER NOT NULL,
+  position INTEGER NOT NULL,
+  card_id INTEGER NOT NULL,
+  PRIMARY KEY(game_uuid, turn_id, tier, position),
+  FOREIGN KEY(game_uuid) REFERENCES games(game_uuid)
+);
+
+CREATE TABLE IF NOT EXISTS turn_nobles (
+  game_uuid TEXT NOT NULL,
+  turn_id INTEGER NOT NULL,
+  position INTEGER NOT NULL,
+  noble_id INTEGER NOT NULL,
+  PRIMARY KEY(game_uuid, turn_id, position),
+  FOREIGN KEY(game_uuid) REFERENCES games(game_uuid)
+);
+
+CREATE INDEX IF NOT EXISTS turn_players_by_turn ON turn_players(turn_id, seat);
+
+-- Backfill from the updates saved so far, skipping rows that never had
+-- their game_update saved, as they have nothing to copy
+INSERT OR REPLACE INTO turn_boards
+SELECT update_uuid, turn_id,
+  json_extract(game_update, '$.info.current_player_num'),
+  json_extract(game_update, '$.info.board.deck_counts[0]'),
+  json_extract(game_update, '$.info.board.deck_counts[1]'),
+  json_extract(game_update, '$.info.board.deck_counts[2]'),
+  json_extract(game_update, '$.info.board.gems.onyx'),
+  json_extract(game_update, '$.info.board.gems.sapphire'),
+  json_extract(game_update, '$.info.board.gems.emerald'),
+  json_extract(game_update, '$.info.board.gems.ruby'),
+  json_extract(game_update, '$.info.board.gems.diamond'),
+  json_extract(game_update, '$.info.board.gems.gold')
+FROM game_updates
+WHERE game_update IS NOT NULL;
+
+INSERT OR REPLACE INTO turn_players
+SELECT u.update_uuid, u.turn_id, CAST(p.key AS INTEGER),
+  json_extract(p.value, '$.points'),
+  json_extract(p.value, '$.num_reserved'),
+  json_extract(p.value, '$.gems.onyx'),
+  json_extract(p.value, '$.gems.sapphire'),
+  json_extract(p.value, '$.gems.emerald'),
+  json_extract(p.value, '$.gems.ruby'),
+  json_extract(p.value, '$.gems.diamond'),
+  json_extract(p.value, '$.gems.gold'),
+  json_extract(p.value, '$.developments.onyx'),
+  json_extract(p.value, '$.developments.sapphire'),
+  json_extract(p.value, '$.developments.emerald'),
+  json_extract(p.value, '$.developments.ruby'),
+  json_extract(p.value, '$.developments.diamond')
+FROM game_updates u, json_each(u.game_update, '$.info.players') p
+WHERE u.game_update IS NOT NULL;
+
+INSERT OR REPLACE INTO turn_cards
+SELECT u.update_uuid, u.turn_id, CAST(t.key AS INTEGER), CAST(c.key AS INTEGER), c.value
+FROM game_updates u,
+  json_each(u.game_update, '$.info.board.available_cards') t,
+  json_each(t.value) c
+WHERE u.game_update IS NOT NULL;
+
+INSERT OR REPLACE INTO turn_nobles
+SELECT u.update_uuid, u.turn_id, CAST(n.key AS INTEGER), n.value
+FROM game_updates u, json_each(u.game_update, '$.info.board.nobles') n
+WHERE u.game_update IS NOT NULL;
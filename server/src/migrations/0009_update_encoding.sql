@@ -0,0 +1,6 @@
+-- How game_update rows are stored, see encoding::Encoding. Rows saved
+-- before this migration are JSON in the game_update column, newer rows
+-- are compressed bincode in the payload column. Deltas are compressed with
+-- the serialized keyframe of their turn as the zstd dictionary
+ALTER TABLE game_updates ADD COLUMN encoding INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE game_updates ADD COLUMN payload BLOB;
@@ -0,0 +1,24 @@
+-- A game has one canonical slug, and keeps the slugs it had before it was
+-- renamed as aliases so that old links still resolve. Slugs saved before
+-- this migration are all canonical. A slug can only name one game, so
+-- duplicates left by racing inserts keep only their first game. Each slug
+-- dropped is recorded in dropped_slugs, with the game it stays with
+ALTER TABLE slugs ADD COLUMN is_alias INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS dropped_slugs (
+  slug TEXT NOT NULL,
+  slug_id TEXT,
+  kept_slug_id TEXT,
+  dropped_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO dropped_slugs (slug, slug_id, kept_slug_id)
+SELECT d.slug, d.slug_id, k.slug_id FROM slugs d
+JOIN (SELECT slug, MIN(rowid) AS kept FROM slugs GROUP BY slug) m ON m.slug = d.slug
+JOIN slugs k ON k.rowid = m.kept
+WHERE d.rowid != m.kept;
+
+DELETE FROM slugs WHERE rowid NOT IN (SELECT MIN(rowid) FROM slugs GROUP BY slug);
+
+CREATE UNIQUE INDEX IF NOT EXISTS slugs_by_slug ON slugs(slug);
+CREATE INDEX IF NOT EXISTS slugs_by_game ON slugs(slug_id, is_alias);
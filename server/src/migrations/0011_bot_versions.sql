@@ -0,0 +1,18 @@
+-- Uploaded builds of a bot. A version is never changed once uploaded, and
+-- deleting it only marks it deleted so its number is never reused. The
+-- artifact itself is stored once per sha256 in the artifact directory
+CREATE TABLE IF NOT EXISTS bot_versions (
+  version_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  bot_id INTEGER NOT NULL,
+  version INTEGER NOT NULL,
+  kind TEXT NOT NULL,
+  filename TEXT NOT NULL,
+  sha256 TEXT NOT NULL,
+  size INTEGER NOT NULL,
+  uploaded_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+  deleted_at TIMESTAMP,
+  UNIQUE(bot_id, version),
+  FOREIGN KEY(bot_id) REFERENCES bots(bot_id)
+);
+
+CREATE INDEX IF NOT EXISTS bot_versions_by_sha256 ON bot_versions(sha256);
@@ -0,0 +1,4 @@
+-- A seat that broke a limit of the hosted match it was playing forfeits
+-- it, and places last whatever its points
+ALTER TABLE games ADD COLUMN forfeit_seat INTEGER;
+ALTER TABLE games ADD COLUMN forfeit_reason TEXT;
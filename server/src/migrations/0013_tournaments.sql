@@ -0,0 +1,56 @@
+-- Tournaments between uploaded bot versions, played by the runner. The
+-- participants are numbered in seed order. Every pairing of a round, and
+-- the seating of each of its games, is saved when the round is drawn, so
+-- a tournament picks up where it left off after a restart
+CREATE TABLE IF NOT EXISTS tournaments (
+  tournament_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  name TEXT NOT NULL,
+  format TEXT NOT NULL,
+  seats INTEGER NOT NULL,
+  games_per_pairing INTEGER NOT NULL,
+  rotation TEXT NOT NULL,
+  rounds INTEGER NOT NULL,
+  created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+  finished_at TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS tournament_participants (
+  tournament_id INTEGER NOT NULL,
+  participant INTEGER NOT NULL,
+  bot_id INTEGER NOT NULL,
+  version INTEGER NOT NULL,
+  PRIMARY KEY(tournament_id, participant),
+  FOREIGN KEY(tournament_id) REFERENCES tournaments(tournament_id),
+  FOREIGN KEY(bot_id) REFERENCES bots(bot_id)
+);
+
+-- The participants of a pairing as a JSON array, a single one has a bye,
+-- and how many times a knockout pairing whose games all failed with no bot
+-- to blame was played again
+CREATE TABLE IF NOT EXISTS tournament_pairings (
+  tournament_id INTEGER NOT NULL,
+  round INTEGER NOT NULL,
+  pairing INTEGER NOT NULL,
+  participants TEXT NOT NULL,
+  replays INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY(tournament_id, round, pairing),
+  FOREIGN KEY(tournament_id) REFERENCES tournaments(tournament_id)
+);
+
+-- A game is being played while it has a match_number, and is over once
+-- it has places by seat (a JSON array) or the reason it failed, with the
+-- seat whose bot made it fail if it was one bot
+CREATE TABLE IF NOT EXISTS tournament_games (
+  tournament_id INTEGER NOT NULL,
+  round INTEGER NOT NULL,
+  pairing INTEGER NOT NULL,
+  game INTEGER NOT NULL,
+  seating TEXT NOT NULL,
+  match_number INTEGER,
+  game_uuid TEXT,
+  places TEXT,
+  reason TEXT,
+  failed_seat INTEGER,
+  PRIMARY KEY(tournament_id, round, pairing, game),
+  FOREIGN KEY(tournament_id) REFERENCES tournaments(tournament_id)
+);
@@ -0,0 +1,3 @@
+-- The api key of the arena that started a game, so after a restart the
+-- game can only be reconnected to by the same key, or its account
+ALTER TABLE games ADD COLUMN key_id INTEGER REFERENCES api_keys(key_id);
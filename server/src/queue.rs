@@ -16,24 +16,83 @@
 // TODO: reading from the database may need to also be added to the queue
 // to prevent phantom reads
 
-use log::debug;
+#[cfg(test)]
+pub mod tests;
+
 use splendor_arena::models::*;
 use sqlx::sqlite::SqlitePool;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedSender, WeakSender};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 use uuid::Uuid;
 
+use crate::config;
 use crate::database;
+use crate::logging;
+use crate::metrics;
+use crate::ratings;
+use crate::shutdown;
+use crate::spool::{self, Spool, SpoolEntry};
 
 // TODO: may want to consider changing the data structure in the following cases:
 //  - horizontal scalability is a concern : swap this with Redis
 //  - mutexes are too slow : use a lock-free work stealing data structure
 //  - throughput is too low or buffer pressure is too high : optimize the incoming GameUpdate
-pub type AsyncQueue = UnboundedSender<QueueUpdate>;
+#[derive(Clone)]
+pub struct AsyncQueue {
+    sender: Sender<Queued>,
+    spool: Option<Arc<Spool>>,
+    max_wait: Duration,
+}
+
+impl AsyncQueue {
+    /// How many updates the queue holds at most
+    pub fn max_capacity(&self) -> usize {
+        self.sender.max_capacity()
+    }
+
+    /// Changes how long game updates wait for room in the queue before
+    /// they are rejected, MAX_WAIT by default
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+}
+
+/// The queue of the server, once start() is called, kept weakly so it
+/// can be reported on without keeping the processor alive
+static STARTED: OnceLock<Started> = OnceLock::new();
+
+struct Started {
+    sender: WeakSender<Queued>,
+    spool: Option<Arc<Spool>>,
+    max_wait: Duration,
+}
+
+/// How long game updates wait for room in a full queue before they are
+/// rejected, unless the queue is started with queue_max_wait_ms.
+/// Meanwhile nothing else is read from the arena sending them
+pub const MAX_WAIT: Duration = Duration::from_secs(10);
+
+/// How long an arena is asked to wait before resending game updates
+/// that did not fit in the queue
+pub const RETRY_AFTER: Duration = Duration::from_millis(500);
+
+/// Game updates were rejected because the queue stayed saturated for its
+/// max wait, or they could not be spooled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueFull {
+    pub retry_after: Duration,
+}
 
 pub enum QueueUpdate {
     AddGameInfo {
         id: Uuid,
         update: GameUpdate,
+        spooled: Option<SpoolEntry>,
     },
 
     GetSlug {
@@ -48,29 +107,274 @@ pub enum QueueUpdate {
     SetGameOver {
         id: Uuid,
     },
+
+    SetGameOwner {
+        id: Uuid,
+        user_id: i64,
+    },
+
+    SetGameKey {
+        id: Uuid,
+        key_id: i64,
+    },
+
+    SetGamePlayers {
+        id: Uuid,
+        owner_id: Option<i64>,
+        players: Vec<database::SeatRecord>,
+    },
+
+    SetGameBots {
+        id: Uuid,
+        versions: Vec<database::BotVersion>,
+    },
+
+    SetForfeit {
+        id: Uuid,
+        seat: usize,
+        reason: String,
+    },
+
+    SetGameSeats {
+        id: Uuid,
+        bot_ids: Vec<i64>,
+    },
+}
+
+/// An update along with the span it was queued from, so the lines logged
+/// while processing it carry the game and connection it came from
+pub struct Queued {
+    pub update: QueueUpdate,
+    pub span: Span,
+}
+
+impl Queued {
+    fn new(update: QueueUpdate) -> Self {
+        Queued {
+            update,
+            span: Span::current(),
+        }
+    }
+}
+
+impl QueueUpdate {
+    /// The name of the database operation, as recorded in the metrics
+    fn operation(&self) -> &'static str {
+        match self {
+            QueueUpdate::AddGameInfo { .. } => "save_game_update",
+            QueueUpdate::GetSlug { .. } => "get_slug",
+            QueueUpdate::GenerateId { .. } => "generate_id",
+            QueueUpdate::SetGameOver { .. } => "set_game_over",
+            QueueUpdate::SetGameOwner { .. } => "set_game_owner",
+            QueueUpdate::SetGameKey { .. } => "set_game_key",
+            QueueUpdate::SetGamePlayers { .. } => "set_game_players",
+            QueueUpdate::SetGameBots { .. } => "set_game_bots",
+            QueueUpdate::SetForfeit { .. } => "set_forfeit",
+            QueueUpdate::SetGameSeats { .. } => "set_game_seats",
+        }
+    }
+}
+
+/// Starts the queue with the capacity and spool directory from the
+/// configuration, see start_in()
+pub async fn start(db_pool: &SqlitePool) -> AsyncQueue {
+    let config = config::get();
+    start_in(db_pool, config.queue_capacity, &config.spool_dir).await
+}
+
+/// Replays the updates left in the spool by the last run, then starts
+/// the queue and its processor
+pub async fn start_in(db_pool: &SqlitePool, capacity: usize, dir: &Path) -> AsyncQueue {
+    let recovered = spool::recover(dir).expect("Failed to read spool");
+    if !recovered.updates.is_empty() {
+        info!(
+            "[+] Replaying {} spooled game updates",
+            recovered.updates.len()
+        );
+        // The spool is only cleared once its updates are saved
+        database::save_game_updates(db_pool, &recovered.updates)
+            .await
+            .expect("Failed to replay spooled game updates");
+        metrics::UPDATES_PERSISTED.inc_by(recovered.updates.len() as u64);
+    }
+    let spool = Spool::create(dir, recovered.next_seq).expect("Failed to create spool");
+
+    let (queue, receiver) = with_capacity(capacity);
+    let mut queue = queue.with_max_wait(Duration::from_millis(config::get().queue_max_wait_ms));
+    queue.spool = Some(Arc::new(spool));
+    let _ = STARTED.set(Started {
+        sender: queue.sender.downgrade(),
+        spool: queue.spool.clone(),
+        max_wait: queue.max_wait,
+    });
+    let (db_pool, spool, guard) = (db_pool.clone(), queue.spool.clone(), shutdown::guard());
+    tokio::spawn(async move {
+        queue_processer(db_pool, receiver, spool).await;
+        drop(guard);
+    });
+    queue
+}
+
+/// Whether the queue was started and its processor is still taking updates
+pub fn is_running() -> bool {
+    running().is_some()
+}
+
+/// The started queue, for code that is not handed it by serve(), None
+/// if it was not started or its processor stopped
+pub fn running() -> Option<AsyncQueue> {
+    let started = STARTED.get()?;
+    let sender = started
+        .sender
+        .upgrade()
+        .filter(|sender| !sender.is_closed())?;
+    Some(AsyncQueue {
+        sender,
+        spool: started.spool.clone(),
+        max_wait: started.max_wait,
+    })
+}
+
+/// How many updates are waiting in the started queue
+pub fn depth() -> usize {
+    STARTED
+        .get()
+        .and_then(|started| started.sender.upgrade())
+        .map_or(0, |sender| sender.max_capacity() - sender.capacity())
 }
 
-/// Process the queue of updates, calling process_update()
-/// on each of them and blocking while the receiver still has active senders
-pub async fn queue_processer(db_pool: SqlitePool, mut receiver: UnboundedReceiver<QueueUpdate>) {
+/// Creates a queue holding at most `capacity` updates, without a spool
+pub fn with_capacity(capacity: usize) -> (AsyncQueue, Receiver<Queued>) {
+    debug!("[+] Creating queue with capacity {}", capacity);
+    let (sender, receiver) = tokio::sync::mpsc::channel(capacity);
+    (
+        AsyncQueue {
+            sender,
+            spool: None,
+            max_wait: MAX_WAIT,
+        },
+        receiver,
+    )
+}
+
+/// The most game updates saved in a single transaction
+const MAX_BATCH_SIZE: usize = 512;
+
+/// Game updates waiting to be saved together
+#[derive(Default)]
+struct Batch {
+    updates: Vec<(Uuid, GameUpdate)>,
+    spooled: Vec<SpoolEntry>,
+    spans: Vec<Span>,
+}
+
+/// Process the queue of updates, blocking while the receiver still has
+/// active senders. Whatever is pending is drained at once, and runs of
+/// game updates are saved in a single transaction by save_batch(),
+/// every other update goes through process_update() in order.
+///
+/// Once the server starts shutting down the queue stops taking updates,
+/// and the processor returns when everything already in it is saved
+pub async fn queue_processer(
+    db_pool: SqlitePool,
+    mut receiver: Receiver<Queued>,
+    spool: Option<Arc<Spool>>,
+) {
+    let mut draining = false;
+    let mut batch = Batch::default();
     loop {
-        match receiver.recv().await {
-            Some(queue_update) => {
-                process_update(&db_pool, queue_update).await;
+        let queue_update = tokio::select! {
+            queue_update = receiver.recv() => queue_update,
+            _ = shutdown::triggered(), if !draining => {
+                info!("[+] Shutting down, draining the queue");
+                draining = true;
+                receiver.close();
+                continue;
             }
-            None => {
-                debug!("[-] Shutting down queue processor, no more senders online.");
-                break;
+        };
+        let Some(queue_update) = queue_update else {
+            break;
+        };
+
+        let mut next = Some(queue_update);
+        while let Some(Queued { update, span }) = next {
+            match update {
+                QueueUpdate::AddGameInfo {
+                    id,
+                    update,
+                    spooled,
+                } => {
+                    batch.updates.push((id, update));
+                    batch.spooled.extend(spooled);
+                    batch.spans.push(span);
+                    if batch.updates.len() >= MAX_BATCH_SIZE {
+                        save_batch(&db_pool, &mut batch, spool.as_deref()).await;
+                    }
+                }
+                other => {
+                    // Earlier updates must be visible to whatever comes next
+                    save_batch(&db_pool, &mut batch, spool.as_deref()).await;
+                    let span =
+                        info_span!(parent: &span, "queue_update", operation = other.operation());
+                    process_update(&db_pool, other).instrument(span).await;
+                }
             }
+            next = receiver.try_recv().ok();
         }
+        save_batch(&db_pool, &mut batch, spool.as_deref()).await;
     }
+
+    if draining {
+        let left = spool.as_ref().map_or(0, |spool| spool.pending());
+        info!("[+] Queue drained with {} updates left in the spool", left);
+        return;
+    }
+    debug!("[-] Shutting down queue processor, no more senders online.");
+}
+
+/// Saves and clears the pending game updates, then releases them from
+/// the spool. A batch that fails to save is rolled back and logged, and
+/// stays in the spool to be replayed when the server starts again
+async fn save_batch(db_pool: &SqlitePool, batch: &mut Batch, spool: Option<&Spool>) {
+    if batch.updates.is_empty() {
+        return;
+    }
+    let start = Instant::now();
+    let timer = metrics::time_db("save_game_updates");
+    let saved = database::save_game_updates(db_pool, &batch.updates).await;
+    timer.observe_duration();
+    match saved {
+        Ok(()) => {
+            metrics::UPDATES_PERSISTED.inc_by(batch.updates.len() as u64);
+            info!(
+                "[+] Saved a batch of {} game updates in {:?}",
+                batch.updates.len(),
+                start.elapsed()
+            );
+            for span in &batch.spans {
+                span.in_scope(|| debug!("[+] Saved game update"));
+            }
+            if let Some(spool) = spool {
+                spool.release(&batch.spooled);
+            }
+        }
+        // Left in the spool, so the batch is replayed on the next start
+        Err(error) => error!(
+            "[-] Failed to save a batch of {} game updates, rolled back: {}",
+            batch.updates.len(),
+            error
+        ),
+    }
+    batch.updates.clear();
+    batch.spooled.clear();
+    batch.spans.clear();
 }
 
 async fn process_update(db_pool: &SqlitePool, update: QueueUpdate) {
+    let _timer = metrics::time_db(update.operation());
     match update {
-        QueueUpdate::AddGameInfo { id, update } => {
-            debug!("[+] Processing game update for {}", id);
-            database::simple_save_game_update(db_pool, update, id).await;
+        QueueUpdate::AddGameInfo { .. } => {
+            unreachable!("Game updates are saved in batches by save_batch()")
         }
         QueueUpdate::GetSlug {
             id,
@@ -87,38 +391,257 @@ async fn process_update(db_pool: &SqlitePool, update: QueueUpdate) {
         }
         QueueUpdate::SetGameOver { id } => {
             debug!("[+] Processing set game over update for {}", id);
-            todo!("unsure what to do in the event of game over");
+            match database::save_game_over(db_pool, id).await {
+                Some(result) => info!(
+                    "[+] Game {} finished on turn {}, winner: {:?}, scores: {:?}",
+                    id, result.final_turn, result.winner, result.final_scores
+                ),
+                None => warn!("[-] Game {} declared game over without any updates", id),
+            }
+            if let Some(changes) = ratings::rate_game(db_pool, id).await {
+                info!("[+] Rated game {}: {:?}", id, changes);
+            }
+        }
+        QueueUpdate::SetGameOwner { id, user_id } => {
+            debug!("[+] Processing set game owner update for {}", id);
+            database::save_game_owner(db_pool, id, user_id).await;
+        }
+        QueueUpdate::SetGameKey { id, key_id } => {
+            debug!("[+] Processing set game key update for {}", id);
+            database::save_game_key(db_pool, id, key_id).await;
+        }
+        QueueUpdate::SetGamePlayers {
+            id,
+            owner_id,
+            players,
+        } => {
+            debug!("[+] Processing set game players update for {}", id);
+            database::save_game_players(db_pool, id, owner_id, &players).await;
+            // Players registered after the game ended can still rate it
+            if let Some(changes) = ratings::rate_game(db_pool, id).await {
+                info!("[+] Rated game {}: {:?}", id, changes);
+            }
+        }
+        QueueUpdate::SetGameBots { id, versions } => {
+            debug!("[+] Processing set game bots update for {}", id);
+            database::save_game_bots(db_pool, id, &versions).await;
+        }
+        QueueUpdate::SetForfeit { id, seat, reason } => {
+            debug!("[+] Processing set forfeit update for {}", id);
+            database::save_forfeit(db_pool, id, seat, &reason).await;
         }
+        QueueUpdate::SetGameSeats { id, bot_ids } => {
+            debug!("[+] Processing set game seats update for {}", id);
+            database::save_game_seats(db_pool, id, &bot_ids).await;
+        }
+    }
+}
+
+/// Queues an update that must not be dropped, waiting for room if the
+/// queue is full
+async fn send_reliably(update: QueueUpdate, sender: &AsyncQueue) {
+    let operation = update.operation();
+    if sender.sender.send(Queued::new(update)).await.is_err() {
+        warn!("[-] Queue is closed, dropped a {} update", operation);
     }
 }
 
-/// Create a new id for a game, blocks until the id is created
-pub async fn create_id(sender: &UnboundedSender<QueueUpdate>) -> Uuid {
+/// Create a new id for a game, blocks until the id is created. None if
+/// the queue closed first, as it does once the server starts shutting down
+pub async fn create_id(sender: &AsyncQueue) -> Option<Uuid> {
     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-    let _ = sender.send(QueueUpdate::GenerateId { callback: tx });
-    rx.recv().await.unwrap()
+    let queued = Queued::new(QueueUpdate::GenerateId { callback: tx });
+    if sender.sender.send(queued).await.is_err() {
+        warn!("[-] Queue is closed, no id was created");
+        return None;
+    }
+    let id = rx.recv().await?;
+    logging::record_game(id);
+    Some(id)
 }
 
 /// Get the slug for a given id from the database,
 /// or generate a new one if it does not exist,
-/// blocks until the slug is retrieved
-pub async fn get_slug(id: Uuid, sender: &UnboundedSender<QueueUpdate>) -> String {
+/// blocks until the slug is retrieved. None if the queue closed first
+pub async fn get_slug(id: Uuid, sender: &AsyncQueue) -> Option<String> {
     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-    let _ = sender.send(QueueUpdate::GetSlug { id, callback: tx });
-    rx.recv().await.unwrap()
+    logging::record_game(id);
+    let queued = Queued::new(QueueUpdate::GetSlug { id, callback: tx });
+    if sender.sender.send(queued).await.is_err() {
+        warn!("[-] Queue is closed, no slug was looked up for {}", id);
+        return None;
+    }
+    let slug = rx.recv().await?;
+    logging::record_slug(&slug);
+    Some(slug)
+}
+
+/// Mark a game as finished, waiting for room in the queue
+pub async fn set_game_over(id: Uuid, sender: &AsyncQueue) {
+    logging::record_game(id);
+    send_reliably(QueueUpdate::SetGameOver { id }, sender).await
+}
+
+/// Attribute a game to the account of the arena that uploads it,
+/// waiting for room in the queue
+pub async fn set_game_owner(id: Uuid, user_id: i64, sender: &AsyncQueue) {
+    send_reliably(QueueUpdate::SetGameOwner { id, user_id }, sender).await
+}
+
+/// Record the api key of the arena that started a game, so only it can
+/// reconnect to the game, waiting for room in the queue
+pub async fn set_game_key(id: Uuid, key_id: i64, sender: &AsyncQueue) {
+    send_reliably(QueueUpdate::SetGameKey { id, key_id }, sender).await
 }
 
-/// Append a game update to the queue, and returns
-/// immediately
-pub fn push_game_updates(
+/// Record the bot sitting in each seat of a game, waiting for room in the queue
+pub async fn set_game_players(
     id: Uuid,
-    updates: &Vec<GameUpdate>,
-    sender: &mut UnboundedSender<QueueUpdate>,
+    owner_id: Option<i64>,
+    players: Vec<database::SeatRecord>,
+    sender: &AsyncQueue,
 ) {
-    for update in updates {
-        let _ = sender.send(QueueUpdate::AddGameInfo {
+    send_reliably(
+        QueueUpdate::SetGamePlayers {
+            id,
+            owner_id,
+            players,
+        },
+        sender,
+    )
+    .await
+}
+
+/// Record the uploaded bot version sitting in each seat of a hosted game,
+/// waiting for room in the queue
+pub async fn set_game_bots(id: Uuid, versions: Vec<database::BotVersion>, sender: &AsyncQueue) {
+    send_reliably(QueueUpdate::SetGameBots { id, versions }, sender).await
+}
+
+/// Record that a seat forfeited a hosted game by breaking a limit, must
+/// be sent before the game is set over so the seat places last. Waits for
+/// room in the queue
+pub async fn set_forfeit(id: Uuid, seat: usize, reason: String, sender: &AsyncQueue) {
+    send_reliably(QueueUpdate::SetForfeit { id, seat, reason }, sender).await
+}
+
+/// Record the bot sitting in each seat of a game played in the lobby, in
+/// seat order, waiting for room in the queue
+pub async fn set_game_seats(id: Uuid, bot_ids: Vec<i64>, sender: &AsyncQueue) {
+    send_reliably(QueueUpdate::SetGameSeats { id, bot_ids }, sender).await
+}
+
+/// Spools game updates if the queue has a spool, so they are replayed
+/// after a restart if they are not committed before then
+fn spool_updates(
+    id: Uuid,
+    updates: &[GameUpdate],
+    sender: &AsyncQueue,
+) -> Result<Vec<Option<SpoolEntry>>, QueueFull> {
+    let Some(spool) = &sender.spool else {
+        return Ok(vec![None; updates.len()]);
+    };
+    match spool.append(id, updates) {
+        Ok(entries) => Ok(entries.into_iter().map(Some).collect()),
+        Err(error) => {
+            error!(
+                "[-] Failed to spool {} updates for {}: {}",
+                updates.len(),
+                id,
+                error
+            );
+            Err(QueueFull {
+                retry_after: RETRY_AFTER,
+            })
+        }
+    }
+}
+
+/// A game update queued in a span recording its turn
+fn queued_game_update(id: Uuid, update: GameUpdate, spooled: Option<SpoolEntry>) -> Queued {
+    Queued {
+        span: info_span!("game_update", turn = update.update_num),
+        update: QueueUpdate::AddGameInfo {
             id,
-            update: update.clone(),
-        });
+            update,
+            spooled,
+        },
+    }
+}
+
+/// Append game updates to the queue, waiting up to the queue's max wait
+/// for room. Either all of the updates are queued or, if there is still
+/// no room for all of them, none are and the arena should resend them
+/// later. Queued updates are spooled first, so they are saved even if the
+/// server stops before the queue gets to them
+pub async fn push_game_updates(
+    id: Uuid,
+    updates: &Vec<GameUpdate>,
+    sender: &mut AsyncQueue,
+) -> Result<(), QueueFull> {
+    logging::record_game(id);
+    if let Some(last) = updates.last() {
+        logging::record_turn(last.update_num);
+    }
+
+    // A batch that could never fit is queued a queue's worth at a time,
+    // keeping the arena waiting until the last of it is in
+    if updates.len() > sender.max_capacity() {
+        warn!(
+            "[-] {} updates for {} are more than the queue holds",
+            updates.len(),
+            id
+        );
+        let spooled = spool_updates(id, updates, sender)?;
+        let mut queued = updates
+            .iter()
+            .zip(spooled)
+            .map(|(update, spooled)| queued_game_update(id, update.clone(), spooled));
+        let mut left = updates.len();
+        while left > 0 {
+            let count = left.min(sender.max_capacity());
+            // Once the queue is closed the rest is saved from the spool
+            let Ok(permits) = sender.sender.reserve_many(count).await else {
+                return Ok(());
+            };
+            for (permit, queued) in permits.zip(queued.by_ref()) {
+                permit.send(queued);
+            }
+            left -= count;
+        }
+        return Ok(());
+    }
+
+    let reserved = match sender.sender.try_reserve_many(updates.len()) {
+        Err(TrySendError::Full(())) => {
+            let reserve = sender.sender.reserve_many(updates.len());
+            tokio::time::timeout(sender.max_wait, reserve).await
+        }
+        reserved => Ok(reserved.map_err(|_| SendError(()))),
+    };
+    let permits = match reserved {
+        Ok(Ok(permits)) => permits,
+        // The server is shutting down, the spooled updates are saved when
+        // it starts again
+        Ok(Err(_)) => {
+            spool_updates(id, updates, sender)?;
+            return Ok(());
+        }
+        Err(_) => {
+            let turns: Vec<usize> = updates.iter().map(|update| update.update_num).collect();
+            warn!(
+                "[-] Queue stayed full, rejecting updates {:?} for {}",
+                turns, id
+            );
+            metrics::UPDATES_REJECTED.inc_by(updates.len() as u64);
+            return Err(QueueFull {
+                retry_after: RETRY_AFTER,
+            });
+        }
+    };
+    let spooled = spool_updates(id, updates, sender)?;
+    for ((permit, update), spooled) in permits.zip(updates).zip(spooled) {
+        permit.send(queued_game_update(id, update.clone(), spooled));
     }
+    Ok(())
 }
@@ -0,0 +1,159 @@
+use super::*;
+use crate::database::tests::{create_test_db, default_game_update};
+
+#[tokio::test]
+pub async fn queued_game_updates_are_saved_before_later_requests() {
+    let db = create_test_db().await;
+    let (mut sender, receiver) = with_capacity(16);
+    tokio::spawn(queue_processer(db.clone(), receiver, None));
+
+    let id = create_id(&sender).await.unwrap();
+    let updates: Vec<GameUpdate> = (0..40)
+        .map(|n| {
+            let mut update = default_game_update();
+            update.update_num = n;
+            update
+        })
+        .collect();
+    for chunk in updates.chunks(8) {
+        while push_game_updates(id, &chunk.to_vec(), &mut sender)
+            .await
+            .is_err()
+        {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    // Answered only after every update queued before it is committed
+    get_slug(id, &sender).await.unwrap();
+    assert_eq!(
+        database::load_game_updates(&db, id, None, None)
+            .await
+            .unwrap()
+            .len(),
+        40
+    );
+}
+
+#[tokio::test]
+pub async fn processor_saves_the_queue_and_returns_once_the_senders_are_gone() {
+    let db = create_test_db().await;
+    let (mut sender, receiver) = with_capacity(16);
+    let processor = tokio::spawn(queue_processer(db.clone(), receiver, None));
+
+    let id = create_id(&sender).await.unwrap();
+    let updates: Vec<GameUpdate> = (0..3)
+        .map(|n| {
+            let mut update = default_game_update();
+            update.update_num = n;
+            update
+        })
+        .collect();
+    push_game_updates(id, &updates, &mut sender)
+        .await
+        .expect("expected room in the queue");
+    set_game_over(id, &sender).await;
+    drop(sender);
+
+    tokio::time::timeout(Duration::from_secs(5), processor)
+        .await
+        .expect("expected the processor to return")
+        .expect("expected the processor not to panic");
+    assert_eq!(
+        database::load_game_updates(&db, id, None, None)
+            .await
+            .unwrap()
+            .len(),
+        3
+    );
+}
+
+#[tokio::test]
+pub async fn processor_keeps_going_after_a_batch_fails_to_save() {
+    let db = create_test_db().await;
+    sqlx::query(
+        "CREATE TRIGGER fail_turn_13 BEFORE INSERT ON game_updates WHEN NEW.turn_id = 13
+         BEGIN SELECT RAISE(ABORT, 'disk full'); END",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    let (mut sender, receiver) = with_capacity(16);
+    let processor = tokio::spawn(queue_processer(db.clone(), receiver, None));
+
+    let id = create_id(&sender).await.unwrap();
+    let turn = |update_num| GameUpdate {
+        update_num,
+        ..default_game_update()
+    };
+    push_game_updates(id, &vec![turn(12), turn(13)], &mut sender)
+        .await
+        .unwrap();
+    get_slug(id, &sender).await.unwrap();
+    push_game_updates(id, &vec![turn(14)], &mut sender)
+        .await
+        .unwrap();
+    get_slug(id, &sender)
+        .await
+        .expect("expected the processor to still be running");
+
+    let saved: Vec<usize> = database::load_game_updates(&db, id, None, None)
+        .await
+        .unwrap()
+        .iter()
+        .map(|update| update.update_num)
+        .collect();
+    assert_eq!(
+        saved,
+        vec![14],
+        "expected the failed batch to be rolled back"
+    );
+    assert!(!processor.is_finished());
+}
+
+#[tokio::test]
+pub async fn ids_and_slugs_are_not_made_once_the_queue_is_closed() {
+    let (sender, mut receiver) = with_capacity(4);
+    receiver.close();
+    assert_eq!(create_id(&sender).await, None);
+    assert_eq!(get_slug(Uuid::new_v4(), &sender).await, None);
+}
+
+#[tokio::test]
+pub async fn batch_larger_than_the_queue_waits_for_room() {
+    let (mut sender, mut receiver) = with_capacity(4);
+    let updates: Vec<GameUpdate> = (0..10)
+        .map(|n| {
+            let mut update = default_game_update();
+            update.update_num = n;
+            update
+        })
+        .collect();
+    let push = push_game_updates(Uuid::new_v4(), &updates, &mut sender);
+    tokio::pin!(push);
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), &mut push)
+            .await
+            .is_err(),
+        "expected the batch to wait until the queue has room"
+    );
+
+    let turn = |queued: Queued| match queued.update {
+        QueueUpdate::AddGameInfo { update, .. } => update.update_num,
+        _ => panic!("expected a game update"),
+    };
+    let mut turns = vec![];
+    loop {
+        tokio::select! {
+            pushed = &mut push => {
+                pushed.expect("expected the batch to be queued");
+                break;
+            }
+            Some(queued) = receiver.recv() => turns.push(turn(queued)),
+        }
+    }
+    while let Ok(queued) = receiver.try_recv() {
+        turns.push(turn(queued));
+    }
+    assert_eq!(turns, (0..10).collect::<Vec<_>>());
+}
@@ -0,0 +1,158 @@
+// Elo ratings for bots, updated every time a game between them finishes.
+//
+// Splendor is played by 2 to 4 players, so a game is scored as every
+// pair of seats playing each other: the seat that placed higher wins the
+// pair, and seats with the same standing draw. Each seat then moves by
+// the usual Elo update, summed over its opponents and scaled down by the
+// number of opponents so a 4 player game moves ratings about as much as
+// a 2 player one:
+//
+//      change = K / opponents * sum(actual - expected)
+//
+// Standings follow the rules of the game: the most points wins, and ties
+// are broken in favor of the player who bought the fewest development
+// cards. A bot sitting in more than one seat does not play itself.
+
+#[cfg(test)]
+pub mod tests;
+
+use splendor_arena::{PlayerPublicInfo, SmallClientInfo};
+use sqlx::sqlite::SqlitePool;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::database;
+
+/// The rating of a bot that has never played a rated game
+pub const INITIAL_RATING: f64 = 1500.0;
+
+/// The most a rating can move after a single game
+const K_FACTOR: f64 = 32.0;
+
+/// How a rating moved because of one game
+#[derive(Debug, Clone, PartialEq)]
+pub struct RatingChange {
+    pub bot_id: i64,
+    pub rating_before: f64,
+    pub rating_after: f64,
+}
+
+/// What a player is ranked by at the end of a game, higher is better
+pub fn standing(player: &PlayerPublicInfo) -> (i32, i32) {
+    let cards = player.developments.onyx as i32
+        + player.developments.sapphire as i32
+        + player.developments.emerald as i32
+        + player.developments.ruby as i32
+        + player.developments.diamond as i32;
+    (player.points as i32, -cards)
+}
+
+/// The place of every seat, 0 for first, seats that tie share a place
+pub fn placements(info: &SmallClientInfo) -> Vec<usize> {
+    info.players
+        .iter()
+        .map(|player| {
+            info.players
+                .iter()
+                .filter(|other| standing(other) > standing(player))
+                .count()
+        })
+        .collect()
+}
+
+/// The place of every seat at the end of a game, where a seat that
+/// forfeited places last and the others are placed among themselves
+pub fn final_placements(info: &SmallClientInfo, forfeit: Option<usize>) -> Vec<usize> {
+    let mut places = placements(info);
+    let Some(forfeit) = forfeit.filter(|&seat| seat < places.len()) else {
+        return places;
+    };
+    let forfeited = standing(&info.players[forfeit]);
+    for (seat, place) in places.iter_mut().enumerate() {
+        if seat == forfeit {
+            *place = info.players.len() - 1;
+        } else if forfeited > standing(&info.players[seat]) {
+            *place -= 1;
+        }
+    }
+    places
+}
+
+/// The chance a player rated `rating` beats one rated `opponent`
+fn expected_score(rating: f64, opponent: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0))
+}
+
+/// New ratings for the bots in a game, given the bot and its rating
+/// before the game for each seat, and the place each seat finished in
+pub fn rate(seats: &[(i64, f64)], places: &[usize]) -> Vec<RatingChange> {
+    let mut changes: Vec<RatingChange> = vec![];
+    for (seat, &(bot_id, rating)) in seats.iter().enumerate() {
+        let opponents: Vec<usize> = (0..seats.len())
+            .filter(|&other| seats[other].0 != bot_id)
+            .collect();
+        if opponents.is_empty() {
+            continue;
+        }
+
+        let surprise: f64 = opponents
+            .iter()
+            .map(|&other| {
+                let actual = match places[seat].cmp(&places[other]) {
+                    std::cmp::Ordering::Less => 1.0,
+                    std::cmp::Ordering::Equal => 0.5,
+                    std::cmp::Ordering::Greater => 0.0,
+                };
+                actual - expected_score(rating, seats[other].1)
+            })
+            .sum();
+        let change = K_FACTOR / opponents.len() as f64 * surprise;
+
+        match changes.iter_mut().find(|c| c.bot_id == bot_id) {
+            Some(existing) => existing.rating_after += change,
+            None => changes.push(RatingChange {
+                bot_id,
+                rating_before: rating,
+                rating_after: rating + change,
+            }),
+        }
+    }
+    changes
+}
+
+/// Rates a finished game once its seats are known, returns None if the
+/// game is not finished, has already been rated, or does not have at
+/// least two different bots in it
+pub async fn rate_game(pool: &SqlitePool, uuid: Uuid) -> Option<Vec<RatingChange>> {
+    let players = database::load_game_players(pool, uuid).await;
+    if players
+        .iter()
+        .all(|player| player.bot_id == players[0].bot_id)
+    {
+        return None;
+    }
+
+    let last_update = match database::load_latest_game_update(pool, uuid).await {
+        Ok(update) => update?,
+        Err(error) => {
+            error!("[!] Cannot rate game {}: {}", uuid, error);
+            return None;
+        }
+    };
+    if last_update.info.players.len() != players.len() {
+        warn!(
+            "[-] Game {} has {} players but {} registered seats, not rating it",
+            uuid,
+            last_update.info.players.len(),
+            players.len()
+        );
+        return None;
+    }
+
+    let bot_ids: Vec<i64> = players.iter().map(|player| player.bot_id).collect();
+    let forfeit = database::load_forfeit(pool, uuid)
+        .await
+        .map(|(seat, _)| seat);
+    let places = final_placements(&last_update.info, forfeit);
+    database::save_game_rating(pool, uuid, &bot_ids, &places).await
+}
@@ -0,0 +1,183 @@
+use super::*;
+use splendor_arena::models::GameUpdate;
+use splendor_arena::{Board, Cost, Gems};
+
+fn player(points: u8, cards: i8) -> PlayerPublicInfo {
+    let mut developments = Cost::from_gems(&Gems::empty());
+    developments.ruby = cards;
+    PlayerPublicInfo {
+        gems: Gems::empty(),
+        developments,
+        num_reserved: 0,
+        points,
+    }
+}
+
+fn final_state(players: Vec<PlayerPublicInfo>) -> SmallClientInfo {
+    SmallClientInfo {
+        board: Board {
+            deck_counts: [30, 20, 10],
+            available_cards: vec![vec![0, 1, 2, 3], vec![40, 41, 42, 43], vec![70, 71, 72, 73]],
+            nobles: vec![0, 1, 2],
+            gems: Gems::start(players.len() as u8),
+        },
+        players,
+        current_player_num: 0,
+    }
+}
+
+#[test]
+pub fn fewest_developments_breaks_a_tie_on_points() {
+    let info = final_state(vec![player(15, 12), player(15, 10), player(9, 8)]);
+    assert_eq!(placements(&info), vec![1, 0, 2]);
+
+    let info = final_state(vec![player(15, 10), player(15, 10)]);
+    assert_eq!(placements(&info), vec![0, 0]);
+}
+
+#[test]
+pub fn seat_that_forfeits_places_last() {
+    let info = final_state(vec![player(15, 12), player(9, 8), player(12, 3)]);
+    assert_eq!(final_placements(&info, None), vec![0, 2, 1]);
+    assert_eq!(final_placements(&info, Some(0)), vec![2, 1, 0]);
+    assert_eq!(final_placements(&info, Some(1)), vec![0, 2, 1]);
+
+    let info = final_state(vec![player(15, 10), player(15, 10), player(4, 1)]);
+    assert_eq!(final_placements(&info, Some(0)), vec![2, 0, 1]);
+}
+
+#[test]
+pub fn winner_of_an_even_game_gains_what_the_loser_loses() {
+    let changes = rate(&[(1, 1500.0), (2, 1500.0)], &[0, 1]);
+    assert_eq!(changes.len(), 2);
+    assert!((changes[0].rating_after - 1516.0).abs() < 1e-9);
+    assert!((changes[1].rating_after - 1484.0).abs() < 1e-9);
+}
+
+#[test]
+pub fn draw_moves_ratings_towards_each_other() {
+    let changes = rate(&[(1, 1600.0), (2, 1400.0)], &[0, 0]);
+    assert!(changes[0].rating_after < 1600.0);
+    assert!(changes[1].rating_after > 1400.0);
+    let total: f64 = changes
+        .iter()
+        .map(|c| c.rating_after - c.rating_before)
+        .sum();
+    assert!(
+        total.abs() < 1e-9,
+        "rating was created or destroyed: {}",
+        total
+    );
+}
+
+#[test]
+pub fn four_player_game_conserves_rating_and_orders_changes_by_place() {
+    let seats = [(1, 1500.0), (2, 1550.0), (3, 1450.0), (4, 1500.0)];
+    let changes = rate(&seats, &[2, 0, 1, 3]);
+    let gain = |bot_id| {
+        let change = changes.iter().find(|c| c.bot_id == bot_id).unwrap();
+        change.rating_after - change.rating_before
+    };
+    assert!(gain(2) > 0.0 && gain(3) > 0.0);
+    assert!(gain(1) < 0.0 && gain(4) < gain(1));
+    let total: f64 = (1..=4).map(gain).sum();
+    assert!(
+        total.abs() < 1e-9,
+        "rating was created or destroyed: {}",
+        total
+    );
+}
+
+#[test]
+pub fn bot_in_two_seats_does_not_play_itself() {
+    let changes = rate(&[(1, 1500.0), (1, 1500.0), (2, 1500.0)], &[0, 1, 2]);
+    assert_eq!(changes.len(), 2);
+    let bot = changes.iter().find(|c| c.bot_id == 1).unwrap();
+    assert!((bot.rating_after - 1532.0).abs() < 1e-9);
+}
+
+#[tokio::test]
+pub async fn finished_game_is_rated_exactly_once() {
+    let db = crate::database::tests::create_test_db().await;
+    let id = database::generate_new_id(&db).await;
+    let update = GameUpdate {
+        update_num: 40,
+        info: final_state(vec![player(12, 10), player(16, 14)]),
+    };
+    database::tests::simple_save_game_update(&db, update, id).await;
+    let seat = |name: &str| database::SeatRecord {
+        name: name.to_string(),
+        version: None,
+        owner: None,
+    };
+    let players =
+        database::save_game_players(&db, id, None, &[seat("greedy"), seat("planner")]).await;
+    assert!(
+        rate_game(&db, id).await.is_none(),
+        "rated a game in progress"
+    );
+
+    database::save_game_over(&db, id).await;
+    let changes = rate_game(&db, id)
+        .await
+        .expect("finished game was not rated");
+    let planner = changes
+        .iter()
+        .find(|c| c.bot_id == players[1].bot_id)
+        .unwrap();
+    assert!(planner.rating_after > INITIAL_RATING);
+    assert!(rate_game(&db, id).await.is_none(), "rated a game twice");
+
+    let leaderboard = database::load_leaderboard(&db, 10).await;
+    assert_eq!(leaderboard[0].name, "planner");
+    assert_eq!(leaderboard[0].games_played, 1);
+    let history = database::load_rating_history(&db, players[0].bot_id)
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 1);
+}
+
+#[tokio::test]
+pub async fn games_rated_at_once_both_move_a_shared_bot() {
+    let db = crate::database::tests::create_test_db().await;
+    let seat = |name: &str| database::SeatRecord {
+        name: name.to_string(),
+        version: None,
+        owner: None,
+    };
+    let mut games = vec![];
+    for opponent in ["greedy", "thief"] {
+        let id = database::generate_new_id(&db).await;
+        let update = GameUpdate {
+            update_num: 40,
+            info: final_state(vec![player(12, 10), player(16, 14)]),
+        };
+        database::tests::simple_save_game_update(&db, update, id).await;
+        database::save_game_players(&db, id, None, &[seat(opponent), seat("planner")]).await;
+        database::save_game_over(&db, id).await;
+        games.push(id);
+    }
+
+    let (first, second) = tokio::join!(rate_game(&db, games[0]), rate_game(&db, games[1]));
+    assert!(first.is_some() && second.is_some());
+    let leaderboard = database::load_leaderboard(&db, 10).await;
+    let planner = leaderboard.iter().find(|e| e.name == "planner").unwrap();
+    assert_eq!(planner.games_played, 2);
+    let history = database::load_rating_history(&db, planner.bot_id)
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 2);
+    // The second game started from the rating the first one left
+    assert!(
+        history[0].rating_after == history[1].rating_before
+            || history[1].rating_after == history[0].rating_before,
+        "{:?}",
+        history
+    );
+    let last = history
+        .iter()
+        .map(|entry| entry.rating_after)
+        .fold(f64::MIN, f64::max);
+    assert!((planner.rating - last).abs() < 1e-9);
+    assert!(planner.rating > INITIAL_RATING + 16.0);
+}
@@ -0,0 +1,407 @@
+// Turning uploaded bot versions into processes that can play a match.
+//
+// Each stored artifact is prepared once, in a directory of the work dir
+// named by its sha256, and reused by every match after:
+//
+//      <work_dir>/builds/<sha256>/
+//          bot.py      a python entrypoint, run with the configured python
+//          bot         a prebuilt binary, run as is
+//          project/    an unpacked project, built with cargo if it has a
+//                      Cargo.toml, otherwise run from its main.py
+//
+// A directory is only used once it holds a .ready marker, so a build that
+// failed or was interrupted is started over. Projects are built offline,
+// against runner.vendor_dir if it is set, and confined by the build
+// sandbox of the match as runner.uid, which the project is lent to until
+// the build is over. Bots are started with the
+// arguments `stourney run` passes them, and connect back to the match:
+//
+//      <program> [args] --port <port> --client-id <client id>
+//
+// They are started from their directory, confined by the sandbox of the
+// match, and killed along with every process they started once the match
+// is over.
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::Mutex;
+use tracing::{debug, info, Instrument, Span};
+
+use super::sandbox::{self, BotLimits, Cgroup, Sandbox};
+use super::RunningBot;
+use crate::artifacts::{ArtifactKind, ArtifactStore};
+use crate::config::RunnerConfig;
+use crate::database::BotVersion;
+
+/// Marks a prepared directory as complete
+const READY_MARKER: &str = ".ready";
+
+/// A file at the root of a project that tells how to build and run it
+const CARGO_MANIFEST: &str = "Cargo.toml";
+const PYTHON_MAIN: &str = "main.py";
+
+/// How many lines of the output of a failed build are kept in its error
+const BUILD_ERROR_LINES: usize = 20;
+
+/// How long a bot whose connection was lost is given to exit before it is
+/// taken to still be running
+const EXIT_GRACE: Duration = Duration::from_millis(500);
+
+lazy_static! {
+    /// A lock for each artifact, held while it is prepared, so the same
+    /// artifact is never built by two matches at once while different ones
+    /// are built side by side
+    static ref PREPARING: StdMutex<HashMap<String, Arc<Mutex<()>>>> = StdMutex::default();
+}
+
+/// How to start a prepared bot
+#[derive(Debug, Clone, PartialEq)]
+pub struct Launch {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    /// The working directory of the bot
+    pub dir: PathBuf,
+}
+
+/// Environment variables cargo is built with, besides those of the
+/// sandbox. Isolated builds keep the files of cargo in their private /tmp
+const ISOLATED_BUILD_ENV: &[&str] = &["RUSTUP_HOME", "RUSTUP_TOOLCHAIN"];
+const BUILD_ENV: &[&str] = &["HOME", "CARGO_HOME", "RUSTUP_HOME", "RUSTUP_TOOLCHAIN"];
+
+/// Unpacks, and builds in the sandbox if needed, a bot version that was
+/// not prepared before, then returns how to start it
+pub async fn prepare(
+    version: &BotVersion,
+    store: &ArtifactStore,
+    config: &RunnerConfig,
+    sandbox: &Sandbox,
+) -> Result<Launch, String> {
+    let kind = ArtifactKind::parse(&version.kind)?;
+    let builds = config.work_dir.join("builds");
+    // Only the server may look in, sandboxed bots are given their own
+    // directory, see sandbox::Confinement
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&builds)
+        .map_err(|error| format!("cannot create {}: {}", builds.display(), error))?;
+    let dir = builds.join(&version.sha256);
+    if !dir.join(READY_MARKER).exists() {
+        let lock = PREPARING
+            .lock()
+            .unwrap()
+            .entry(version.sha256.clone())
+            .or_default()
+            .clone();
+        let _preparing = lock.lock().await;
+        let prepared = prepare_once(kind, version, &dir, store, config, sandbox).await;
+        let mut preparing = PREPARING.lock().unwrap();
+        // Held by the map and this match only, no other match waits for it
+        if Arc::strong_count(&lock) == 2 {
+            preparing.remove(&version.sha256);
+        }
+        prepared?;
+    }
+    let dir = fs::canonicalize(&dir).map_err(|error| error.to_string())?;
+    launch(kind, &dir, config)
+}
+
+/// Unpacks and builds a bot into the directory, unless a match that held
+/// the lock of its artifact before did so already
+async fn prepare_once(
+    kind: ArtifactKind,
+    version: &BotVersion,
+    dir: &Path,
+    store: &ArtifactStore,
+    config: &RunnerConfig,
+    sandbox: &Sandbox,
+) -> Result<(), String> {
+    if !dir.join(READY_MARKER).exists() {
+        let bytes = store
+            .get(&version.sha256)
+            .map_err(|error| format!("cannot read artifact {}: {}", version.sha256, error))?;
+        let unpacked = dir.to_path_buf();
+        tokio::task::spawn_blocking(move || unpack(kind, &bytes, &unpacked))
+            .await
+            .expect("Failed to unpack bot")
+            .map_err(|error| format!("cannot unpack artifact: {}", error))?;
+        if kind == ArtifactKind::Project {
+            let root = project_root(&dir.join("project"))?;
+            if root.join(CARGO_MANIFEST).exists() {
+                let name = format!("build_{}", &version.sha256[..12]);
+                build(&root, &name, config, sandbox).await?;
+            }
+        }
+        fs::write(dir.join(READY_MARKER), "").map_err(|error| error.to_string())?;
+    }
+    Ok(())
+}
+
+/// Writes an artifact out into an empty directory
+fn unpack(kind: ArtifactKind, bytes: &[u8], dir: &Path) -> io::Result<()> {
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    fs::create_dir_all(dir)?;
+    match kind {
+        ArtifactKind::Python => fs::write(dir.join("bot.py"), bytes),
+        ArtifactKind::Binary => {
+            use std::os::unix::fs::PermissionsExt;
+            let path = dir.join("bot");
+            fs::write(&path, bytes)?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+        }
+        ArtifactKind::Project => {
+            let archive = flate2::read::GzDecoder::new(bytes);
+            tar::Archive::new(archive).unpack(dir.join("project"))
+        }
+    }
+}
+
+/// The directory of an unpacked project that holds its Cargo.toml or
+/// main.py, either the top of the archive or the single directory in it
+pub fn project_root(dir: &Path) -> Result<PathBuf, String> {
+    let has_marker =
+        |dir: &Path| dir.join(CARGO_MANIFEST).is_file() || dir.join(PYTHON_MAIN).is_file();
+    if has_marker(dir) {
+        return Ok(dir.to_path_buf());
+    }
+    let entries: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|error| error.to_string())?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()
+        .map_err(|error| error.to_string())?;
+    match &entries[..] {
+        [only] if only.is_dir() && has_marker(only) => Ok(only.clone()),
+        _ => Err(format!(
+            "project has no {} or {} at its root",
+            CARGO_MANIFEST, PYTHON_MAIN
+        )),
+    }
+}
+
+/// Builds a rust project in release mode, into its own target directory,
+/// confined by the build sandbox in a cgroup of the name
+async fn build(
+    root: &Path,
+    name: &str,
+    config: &RunnerConfig,
+    sandbox: &Sandbox,
+) -> Result<(), String> {
+    info!("[+] Building {}", root.display());
+    let mut command = Command::new(&config.cargo);
+    command.args(["build", "--release", "--quiet", "--offline"]);
+    if let Some(vendor) = &config.vendor_dir {
+        let vendor = fs::canonicalize(vendor)
+            .map_err(|error| format!("cannot find {}: {}", vendor.display(), error))?;
+        let directory = toml::Value::String(vendor.display().to_string());
+        command
+            .args([
+                "--config",
+                "source.crates-io.replace-with=\"vendored\"",
+                "--config",
+            ])
+            .arg(format!("source.vendored.directory={}", directory));
+    }
+    command
+        .current_dir(root)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let cgroup = sandbox
+        .confine(&mut command, name)
+        .map_err(|error| format!("cannot confine the build: {}", error))?;
+    let kept = match sandbox.isolated() {
+        true => ISOLATED_BUILD_ENV,
+        false => BUILD_ENV,
+    };
+    for &variable in kept {
+        if let Some(value) = std::env::var_os(variable) {
+            command.env(variable, value);
+        }
+    }
+    command.env("CARGO_TARGET_DIR", "target");
+
+    sandbox
+        .lend(root)
+        .map_err(|error| format!("cannot lend the project to the build: {}", error))?;
+    let built = run_build(command, config).await;
+    // Whatever the build left running goes before the project is taken back
+    drop(cgroup);
+    sandbox
+        .take_back(root)
+        .map_err(|error| format!("cannot take the project back from the build: {}", error))?;
+    built
+}
+
+/// Runs cargo, for at most build_time_secs
+async fn run_build(mut command: Command, config: &RunnerConfig) -> Result<(), String> {
+    let child = command
+        .spawn()
+        .map_err(|error| format!("cannot start {}: {}", config.cargo, error))?;
+    let limit = Duration::from_secs(config.build_time_secs);
+    let output = tokio::time::timeout(limit, child.wait_with_output())
+        .await
+        .map_err(|_| format!("build took longer than {} seconds", limit.as_secs()))?
+        .map_err(|error| format!("build failed: {}", error))?;
+    if !output.status.success() {
+        let errors = String::from_utf8_lossy(&output.stderr);
+        let lines: Vec<&str> = errors.lines().collect();
+        let tail = &lines[lines.len().saturating_sub(BUILD_ERROR_LINES)..];
+        return Err(format!("build failed:\n{}", tail.join("\n")));
+    }
+    Ok(())
+}
+
+/// The name of the binary cargo builds for a project, its first [[bin]]
+/// or otherwise the package
+pub fn binary_name(manifest: &str) -> Result<String, String> {
+    let manifest: toml::Value =
+        toml::from_str(manifest).map_err(|error| format!("invalid Cargo.toml: {}", error))?;
+    let first_bin = manifest
+        .get("bin")
+        .and_then(|bins| bins.as_array())
+        .and_then(|bins| bins.first())
+        .and_then(|bin| bin.get("name"));
+    let package = manifest
+        .get("package")
+        .and_then(|package| package.get("name"));
+    first_bin
+        .or(package)
+        .and_then(|name| name.as_str())
+        .map(str::to_string)
+        .ok_or_else(|| "Cargo.toml names no package or binary".to_string())
+}
+
+/// How to start a bot prepared in the directory
+fn launch(kind: ArtifactKind, dir: &Path, config: &RunnerConfig) -> Result<Launch, String> {
+    let python = |dir: &Path, entrypoint: &str| Launch {
+        program: PathBuf::from(&config.python),
+        args: vec![entrypoint.to_string()],
+        dir: dir.to_path_buf(),
+    };
+    match kind {
+        ArtifactKind::Python => Ok(python(dir, "bot.py")),
+        ArtifactKind::Binary => Ok(Launch {
+            program: dir.join("bot"),
+            args: vec![],
+            dir: dir.to_path_buf(),
+        }),
+        ArtifactKind::Project => {
+            let root = project_root(&dir.join("project"))?;
+            match fs::read_to_string(root.join(CARGO_MANIFEST)) {
+                Ok(manifest) => Ok(Launch {
+                    program: root
+                        .join("target")
+                        .join("release")
+                        .join(binary_name(&manifest)?),
+                    args: vec![],
+                    dir: root,
+                }),
+                Err(_) => Ok(python(&root, PYTHON_MAIN)),
+            }
+        }
+    }
+}
+
+/// A bot playing a match
+pub struct BotProcess {
+    child: Child,
+    /// The process group the bot and everything it starts are in
+    group: Option<u32>,
+    cgroup: Option<Cgroup>,
+    limits: BotLimits,
+}
+
+impl RunningBot for BotProcess {
+    async fn violation(&mut self) -> Option<String> {
+        let status = tokio::time::timeout(EXIT_GRACE, self.child.wait())
+            .await
+            .ok()?
+            .ok()?;
+        let usage = self.cgroup.as_ref().map(Cgroup::usage).unwrap_or_default();
+        sandbox::violation(status, &usage, &self.limits)
+    }
+}
+
+impl Drop for BotProcess {
+    /// A confined bot is killed along with its cgroup. Otherwise its group
+    /// is only killed while the bot is not reaped, since its id may belong
+    /// to another process once it is
+    fn drop(&mut self) {
+        if self.cgroup.is_some() || self.child.id().is_none() {
+            return;
+        }
+        if let Some(group) = self.group {
+            unsafe {
+                libc::killpg(group as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Starts a prepared bot in a seat of a match listening on the port,
+/// what it prints is logged in the current span
+pub fn spawn(
+    launch: &Launch,
+    seat: usize,
+    port: u16,
+    client_id: u64,
+    sandbox: &Sandbox,
+) -> io::Result<BotProcess> {
+    // Paths outside the directory may not be there once the bot is
+    // confined, since it is already in it by then
+    let program = match launch.program.strip_prefix(&launch.dir) {
+        Ok(inside) => Path::new(".").join(inside),
+        Err(_) => launch.program.clone(),
+    };
+    let mut command = Command::new(program);
+    command
+        .args(&launch.args)
+        .arg("--port")
+        .arg(port.to_string())
+        .arg("--client-id")
+        .arg(client_id.to_string())
+        .current_dir(&launch.dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let cgroup = sandbox.confine(&mut command, &format!("seat{}", seat))?;
+    let mut child = command.spawn()?;
+    if let Some(stdout) = child.stdout.take() {
+        forward(stdout, seat);
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward(stderr, seat);
+    }
+    Ok(BotProcess {
+        group: child.id(),
+        child,
+        cgroup,
+        limits: sandbox.limits(),
+    })
+}
+
+/// Logs what a bot prints, line by line
+fn forward(output: impl AsyncRead + Unpin + Send + 'static, seat: usize) {
+    tokio::spawn(
+        async move {
+            let mut lines = BufReader::new(output).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                debug!(seat, "{}", line);
+            }
+        }
+        .instrument(Span::current()),
+    );
+}
@@ -0,0 +1,607 @@
+// Matches played by the server between uploaded bot versions.
+//
+// An admin submits a match between two to four bot versions, which waits
+// for one of the max_matches slots and is then played out:
+//
+//      POST /api/admin/matches -> submit() -> prepare the bots -> play()
+//
+// Each match serves the protocol of splendor_arena on a port of its own,
+// and starts its bots as local processes the way `stourney run` does.
+// The runner drives the Arena itself instead of launching it, so that:
+//
+//   - every request for an action is bounded by move_time_ms, and a bot
+//     that is too slow, disconnects, or answers with something that is
+//     not a legal action has the first legal action played for it
+//   - a bot that breaks a limit of its sandbox, or spends more than
+//     wall_time_secs on its turns, forfeits: the game ends there, and the
+//     bot places last whatever its points
+//   - the states of the game go through the queue as the GameUpdates an
+//     arena would upload, so hosted matches are saved, rated and watched
+//     like any other game
+//
+// The status of recent matches is kept in memory, and is lost when the
+// server restarts, but the games they played are not.
+
+pub mod bots;
+pub mod sandbox;
+#[cfg(test)]
+pub mod tests;
+
+use futures::{SinkExt, StreamExt};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use splendor_arena::models::GameUpdate;
+use splendor_arena::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, timeout_at, Instant};
+use tracing::{debug, info, warn, Instrument};
+use uuid::Uuid;
+use warp::ws::{Message, WebSocket};
+use warp::Filter;
+
+use crate::artifacts::ArtifactStore;
+use crate::config;
+use crate::database::BotVersion;
+use crate::logging;
+use crate::queue::{self, AsyncQueue};
+use crate::ratings;
+use crate::shutdown;
+
+/// The most actions a match may take before it is given up on
+pub const MAX_ACTIONS: usize = 5000;
+
+/// How many finished matches are remembered
+const MAX_TRACKED: usize = 256;
+
+/// The bot versions playing a match, in seat order
+#[derive(Debug, Clone)]
+pub struct MatchRequest {
+    pub seats: Vec<BotVersion>,
+    /// The account the game is attributed to
+    pub owner_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchResult {
+    pub id: Uuid,
+    pub slug: String,
+    /// The place each seat finished in, 0 for the winner
+    pub places: Vec<usize>,
+    pub points: Vec<u8>,
+    /// How many actions were played for each seat because of a fault
+    pub faults: Vec<usize>,
+    /// The seat that broke a limit and forfeited, and the limit it broke
+    pub forfeit: Option<(usize, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MatchState {
+    Waiting,
+    Starting,
+    Playing { id: Uuid, slug: String },
+    Finished(MatchResult),
+    Failed(MatchFailure),
+}
+
+/// Why a match could not be played
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchFailure {
+    pub reason: String,
+    /// The seat whose bot could not be prepared or did not connect, when
+    /// the match failed because of a single bot
+    pub seat: Option<usize>,
+}
+
+impl From<String> for MatchFailure {
+    fn from(reason: String) -> Self {
+        MatchFailure { reason, seat: None }
+    }
+}
+
+/// How long bots are given to connect and play
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub move_time: Duration,
+    pub connect_time: Duration,
+    /// The time a bot may spend on its turns over the match
+    pub wall_time: Duration,
+    pub max_actions: usize,
+}
+
+impl Limits {
+    pub fn configured() -> Self {
+        let runner = &config::get().runner;
+        Limits {
+            move_time: Duration::from_millis(runner.move_time_ms),
+            connect_time: Duration::from_secs(runner.connect_time_secs),
+            wall_time: Duration::from_secs(runner.wall_time_secs),
+            max_actions: MAX_ACTIONS,
+        }
+    }
+}
+
+/// A bot started to play a seat of a match, which is stopped when dropped
+pub trait RunningBot {
+    /// The limit the bot broke, asked once its connection is lost, None
+    /// if it is still running or stopped for another reason
+    fn violation(&mut self) -> impl Future<Output = Option<String>> + Send;
+}
+
+/// Why a bot did not play an action itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    TimedOut,
+    Disconnected,
+    InvalidMessage,
+    IllegalAction,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fault = match self {
+            Fault::TimedOut => "timed out",
+            Fault::Disconnected => "disconnected",
+            Fault::InvalidMessage => "sent an invalid message",
+            Fault::IllegalAction => "played an illegal action",
+        };
+        f.write_str(fault)
+    }
+}
+
+lazy_static! {
+    static ref MATCHES: Mutex<BTreeMap<u64, MatchState>> = Mutex::new(BTreeMap::new());
+    static ref SLOTS: Semaphore = Semaphore::new(config::get().runner.max_matches);
+}
+
+static NEXT_MATCH: AtomicU64 = AtomicU64::new(1);
+
+/// Records the state of a match, forgetting the oldest finished matches
+/// past MAX_TRACKED
+fn track(number: u64, state: MatchState) {
+    let mut matches = MATCHES.lock().unwrap();
+    matches.insert(number, state);
+    while matches.len() > MAX_TRACKED {
+        let finished = matches.iter().find_map(|(&number, state)| {
+            matches!(state, MatchState::Finished(_) | MatchState::Failed(_)).then_some(number)
+        });
+        match finished {
+            Some(number) => matches.remove(&number),
+            None => break,
+        };
+    }
+}
+
+/// The state of a submitted match, None if it is not known
+pub fn status(number: u64) -> Option<MatchState> {
+    MATCHES.lock().unwrap().get(&number).cloned()
+}
+
+/// Queues a match to be played once a slot is free, and returns its number
+pub fn submit(request: MatchRequest) -> u64 {
+    let (number, played) = schedule(request);
+    tokio::spawn(played);
+    number
+}
+
+/// Numbers a match and returns it with the future that plays it once a
+/// slot is free, resolving to its final state, for callers that wait on
+/// the match themselves
+pub fn schedule(request: MatchRequest) -> (u64, impl Future<Output = MatchState> + Send) {
+    let number = NEXT_MATCH.fetch_add(1, Ordering::Relaxed);
+    track(number, MatchState::Waiting);
+    let played = async move {
+        let _slot = SLOTS.acquire().await.expect("Match slots are never closed");
+        track(number, MatchState::Starting);
+        let state = match run_match(number, request).await {
+            Ok(result) => {
+                info!("[+] Match finished with places {:?}", result.places);
+                MatchState::Finished(result)
+            }
+            Err(failure) => {
+                warn!("[-] Match failed: {}", failure.reason);
+                MatchState::Failed(failure)
+            }
+        };
+        track(number, state.clone());
+        state
+    }
+    .instrument(logging::match_span(number));
+    (number, played)
+}
+
+/// Prepares the bots of a match and plays it, unless the server starts
+/// shutting down first
+async fn run_match(number: u64, request: MatchRequest) -> Result<MatchResult, MatchFailure> {
+    let config = config::get();
+    let store = ArtifactStore::new(&config.artifact_dir);
+    let hidden = sandbox::server_paths(config);
+    let builds = sandbox::Sandbox::for_build(number, &config.runner, &hidden)?;
+    let mut launches = vec![];
+    for (seat, version) in request.seats.iter().enumerate() {
+        let launch = bots::prepare(version, &store, &config.runner, &builds)
+            .await
+            .map_err(|reason| MatchFailure {
+                reason: format!(
+                    "cannot prepare {} version {}: {}",
+                    version.name, version.version, reason
+                ),
+                seat: Some(seat),
+            })?;
+        launches.push(launch);
+    }
+    let sender = queue::running().ok_or_else(|| "the queue is not running".to_string())?;
+    let (sandbox, listener) = sandbox::Sandbox::new(number, &config.runner, &hidden)?;
+
+    let start = |port: u16, clients: &[u64]| {
+        launches
+            .iter()
+            .zip(clients)
+            .enumerate()
+            .map(|(seat, (launch, &client))| {
+                bots::spawn(launch, seat, port, client, &sandbox)
+                    .map_err(|error| format!("cannot start the bot in seat {}: {}", seat, error))
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let limits = Limits::configured();
+    tokio::select! {
+        result = play(number, &request, limits, sender, listener, start) => result,
+        _ = shutdown::triggered() => Err("the server is shutting down".to_string().into()),
+    }
+}
+
+/// A websocket a bot opened to the match
+enum Connection {
+    Game(u64, WebSocket),
+    Log(u64, WebSocket),
+}
+
+/// When the bot being asked for an action has to answer by
+pub type Deadline = Arc<Mutex<Option<Instant>>>;
+
+#[derive(Serialize)]
+struct TimeRemaining {
+    time_remaining: Duration,
+}
+
+/// Stops serving a match once it ends, however it ends
+struct Serving(JoinHandle<()>);
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Serves the protocol of splendor_arena on the listener of the match,
+/// passing the sockets bots open on to the match
+fn serve(
+    listener: TcpListener,
+    deadline: Deadline,
+) -> Result<(u16, UnboundedReceiver<Connection>, Serving), String> {
+    let (connections, connected) = mpsc::unbounded_channel();
+    let with_connections = warp::any().map(move || connections.clone());
+
+    let game = warp::path!("game" / u64 / u64)
+        .and(warp::ws())
+        .and(with_connections.clone())
+        .map(
+            |_game, client, ws: warp::ws::Ws, connections: UnboundedSender<Connection>| {
+                ws.on_upgrade(move |socket| async move {
+                    let _ = connections.send(Connection::Game(client, socket));
+                })
+            },
+        );
+    let log = warp::path!("log" / u64)
+        .and(warp::ws())
+        .and(with_connections)
+        .map(
+            |client, ws: warp::ws::Ws, connections: UnboundedSender<Connection>| {
+                ws.on_upgrade(move |socket| async move {
+                    let _ = connections.send(Connection::Log(client, socket));
+                })
+            },
+        );
+    let time = warp::path!("time").map(move || {
+        let until = *deadline.lock().unwrap();
+        let time_remaining = until.map_or(Duration::ZERO, |until| {
+            until.saturating_duration_since(Instant::now())
+        });
+        warp::reply::json(&TimeRemaining { time_remaining })
+    });
+
+    let listening = listener
+        .set_nonblocking(true)
+        .and_then(|()| tokio::net::TcpListener::from_std(listener))
+        .and_then(|listener| Ok((listener.local_addr()?.port(), listener)));
+    let (port, listener) =
+        listening.map_err(|error| format!("cannot listen for bots: {}", error))?;
+    let incoming = futures::stream::unfold(listener, |listener| async move {
+        let accepted = listener.accept().await.map(|(stream, _)| stream);
+        Some((accepted, listener))
+    });
+    let server = warp::serve(game.or(log).or(time)).serve_incoming(incoming);
+    let serving = Serving(tokio::spawn(server.in_current_span()));
+    Ok((port, connected, serving))
+}
+
+/// Takes a socket a bot opened, game sockets are kept by seat and what
+/// bots send to their log socket is logged
+fn accept(connection: Connection, clients: &[u64], sockets: &mut [Option<WebSocket>]) {
+    let (client, socket, is_game) = match connection {
+        Connection::Game(client, socket) => (client, socket, true),
+        Connection::Log(client, socket) => (client, socket, false),
+    };
+    let Some(seat) = clients.iter().position(|&id| id == client) else {
+        warn!("[-] Refused a connection from unknown client {}", client);
+        return;
+    };
+    if !is_game {
+        tokio::spawn(forward_logs(seat, socket).in_current_span());
+    } else if sockets[seat].is_none() {
+        sockets[seat] = Some(socket);
+    } else {
+        warn!(seat, "[-] Refused a second game connection");
+    }
+}
+
+/// Logs what a bot sends to its log socket
+async fn forward_logs(seat: usize, mut socket: WebSocket) {
+    while let Some(Ok(message)) = socket.next().await {
+        if let Ok(text) = message.to_str() {
+            match serde_json::from_str(text) {
+                Ok(ClientMessage::Log(line)) => debug!(seat, "{}", line),
+                _ => debug!(seat, "{}", text),
+            }
+        }
+    }
+}
+
+/// The state of the game as uploaded by an arena. This is built from
+/// the board and players, since the Arena can only describe itself to
+/// clients while there are legal actions left
+pub fn game_update(arena: &Arena) -> GameUpdate {
+    GameUpdate {
+        info: SmallClientInfo {
+            board: arena.board(),
+            players: arena.players().iter().map(|p| p.to_public()).collect(),
+            current_player_num: arena.current_player_num().unwrap_or(0),
+        },
+        update_num: arena.num_moves() + 1,
+    }
+}
+
+/// Queues a state of the game, waiting for room if the queue is full
+pub async fn push(id: Uuid, update: GameUpdate, sender: &mut AsyncQueue) {
+    let updates = vec![update];
+    while let Err(full) = queue::push_game_updates(id, &updates, sender).await {
+        tokio::time::sleep(full.retry_after).await;
+    }
+}
+
+/// Sends the state of the game to every connected bot, returns the seats
+/// that could not be sent it in time
+pub async fn broadcast(
+    sockets: &mut [Option<WebSocket>],
+    arena: &Arena,
+    limit: Duration,
+) -> Vec<usize> {
+    let message = ServerMessage::Broadcast(BroadcastInfo::from(arena.client_info()));
+    let message = Message::text(serde_json::to_string(&message).expect("Failed to serialize"));
+    let mut dropped = vec![];
+    for (seat, socket) in sockets.iter_mut().enumerate() {
+        let Some(open) = socket else { continue };
+        if !matches!(timeout(limit, open.send(message.clone())).await, Ok(Ok(()))) {
+            *socket = None;
+            dropped.push(seat);
+        }
+    }
+    dropped
+}
+
+/// Asks the bot of the current player for an action, which has to be one
+/// of the legal actions and arrive before the deadline. `stale` counts the
+/// requests of the seat that timed out and were not answered yet, their
+/// answers are skipped when they come
+pub async fn request_action(
+    socket: &mut WebSocket,
+    stale: &mut usize,
+    arena: &Arena,
+    legal: &[Action],
+    limit: Duration,
+    deadline: &Deadline,
+) -> Result<Action, Fault> {
+    let request = ServerMessage::PlayerActionRequest(arena.client_info());
+    let request = Message::text(serde_json::to_string(&request).expect("Failed to serialize"));
+    let until = Instant::now() + limit;
+    *deadline.lock().unwrap() = Some(until);
+    match timeout_at(until, socket.send(request)).await {
+        Err(_) => return Err(Fault::TimedOut),
+        Ok(Err(_)) => return Err(Fault::Disconnected),
+        Ok(Ok(())) => {}
+    }
+    loop {
+        let message = match timeout_at(until, socket.next()).await {
+            Err(_) => {
+                *stale += 1;
+                return Err(Fault::TimedOut);
+            }
+            Ok(Some(Ok(message))) => message,
+            Ok(_) => return Err(Fault::Disconnected),
+        };
+        if message.is_close() {
+            return Err(Fault::Disconnected);
+        }
+        if message.is_ping() || message.is_pong() {
+            continue;
+        }
+        let answer = message
+            .to_str()
+            .ok()
+            .and_then(|text| serde_json::from_str(text).ok());
+        match answer {
+            Some(ClientMessage::Log(line)) => debug!("{}", line),
+            // Answers a request that already timed out
+            _ if *stale > 0 => *stale -= 1,
+            Some(ClientMessage::Action(action)) if legal.contains(&action) => return Ok(action),
+            Some(ClientMessage::Action(_)) => return Err(Fault::IllegalAction),
+            None => return Err(Fault::InvalidMessage),
+        }
+    }
+}
+
+/// Plays a match on the listener, once `start` has started a bot for
+/// each of the client ids it is given, in seat order. The bots are kept
+/// until the match ends, and are stopped when they are dropped
+pub async fn play<B: RunningBot>(
+    number: u64,
+    request: &MatchRequest,
+    limits: Limits,
+    mut sender: AsyncQueue,
+    listener: TcpListener,
+    start: impl FnOnce(u16, &[u64]) -> Result<Vec<B>, String>,
+) -> Result<MatchResult, MatchFailure> {
+    let players = request.seats.len();
+    if !(2..=4).contains(&players) {
+        return Err(format!("a match needs 2 to 4 bots, got {}", players).into());
+    }
+    let deadline = Deadline::default();
+    let (port, mut connected, _serving) = serve(listener, deadline.clone())?;
+    let mut arena = ArenaBuilder::new().num_players(players).port(port).build();
+    let clients: Vec<u64> = arena.allowed_clients().iter().map(|id| id.0).collect();
+    let mut bots = start(port, &clients)?;
+
+    let mut sockets: Vec<Option<WebSocket>> = (0..players).map(|_| None).collect();
+    let connect_by = Instant::now() + limits.connect_time;
+    while sockets.iter().any(Option::is_none) {
+        match timeout_at(connect_by, connected.recv()).await {
+            Ok(Some(connection)) => accept(connection, &clients, &mut sockets),
+            _ => {
+                let missing: Vec<usize> = (0..players).filter(|&s| sockets[s].is_none()).collect();
+                return Err(MatchFailure {
+                    reason: format!(
+                        "seats {:?} did not connect within {} seconds",
+                        missing,
+                        limits.connect_time.as_secs()
+                    ),
+                    seat: match missing[..] {
+                        [seat] => Some(seat),
+                        _ => None,
+                    },
+                });
+            }
+        }
+    }
+
+    let closed = || MatchFailure::from("the queue is closed".to_string());
+    let id = queue::create_id(&sender).await.ok_or_else(closed)?;
+    if let Some(owner_id) = request.owner_id {
+        queue::set_game_owner(id, owner_id, &sender).await;
+    }
+    let slug = queue::get_slug(id, &sender).await.ok_or_else(closed)?;
+    queue::set_game_bots(id, request.seats.clone(), &sender).await;
+    track(
+        number,
+        MatchState::Playing {
+            id,
+            slug: slug.clone(),
+        },
+    );
+    info!("[+] Playing {} bots", players);
+
+    arena.start_game();
+    let mut faults = vec![0; players];
+    let mut spent = vec![Duration::ZERO; players];
+    let mut stale = vec![0; players];
+    let mut forfeit = None;
+    let mut actions = 0;
+    while !arena.is_game_over() {
+        if actions >= limits.max_actions {
+            queue::set_game_over(id, &sender).await;
+            return Err(format!("the game did not end after {} actions", actions).into());
+        }
+        // Bots open their log socket once they are playing
+        while let Ok(connection) = connected.try_recv() {
+            accept(connection, &clients, &mut sockets);
+        }
+        push(id, game_update(&arena), &mut sender).await;
+        let mut lost = broadcast(&mut sockets, &arena, limits.move_time).await;
+        for &seat in &lost {
+            warn!(seat, "[-] Bot disconnected, playing the rest of its turns");
+            faults[seat] += 1;
+        }
+
+        let seat = arena.current_player_num().expect("The game is started");
+        let legal = arena.get_legal_actions().expect("The game is not over");
+        let asked = Instant::now();
+        let played = match &mut sockets[seat] {
+            Some(socket) => {
+                let stale = &mut stale[seat];
+                request_action(socket, stale, &arena, &legal, limits.move_time, &deadline).await
+            }
+            // Already counted when it disconnected
+            None => Ok(legal[0].clone()),
+        };
+        spent[seat] += asked.elapsed();
+        let action = played.unwrap_or_else(|fault| {
+            warn!(seat, "[-] Bot {}, playing the first legal action", fault);
+            faults[seat] += 1;
+            if fault == Fault::Disconnected {
+                sockets[seat] = None;
+                lost.push(seat);
+            }
+            legal[0].clone()
+        });
+
+        // A bot that broke a limit of its sandbox is gone by now
+        for dropped in lost {
+            let Some(bot) = bots.get_mut(dropped) else {
+                continue;
+            };
+            if let Some(reason) = bot.violation().await {
+                forfeit = Some((dropped, reason));
+                break;
+            }
+        }
+        if forfeit.is_none() && spent[seat] > limits.wall_time {
+            let reason = format!(
+                "spent more than {} seconds on its turns",
+                limits.wall_time.as_secs()
+            );
+            forfeit = Some((seat, reason));
+        }
+        if let Some((seat, reason)) = &forfeit {
+            warn!(seat, "[-] Bot {}, forfeiting the match", reason);
+            break;
+        }
+        arena.play_action(action);
+        actions += 1;
+    }
+
+    let update = game_update(&arena);
+    let seat = forfeit.as_ref().map(|(seat, _)| *seat);
+    let places = ratings::final_placements(&update.info, seat);
+    let points = update.info.players.iter().map(|p| p.points).collect();
+    push(id, update, &mut sender).await;
+    if let Some((seat, reason)) = &forfeit {
+        queue::set_forfeit(id, *seat, reason.clone(), &sender).await;
+    }
+    queue::set_game_over(id, &sender).await;
+    Ok(MatchResult {
+        id,
+        slug,
+        places,
+        points,
+        faults,
+        forfeit,
+    })
+}
@@ -0,0 +1,763 @@
+// Confining the processes of hosted bots.
+//
+// With runner.sandbox on, the bots of a match are started:
+//
+//   - in a network namespace of the match where only the loopback
+//     interface is up, so they can reach the match and nothing else
+//   - in mount, ipc and uts namespaces of their own, with an empty tmpfs
+//     mounted over /tmp and their directory mounted at /tmp/bot, where
+//     they are started
+//   - with the files of the server hidden, see server_paths: directories
+//     behind an empty read-only tmpfs and files behind /dev/null
+//   - as runner.uid and runner.gid, without supplementary groups or any
+//     way to gain privileges
+//   - under a seccomp filter that kills them on system calls no bot needs,
+//     see seccomp_filter
+//
+// Every bot is started with a clean environment and limited by rlimits on
+// its cpu time, file sizes and open files. When runner.cgroup_dir is a
+// cgroup v2 with the memory and pids controllers, each bot is also put in
+// a cgroup of its own that caps its memory and processes, otherwise its
+// address space is capped by an rlimit instead.
+//
+// Rust projects are built the same way, in a sandbox of their own with the
+// limits of a build, see Sandbox::for_build.
+//
+// A bot stopped by one of its limits forfeits the match, see violation.
+
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, TcpListener};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::warn;
+
+use crate::config::{Config, RunnerConfig};
+
+/// The PATH of sandboxed bots, the server's may not be readable by them
+const SANDBOX_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// Mount options of the private /tmp of a sandboxed bot
+const TMP_OPTIONS: &CStr = c"size=64m,mode=1777";
+
+/// Where the directory of a sandboxed bot is mounted, it is started there
+const BOT_DIR: &CStr = c"/tmp/bot";
+
+/// The largest file a bot may write
+const MAX_FILE_BYTES: u64 = 64 << 20;
+const MAX_OPEN_FILES: u64 = 256;
+
+/// How many times, 20ms apart, removing the cgroup of a bot is tried
+/// while its processes are still exiting
+const CGROUP_REMOVE_ATTEMPTS: usize = 50;
+
+/// Interface flag requests, which libc only has for some targets
+const SIOCGIFFLAGS: libc::c_ulong = 0x8913;
+const SIOCSIFFLAGS: libc::c_ulong = 0x8914;
+
+/// The architecture a seccomp filter expects system calls from
+#[cfg(target_arch = "x86_64")]
+pub const AUDIT_ARCH: u32 = 0xC000_003E;
+#[cfg(target_arch = "aarch64")]
+pub const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+/// Where the fields of a system call are in the seccomp_data a filter
+/// is run on, arguments are read by their low 32 bits
+const SECCOMP_NR: u32 = 0;
+const SECCOMP_ARCH: u32 = 4;
+const SECCOMP_ARG0: u32 = 16;
+
+/// System calls that kill the bot making them
+const FORBIDDEN: &[libc::c_long] = &[
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_move_mount,
+    libc::SYS_open_tree,
+    libc::SYS_fsopen,
+    libc::SYS_fsconfig,
+    libc::SYS_fsmount,
+    libc::SYS_fspick,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_reboot,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_userfaultfd,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_sethostname,
+    libc::SYS_setdomainname,
+    libc::SYS_fanotify_init,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_iopl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ioperm,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_modify_ldt,
+];
+
+/// The only address families bots may open sockets in
+const ALLOWED_FAMILIES: [i32; 3] = [libc::AF_UNIX, libc::AF_INET, libc::AF_INET6];
+
+/// Flags of clone that would create a namespace
+const CLONE_NAMESPACES: i32 = libc::CLONE_NEWNS
+    | libc::CLONE_NEWCGROUP
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET;
+
+/// What each bot of a match may use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BotLimits {
+    pub memory_bytes: u64,
+    /// Cpu time over the whole match
+    pub cpu_time: Duration,
+    /// Processes and threads at once
+    pub max_processes: u64,
+    /// Processes and threads at once of every sandboxed bot together,
+    /// since they all run as the same user
+    pub user_processes: u64,
+}
+
+impl BotLimits {
+    pub fn new(config: &RunnerConfig) -> Self {
+        BotLimits {
+            memory_bytes: config.memory_mb << 20,
+            cpu_time: Duration::from_secs(config.cpu_time_secs),
+            max_processes: config.max_processes,
+            user_processes: config.max_processes * 4 * config.max_matches as u64,
+        }
+    }
+
+    /// What the build of a rust project may use
+    pub fn build(config: &RunnerConfig) -> Self {
+        BotLimits {
+            memory_bytes: config.build_memory_mb << 20,
+            cpu_time: Duration::from_secs(config.build_time_secs),
+            ..BotLimits::new(config)
+        }
+    }
+}
+
+/// What the server keeps on disk, which sandboxed bots may not see
+pub fn server_paths(config: &Config) -> Vec<PathBuf> {
+    let mut paths = vec![
+        config.artifact_dir.clone(),
+        config.spool_dir.clone(),
+        config.runner.work_dir.clone(),
+    ];
+    paths.extend(config.log_dir.clone());
+    if let Some(database) = config.database_file() {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = database.clone().into_os_string();
+            path.push(suffix);
+            paths.push(path.into());
+        }
+    }
+    paths
+}
+
+/// The paths hidden from sandboxed bots, the ones inside others first so
+/// they are hidden before what they are in
+#[derive(Debug, Default)]
+struct Hidden {
+    files: Vec<CString>,
+    dirs: Vec<CString>,
+}
+
+impl Hidden {
+    /// The paths that exist, resolved while the server is still in its
+    /// own working directory
+    fn new(paths: &[PathBuf]) -> io::Result<Hidden> {
+        let mut hidden = Hidden::default();
+        for path in paths {
+            let Ok(path) = fs::canonicalize(path) else {
+                continue;
+            };
+            let name = CString::new(path.as_os_str().as_bytes())?;
+            match path.is_dir() {
+                true => hidden.dirs.push(name),
+                false => hidden.files.push(name),
+            }
+        }
+        hidden
+            .dirs
+            .sort_by_key(|dir| std::cmp::Reverse(dir.as_bytes().len()));
+        Ok(hidden)
+    }
+}
+
+/// How the bots of one match are confined
+pub struct Sandbox {
+    /// The network namespace of the match, None when the sandbox is off
+    network: Option<OwnedFd>,
+    /// The cgroup the cgroups of bots are made in, when there is one
+    cgroups: Option<PathBuf>,
+    number: u64,
+    limits: BotLimits,
+    uid: u32,
+    gid: u32,
+    filter: Arc<Vec<libc::sock_filter>>,
+    hidden: Arc<Hidden>,
+}
+
+impl Sandbox {
+    /// Sets up the sandbox of a match, and the listener its bots connect
+    /// to, inside the network of the match when the sandbox is on. The
+    /// paths are hidden from the bots
+    pub fn new(
+        number: u64,
+        config: &RunnerConfig,
+        hidden: &[PathBuf],
+    ) -> Result<(Sandbox, TcpListener), String> {
+        Sandbox::with_limits(number, config, hidden, BotLimits::new(config))
+    }
+
+    /// Sets up the sandbox the rust projects of a match are built in,
+    /// which has no network at all when the sandbox is on
+    pub fn for_build(
+        number: u64,
+        config: &RunnerConfig,
+        hidden: &[PathBuf],
+    ) -> Result<Sandbox, String> {
+        let limits = BotLimits::build(config);
+        let (sandbox, _) = Sandbox::with_limits(number, config, hidden, limits)?;
+        Ok(sandbox)
+    }
+
+    fn with_limits(
+        number: u64,
+        config: &RunnerConfig,
+        hidden: &[PathBuf],
+        limits: BotLimits,
+    ) -> Result<(Sandbox, TcpListener), String> {
+        let (listener, network) = match config.sandbox {
+            true => {
+                let (listener, network) = isolated_listener().map_err(|error| {
+                    format!(
+                        "cannot isolate the network of the match, \
+                         the sandbox needs CAP_SYS_ADMIN: {}",
+                        error
+                    )
+                })?;
+                (listener, Some(network))
+            }
+            false => {
+                let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+                    .map_err(|error| format!("cannot listen for bots: {}", error))?;
+                (listener, None)
+            }
+        };
+        let sandbox = Sandbox {
+            network,
+            cgroups: delegated_cgroup(&config.cgroup_dir),
+            number,
+            limits,
+            uid: config.uid,
+            gid: config.gid,
+            filter: Arc::new(seccomp_filter()),
+            hidden: Arc::new(
+                Hidden::new(hidden)
+                    .map_err(|error| format!("cannot hide {:?}: {}", hidden, error))?,
+            ),
+        };
+        Ok((sandbox, listener))
+    }
+
+    pub fn limits(&self) -> BotLimits {
+        self.limits
+    }
+
+    /// Whether processes are confined to namespaces and run as runner.uid
+    pub fn isolated(&self) -> bool {
+        self.network.is_some()
+    }
+
+    /// Has the process started by the command confined, returns the cgroup
+    /// named after it within the match that it will run in, which kills
+    /// whatever is left in it when dropped
+    pub fn confine(&self, command: &mut Command, name: &str) -> io::Result<Option<Cgroup>> {
+        let cgroup = match &self.cgroups {
+            Some(parent) => {
+                let name = format!("match{}_{}", self.number, name);
+                Some(Cgroup::create(&parent.join(name), &self.limits)?)
+            }
+            None => None,
+        };
+        let confinement = Confinement {
+            cgroup_procs: cgroup
+                .as_ref()
+                .map(|cgroup| CString::new(cgroup.dir.join("cgroup.procs").as_os_str().as_bytes()))
+                .transpose()?,
+            network: self.network.as_ref().map(AsRawFd::as_raw_fd),
+            limits: self.limits,
+            limit_address_space: cgroup.is_none(),
+            uid: self.uid,
+            gid: self.gid,
+            filter: self.filter.clone(),
+            hidden: self.hidden.clone(),
+        };
+
+        let path = match self.network {
+            Some(_) => SANDBOX_PATH.into(),
+            None => std::env::var_os("PATH").unwrap_or_else(|| SANDBOX_PATH.into()),
+        };
+        command
+            .env_clear()
+            .env("PATH", path)
+            .env("HOME", "/tmp")
+            .env("TMPDIR", "/tmp")
+            .process_group(0);
+        // Safety: the closure runs between fork and exec, so it only makes
+        // system calls on what was prepared above and never allocates
+        unsafe {
+            command.pre_exec(move || confinement.apply());
+        }
+        Ok(cgroup)
+    }
+
+    /// Hands a directory to runner.uid when processes are isolated, so
+    /// they can write in it
+    pub fn lend(&self, dir: &Path) -> io::Result<()> {
+        match self.isolated() {
+            true => chown_tree(dir, self.uid, self.gid),
+            false => Ok(()),
+        }
+    }
+
+    /// Takes a directory lent to runner.uid back
+    pub fn take_back(&self, dir: &Path) -> io::Result<()> {
+        match self.isolated() {
+            true => chown_tree(dir, unsafe { libc::geteuid() }, unsafe { libc::getegid() }),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Changes the owner of a directory and of everything in it, without
+/// following links
+fn chown_tree(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    std::os::unix::fs::lchown(path, Some(uid), Some(gid))?;
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_tree(&entry?.path(), uid, gid)?;
+        }
+    }
+    Ok(())
+}
+
+/// Everything a bot is confined with, prepared before it is forked
+struct Confinement {
+    cgroup_procs: Option<CString>,
+    network: Option<RawFd>,
+    limits: BotLimits,
+    limit_address_space: bool,
+    uid: u32,
+    gid: u32,
+    filter: Arc<Vec<libc::sock_filter>>,
+    hidden: Arc<Hidden>,
+}
+
+impl Confinement {
+    /// Confines the forked process that is about to exec the bot
+    fn apply(&self) -> io::Result<()> {
+        if let Some(procs) = &self.cgroup_procs {
+            join_cgroup(procs)?;
+        }
+        let isolated = self.network.is_some();
+        unsafe {
+            if let Some(network) = self.network {
+                check(libc::setns(network, libc::CLONE_NEWNET))?;
+                check(libc::unshare(
+                    libc::CLONE_NEWNS | libc::CLONE_NEWIPC | libc::CLONE_NEWUTS,
+                ))?;
+                check(libc::mount(
+                    std::ptr::null(),
+                    c"/".as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    std::ptr::null(),
+                ))?;
+                check(libc::mount(
+                    c"tmpfs".as_ptr(),
+                    c"/tmp".as_ptr(),
+                    c"tmpfs".as_ptr(),
+                    libc::MS_NOSUID | libc::MS_NODEV,
+                    TMP_OPTIONS.as_ptr().cast(),
+                ))?;
+                // The bot is already in its directory, which stays in reach
+                // once the paths of the server it is in are hidden
+                check(libc::mkdir(BOT_DIR.as_ptr(), 0o755))?;
+                check(libc::mount(
+                    c".".as_ptr(),
+                    BOT_DIR.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_BIND,
+                    std::ptr::null(),
+                ))?;
+                for file in &self.hidden.files {
+                    hide(libc::mount(
+                        c"/dev/null".as_ptr(),
+                        file.as_ptr(),
+                        std::ptr::null(),
+                        libc::MS_BIND,
+                        std::ptr::null(),
+                    ))?;
+                }
+                for dir in &self.hidden.dirs {
+                    hide(libc::mount(
+                        c"tmpfs".as_ptr(),
+                        dir.as_ptr(),
+                        c"tmpfs".as_ptr(),
+                        libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                        std::ptr::null(),
+                    ))?;
+                }
+                check(libc::chdir(BOT_DIR.as_ptr()))?;
+            }
+
+            // Past the soft limit the bot is sent SIGXCPU, past the hard
+            // one SIGKILL
+            let cpu = self.limits.cpu_time.as_secs();
+            check(libc::setrlimit(libc::RLIMIT_CPU, &limit(cpu, cpu + 1)))?;
+            check(libc::setrlimit(
+                libc::RLIMIT_FSIZE,
+                &limit(MAX_FILE_BYTES, MAX_FILE_BYTES),
+            ))?;
+            check(libc::setrlimit(
+                libc::RLIMIT_NOFILE,
+                &limit(MAX_OPEN_FILES, MAX_OPEN_FILES),
+            ))?;
+            check(libc::setrlimit(libc::RLIMIT_CORE, &limit(0, 0)))?;
+            if self.limit_address_space {
+                let memory = self.limits.memory_bytes;
+                check(libc::setrlimit(libc::RLIMIT_AS, &limit(memory, memory)))?;
+            }
+
+            if isolated {
+                let processes = self.limits.user_processes;
+                check(libc::setrlimit(
+                    libc::RLIMIT_NPROC,
+                    &limit(processes, processes),
+                ))?;
+                check(libc::setgroups(0, std::ptr::null()))?;
+                check(libc::setgid(self.gid))?;
+                check(libc::setuid(self.uid))?;
+                check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+                let program = libc::sock_fprog {
+                    len: self.filter.len() as libc::c_ushort,
+                    filter: self.filter.as_ptr() as *mut libc::sock_filter,
+                };
+                check(libc::prctl(
+                    libc::PR_SET_SECCOMP,
+                    libc::SECCOMP_MODE_FILTER,
+                    &program as *const libc::sock_fprog,
+                ))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fails with the error of a system call that returned -1
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(result),
+    }
+}
+
+/// Fails with the error of a mount that hid a path, unless the path is
+/// already gone, such as one in /tmp
+fn hide(result: libc::c_int) -> io::Result<()> {
+    match check(result) {
+        Err(error) if error.raw_os_error() != Some(libc::ENOENT) => Err(error),
+        _ => Ok(()),
+    }
+}
+
+fn limit(soft: u64, hard: u64) -> libc::rlimit {
+    libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    }
+}
+
+/// Moves the calling process into the cgroup with the cgroup.procs file
+fn join_cgroup(procs: &CStr) -> io::Result<()> {
+    unsafe {
+        let file = check(libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC))?;
+        let written = libc::write(file, c"0".as_ptr().cast(), 1);
+        let error = io::Error::last_os_error();
+        libc::close(file);
+        match written {
+            1 => Ok(()),
+            _ => Err(error),
+        }
+    }
+}
+
+/// Binds a listener in a new network namespace where only the loopback
+/// interface is up, and opens the namespace. This is done on a thread of
+/// its own, since the thread that unshares its network stays in it
+fn isolated_listener() -> io::Result<(TcpListener, OwnedFd)> {
+    std::thread::spawn(|| {
+        check(unsafe { libc::unshare(libc::CLONE_NEWNET) })?;
+        bring_up_loopback()?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let namespace = fs::File::open("/proc/thread-self/ns/net")?;
+        Ok((listener, OwnedFd::from(namespace)))
+    })
+    .join()
+    .expect("Failed to isolate the network of a match")
+}
+
+/// Brings up the loopback interface of the network the thread is in
+fn bring_up_loopback() -> io::Result<()> {
+    unsafe {
+        let socket = check(libc::socket(
+            libc::AF_INET,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            0,
+        ))?;
+        let socket = OwnedFd::from_raw_fd(socket);
+        let mut request: libc::ifreq = std::mem::zeroed();
+        for (name, &byte) in request.ifr_name.iter_mut().zip(b"lo") {
+            *name = byte as libc::c_char;
+        }
+        check(libc::ioctl(
+            socket.as_raw_fd(),
+            SIOCGIFFLAGS as _,
+            &mut request,
+        ))?;
+        request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+        check(libc::ioctl(socket.as_raw_fd(), SIOCSIFFLAGS as _, &request))?;
+    }
+    Ok(())
+}
+
+/// The cgroup bots are given cgroups in, if it is a cgroup v2 with the
+/// memory and pids controllers that the server may delegate to bots
+fn delegated_cgroup(dir: &Path) -> Option<PathBuf> {
+    let controllers = fs::read_to_string(dir.join("cgroup.controllers")).ok()?;
+    let controllers: Vec<&str> = controllers.split_whitespace().collect();
+    if !controllers.contains(&"memory") || !controllers.contains(&"pids") {
+        warn!(
+            "[-] {} has no memory or pids controller, bots are not put in cgroups",
+            dir.display()
+        );
+        return None;
+    }
+    match fs::write(dir.join("cgroup.subtree_control"), "+memory +pids") {
+        Ok(()) => Some(dir.to_path_buf()),
+        Err(error) => {
+            warn!(
+                "[-] Cannot delegate the controllers of {}, bots are not put in cgroups: {}",
+                dir.display(),
+                error
+            );
+            None
+        }
+    }
+}
+
+/// The cgroup of one bot
+pub struct Cgroup {
+    dir: PathBuf,
+}
+
+impl Cgroup {
+    /// Makes a cgroup that caps the memory and processes of what runs in it
+    fn create(dir: &Path, limits: &BotLimits) -> io::Result<Cgroup> {
+        if let Err(error) = fs::create_dir(dir) {
+            if error.kind() != io::ErrorKind::AlreadyExists {
+                return Err(error);
+            }
+        }
+        let cgroup = Cgroup {
+            dir: dir.to_path_buf(),
+        };
+        fs::write(dir.join("memory.max"), limits.memory_bytes.to_string())?;
+        // Only there when swap is accounted for
+        let _ = fs::write(dir.join("memory.swap.max"), "0");
+        fs::write(dir.join("pids.max"), limits.max_processes.to_string())?;
+        Ok(cgroup)
+    }
+
+    /// What the processes in the cgroup used, and which of its limits
+    /// they ran into
+    pub fn usage(&self) -> Usage {
+        let stat = |file: &str, key: &str| {
+            let stat = fs::read_to_string(self.dir.join(file)).unwrap_or_default();
+            stat.lines()
+                .filter_map(|line| line.split_once(' '))
+                .find(|(name, _)| *name == key)
+                .and_then(|(_, value)| value.trim().parse::<u64>().ok())
+        };
+        Usage {
+            oom_killed: stat("memory.events", "oom_kill").is_some_and(|count| count > 0),
+            processes_maxed: stat("pids.events", "max").is_some_and(|count| count > 0),
+            cpu_time: stat("cpu.stat", "usage_usec").map(Duration::from_micros),
+        }
+    }
+}
+
+/// What a bot used, read from its cgroup once it stopped
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    /// Whether the kernel killed one of its processes for using more
+    /// than its memory
+    pub oom_killed: bool,
+    /// Whether it was refused a process for running max_processes
+    pub processes_maxed: bool,
+    /// The cpu time of the bot and of every process it started, None
+    /// when it has no cgroup to measure it by
+    pub cpu_time: Option<Duration>,
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        let _ = fs::write(self.dir.join("cgroup.kill"), "1");
+        let dir = self.dir.clone();
+        std::thread::spawn(move || {
+            for _ in 0..CGROUP_REMOVE_ATTEMPTS {
+                if fs::remove_dir(&dir).is_ok() {
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            warn!("[-] Could not remove the cgroup {}", dir.display());
+        });
+    }
+}
+
+/// The limit a bot broke, if it exited because of one. The kernel sends
+/// SIGXCPU past the cpu time limit and SIGKILL a second later, but other
+/// things kill too, so a SIGKILL is only put down to cpu time when the
+/// cgroup of the bot measured that much
+pub fn violation(status: ExitStatus, usage: &Usage, limits: &BotLimits) -> Option<String> {
+    if usage.oom_killed {
+        return Some(format!(
+            "used more than its {} MiB of memory",
+            limits.memory_bytes >> 20
+        ));
+    }
+    let cpu_time_spent = usage.cpu_time.is_some_and(|used| used >= limits.cpu_time);
+    match status.signal() {
+        Some(libc::SIGXCPU) => Some(cpu_time_violation(limits)),
+        Some(libc::SIGKILL) if cpu_time_spent => Some(cpu_time_violation(limits)),
+        Some(libc::SIGSYS) => Some("made a forbidden system call".to_string()),
+        Some(libc::SIGXFSZ) => Some(format!(
+            "wrote a file larger than {} MiB",
+            MAX_FILE_BYTES >> 20
+        )),
+        _ if usage.processes_maxed => Some(format!(
+            "tried to run more than its {} processes",
+            limits.max_processes
+        )),
+        _ => None,
+    }
+}
+
+fn cpu_time_violation(limits: &BotLimits) -> String {
+    format!(
+        "used more than its {} seconds of cpu time",
+        limits.cpu_time.as_secs()
+    )
+}
+
+fn statement(code: u32, k: u32) -> libc::sock_filter {
+    jump(code, k, 0, 0)
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// The seccomp program bots run under. It kills a bot that makes a
+/// FORBIDDEN system call, makes a system call of another architecture, or
+/// clones itself into a namespace. Sockets outside ALLOWED_FAMILIES fail
+/// with EAFNOSUPPORT, and clone3, whose flags cannot be checked, with
+/// ENOSYS so that libc falls back to clone
+pub fn seccomp_filter() -> Vec<libc::sock_filter> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_JSET, BPF_K, BPF_LD, BPF_RET, BPF_W};
+    let load = |offset| statement(BPF_LD | BPF_W | BPF_ABS, offset);
+    let equals = |value, jt, jf| jump(BPF_JMP | BPF_JEQ | BPF_K, value, jt, jf);
+    let kill = statement(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS);
+    let allow = statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW);
+    let fail = |errno: i32| {
+        statement(
+            BPF_RET | BPF_K,
+            libc::SECCOMP_RET_ERRNO | (errno as u32 & libc::SECCOMP_RET_DATA),
+        )
+    };
+
+    let mut filter = vec![
+        load(SECCOMP_ARCH),
+        equals(AUDIT_ARCH, 1, 0),
+        kill,
+        load(SECCOMP_NR),
+    ];
+    // The x32 system calls of x86_64 are numbered from 0x40000000
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([
+        jump(BPF_JMP | libc::BPF_JGE | BPF_K, 0x4000_0000, 0, 1),
+        kill,
+    ]);
+    for &call in FORBIDDEN {
+        filter.extend([equals(call as u32, 0, 1), kill]);
+    }
+    filter.extend([equals(libc::SYS_clone3 as u32, 0, 1), fail(libc::ENOSYS)]);
+
+    let [unix, inet, inet6] = ALLOWED_FAMILIES.map(|family| family as u32);
+    filter.extend([
+        equals(libc::SYS_socket as u32, 0, 6),
+        load(SECCOMP_ARG0),
+        equals(unix, 2, 0),
+        equals(inet, 1, 0),
+        equals(inet6, 0, 1),
+        allow,
+        fail(libc::EAFNOSUPPORT),
+    ]);
+    filter.extend([
+        equals(libc::SYS_clone as u32, 0, 3),
+        load(SECCOMP_ARG0),
+        jump(BPF_JMP | BPF_JSET | BPF_K, CLONE_NAMESPACES as u32, 0, 1),
+        kill,
+        allow,
+    ]);
+    filter
+}
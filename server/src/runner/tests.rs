@@ -0,0 +1,585 @@
+use super::*;
+use crate::database::tests::create_test_db;
+use crate::database::{self, GameFilter};
+use splendor_arena::tungstenite;
+use std::fs;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::thread::JoinHandle;
+
+/// A fresh directory to prepare bots in
+fn temporary_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("stourney_runner_{}_{}", name, Uuid::new_v4()))
+}
+
+fn version(bot_id: i64, kind: &str, sha256: &str) -> BotVersion {
+    BotVersion {
+        bot_id,
+        name: format!("bot{}", bot_id),
+        version: 1,
+        kind: kind.to_string(),
+        filename: "bot".to_string(),
+        sha256: sha256.to_string(),
+        size: 0,
+        uploaded_at: String::new(),
+    }
+}
+
+/// How a bot on a thread answers the requests for an action it is sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Plays {
+    /// With the first legal action
+    Legal,
+    /// With garbage the first time, then like Legal
+    GarbageFirst,
+    /// Like Legal, after a delay
+    Slowly(Duration),
+    /// With garbage after a delay the first time, then like Legal
+    LateGarbageFirst(Duration),
+    /// By disconnecting
+    Quits,
+}
+
+/// A bot on a thread, which never breaks a limit since it has none
+fn thread_bot(port: u16, client: u64, plays: Plays) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let url = format!("ws://127.0.0.1:{}/game/0/{}", port, client);
+        let (mut socket, _) = tungstenite::connect(url).unwrap();
+        let mut garbage = matches!(plays, Plays::GarbageFirst | Plays::LateGarbageFirst(_));
+        while let Ok(message) = socket.read() {
+            let Ok(text) = message.to_text() else { break };
+            let Ok(ServerMessage::PlayerActionRequest(info)) = serde_json::from_str(text) else {
+                continue;
+            };
+            let reply = match (garbage, plays) {
+                (_, Plays::Quits) => break,
+                (true, Plays::LateGarbageFirst(delay)) => {
+                    std::thread::sleep(delay);
+                    "hello".to_string()
+                }
+                (true, _) => "hello".to_string(),
+                (false, _) => {
+                    if let Plays::Slowly(delay) = plays {
+                        std::thread::sleep(delay);
+                    }
+                    let action = ClientMessage::Action(info.legal_actions[0].clone());
+                    serde_json::to_string(&action).unwrap()
+                }
+            };
+            garbage = false;
+            if socket.send(tungstenite::Message::Text(reply)).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+impl RunningBot for JoinHandle<()> {
+    async fn violation(&mut self) -> Option<String> {
+        None
+    }
+}
+
+/// A bot on a thread that is said to have broken its cpu time limit
+/// once it disconnects
+struct Broken {
+    _thread: JoinHandle<()>,
+}
+
+impl RunningBot for Broken {
+    async fn violation(&mut self) -> Option<String> {
+        Some("used more than its 1 seconds of cpu time".to_string())
+    }
+}
+
+fn listener() -> TcpListener {
+    TcpListener::bind(("127.0.0.1", 0)).unwrap()
+}
+
+fn limits(move_time: Duration, connect_time: Duration) -> Limits {
+    Limits {
+        move_time,
+        connect_time,
+        wall_time: Duration::from_secs(60),
+        max_actions: MAX_ACTIONS,
+    }
+}
+
+/// Two bots owned by a new user, to seat in matches
+async fn two_bots(db: &sqlx::SqlitePool) -> (i64, Vec<BotVersion>) {
+    let user_id = database::save_user(db, "runner", "hash").await.unwrap();
+    let seats = vec![
+        database::save_bot_version(db, user_id, "alpha", "python", "a.py", "aa", 1).await,
+        database::save_bot_version(db, user_id, "beta", "python", "b.py", "bb", 1).await,
+    ];
+    (user_id, seats)
+}
+
+/// Runs a seccomp program on a system call the way the kernel does, and
+/// returns what it decided
+fn run_filter(filter: &[libc::sock_filter], arch: u32, call: libc::c_long, arg0: i32) -> u32 {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_JSET, BPF_K, BPF_LD, BPF_RET, BPF_W};
+    let (mut next, mut accumulator) = (0, 0);
+    loop {
+        let instruction = filter[next];
+        next += 1;
+        let code = instruction.code as u32;
+        let taken = match code {
+            _ if code == BPF_LD | BPF_W | BPF_ABS => {
+                accumulator = match instruction.k {
+                    0 => call as u32,
+                    4 => arch,
+                    16 => arg0 as u32,
+                    offset => panic!("loads unknown offset {}", offset),
+                };
+                continue;
+            }
+            _ if code == BPF_RET | BPF_K => return instruction.k,
+            _ if code == BPF_JMP | BPF_JEQ | BPF_K => accumulator == instruction.k,
+            _ if code == BPF_JMP | BPF_JGE | BPF_K => accumulator >= instruction.k,
+            _ if code == BPF_JMP | BPF_JSET | BPF_K => accumulator & instruction.k != 0,
+            _ => panic!("unknown instruction {:#x}", code),
+        };
+        next += match taken {
+            true => instruction.jt,
+            false => instruction.jf,
+        } as usize;
+    }
+}
+
+#[test]
+pub fn projects_are_found_at_the_top_or_in_their_only_directory() {
+    let dir = temporary_dir("root");
+    fs::create_dir_all(dir.join("my_bot/src")).unwrap();
+    fs::write(dir.join("my_bot/Cargo.toml"), "").unwrap();
+    assert_eq!(bots::project_root(&dir), Ok(dir.join("my_bot")));
+
+    fs::write(dir.join("main.py"), "").unwrap();
+    assert_eq!(bots::project_root(&dir), Ok(dir.clone()));
+
+    fs::remove_file(dir.join("main.py")).unwrap();
+    fs::create_dir(dir.join("other")).unwrap();
+    assert!(bots::project_root(&dir).is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+pub fn rust_projects_run_their_first_binary() {
+    let package = "[package]\nname = \"my_bot\"\nversion = \"0.1.0\"\n";
+    assert_eq!(bots::binary_name(package), Ok("my_bot".to_string()));
+
+    let with_bins = format!(
+        "{}[[bin]]\nname = \"play\"\n[[bin]]\nname = \"train\"\n",
+        package
+    );
+    assert_eq!(bots::binary_name(&with_bins), Ok("play".to_string()));
+
+    assert!(bots::binary_name("[dependencies]").is_err());
+    assert!(bots::binary_name("not toml").is_err());
+}
+
+#[tokio::test]
+pub async fn bots_are_prepared_once_by_their_artifact() {
+    let dir = temporary_dir("prepare");
+    let store = ArtifactStore::new(&dir.join("artifacts"));
+    let config = config::RunnerConfig {
+        work_dir: dir.join("work"),
+        sandbox: false,
+        ..Default::default()
+    };
+    let (sandbox, _) = sandbox::Sandbox::new(0, &config, &[]).unwrap();
+
+    let python = store.put(b"print('hello')\n").unwrap();
+    let launch = bots::prepare(&version(1, "python", &python), &store, &config, &sandbox)
+        .await
+        .unwrap();
+    assert_eq!(launch.program, PathBuf::from("python3"));
+    assert_eq!(launch.args, vec!["bot.py".to_string()]);
+    assert_eq!(
+        fs::read(launch.dir.join("bot.py")).unwrap(),
+        b"print('hello')\n"
+    );
+
+    // A prepared bot is not unpacked again
+    fs::write(launch.dir.join("bot.py"), "print('changed')\n").unwrap();
+    let again = bots::prepare(&version(2, "python", &python), &store, &config, &sandbox)
+        .await
+        .unwrap();
+    assert_eq!(again, launch);
+    assert_eq!(
+        fs::read(again.dir.join("bot.py")).unwrap(),
+        b"print('changed')\n"
+    );
+
+    let binary = store.put(b"#!/bin/sh\necho $@ > arguments\n").unwrap();
+    let launch = bots::prepare(&version(3, "binary", &binary), &store, &config, &sandbox)
+        .await
+        .unwrap();
+    assert_eq!(launch.program, launch.dir.join("bot"));
+    let mut bot = bots::spawn(&launch, 0, 3040, 7, &sandbox).unwrap();
+    assert_eq!(bot.violation().await, None);
+    assert_eq!(
+        fs::read_to_string(launch.dir.join("arguments")).unwrap(),
+        "--port 3040 --client-id 7\n"
+    );
+
+    let missing = "0".repeat(64);
+    assert!(
+        bots::prepare(&version(4, "python", &missing), &store, &config, &sandbox)
+            .await
+            .is_err()
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+pub async fn rust_projects_are_built_offline() {
+    let dir = temporary_dir("build");
+    let store = ArtifactStore::new(&dir.join("artifacts"));
+    let config = config::RunnerConfig {
+        work_dir: dir.join("work"),
+        sandbox: false,
+        ..Default::default()
+    };
+    let (sandbox, _) = sandbox::Sandbox::new(0, &config, &[]).unwrap();
+    let manifest = "[package]\nname = \"tiny\"\nversion = \"0.1.0\"\nedition = \"2021\"\n";
+    let project = crate::artifacts::tests::project(&[
+        ("tiny/Cargo.toml", manifest),
+        ("tiny/src/main.rs", "fn main() {}"),
+    ]);
+    let sha256 = store.put(&project).unwrap();
+    let launch = bots::prepare(&version(1, "project", &sha256), &store, &config, &sandbox)
+        .await
+        .unwrap();
+    assert!(launch.program.ends_with("tiny/target/release/tiny"));
+    assert!(launch.program.is_file());
+
+    // Dependencies can only come from the vendored directory
+    let manifest = format!("{}[dependencies]\nrand = \"0.8\"\n", manifest);
+    let project = crate::artifacts::tests::project(&[
+        ("tiny/Cargo.toml", &manifest),
+        ("tiny/src/main.rs", "fn main() {}"),
+    ]);
+    let sha256 = store.put(&project).unwrap();
+    let error = bots::prepare(&version(2, "project", &sha256), &store, &config, &sandbox)
+        .await
+        .unwrap_err();
+    assert!(error.contains("build failed"), "{}", error);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+pub async fn matches_are_played_and_saved_as_games() {
+    let db = create_test_db().await;
+    let (user_id, seats) = two_bots(&db).await;
+    let (sender, receiver) = queue::with_capacity(64);
+    let processor = tokio::spawn(queue::queue_processer(db.clone(), receiver, None));
+
+    let request = MatchRequest {
+        seats,
+        owner_id: Some(user_id),
+    };
+    let limits = limits(Duration::from_secs(5), Duration::from_secs(5));
+    let start = |port: u16, clients: &[u64]| {
+        Ok(vec![
+            thread_bot(port, clients[0], Plays::Legal),
+            thread_bot(port, clients[1], Plays::GarbageFirst),
+        ])
+    };
+    let result = play(0, &request, limits, sender.clone(), listener(), start)
+        .await
+        .unwrap();
+    assert_eq!(result.faults, vec![0, 1]);
+    assert_eq!(result.forfeit, None);
+    assert_eq!(result.places.len(), 2);
+    assert!(result.places.contains(&0));
+    drop(sender);
+    processor.await.unwrap();
+
+    let uuid = database::load_uuid_from_slug(&db, &result.slug)
+        .await
+        .unwrap();
+    assert_eq!(uuid, result.id);
+    assert_eq!(database::load_game_owner(&db, uuid).await, Some(user_id));
+    let players = database::load_game_players(&db, uuid).await;
+    let names: Vec<&str> = players.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, vec!["alpha", "beta"]);
+    assert_eq!(players[0].version.as_deref(), Some("1"));
+
+    let finished = database::list_games(
+        &db,
+        &GameFilter {
+            finished: Some(true),
+            limit: 10,
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(finished.len(), 1);
+    let last = database::load_latest_game_update(&db, uuid)
+        .await
+        .unwrap()
+        .unwrap();
+    let points: Vec<u8> = last.info.players.iter().map(|p| p.points).collect();
+    assert_eq!(points, result.points);
+}
+
+#[tokio::test]
+pub async fn answers_to_requests_that_timed_out_are_skipped() {
+    let db = create_test_db().await;
+    let (user_id, seats) = two_bots(&db).await;
+    let (sender, receiver) = queue::with_capacity(64);
+    let processor = tokio::spawn(queue::queue_processer(db, receiver, None));
+    let request = MatchRequest {
+        seats,
+        owner_id: Some(user_id),
+    };
+    // The garbage arrives while the next request of the seat is waited on
+    let start = |port: u16, clients: &[u64]| {
+        Ok(vec![
+            thread_bot(
+                port,
+                clients[0],
+                Plays::LateGarbageFirst(Duration::from_millis(400)),
+            ),
+            thread_bot(port, clients[1], Plays::Legal),
+        ])
+    };
+    let limits = limits(Duration::from_millis(300), Duration::from_secs(5));
+    let result = play(0, &request, limits, sender.clone(), listener(), start)
+        .await
+        .unwrap();
+    assert_eq!(result.faults, vec![1, 0]);
+    drop(sender);
+    processor.await.unwrap();
+}
+
+#[tokio::test]
+pub async fn matches_fail_when_bots_do_not_connect() {
+    let (sender, _receiver) = queue::with_capacity(16);
+    let request = MatchRequest {
+        seats: vec![version(1, "python", "aa"), version(2, "python", "bb")],
+        owner_id: None,
+    };
+    let limits = limits(Duration::from_millis(100), Duration::from_millis(200));
+    let start = |port: u16, clients: &[u64]| Ok(vec![thread_bot(port, clients[0], Plays::Legal)]);
+    let error = play(0, &request, limits, sender.clone(), listener(), start)
+        .await
+        .unwrap_err();
+    assert!(
+        error.reason.contains("seats [1] did not connect"),
+        "{}",
+        error.reason
+    );
+    // The one bot that did not connect is why the match failed
+    assert_eq!(error.seat, Some(1));
+
+    let request = MatchRequest {
+        seats: vec![version(1, "python", "aa")],
+        owner_id: None,
+    };
+    let start = |_: u16, _: &[u64]| Ok(Vec::<JoinHandle<()>>::new());
+    let played = play(0, &request, limits, sender, listener(), start).await;
+    assert!(played.is_err());
+}
+
+#[tokio::test]
+pub async fn bots_that_break_a_limit_forfeit_and_place_last() {
+    let db = create_test_db().await;
+    let (user_id, seats) = two_bots(&db).await;
+    let (sender, receiver) = queue::with_capacity(64);
+    let processor = tokio::spawn(queue::queue_processer(db.clone(), receiver, None));
+    let request = MatchRequest {
+        seats,
+        owner_id: Some(user_id),
+    };
+
+    let start = |port: u16, clients: &[u64]| {
+        Ok(vec![
+            Broken {
+                _thread: thread_bot(port, clients[0], Plays::Legal),
+            },
+            Broken {
+                _thread: thread_bot(port, clients[1], Plays::Quits),
+            },
+        ])
+    };
+    let limits = limits(Duration::from_secs(5), Duration::from_secs(5));
+    let broken = play(0, &request, limits, sender.clone(), listener(), start)
+        .await
+        .unwrap();
+    let reason = "used more than its 1 seconds of cpu time".to_string();
+    assert_eq!(broken.forfeit, Some((1, reason.clone())));
+    assert_eq!(broken.places, vec![0, 1]);
+
+    let start = |port: u16, clients: &[u64]| {
+        let slowly = Plays::Slowly(Duration::from_millis(60));
+        Ok(vec![
+            thread_bot(port, clients[0], slowly),
+            thread_bot(port, clients[1], Plays::Legal),
+        ])
+    };
+    let limits = Limits {
+        wall_time: Duration::from_millis(150),
+        ..limits
+    };
+    let slow = play(1, &request, limits, sender.clone(), listener(), start)
+        .await
+        .unwrap();
+    assert_eq!(slow.forfeit.as_ref().map(|(seat, _)| *seat), Some(0));
+    assert_eq!(slow.places, vec![1, 0]);
+    drop(sender);
+    processor.await.unwrap();
+
+    assert_eq!(
+        database::load_forfeit(&db, broken.id).await,
+        Some((1, reason.clone()))
+    );
+    let games = database::list_games(
+        &db,
+        &GameFilter {
+            limit: 10,
+            ..Default::default()
+        },
+    )
+    .await;
+    let listed = games
+        .iter()
+        .find(|game| game.uuid == broken.id.to_string())
+        .unwrap();
+    assert_eq!(listed.forfeit_seat, Some(1));
+    assert_eq!(listed.forfeit_reason, Some(reason));
+    assert!(listed.finished_at.is_some());
+}
+
+#[test]
+pub fn seccomp_filter_kills_forbidden_calls_and_allows_the_rest() {
+    let filter = sandbox::seccomp_filter();
+    let arch = sandbox::AUDIT_ARCH;
+    let run = |call, arg0| run_filter(&filter, arch, call, arg0);
+
+    assert_eq!(run(libc::SYS_read, 0), libc::SECCOMP_RET_ALLOW);
+    assert_eq!(run(libc::SYS_ptrace, 0), libc::SECCOMP_RET_KILL_PROCESS);
+    assert_eq!(run(libc::SYS_mount, 0), libc::SECCOMP_RET_KILL_PROCESS);
+    assert_eq!(
+        run_filter(&filter, !arch, libc::SYS_read, 0),
+        libc::SECCOMP_RET_KILL_PROCESS
+    );
+
+    assert_eq!(
+        run(libc::SYS_socket, libc::AF_INET),
+        libc::SECCOMP_RET_ALLOW
+    );
+    assert_eq!(
+        run(libc::SYS_socket, libc::AF_UNIX),
+        libc::SECCOMP_RET_ALLOW
+    );
+    assert_eq!(
+        run(libc::SYS_socket, libc::AF_NETLINK),
+        libc::SECCOMP_RET_ERRNO | libc::EAFNOSUPPORT as u32
+    );
+    assert_eq!(
+        run(libc::SYS_clone3, 0),
+        libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32
+    );
+
+    let thread = libc::CLONE_VM | libc::CLONE_THREAD | libc::CLONE_SIGHAND;
+    assert_eq!(run(libc::SYS_clone, thread), libc::SECCOMP_RET_ALLOW);
+    assert_eq!(
+        run(libc::SYS_clone, libc::CLONE_NEWNET | libc::SIGCHLD),
+        libc::SECCOMP_RET_KILL_PROCESS
+    );
+}
+
+#[test]
+pub fn violations_are_told_by_how_bots_stopped() {
+    let config = config::RunnerConfig::default();
+    let limits = sandbox::BotLimits::new(&config);
+    let signaled = |signal| std::process::ExitStatus::from_raw(signal);
+    let exited = std::process::ExitStatus::from_raw(1 << 8);
+
+    let none = sandbox::Usage::default();
+
+    let cpu = sandbox::violation(signaled(libc::SIGXCPU), &none, &limits).unwrap();
+    assert!(cpu.contains("300 seconds of cpu time"), "{}", cpu);
+    let oom = sandbox::Usage {
+        oom_killed: true,
+        ..none
+    };
+    let memory = sandbox::violation(exited, &oom, &limits).unwrap();
+    assert!(memory.contains("512 MiB of memory"), "{}", memory);
+    let call = sandbox::violation(signaled(libc::SIGSYS), &none, &limits);
+    assert_eq!(call.as_deref(), Some("made a forbidden system call"));
+    assert_eq!(sandbox::violation(exited, &none, &limits), None);
+    assert_eq!(
+        sandbox::violation(signaled(libc::SIGTERM), &none, &limits),
+        None
+    );
+
+    // A SIGKILL is only put down to cpu time that was measured
+    assert_eq!(
+        sandbox::violation(signaled(libc::SIGKILL), &none, &limits),
+        None
+    );
+    let spent = |seconds| sandbox::Usage {
+        cpu_time: Some(Duration::from_secs(seconds)),
+        ..none
+    };
+    assert_eq!(
+        sandbox::violation(signaled(libc::SIGKILL), &spent(10), &limits),
+        None
+    );
+    let cpu = sandbox::violation(signaled(libc::SIGKILL), &spent(301), &limits).unwrap();
+    assert!(cpu.contains("cpu time"), "{}", cpu);
+
+    let maxed = sandbox::Usage {
+        processes_maxed: true,
+        ..none
+    };
+    let processes = sandbox::violation(exited, &maxed, &limits).unwrap();
+    assert!(processes.contains("64 processes"), "{}", processes);
+}
+
+#[tokio::test]
+pub async fn sandboxed_bots_are_confined() {
+    let config = config::RunnerConfig {
+        cpu_time_secs: 1,
+        cgroup_dir: temporary_dir("cgroup"),
+        ..Default::default()
+    };
+    // Outside of /tmp, which bots have a private one of anyway
+    let server = PathBuf::from("/var/tmp").join(format!("stourney_server_{}", Uuid::new_v4()));
+    fs::create_dir_all(server.join("artifacts")).unwrap();
+    fs::write(server.join("artifacts").join("secret"), "secret").unwrap();
+    fs::write(server.join("stourney.db"), "secret").unwrap();
+    let hidden = [server.join("artifacts"), server.join("stourney.db")];
+    // The sandbox needs CAP_SYS_ADMIN, which tests may not have
+    let Ok((sandbox, _listener)) = sandbox::Sandbox::new(0, &config, &hidden) else {
+        fs::remove_dir_all(server).unwrap();
+        return;
+    };
+    let run = |script: &str| {
+        let mut command = tokio::process::Command::new("/bin/sh");
+        command.args(["-c", script]).current_dir("/");
+        sandbox.confine(&mut command, "seat0").unwrap();
+        command.status()
+    };
+
+    let confined = [
+        "test \"$(id -u)\" = 65534".to_string(),
+        "test \"$(ls -A /tmp)\" = bot".to_string(),
+        "test \"$(pwd)\" = /tmp/bot".to_string(),
+        "test \"$(grep -c : /proc/net/dev)\" = 1".to_string(),
+        "test -z \"$ADMIN_TOKEN\"".to_string(),
+        "! unshare -U true".to_string(),
+        format!("test -z \"$(ls -A {})\"", hidden[0].display()),
+        format!("test ! -s {}", hidden[1].display()),
+    ];
+    let status = run(&confined.join(" && ")).await.unwrap();
+    assert!(status.success(), "{:?}", status);
+
+    let status = run("while :; do :; done").await.unwrap();
+    let violation = sandbox::violation(status, &Default::default(), &sandbox.limits()).unwrap();
+    assert!(violation.contains("cpu time"), "{}", violation);
+    fs::remove_dir_all(server).unwrap();
+}
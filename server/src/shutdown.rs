@@ -0,0 +1,85 @@
+// Graceful shutdown of the server on SIGINT or SIGTERM.
+//
+// Shutting down is a process wide flag that the long running parts of the
+// server wait on, each of them winding itself down once it is set:
+//
+//      signal -> trigger() -> stop accepting connections
+//                          -> tell connected arenas to reconnect later
+//                          -> drain the queue into the database
+//
+// Tasks that must finish before the server exits hold a TaskGuard, and
+// main closes the database once every guard is dropped, or once the
+// SHUTDOWN_TIMEOUT runs out. A second signal exits immediately.
+
+use lazy_static::lazy_static;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// How long to wait for tasks to finish before exiting anyway
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    static ref SHUTTING_DOWN: watch::Sender<bool> = watch::channel(false).0;
+    static ref RUNNING_TASKS: watch::Sender<usize> = watch::channel(0).0;
+}
+
+/// Held by a task that has to finish before the server exits
+pub struct TaskGuard;
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        RUNNING_TASKS.send_modify(|tasks| *tasks -= 1);
+    }
+}
+
+/// Registers a task that has to finish before the server exits
+pub fn guard() -> TaskGuard {
+    RUNNING_TASKS.send_modify(|tasks| *tasks += 1);
+    TaskGuard
+}
+
+/// Starts shutting down the server
+pub fn trigger() {
+    SHUTTING_DOWN.send_replace(true);
+}
+
+/// Resolves once the server starts shutting down
+pub async fn triggered() {
+    let _ = SHUTTING_DOWN.subscribe().wait_for(|&down| down).await;
+}
+
+/// Whether the server has started shutting down
+pub fn is_triggered() -> bool {
+    *SHUTTING_DOWN.borrow()
+}
+
+/// Triggers a shutdown on the first SIGINT or SIGTERM, and exits
+/// immediately on the second
+pub async fn listen_for_signals() {
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT");
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    for signals in 0.. {
+        tokio::select! {
+            _ = interrupt.recv() => {},
+            _ = terminate.recv() => {},
+        }
+        if signals > 0 {
+            warn!("[-] Received a second signal, exiting without cleaning up");
+            std::process::exit(1);
+        }
+        info!("[+] Received a signal, shutting down");
+        trigger();
+    }
+}
+
+/// Waits for every task holding a guard to finish, returns false if they
+/// did not within the SHUTDOWN_TIMEOUT
+pub async fn finished() -> bool {
+    let mut tasks = RUNNING_TASKS.subscribe();
+    let finished = tokio::time::timeout(SHUTDOWN_TIMEOUT, tasks.wait_for(|&tasks| tasks == 0))
+        .await
+        .is_ok();
+    finished
+}
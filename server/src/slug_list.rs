@@ -11,15 +11,59 @@ pub const ADJECTIVES: [&str; 97] = [
     "greedy", "grumpy", "lazy", "moody", "rotten", "vain",
 ];
 
-pub const NOUNS: [&str; 99] = [
+pub const NOUNS: [&str; 86] = [
     "dog", "cow", "cat", "horse", "donkey", "tiger", "lion", "panther", "leopard", "cheetah",
-    "bear", "elephant", "polar", "bear", "turtle", "tortoise", "rabbit", "hare", "hen", "pigeon",
-    "crow", "fish", "dolphin", "frog", "whale", "eagle", "flying", "squirrel", "ostrich", "fox",
-    "goat", "jackal", "emu", "eel", "goose", "arctic", "fox", "wolf", "beagle", "gorilla",
-    "monkey", "beaver", "antelope", "bat", "badger", "giraffe", "hermit", "crab", "giant", "panda",
-    "hamster", "cobra", "shark", "camel", "hawk", "deer", "jaguar", "king", "cobra", "ibex",
-    "lizard", "koala", "kangaroo", "iguana", "llama", "dodo", "hedgehog", "zebra", "possum",
-    "wombat", "bison", "bull", "buffalo", "sheep", "meerkat", "mouse", "otter", "sloth", "owl",
-    "vulture", "flamingo", "racoon", "mole", "duck", "swan", "lynx", "monitor", "lizard", "elk",
-    "boar", "lemur", "mule", "baboon", "mammoth", "blue", "whale", "rat", "snake", "peacock",
+    "bear", "elephant", "turtle", "tortoise", "rabbit", "hare", "hen", "pigeon", "crow", "fish",
+    "dolphin", "frog", "whale", "eagle", "squirrel", "ostrich", "fox", "goat", "jackal", "emu",
+    "eel", "goose", "wolf", "beagle", "gorilla", "monkey", "beaver", "antelope", "bat", "badger",
+    "giraffe", "crab", "panda", "hamster", "cobra", "shark", "camel", "hawk", "deer", "jaguar",
+    "ibex", "lizard", "koala", "kangaroo", "iguana", "llama", "dodo", "hedgehog", "zebra",
+    "possum", "wombat", "bison", "bull", "buffalo", "sheep", "meerkat", "mouse", "otter", "sloth",
+    "owl", "vulture", "flamingo", "racoon", "mole", "duck", "swan", "lynx", "elk", "boar", "lemur",
+    "mule", "baboon", "mammoth", "rat", "snake", "peacock",
+];
+
+/// Words custom slugs are checked against, see slugs::validate
+pub const BLOCKED_WORDS: [&str; 31] = [
+    "anal", "anus", "arse", "ass", "asshole", "bastard", "bitch", "bollocks", "boner", "chink",
+    "cock", "cunt", "dick", "dildo", "fag", "faggot", "fuck", "fucker", "fucking", "kike", "nazi",
+    "nigga", "nigger", "porn", "pussy", "rape", "retard", "shit", "shitty", "slut", "whore",
+];
+
+/// Innocent words that contain a blocked word, such as the ass in class,
+/// which custom slugs may use, see slugs::validate
+pub const ALLOWED_WORDS: [&str; 33] = [
+    "analy",
+    "arsenal",
+    "assassin",
+    "assert",
+    "asset",
+    "assign",
+    "assist",
+    "banal",
+    "bass",
+    "brass",
+    "canal",
+    "class",
+    "cockatoo",
+    "cockpit",
+    "cocktail",
+    "cocky",
+    "compass",
+    "dickens",
+    "drape",
+    "glass",
+    "grape",
+    "grass",
+    "manuscript",
+    "mass",
+    "parapet",
+    "parse",
+    "pass",
+    "peacock",
+    "sassy",
+    "scrape",
+    "scunthorpe",
+    "trapeze",
+    "uranus",
 ];
@@ -0,0 +1,113 @@
+// Slugs name games in urls, such as /demo/brave_fox0042.
+//
+// Every game is given a random slug made of an adjective, a noun and a four
+// digit number when it is created, and its owner can replace it with a
+// custom one. The slugs a game had before are kept as aliases, which the api
+// redirects to its current slug:
+//
+//      brave_fox0042 (alias) -> my-first-win (canonical) -> game uuid
+//
+// Random slugs are generated by picking an adjective and a noun, then a
+// number that is not taken for that pair. A pair whose numbers are all
+// taken is skipped, and generation gives up after MAX_PREFIX_ATTEMPTS
+// pairs, reporting that the namespace is exhausted instead of retrying
+// forever.
+
+#[cfg(test)]
+pub mod tests;
+
+use rand::Rng;
+use std::collections::HashSet;
+
+use crate::slug_list::{ADJECTIVES, ALLOWED_WORDS, BLOCKED_WORDS, NOUNS};
+
+pub const MIN_LENGTH: usize = 3;
+pub const MAX_LENGTH: usize = 48;
+
+/// How many numbers can follow each adjective and noun
+pub const NUMBERS_PER_PREFIX: u32 = 10_000;
+
+/// How many adjective and noun pairs are tried before giving up
+pub const MAX_PREFIX_ATTEMPTS: usize = 32;
+
+/// How many old slugs a game keeps, the oldest are released past this
+pub const MAX_ALIASES: i64 = 8;
+
+/// Checks a custom slug is made of 3 to 48 lower case letters, digits,
+/// dashes and underscores, starting and ending with a letter or digit,
+/// and is not offensive. Returns the slug in lower case
+pub fn validate(slug: &str) -> Result<String, String> {
+    let slug = slug.trim().to_lowercase();
+    if slug.len() < MIN_LENGTH || slug.len() > MAX_LENGTH {
+        return Err(format!(
+            "slug must be between {} and {} characters long",
+            MIN_LENGTH, MAX_LENGTH
+        ));
+    }
+    if let Some(invalid) = slug
+        .chars()
+        .find(|&c| !c.is_ascii_lowercase() && !c.is_ascii_digit() && c != '-' && c != '_')
+    {
+        return Err(format!(
+            "slug can only contain letters, digits, - and _, found {:?}",
+            invalid
+        ));
+    }
+    let is_separator = |c: char| c == '-' || c == '_';
+    if slug.starts_with(is_separator) || slug.ends_with(is_separator) {
+        return Err("slug must start and end with a letter or digit".to_string());
+    }
+    if is_blocked(&slug) {
+        return Err("slug contains a blocked word".to_string());
+    }
+    Ok(slug)
+}
+
+/// Whether the slug spells a blocked word anywhere once its separators
+/// and digits are removed, like fuckyou or my_fuckingbot. A blocked word
+/// inside an allowed one, like the ass in classic, does not count
+fn is_blocked(slug: &str) -> bool {
+    let mut joined: String = slug.chars().filter(char::is_ascii_lowercase).collect();
+    for allowed in ALLOWED_WORDS {
+        joined = joined.replace(allowed, "-");
+    }
+    BLOCKED_WORDS
+        .iter()
+        .any(|&blocked| joined.contains(blocked))
+}
+
+/// A random adjective and noun, such as brave_fox
+pub fn random_prefix(rng: &mut impl Rng) -> String {
+    format!(
+        "{}_{}",
+        ADJECTIVES[rng.gen_range(0..ADJECTIVES.len())],
+        NOUNS[rng.gen_range(0..NOUNS.len())]
+    )
+}
+
+/// A generated slug, such as brave_fox0042
+pub fn with_number(prefix: &str, number: u32) -> String {
+    format!("{}{:04}", prefix, number)
+}
+
+/// The number of a slug generated from the prefix, if it is one
+pub fn number_of(prefix: &str, slug: &str) -> Option<u32> {
+    let number = slug.strip_prefix(prefix)?;
+    if number.len() != 4 || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    number.parse().ok()
+}
+
+/// A random number that is not taken, None if they all are
+pub fn free_number(taken: &HashSet<u32>, rng: &mut impl Rng) -> Option<u32> {
+    let free = (NUMBERS_PER_PREFIX as usize).saturating_sub(taken.len());
+    if free == 0 {
+        return None;
+    }
+    // Walks to the nth free number, which is bounded by the namespace
+    let nth = rng.gen_range(0..free);
+    (0..NUMBERS_PER_PREFIX)
+        .filter(|number| !taken.contains(number))
+        .nth(nth)
+}
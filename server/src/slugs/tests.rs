@@ -0,0 +1,196 @@
+use super::*;
+use crate::database::tests::create_test_db;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+#[test]
+pub fn valid_slugs_are_lower_cased() {
+    assert_eq!(validate("My-First_Win2").unwrap(), "my-first_win2");
+    assert_eq!(validate(" abc ").unwrap(), "abc");
+}
+
+#[test]
+pub fn invalid_slugs_are_rejected() {
+    for slug in [
+        "ab",
+        &"a".repeat(MAX_LENGTH + 1),
+        "has space",
+        "slash/slug",
+        "émigré",
+        "-leading",
+        "trailing_",
+    ] {
+        assert!(validate(slug).is_err(), "{} should be invalid", slug);
+    }
+}
+
+#[test]
+pub fn offensive_slugs_are_rejected() {
+    for slug in [
+        "shit",
+        "big-shit",
+        "shitty-bot",
+        "f_u_c_k",
+        "bot-ass-2",
+        "fuckyou",
+        "shitbot",
+        "my_fuckingbot",
+        "grass-ass",
+    ] {
+        assert!(validate(slug).is_err(), "{} should be blocked", slug);
+    }
+    for slug in [
+        "classic",
+        "grass_snake",
+        "assassin",
+        "scrapes",
+        "arsenal-fan",
+        "analysis",
+        "cockatoo",
+        "sassy_peacock0042",
+        "canal-boat",
+        "compass",
+    ] {
+        assert!(validate(slug).is_ok(), "{} should be allowed", slug);
+    }
+}
+
+#[test]
+pub fn word_lists_have_no_duplicates() {
+    for words in [&ADJECTIVES[..], &NOUNS[..]] {
+        let unique: HashSet<_> = words.iter().collect();
+        assert_eq!(unique.len(), words.len());
+    }
+}
+
+#[test]
+pub fn generated_slugs_round_trip_their_number() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let prefix = random_prefix(&mut rng);
+    let slug = with_number(&prefix, 42);
+    assert!(slug.ends_with("0042"));
+    assert_eq!(number_of(&prefix, &slug), Some(42));
+    assert_eq!(number_of(&prefix, &format!("{}42", prefix)), None);
+    assert_eq!(number_of(&prefix, "my-first-win"), None);
+}
+
+#[test]
+pub fn free_number_avoids_taken_numbers() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut taken: HashSet<u32> = (0..NUMBERS_PER_PREFIX).collect();
+    assert_eq!(free_number(&taken, &mut rng), None);
+
+    taken.remove(&1234);
+    assert_eq!(free_number(&taken, &mut rng), Some(1234));
+}
+
+#[tokio::test]
+pub async fn renamed_games_keep_their_old_slug_as_an_alias() {
+    let db = create_test_db().await;
+    let game = crate::database::generate_new_id(&db).await;
+    let other = crate::database::generate_new_id(&db).await;
+    let old = crate::database::load_slug_default(&db, game).await;
+    crate::database::load_slug_default(&db, other).await;
+
+    crate::database::save_custom_slug(&db, game, "my-first-win")
+        .await
+        .expect("expected the slug to be free");
+    assert_eq!(
+        crate::database::load_slug(&db, game).await.as_deref(),
+        Some("my-first-win")
+    );
+    assert_eq!(
+        crate::database::load_uuid_from_slug(&db, &old).await.ok(),
+        Some(game)
+    );
+    assert_eq!(
+        crate::database::load_canonical_slug(&db, &old)
+            .await
+            .as_deref(),
+        Some("my-first-win")
+    );
+    assert_eq!(
+        crate::database::load_slug_aliases(&db, game).await,
+        vec![old.clone()]
+    );
+    assert!(
+        crate::database::save_custom_slug(&db, other, "my-first-win")
+            .await
+            .is_err()
+    );
+    assert!(crate::database::save_custom_slug(&db, other, &old)
+        .await
+        .is_err());
+
+    // Taking back an old slug turns the current one into an alias
+    crate::database::save_custom_slug(&db, game, &old)
+        .await
+        .expect("expected the alias to be taken back");
+    assert_eq!(crate::database::load_slug(&db, game).await, Some(old));
+    assert_eq!(
+        crate::database::load_slug_aliases(&db, game).await,
+        vec!["my-first-win".to_string()]
+    );
+
+    let filter = crate::database::GameFilter {
+        limit: 10,
+        ..Default::default()
+    };
+    assert_eq!(crate::database::list_games(&db, &filter).await.len(), 2);
+}
+
+#[tokio::test]
+pub async fn oldest_aliases_are_released() {
+    let db = create_test_db().await;
+    let game = crate::database::generate_new_id(&db).await;
+    let first = crate::database::load_slug_default(&db, game).await;
+    for rename in 0..MAX_ALIASES + 1 {
+        crate::database::save_custom_slug(&db, game, &format!("rename-{}", rename))
+            .await
+            .unwrap();
+    }
+    let aliases = crate::database::load_slug_aliases(&db, game).await;
+    assert_eq!(aliases.len() as i64, MAX_ALIASES);
+    assert!(!aliases.contains(&first));
+    assert!(crate::database::load_uuid_from_slug(&db, &first)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+pub async fn requests_by_an_alias_redirect_to_the_current_slug() {
+    let db = create_test_db().await;
+    let game = crate::database::generate_new_id(&db).await;
+    let old = crate::database::load_slug_default(&db, game).await;
+    crate::database::save_custom_slug(&db, game, "my-first-win")
+        .await
+        .unwrap();
+    let routes = crate::api::routes(db.clone());
+
+    let response = warp::test::request()
+        .path(&format!("/api/games/{}/replay?from=1", old))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 308);
+    assert_eq!(
+        response.headers()["location"],
+        "/api/games/my-first-win/replay?from=1"
+    );
+
+    let response = warp::test::request()
+        .path("/api/games/my-first-win/replay")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 200);
+
+    let response = warp::test::request()
+        .path(&format!("/api/games/{}/slug", old))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 308);
+    let response = warp::test::request()
+        .path("/api/games/no-such-game/replay")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 404);
+}
@@ -0,0 +1,228 @@
+// Write-ahead spool for the game updates waiting in the queue.
+//
+// Every update is appended to the spool before the arena is told it was
+// accepted, and released by the queue processor once it is committed to
+// the database. Whatever is still in the spool when the server stops,
+// cleanly or not, is replayed into the database when it starts again:
+//
+//      Arena -> push_game_updates -> Spool + Queue -> Database -> release
+//
+// The spool is a directory of segment files named after the sequence
+// number of their first record, each record being
+//
+//      [seq: u64][game: uuid, 16 bytes][length: u32][bincode GameUpdate]
+//
+// in little endian. A segment is truncated as soon as every update in it
+// is committed, and once it grows past SEGMENT_BYTES a new one is started
+// so the old one can be deleted when its updates are committed. A record
+// cut short by a crash was never acknowledged, and is dropped on replay.
+
+#[cfg(test)]
+pub mod tests;
+
+use splendor_arena::models::GameUpdate;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::encoding;
+
+/// How large a segment grows before a new one is started
+const SEGMENT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// The size of a record before its update
+const HEADER_BYTES: usize = 8 + 16 + 4;
+
+/// Where an update was spooled, so it can be released once committed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpoolEntry {
+    pub seq: u64,
+    segment: u64,
+}
+
+/// The updates left in a spool by the last run of the server, in the
+/// order they were spooled
+pub struct Recovered {
+    pub updates: Vec<(Uuid, GameUpdate)>,
+    pub next_seq: u64,
+}
+
+pub struct Spool {
+    dir: PathBuf,
+    state: Mutex<SpoolState>,
+}
+
+struct SpoolState {
+    next_seq: u64,
+    current: Segment,
+    /// Uncommitted updates in the segments no longer written to
+    sealed: BTreeMap<u64, usize>,
+}
+
+struct Segment {
+    first_seq: u64,
+    file: File,
+    bytes: u64,
+    pending: usize,
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.spool", first_seq))
+}
+
+/// The segments in a spool directory, oldest first
+fn segments(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "spool"))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn open_segment(dir: &Path, first_seq: u64) -> io::Result<Segment> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, first_seq))?;
+    Ok(Segment {
+        first_seq,
+        file,
+        bytes: 0,
+        pending: 0,
+    })
+}
+
+/// Reads the updates left in a spool directory, creating it if needed.
+/// The segments are kept until Spool::create, so nothing is lost if the
+/// server stops again before they are replayed
+pub fn recover(dir: &Path) -> io::Result<Recovered> {
+    fs::create_dir_all(dir)?;
+    let mut recovered = Recovered {
+        updates: vec![],
+        next_seq: 0,
+    };
+
+    for path in segments(dir)? {
+        let bytes = fs::read(&path)?;
+        let mut offset = 0;
+        while bytes.len() - offset >= HEADER_BYTES {
+            let header = &bytes[offset..offset + HEADER_BYTES];
+            let seq = u64::from_le_bytes(header[0..8].try_into().unwrap());
+            let id = Uuid::from_bytes(header[8..24].try_into().unwrap());
+            let length = u32::from_le_bytes(header[24..28].try_into().unwrap()) as usize;
+            let start = offset + HEADER_BYTES;
+            if bytes.len() - start < length {
+                break;
+            }
+            match bincode::deserialize(&bytes[start..start + length]) {
+                Ok(update) => recovered.updates.push((id, update)),
+                Err(error) => warn!("[-] Skipping unreadable spooled update {}: {}", seq, error),
+            }
+            recovered.next_seq = recovered.next_seq.max(seq + 1);
+            offset = start + length;
+        }
+        if offset != bytes.len() {
+            warn!(
+                "[-] Dropping {} bytes of an incomplete update at the end of {}",
+                bytes.len() - offset,
+                path.display()
+            );
+        }
+    }
+    Ok(recovered)
+}
+
+impl Spool {
+    /// Starts an empty spool in the directory, deleting the segments left
+    /// by the last run, so only call this once they have been replayed
+    pub fn create(dir: &Path, next_seq: u64) -> io::Result<Spool> {
+        fs::create_dir_all(dir)?;
+        for path in segments(dir)? {
+            fs::remove_file(path)?;
+        }
+        Ok(Spool {
+            dir: dir.to_path_buf(),
+            state: Mutex::new(SpoolState {
+                next_seq,
+                current: open_segment(dir, next_seq)?,
+                sealed: BTreeMap::new(),
+            }),
+        })
+    }
+
+    /// Durably appends game updates to the spool, returning where each
+    /// one was spooled
+    pub fn append(&self, id: Uuid, updates: &[GameUpdate]) -> io::Result<Vec<SpoolEntry>> {
+        let mut state = self.state.lock().unwrap();
+        let mut records = vec![];
+        let mut entries = vec![];
+        for (seq, update) in (state.next_seq..).zip(updates) {
+            let bytes = encoding::serialize(update);
+            records.extend_from_slice(&seq.to_le_bytes());
+            records.extend_from_slice(id.as_bytes());
+            records.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            records.extend_from_slice(&bytes);
+            entries.push(SpoolEntry {
+                seq,
+                segment: state.current.first_seq,
+            });
+        }
+
+        state.current.file.write_all(&records)?;
+        state.current.file.sync_data()?;
+        state.current.bytes += records.len() as u64;
+        state.current.pending += entries.len();
+        state.next_seq += entries.len() as u64;
+
+        if state.current.bytes >= SEGMENT_BYTES {
+            let next = open_segment(&self.dir, state.next_seq)?;
+            let sealed = std::mem::replace(&mut state.current, next);
+            debug!("[+] Starting spool segment {}", state.next_seq);
+            state.sealed.insert(sealed.first_seq, sealed.pending);
+        }
+        Ok(entries)
+    }
+
+    /// Marks spooled updates as committed, truncating or deleting the
+    /// segments that no longer hold anything uncommitted
+    pub fn release(&self, entries: &[SpoolEntry]) {
+        let mut state = self.state.lock().unwrap();
+        for entry in entries {
+            if entry.segment == state.current.first_seq {
+                state.current.pending -= 1;
+                continue;
+            }
+            let Some(pending) = state.sealed.get_mut(&entry.segment) else {
+                continue;
+            };
+            *pending -= 1;
+            if *pending == 0 {
+                state.sealed.remove(&entry.segment);
+                if let Err(error) = fs::remove_file(segment_path(&self.dir, entry.segment)) {
+                    warn!(
+                        "[-] Failed to delete spool segment {}: {}",
+                        entry.segment, error
+                    );
+                }
+            }
+        }
+
+        if state.current.pending == 0 && state.current.bytes > 0 {
+            match state.current.file.set_len(0) {
+                Ok(()) => state.current.bytes = 0,
+                Err(error) => warn!("[-] Failed to truncate spool: {}", error),
+            }
+        }
+    }
+
+    /// How many spooled updates are not committed yet
+    pub fn pending(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.current.pending + state.sealed.values().sum::<usize>()
+    }
+}
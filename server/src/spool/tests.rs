@@ -0,0 +1,96 @@
+use super::*;
+use crate::database::tests::default_game_update;
+use std::io::Write;
+
+fn spool_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("stourney-spool-{}", Uuid::new_v4()))
+}
+
+fn turns(recovered: &Recovered) -> Vec<usize> {
+    recovered
+        .updates
+        .iter()
+        .map(|(_, update)| update.update_num)
+        .collect()
+}
+
+#[test]
+pub fn uncommitted_updates_are_recovered_in_order() {
+    let turn = |update_num| GameUpdate {
+        update_num,
+        ..default_game_update()
+    };
+    let dir = spool_dir();
+    let id = Uuid::new_v4();
+    let spool = Spool::create(&dir, 0).unwrap();
+    let first = spool.append(id, &[turn(0), turn(1)]).unwrap();
+    spool.append(id, &[turn(2)]).unwrap();
+    spool.release(&first[..1]);
+    drop(spool);
+
+    // Releasing part of a segment keeps all of it, replaying is idempotent
+    let recovered = recover(&dir).unwrap();
+    assert_eq!(turns(&recovered), vec![0, 1, 2]);
+    assert!(recovered.updates.iter().all(|(game, _)| *game == id));
+    assert_eq!(recovered.next_seq, 3);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+pub fn committed_updates_are_truncated() {
+    let turn = |update_num| GameUpdate {
+        update_num,
+        ..default_game_update()
+    };
+    let dir = spool_dir();
+    let spool = Spool::create(&dir, 7).unwrap();
+    let entries = spool.append(Uuid::new_v4(), &[turn(0), turn(1)]).unwrap();
+    assert_eq!(
+        entries.iter().map(|e| e.seq).collect::<Vec<_>>(),
+        vec![7, 8]
+    );
+    spool.release(&entries);
+    assert_eq!(spool.pending(), 0);
+
+    spool.append(Uuid::new_v4(), &[turn(5)]).unwrap();
+    drop(spool);
+    assert_eq!(turns(&recover(&dir).unwrap()), vec![5]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+pub fn incomplete_record_is_dropped() {
+    let dir = spool_dir();
+    let spool = Spool::create(&dir, 0).unwrap();
+    let update = GameUpdate {
+        update_num: 3,
+        ..default_game_update()
+    };
+    spool.append(Uuid::new_v4(), &[update]).unwrap();
+    drop(spool);
+
+    let path = segments(&dir).unwrap().remove(0);
+    let mut file = OpenOptions::new().append(true).open(path).unwrap();
+    file.write_all(&[1, 0, 0, 0, 0, 0, 0, 0, 9]).unwrap();
+
+    assert_eq!(turns(&recover(&dir).unwrap()), vec![3]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+pub fn create_removes_replayed_segments() {
+    let dir = spool_dir();
+    let spool = Spool::create(&dir, 0).unwrap();
+    let update = GameUpdate {
+        update_num: 1,
+        ..default_game_update()
+    };
+    spool.append(Uuid::new_v4(), &[update]).unwrap();
+    drop(spool);
+
+    let recovered = recover(&dir).unwrap();
+    let spool = Spool::create(&dir, recovered.next_seq).unwrap();
+    assert_eq!(spool.pending(), 0);
+    assert!(recover(&dir).unwrap().updates.is_empty());
+    fs::remove_dir_all(dir).unwrap();
+}
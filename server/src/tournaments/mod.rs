@@ -0,0 +1,311 @@
+// Tournaments between uploaded bot versions, played by the runner.
+//
+// An admin creates a tournament with its participants in seed order and
+// its settings, see schedule.rs for the formats. A task then drives it
+// round by round:
+//
+//      POST /api/admin/tournaments -> create() -> drive()
+//          -> draw the next round and save its pairings and games
+//          -> play the games of the round through runner::schedule
+//          -> record their places, and draw the next round once they are over
+//
+// Everything is saved as it happens, so after a restart resume() carries
+// on with the games that were not over, and GET /api/tournaments/{id}
+// rebuilds the bracket and standings from the database.
+
+pub mod schedule;
+#[cfg(test)]
+pub mod tests;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use tracing::{info, warn, Instrument};
+
+use crate::config;
+use crate::database::{self, BotVersion, TournamentGame, TournamentPairing, TournamentRecord};
+use crate::logging;
+use crate::queue;
+use crate::runner::{self, MatchFailure, MatchRequest, MatchState};
+use crate::shutdown;
+use schedule::{Format, Game, Pairing, Rotation, Round, Settings};
+
+/// A tournament and everything played in it so far
+#[derive(Debug, Clone)]
+pub struct Tournament {
+    pub record: TournamentRecord,
+    pub settings: Settings,
+    /// The bot versions playing, in seed order
+    pub participants: Vec<BotVersion>,
+    pub pairings: Vec<TournamentPairing>,
+    pub games: Vec<TournamentGame>,
+    /// The pairings and games by round, as schedule.rs sees them
+    pub rounds: Vec<Round>,
+    /// The indices in `games` of the games of each (round, pairing)
+    pub by_pairing: HashMap<(usize, usize), Vec<usize>>,
+}
+
+impl Tournament {
+    /// The games of a pairing, in order
+    pub fn games_of<'a>(
+        &'a self,
+        pairing: &'a TournamentPairing,
+    ) -> impl Iterator<Item = &'a TournamentGame> + 'a {
+        self.by_pairing
+            .get(&(pairing.round, pairing.pairing))
+            .into_iter()
+            .flatten()
+            .map(|&index| &self.games[index])
+    }
+}
+
+/// Loads a tournament, None if there is no such tournament
+pub async fn load(pool: &SqlitePool, tournament_id: i64) -> Option<Tournament> {
+    let record = database::load_tournament(pool, tournament_id).await?;
+    let settings = Settings {
+        format: Format::parse(&record.format).expect("Tournaments are saved with a valid format"),
+        seats: record.seats as usize,
+        games_per_pairing: record.games_per_pairing as usize,
+        rotation: Rotation::parse(&record.rotation)
+            .expect("Tournaments are saved with a valid rotation"),
+        rounds: record.rounds as usize,
+    };
+    let participants = database::load_tournament_participants(pool, tournament_id).await;
+    let pairings = database::load_tournament_pairings(pool, tournament_id).await;
+    let games = database::load_tournament_games(pool, tournament_id).await;
+    let mut by_pairing: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+    for (index, game) in games.iter().enumerate() {
+        by_pairing
+            .entry((game.round, game.pairing))
+            .or_default()
+            .push(index);
+    }
+
+    let mut tournament = Tournament {
+        record,
+        settings,
+        participants,
+        pairings,
+        games,
+        rounds: vec![],
+        by_pairing,
+    };
+    for pairing in &tournament.pairings {
+        if pairing.round == tournament.rounds.len() {
+            tournament.rounds.push(vec![]);
+        }
+        let games = tournament
+            .games_of(pairing)
+            .map(|game| Game {
+                seating: game.seating.clone(),
+                places: game.places.clone(),
+                failed: game.reason.is_some(),
+                culprit: game
+                    .failed_seat
+                    .and_then(|seat| game.seating.get(seat).copied()),
+            })
+            .collect();
+        tournament.rounds[pairing.round].push(Pairing {
+            participants: pairing.participants.clone(),
+            games,
+            replays: pairing.replays,
+        });
+    }
+    Some(tournament)
+}
+
+/// Saves a new tournament and starts playing it, returns its id
+pub async fn create(
+    pool: &SqlitePool,
+    name: &str,
+    settings: Settings,
+    participants: &[BotVersion],
+) -> Result<i64, String> {
+    schedule::validate(&settings, participants.len())?;
+    for (seed, version) in participants.iter().enumerate() {
+        let entered =
+            |other: &BotVersion| other.bot_id == version.bot_id && other.version == version.version;
+        if participants[..seed].iter().any(entered) {
+            return Err(format!(
+                "bot {} version {} is entered twice",
+                version.bot_id, version.version
+            ));
+        }
+    }
+
+    let record = TournamentRecord {
+        tournament_id: 0,
+        name: name.to_string(),
+        format: settings.format.as_str().to_string(),
+        seats: settings.seats as i64,
+        games_per_pairing: settings.games_per_pairing as i64,
+        rotation: settings.rotation.as_str().to_string(),
+        rounds: settings.rounds as i64,
+        created_at: String::new(),
+        finished_at: None,
+    };
+    let tournament_id = database::save_tournament(pool, &record, participants).await;
+    info!("[+] Created tournament {} ({})", tournament_id, name);
+    start(pool.clone(), tournament_id);
+    Ok(tournament_id)
+}
+
+/// Carries on with the tournaments that were not finished when the server
+/// last stopped, once the queue their games are saved through is running
+pub async fn resume(pool: SqlitePool) {
+    while queue::running().is_none() {
+        if shutdown::is_triggered() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    for tournament_id in database::load_unfinished_tournaments(&pool).await {
+        database::reset_tournament_matches(&pool, tournament_id).await;
+        info!("[+] Resuming tournament {}", tournament_id);
+        start(pool.clone(), tournament_id);
+    }
+}
+
+/// Drives a tournament on the runner in the background
+fn start(pool: SqlitePool, tournament_id: i64) {
+    let concurrency = config::get().runner.max_matches;
+    tokio::spawn(
+        drive(pool, tournament_id, concurrency, runner::schedule)
+            .instrument(logging::tournament_span(tournament_id)),
+    );
+}
+
+/// Plays a tournament until it is finished or the server shuts down,
+/// with at most `concurrency` of its games submitted at once. Matches
+/// are played by `schedule`, runner::schedule outside of tests
+pub async fn drive<S, P>(pool: SqlitePool, tournament_id: i64, concurrency: usize, schedule: S)
+where
+    S: Fn(MatchRequest) -> (u64, P),
+    P: Future<Output = MatchState>,
+{
+    loop {
+        if shutdown::is_triggered() {
+            return;
+        }
+        let Some(tournament) = load(&pool, tournament_id).await else {
+            warn!("[-] Tournament {} does not exist", tournament_id);
+            return;
+        };
+
+        let pending: Vec<TournamentGame> = tournament
+            .games
+            .iter()
+            .filter(|game| game.places.is_none() && game.reason.is_none())
+            .cloned()
+            .collect();
+        if !pending.is_empty() {
+            let mut pending = pending.into_iter();
+            let mut playing = FuturesUnordered::new();
+            loop {
+                while playing.len() < concurrency.max(1) {
+                    match pending.next() {
+                        Some(game) => playing.push(play_game(&pool, &tournament, game, &schedule)),
+                        None => break,
+                    }
+                }
+                if playing.next().await.is_none() {
+                    break;
+                }
+            }
+            continue;
+        }
+
+        let settings = &tournament.settings;
+        let replays = schedule::replays(settings, &tournament.rounds);
+        if !replays.is_empty() {
+            let round = tournament.rounds.len() - 1;
+            for pairing in replays {
+                database::replay_tournament_pairing(&pool, tournament_id, round, pairing).await;
+                info!(
+                    "[+] Replaying pairing {} of round {} of tournament {}, every game failed",
+                    pairing, round, tournament_id
+                );
+            }
+            continue;
+        }
+        let Some(tables) =
+            schedule::next_round(settings, tournament.participants.len(), &tournament.rounds)
+        else {
+            database::save_tournament_finished(&pool, tournament_id).await;
+            info!("[+] Tournament {} finished", tournament_id);
+            return;
+        };
+        let round = tournament.rounds.len();
+        let pairings: Vec<(Vec<usize>, Vec<Vec<usize>>)> = tables
+            .into_iter()
+            .map(|table| {
+                let seatings = match table.len() {
+                    1 => vec![],
+                    _ => schedule::seatings(settings.rotation, &table, settings.games_per_pairing),
+                };
+                (table, seatings)
+            })
+            .collect();
+        database::save_tournament_round(&pool, tournament_id, round, &pairings).await;
+        info!(
+            "[+] Drew round {} of tournament {} with {} pairings",
+            round,
+            tournament_id,
+            pairings.len()
+        );
+    }
+}
+
+/// Plays a game of a tournament and records how it ended. Games stopped
+/// by a shutdown are left to be played again on the next start
+async fn play_game<S, P>(
+    pool: &SqlitePool,
+    tournament: &Tournament,
+    game: TournamentGame,
+    schedule: &S,
+) where
+    S: Fn(MatchRequest) -> (u64, P),
+    P: Future<Output = MatchState>,
+{
+    let tournament_id = tournament.record.tournament_id;
+    let request = MatchRequest {
+        seats: game
+            .seating
+            .iter()
+            .map(|&participant| tournament.participants[participant].clone())
+            .collect(),
+        owner_id: None,
+    };
+    let (number, played) = schedule(request);
+    database::save_tournament_game_match(pool, tournament_id, &game, number).await;
+    let state = played.await;
+    if shutdown::is_triggered() {
+        return;
+    }
+    match state {
+        MatchState::Finished(result) => {
+            database::save_tournament_game_result(
+                pool,
+                tournament_id,
+                &game,
+                Ok((result.id, &result.places)),
+            )
+            .await
+        }
+        MatchState::Failed(failure) => {
+            database::save_tournament_game_result(pool, tournament_id, &game, Err(&failure)).await
+        }
+        state => {
+            warn!("[-] Match {} ended as {:?}", number, state);
+            database::save_tournament_game_result(
+                pool,
+                tournament_id,
+                &game,
+                Err(&MatchFailure::from("the match did not finish".to_string())),
+            )
+            .await
+        }
+    }
+}
@@ -0,0 +1,546 @@
+// Drawing the rounds of a tournament and ranking its participants.
+//
+// Participants are numbered from 0 in seed order. A round is a list of
+// pairings, the tables of 2 to 4 participants that play each other, and
+// each pairing plays games_per_pairing games with its participants seated
+// by the rotation. A pairing of a single participant is a bye.
+//
+//   - round robin: one round of every combination of `seats` participants
+//   - swiss: `rounds` rounds, the first with the top half of the seeds
+//     against the bottom half, the next ones with participants of similar
+//     scores that have not met yet. A participant left over gets a bye,
+//     which scores as winning every game of its pairing
+//   - knockout: the winner of each pairing goes through to the next round
+//     until one is left. Byes go to the top seeds in the first round, and
+//     every round is seeded so the top seeds meet as late as they can.
+//     When every game of a pairing failed, the participants whose bot
+//     made one fail are out, and if no bot did the pairing is played
+//     again, see pairing_winner
+//
+// In every game, each participant scores a point for every participant
+// placed behind them and half a point for every participant tied with them.
+// Games that failed score nothing.
+
+/// How participants are drawn against each other
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    RoundRobin,
+    Swiss,
+    Knockout,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Result<Format, String> {
+        match format {
+            "round_robin" => Ok(Format::RoundRobin),
+            "swiss" => Ok(Format::Swiss),
+            "knockout" => Ok(Format::Knockout),
+            _ => Err(format!(
+                "unknown format {}, expected round_robin, swiss or knockout",
+                format
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::RoundRobin => "round_robin",
+            Format::Swiss => "swiss",
+            Format::Knockout => "knockout",
+        }
+    }
+}
+
+/// How participants are seated over the games of a pairing, to cancel
+/// out the advantage of playing first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    /// In the order of the pairing every game
+    Fixed,
+    /// Each game moves every participant one seat earlier
+    Rotate,
+    /// Every order of the participants in turn
+    AllOrders,
+}
+
+impl Rotation {
+    pub fn parse(rotation: &str) -> Result<Rotation, String> {
+        match rotation {
+            "fixed" => Ok(Rotation::Fixed),
+            "rotate" => Ok(Rotation::Rotate),
+            "all_orders" => Ok(Rotation::AllOrders),
+            _ => Err(format!(
+                "unknown rotation {}, expected fixed, rotate or all_orders",
+                rotation
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rotation::Fixed => "fixed",
+            Rotation::Rotate => "rotate",
+            Rotation::AllOrders => "all_orders",
+        }
+    }
+}
+
+/// The most participants a tournament may have
+pub const MAX_PARTICIPANTS: usize = 256;
+
+/// The most games a tournament may plan to play
+pub const MAX_GAMES: usize = 10_000;
+
+/// How many times a knockout pairing is played again when all its games
+/// failed with no bot to blame, before its best seed goes through anyway
+pub const MAX_REPLAYS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    pub format: Format,
+    /// How many participants play each game, 2 to 4
+    pub seats: usize,
+    pub games_per_pairing: usize,
+    pub rotation: Rotation,
+    /// How many rounds a swiss tournament plays
+    pub rounds: usize,
+}
+
+/// A game of a pairing, with its result once it is played
+#[derive(Debug, Clone, PartialEq)]
+pub struct Game {
+    /// The participant in each seat
+    pub seating: Vec<usize>,
+    /// The place of each seat, None until the game is finished
+    pub places: Option<Vec<usize>>,
+    /// Whether the game could not be played, it then counts for no one
+    pub failed: bool,
+    /// The participant whose bot made the game fail, if it was one bot
+    pub culprit: Option<usize>,
+}
+
+impl Game {
+    pub fn is_over(&self) -> bool {
+        self.places.is_some() || self.failed
+    }
+}
+
+/// Participants drawn to play each other in a round
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pairing {
+    pub participants: Vec<usize>,
+    pub games: Vec<Game>,
+    /// How many times the games of the pairing were played again
+    pub replays: usize,
+}
+
+impl Pairing {
+    pub fn is_bye(&self) -> bool {
+        self.participants.len() == 1
+    }
+}
+
+pub type Round = Vec<Pairing>;
+
+/// How many rounds a swiss tournament plays unless it is told, enough
+/// for a single participant to be left undefeated in two player games
+pub fn default_rounds(participants: usize) -> usize {
+    (participants.max(2) as f64).log2().ceil() as usize
+}
+
+/// Checks the settings can be played with this many participants
+pub fn validate(settings: &Settings, participants: usize) -> Result<(), String> {
+    if !(2..=4).contains(&settings.seats) {
+        return Err("games are played by 2 to 4 participants".to_string());
+    }
+    if !(2..=MAX_PARTICIPANTS).contains(&participants) {
+        return Err(format!(
+            "a tournament has 2 to {} participants",
+            MAX_PARTICIPANTS
+        ));
+    }
+    if settings.format == Format::RoundRobin && participants < settings.seats {
+        return Err(format!(
+            "a round robin of {} player games needs at least {} participants",
+            settings.seats, settings.seats
+        ));
+    }
+    if settings.games_per_pairing == 0 {
+        return Err("every pairing plays at least 1 game".to_string());
+    }
+    if settings.format == Format::Swiss && settings.rounds == 0 {
+        return Err("a swiss tournament plays at least 1 round".to_string());
+    }
+    let games = planned_games(settings, participants);
+    if games > MAX_GAMES {
+        return Err(format!(
+            "the tournament would play {} games, more than {}",
+            games, MAX_GAMES
+        ));
+    }
+    Ok(())
+}
+
+/// The most games the tournament plays
+pub fn planned_games(settings: &Settings, participants: usize) -> usize {
+    let seats = settings.seats;
+    let pairings = match settings.format {
+        Format::RoundRobin => combinations(participants, seats),
+        Format::Swiss => settings.rounds.saturating_mul(participants.div_ceil(seats)),
+        Format::Knockout => {
+            let mut tables = bracket_size(participants, seats) / seats;
+            let mut pairings = 0;
+            while tables > 0 {
+                pairings += tables;
+                tables /= seats;
+            }
+            pairings
+        }
+    };
+    pairings.saturating_mul(settings.games_per_pairing)
+}
+
+/// n choose k, saturating
+fn combinations(n: usize, k: usize) -> usize {
+    (0..k).fold(1usize, |count, i| count.saturating_mul(n - i) / (i + 1))
+}
+
+/// The seating of every game of a pairing
+pub fn seatings(rotation: Rotation, participants: &[usize], games: usize) -> Vec<Vec<usize>> {
+    (0..games)
+        .map(|game| {
+            let mut seating = participants.to_vec();
+            match rotation {
+                Rotation::Fixed => {}
+                Rotation::Rotate => seating.rotate_left(game % participants.len().max(1)),
+                Rotation::AllOrders => seating = nth_order(participants, game),
+            }
+            seating
+        })
+        .collect()
+}
+
+/// The nth of the orders of the participants, counting in lexicographic
+/// order of their positions and wrapping around
+fn nth_order(participants: &[usize], n: usize) -> Vec<usize> {
+    let orders: usize = (1..=participants.len()).product();
+    let mut rest = n % orders;
+    let mut left = participants.to_vec();
+    let mut order = vec![];
+    while !left.is_empty() {
+        let block: usize = (1..left.len()).product();
+        order.push(left.remove(rest / block));
+        rest %= block;
+    }
+    order
+}
+
+/// The score of every seat of a finished game
+fn game_scores(places: &[usize]) -> Vec<f64> {
+    places
+        .iter()
+        .map(|place| {
+            places
+                .iter()
+                .map(|other| match other.cmp(place) {
+                    std::cmp::Ordering::Greater => 1.0,
+                    std::cmp::Ordering::Equal => 0.5,
+                    std::cmp::Ordering::Less => 0.0,
+                })
+                .sum::<f64>()
+                // Every seat ties with itself
+                - 0.5
+        })
+        .collect()
+}
+
+/// The score of every participant over the rounds
+pub fn scores(settings: &Settings, participants: usize, rounds: &[Round]) -> Vec<f64> {
+    let mut scores = vec![0.0; participants];
+    for pairing in rounds.iter().flatten() {
+        if pairing.is_bye() {
+            if settings.format == Format::Swiss {
+                let won = (settings.seats - 1) * settings.games_per_pairing;
+                scores[pairing.participants[0]] += won as f64;
+            }
+            continue;
+        }
+        for game in &pairing.games {
+            let Some(places) = &game.places else { continue };
+            for (&participant, score) in game.seating.iter().zip(game_scores(places)) {
+                scores[participant] += score;
+            }
+        }
+    }
+    scores
+}
+
+/// The participant that goes through from a pairing of a knockout: the
+/// best score over its games, then the best seed. When every game failed
+/// the participants whose bot made one fail are out, and if no bot did
+/// the pairing has to be played again, None until it was MAX_REPLAYS times
+pub fn pairing_winner(pairing: &Pairing) -> Option<usize> {
+    let mut candidates = pairing.participants.clone();
+    if !pairing.games.is_empty() && pairing.games.iter().all(|game| game.failed) {
+        let culprits: Vec<usize> = pairing
+            .games
+            .iter()
+            .filter_map(|game| game.culprit)
+            .collect();
+        if culprits.is_empty() && pairing.replays < MAX_REPLAYS {
+            return None;
+        }
+        candidates.retain(|participant| !culprits.contains(participant));
+        // Every bot failed, so none is better than the others
+        if candidates.is_empty() {
+            candidates = pairing.participants.clone();
+        }
+    }
+    let mut scores: Vec<(usize, f64)> = candidates.iter().map(|&p| (p, 0.0)).collect();
+    for game in &pairing.games {
+        let Some(places) = &game.places else { continue };
+        for (participant, score) in game.seating.iter().zip(game_scores(places)) {
+            if let Some(entry) = scores.iter_mut().find(|(p, _)| p == participant) {
+                entry.1 += score;
+            }
+        }
+    }
+    scores
+        .iter()
+        .fold(
+            None,
+            |best: Option<(usize, f64)>, &(participant, score)| match best {
+                Some((seed, best)) if best > score || (best == score && seed < participant) => {
+                    Some((seed, best))
+                }
+                _ => Some((participant, score)),
+            },
+        )
+        .map(|(participant, _)| participant)
+}
+
+/// The pairings of the last round of a knockout, with all their games
+/// over, that have to be played again before the next round is drawn
+pub fn replays(settings: &Settings, rounds: &[Round]) -> Vec<usize> {
+    let Some(last) = rounds.last() else {
+        return vec![];
+    };
+    match settings.format {
+        Format::Knockout => (0..last.len())
+            .filter(|&pairing| pairing_winner(&last[pairing]).is_none())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// The tables of the next round, given every round so far with all its
+/// games over, None once the tournament is over
+pub fn next_round(
+    settings: &Settings,
+    participants: usize,
+    rounds: &[Round],
+) -> Option<Vec<Vec<usize>>> {
+    let seats = settings.seats;
+    match settings.format {
+        Format::RoundRobin => match rounds.is_empty() {
+            true => Some(all_tables(participants, seats)),
+            false => None,
+        },
+        Format::Swiss => match rounds.len() < settings.rounds {
+            true => Some(swiss_round(settings, participants, rounds)),
+            false => None,
+        },
+        Format::Knockout => {
+            let Some(last) = rounds.last() else {
+                return Some(knockout_round(
+                    &(0..participants).collect::<Vec<_>>(),
+                    seats,
+                ));
+            };
+            // Pairings to play again are played before this, see replays
+            let mut through: Vec<usize> = last.iter().filter_map(pairing_winner).collect();
+            through.sort();
+            match through.len() {
+                0 | 1 => None,
+                _ => Some(knockout_round(&through, seats)),
+            }
+        }
+    }
+}
+
+/// Every combination of `seats` participants, in lexicographic order
+fn all_tables(participants: usize, seats: usize) -> Vec<Vec<usize>> {
+    let mut tables = vec![];
+    let mut table: Vec<usize> = (0..seats).collect();
+    if seats > participants {
+        return tables;
+    }
+    loop {
+        tables.push(table.clone());
+        // The last position that can still move up
+        let Some(i) = (0..seats)
+            .rev()
+            .find(|&i| table[i] < participants - seats + i)
+        else {
+            return tables;
+        };
+        table[i] += 1;
+        for j in i + 1..seats {
+            table[j] = table[j - 1] + 1;
+        }
+    }
+}
+
+/// The smallest power of `seats` that fits every participant
+fn bracket_size(participants: usize, seats: usize) -> usize {
+    let mut size = seats;
+    while size < participants {
+        size *= seats;
+    }
+    size
+}
+
+/// Draws a knockout round between participants in seed order. The slots
+/// of the bracket are dealt to the tables back and forth, so the top seeds
+/// are spread over the tables, and the slots past the last participant are
+/// byes
+fn knockout_round(seeded: &[usize], seats: usize) -> Vec<Vec<usize>> {
+    let tables = bracket_size(seeded.len(), seats) / seats;
+    (0..tables)
+        .map(|table| {
+            (0..seats)
+                .map(|column| match column % 2 {
+                    0 => column * tables + table,
+                    _ => column * tables + tables - 1 - table,
+                })
+                .filter_map(|slot| seeded.get(slot).copied())
+                .collect()
+        })
+        .collect()
+}
+
+/// Draws a swiss round, see the top of the file
+fn swiss_round(settings: &Settings, participants: usize, rounds: &[Round]) -> Vec<Vec<usize>> {
+    let seats = settings.seats;
+    let scores = scores(settings, participants, rounds);
+    let mut ranked: Vec<usize> = (0..participants).collect();
+    ranked.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]).then(a.cmp(&b)));
+
+    let mut tables = vec![];
+    if ranked.len() % seats == 1 {
+        let had_bye = |participant: usize| {
+            rounds
+                .iter()
+                .flatten()
+                .any(|pairing| pairing.is_bye() && pairing.participants[0] == participant)
+        };
+        let position = ranked
+            .iter()
+            .rposition(|&participant| !had_bye(participant))
+            .unwrap_or(ranked.len() - 1);
+        tables.push(vec![ranked.remove(position)]);
+    }
+
+    if rounds.is_empty() {
+        // The top of the seeds against the rest
+        let count = ranked.len().div_ceil(seats);
+        let mut first: Vec<Vec<usize>> = vec![vec![]; count];
+        for (i, participant) in ranked.into_iter().enumerate() {
+            first[i % count].push(participant);
+        }
+        first.extend(tables);
+        return first;
+    }
+
+    let met = |a: usize, b: usize| {
+        rounds
+            .iter()
+            .flatten()
+            .any(|pairing| pairing.participants.contains(&a) && pairing.participants.contains(&b))
+    };
+    let mut played = vec![];
+    while !ranked.is_empty() {
+        let size = seats.min(ranked.len());
+        let mut table = vec![ranked.remove(0)];
+        while table.len() < size {
+            let position = ranked
+                .iter()
+                .position(|&candidate| table.iter().all(|&seated| !met(seated, candidate)))
+                .unwrap_or(0);
+            table.push(ranked.remove(position));
+        }
+        played.push(table);
+    }
+    played.extend(tables);
+    played
+}
+
+/// Where a participant stands in a tournament
+#[derive(Debug, Clone, PartialEq)]
+pub struct Standing {
+    pub participant: usize,
+    /// 0 for first, participants that are level share a place
+    pub place: usize,
+    pub score: f64,
+    /// Games finished
+    pub played: usize,
+    /// Games finished in first place, ties included
+    pub wins: usize,
+}
+
+/// The participants from first to last. Round robin and swiss rank them
+/// by score, knockout by the round they went out in and then by score
+pub fn standings(settings: &Settings, participants: usize, rounds: &[Round]) -> Vec<Standing> {
+    let scores = scores(settings, participants, rounds);
+    let mut played = vec![0; participants];
+    let mut wins = vec![0; participants];
+    for game in rounds.iter().flatten().flat_map(|pairing| &pairing.games) {
+        let Some(places) = &game.places else { continue };
+        for (&participant, &place) in game.seating.iter().zip(places) {
+            played[participant] += 1;
+            wins[participant] += (place == 0) as usize;
+        }
+    }
+
+    // How far each participant got, only counted in knockouts
+    let mut reached = vec![0; participants];
+    if settings.format == Format::Knockout {
+        for (number, round) in rounds.iter().enumerate() {
+            let complete = round
+                .iter()
+                .all(|pairing| pairing.games.iter().all(Game::is_over));
+            for pairing in round {
+                for &participant in &pairing.participants {
+                    reached[participant] = number;
+                }
+                if let Some(winner) = pairing_winner(pairing).filter(|_| complete) {
+                    reached[winner] = number + 1;
+                }
+            }
+        }
+    }
+
+    let mut order: Vec<usize> = (0..participants).collect();
+    order.sort_by(|&a, &b| {
+        reached[b]
+            .cmp(&reached[a])
+            .then(scores[b].total_cmp(&scores[a]))
+            .then(a.cmp(&b))
+    });
+    order
+        .iter()
+        .map(|&participant| {
+            let ahead = |other: &usize| match settings.format {
+                Format::Knockout => reached[*other] > reached[participant],
+                _ => scores[*other] > scores[participant],
+            };
+            Standing {
+                participant,
+                place: order.iter().filter(|other| ahead(other)).count(),
+                score: scores[participant],
+                played: played[participant],
+                wins: wins[participant],
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,431 @@
+use super::schedule::*;
+use super::*;
+use crate::database::tests::create_test_db;
+use crate::runner::MatchResult;
+use std::collections::HashSet;
+use uuid::Uuid;
+
+fn settings(format: Format, seats: usize, rounds: usize) -> Settings {
+    Settings {
+        format,
+        seats,
+        games_per_pairing: 1,
+        rotation: Rotation::Rotate,
+        rounds,
+    }
+}
+
+/// Plays every table of a round, the top seed at each table wins and the
+/// rest follow in seed order
+fn play_by_seed(tables: Vec<Vec<usize>>) -> Round {
+    tables
+        .into_iter()
+        .map(|participants| {
+            let games = match participants.len() {
+                1 => vec![],
+                _ => vec![Game {
+                    seating: participants.clone(),
+                    places: Some((0..participants.len()).collect()),
+                    failed: false,
+                    culprit: None,
+                }],
+            };
+            Pairing {
+                participants,
+                games,
+                replays: 0,
+            }
+        })
+        .collect()
+}
+
+/// Plays a tournament out with play_by_seed
+fn play_out(settings: &Settings, participants: usize) -> Vec<Round> {
+    let mut rounds = vec![];
+    while let Some(tables) = next_round(settings, participants, &rounds) {
+        assert!(rounds.len() < 20, "the tournament never ends");
+        rounds.push(play_by_seed(tables));
+    }
+    rounds
+}
+
+#[test]
+fn round_robin_plays_every_combination_once() {
+    for (seats, participants, tables) in [(2, 5, 10), (3, 5, 10), (4, 6, 15), (4, 4, 1)] {
+        let settings = settings(Format::RoundRobin, seats, 1);
+        let rounds = play_out(&settings, participants);
+        assert_eq!(rounds.len(), 1);
+        let drawn: HashSet<Vec<usize>> = rounds[0]
+            .iter()
+            .map(|pairing| pairing.participants.clone())
+            .collect();
+        assert_eq!(drawn.len(), tables);
+        assert!(drawn.iter().all(|table| table.len() == seats));
+        assert_eq!(planned_games(&settings, participants), tables);
+    }
+}
+
+#[test]
+fn seatings_follow_the_rotation() {
+    assert_eq!(
+        seatings(Rotation::Fixed, &[3, 5], 2),
+        vec![vec![3, 5], vec![3, 5]]
+    );
+    assert_eq!(
+        seatings(Rotation::Rotate, &[1, 2, 3], 4),
+        vec![vec![1, 2, 3], vec![2, 3, 1], vec![3, 1, 2], vec![1, 2, 3]]
+    );
+    let orders = seatings(Rotation::AllOrders, &[1, 2, 3], 6);
+    assert_eq!(orders.iter().collect::<HashSet<_>>().len(), 6);
+    assert_eq!(orders[0], vec![1, 2, 3]);
+    assert_eq!(orders[5], vec![3, 2, 1]);
+}
+
+#[test]
+fn swiss_avoids_rematches_and_spreads_byes() {
+    let settings = settings(Format::Swiss, 2, 4);
+    let rounds = play_out(&settings, 7);
+    assert_eq!(rounds.len(), 4);
+
+    let mut met = HashSet::new();
+    let mut byes = vec![];
+    for pairing in rounds.iter().flatten() {
+        if pairing.is_bye() {
+            byes.push(pairing.participants[0]);
+            continue;
+        }
+        let mut table = pairing.participants.clone();
+        table.sort();
+        assert!(met.insert(table), "{:?} played twice", pairing.participants);
+    }
+    assert_eq!(byes.len(), 4);
+    assert_eq!(byes.iter().collect::<HashSet<_>>().len(), 4);
+
+    // The first round is the top half of the seeds against the bottom half
+    assert_eq!(rounds[0][0].participants, vec![0, 3]);
+    // The top seed wins every game it plays
+    let standings = standings(&settings, 7, &rounds);
+    assert_eq!(standings[0].participant, 0);
+    assert_eq!(standings[0].place, 0);
+}
+
+#[test]
+fn knockout_gives_byes_to_top_seeds_and_keeps_them_apart() {
+    let settings = settings(Format::Knockout, 2, 1);
+    let first = next_round(&settings, 6, &[]).unwrap();
+    assert_eq!(
+        first,
+        vec![vec![0], vec![1], vec![2, 5], vec![3, 4]],
+        "a bracket of 8 with byes for the top 2 seeds"
+    );
+
+    let rounds = play_out(&settings, 6);
+    assert_eq!(rounds.len(), 3);
+    assert_eq!(rounds[1], play_by_seed(vec![vec![0, 3], vec![1, 2]]));
+    assert_eq!(rounds[2], play_by_seed(vec![vec![0, 1]]));
+
+    let places: Vec<(usize, usize)> = standings(&settings, 6, &rounds)
+        .iter()
+        .map(|standing| (standing.participant, standing.place))
+        .collect();
+    assert_eq!(places, vec![(0, 0), (1, 1), (2, 2), (3, 2), (4, 4), (5, 4)]);
+}
+
+#[test]
+fn knockout_of_four_player_games() {
+    let settings = settings(Format::Knockout, 4, 1);
+    let rounds = play_out(&settings, 10);
+    // 16 slots over 4 tables, then the final table of 4 winners
+    assert_eq!(rounds.len(), 2);
+    assert_eq!(rounds[0].len(), 4);
+    assert_eq!(rounds[1][0].participants, vec![0, 1, 2, 3]);
+    assert_eq!(planned_games(&settings, 10), 5);
+}
+
+#[test]
+fn scores_count_places_and_ties() {
+    let settings = settings(Format::RoundRobin, 3, 1);
+    let rounds = vec![vec![Pairing {
+        participants: vec![0, 1, 2],
+        games: vec![
+            Game {
+                seating: vec![2, 0, 1],
+                places: Some(vec![0, 1, 1]),
+                failed: false,
+                culprit: None,
+            },
+            Game {
+                seating: vec![0, 1, 2],
+                places: None,
+                failed: true,
+                culprit: None,
+            },
+        ],
+        replays: 0,
+    }]];
+    assert_eq!(scores(&settings, 3, &rounds), vec![0.5, 0.5, 2.0]);
+    let standings = standings(&settings, 3, &rounds);
+    assert_eq!(standings[0].participant, 2);
+    assert_eq!(standings[0].wins, 1);
+    assert_eq!((standings[1].place, standings[2].place), (1, 1));
+    assert_eq!(standings[1].played, 1);
+}
+
+/// A pairing of seeds 0 and 1 whose games all failed, blamed on `culprits`
+fn failed_pairing(culprits: &[Option<usize>], replays: usize) -> Pairing {
+    Pairing {
+        participants: vec![0, 1],
+        games: culprits
+            .iter()
+            .map(|&culprit| Game {
+                seating: vec![0, 1],
+                places: None,
+                failed: true,
+                culprit,
+            })
+            .collect(),
+        replays,
+    }
+}
+
+#[test]
+fn failed_knockout_pairings_eliminate_the_culprit_or_are_replayed() {
+    let knockout = settings(Format::Knockout, 2, 1);
+    assert_eq!(
+        pairing_winner(&failed_pairing(&[Some(0), None], 0)),
+        Some(1)
+    );
+    // Both bots failed a game, neither is better so the top seed goes through
+    assert_eq!(
+        pairing_winner(&failed_pairing(&[Some(0), Some(1)], 0)),
+        Some(0)
+    );
+
+    let rounds = vec![vec![failed_pairing(&[None, None], 0)]];
+    assert_eq!(pairing_winner(&rounds[0][0]), None);
+    assert_eq!(replays(&knockout, &rounds), vec![0]);
+    let rounds = vec![vec![failed_pairing(&[None, None], MAX_REPLAYS)]];
+    assert_eq!(pairing_winner(&rounds[0][0]), Some(0));
+    assert!(replays(&knockout, &rounds).is_empty());
+    assert_eq!(next_round(&knockout, 2, &rounds), None);
+}
+
+#[test]
+fn settings_are_validated() {
+    let valid = settings(Format::RoundRobin, 3, 1);
+    assert!(validate(&valid, 3).is_ok());
+    assert!(validate(&valid, 2).is_err());
+    assert!(validate(&settings(Format::Swiss, 5, 1), 8).is_err());
+    assert!(validate(&settings(Format::Swiss, 2, 0), 8).is_err());
+    assert!(validate(&settings(Format::Knockout, 2, 1), 1).is_err());
+    assert!(validate(&settings(Format::RoundRobin, 4, 1), 100).is_err());
+    assert_eq!(default_rounds(2), 1);
+    assert_eq!(default_rounds(9), 4);
+}
+
+async fn participants(db: &SqlitePool, count: usize) -> Vec<BotVersion> {
+    let user_id = database::save_user(db, "organiser", "hash").await.unwrap();
+    let mut versions = vec![];
+    for bot in 0..count {
+        let name = format!("bot{}", bot);
+        versions.push(
+            database::save_bot_version(db, user_id, &name, "python", "bot.py", "aa", 1).await,
+        );
+    }
+    versions
+}
+
+/// Schedules matches that finish at once, won by the lowest bot id
+fn fake_schedule(request: MatchRequest) -> (u64, impl Future<Output = MatchState> + Send) {
+    let number = request.seats[0].bot_id as u64;
+    let played = async move {
+        let mut order: Vec<usize> = (0..request.seats.len()).collect();
+        order.sort_by_key(|&seat| request.seats[seat].bot_id);
+        let mut places = vec![0; order.len()];
+        for (place, seat) in order.into_iter().enumerate() {
+            places[seat] = place;
+        }
+        MatchState::Finished(MatchResult {
+            id: Uuid::new_v4(),
+            slug: "fake".to_string(),
+            points: vec![0; places.len()],
+            faults: vec![0; places.len()],
+            places,
+            forfeit: None,
+        })
+    };
+    (number, played)
+}
+
+#[tokio::test]
+async fn drives_a_knockout_to_the_end() {
+    let db = create_test_db().await;
+    let versions = participants(&db, 5).await;
+    let settings = Settings {
+        games_per_pairing: 2,
+        ..settings(Format::Knockout, 2, 1)
+    };
+    let record = TournamentRecord {
+        tournament_id: 0,
+        name: "cup".to_string(),
+        format: "knockout".to_string(),
+        seats: 2,
+        games_per_pairing: 2,
+        rotation: "rotate".to_string(),
+        rounds: 1,
+        created_at: String::new(),
+        finished_at: None,
+    };
+    let id = database::save_tournament(&db, &record, &versions).await;
+    drive(db.clone(), id, 2, fake_schedule).await;
+
+    let tournament = load(&db, id).await.unwrap();
+    assert!(tournament.record.finished_at.is_some());
+    assert_eq!(tournament.settings, settings);
+    assert_eq!(tournament.rounds.len(), 3);
+    // 8 slots: 3 byes and 1 game, then 2 pairings and the final, 2 games each
+    assert_eq!(tournament.games.len(), 2 * (1 + 2 + 1));
+    assert!(tournament
+        .games
+        .iter()
+        .all(|game| game.places.is_some() && game.match_number.is_some()));
+    assert_eq!(tournament.games[1].seating, vec![4, 3]);
+
+    let standings = standings(&tournament.settings, 5, &tournament.rounds);
+    assert_eq!(standings[0].participant, 0);
+    assert_eq!(standings[0].wins, 4);
+    assert_eq!(standings[4].participant, 4);
+}
+
+/// Schedules matches that fail, blamed on the bot with the lowest id when
+/// `blame` is set
+fn failing_schedule(blame: bool) -> impl Fn(MatchRequest) -> (u64, std::future::Ready<MatchState>) {
+    move |request| {
+        let seat = (0..request.seats.len()).min_by_key(|&seat| request.seats[seat].bot_id);
+        let failure = MatchFailure {
+            reason: "the bot crashed".to_string(),
+            seat: seat.filter(|_| blame),
+        };
+        (0, std::future::ready(MatchState::Failed(failure)))
+    }
+}
+
+#[tokio::test]
+async fn failed_knockout_games_are_blamed_or_replayed() {
+    let db = create_test_db().await;
+    let versions = participants(&db, 2).await;
+    let record = TournamentRecord {
+        tournament_id: 0,
+        name: "cup".to_string(),
+        format: "knockout".to_string(),
+        seats: 2,
+        games_per_pairing: 1,
+        rotation: "rotate".to_string(),
+        rounds: 1,
+        created_at: String::new(),
+        finished_at: None,
+    };
+
+    let id = database::save_tournament(&db, &record, &versions).await;
+    drive(db.clone(), id, 1, failing_schedule(true)).await;
+    let tournament = load(&db, id).await.unwrap();
+    assert!(tournament.record.finished_at.is_some());
+    assert_eq!(tournament.pairings[0].replays, 0);
+    assert_eq!(tournament.games[0].failed_seat, Some(0));
+    assert_eq!(pairing_winner(&tournament.rounds[0][0]), Some(1));
+
+    let id = database::save_tournament(&db, &record, &versions).await;
+    drive(db.clone(), id, 1, failing_schedule(false)).await;
+    let tournament = load(&db, id).await.unwrap();
+    assert!(tournament.record.finished_at.is_some());
+    assert_eq!(tournament.pairings[0].replays, MAX_REPLAYS);
+    assert_eq!(tournament.games[0].failed_seat, None);
+    assert_eq!(pairing_winner(&tournament.rounds[0][0]), Some(0));
+}
+
+#[tokio::test]
+async fn duplicate_participants_are_refused() {
+    let db = create_test_db().await;
+    let versions = participants(&db, 2).await;
+    let entered = vec![
+        versions[0].clone(),
+        versions[1].clone(),
+        versions[0].clone(),
+    ];
+    let settings = settings(Format::RoundRobin, 2, 1);
+    let refused = create(&db, "twice", settings, &entered).await.unwrap_err();
+    assert!(refused.contains("entered twice"), "{}", refused);
+    assert!(database::load_unfinished_tournaments(&db).await.is_empty());
+}
+
+#[tokio::test]
+pub async fn tournaments_show_their_bracket_and_standings() {
+    let db = create_test_db().await;
+    let owner = crate::database::save_user(&db, "owner", "hash")
+        .await
+        .unwrap();
+    let mut participants = vec![];
+    for name in ["first", "second", "third"] {
+        participants.push(
+            crate::database::save_bot_version(&db, owner, name, "python", "bot.py", "a", 7).await,
+        );
+    }
+    let record = crate::database::TournamentRecord {
+        tournament_id: 0,
+        name: "cup".to_string(),
+        format: "knockout".to_string(),
+        seats: 2,
+        games_per_pairing: 1,
+        rotation: "rotate".to_string(),
+        rounds: 2,
+        created_at: String::new(),
+        finished_at: None,
+    };
+    let id = crate::database::save_tournament(&db, &record, &participants).await;
+    let pairings = vec![(vec![0], vec![]), (vec![1, 2], vec![vec![1, 2]])];
+    crate::database::save_tournament_round(&db, id, 0, &pairings).await;
+    let game = crate::database::load_tournament_games(&db, id)
+        .await
+        .remove(0);
+    crate::database::save_tournament_game_result(&db, id, &game, Ok((Uuid::new_v4(), &[1, 0])))
+        .await;
+
+    let routes = crate::api::routes(db.clone());
+    let response = warp::test::request()
+        .path(&format!("/api/tournaments/{}", id))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    let tournament = &body["success"]["tournament"];
+    assert_eq!(tournament["state"], "running");
+    assert_eq!(tournament["participants"][2]["name"], "third");
+    let bracket = &tournament["bracket"][0]["pairings"];
+    assert_eq!(bracket[0]["bye"], true);
+    assert_eq!(bracket[1]["winner"], 2);
+    assert_eq!(bracket[1]["games"][0]["state"], "finished");
+    assert_eq!(bracket[1]["games"][0]["places"], serde_json::json!([1, 0]));
+    // Both are through to the next round, the winner of a game ahead of the bye
+    assert_eq!(tournament["standings"][0]["participant"], 2);
+    assert_eq!(tournament["standings"][1]["participant"], 0);
+    assert_eq!(tournament["standings"][1]["place"], 0);
+    assert_eq!(tournament["standings"][2]["place"], 2);
+
+    let response = warp::test::request()
+        .path("/api/tournaments/99")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 404);
+
+    let response = warp::test::request()
+        .method("POST")
+        .path("/api/admin/tournaments")
+        .json(&serde_json::json!({
+            "name": "open",
+            "format": "swiss",
+            "participants": [{"botId": 1, "version": 1}, {"botId": 2, "version": 1}]
+        }))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), 401);
+}
@@ -1,27 +1,250 @@
+pub mod server;
+pub mod spectator;
 #[cfg(test)]
 pub mod tests;
-mod websocket;
 
-pub use websocket::*;
-
-use futures_util::{stream::SplitSink, SinkExt, StreamExt};
-use log::{debug, error, info, trace, warn};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
 use splendor_arena::{models::*, SmallClientInfo};
+use sqlx::sqlite::SqlitePool;
 use std::collections::HashMap;
-use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::time::timeout;
+use tracing::{debug, error, info, trace, warn};
 use uuid::Uuid;
 use warp::Filter;
 
 use crate::api;
-use crate::constants::HOST_NAME;
-use crate::{queue as queue_funcs, queue::AsyncQueue};
+use crate::auth::{self, ApiKeyOwner};
+use crate::config;
+use crate::database;
+use crate::shutdown;
+use crate::{queue as queue_funcs, queue::AsyncQueue, queue::QueueFull};
+
+/// Requests an arena can send on top of the ones in splendor_arena,
+/// tried when a message is not a valid ArenaRequest
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ExtendedArenaRequest {
+    /// Name the bot sitting in each seat of the current game, in seat
+    /// order, sent after InitializeGame
+    RegisterPlayers(Vec<api::SeatRegistration>),
+}
+
+/// How the reason of an Updated::Failure starts when the updates were
+/// not accepted because the server is busy, and should be resent
+pub const BUSY_REASON: &str = "busy, retry after";
+
+/// How the warning sent to arenas when the server shuts down starts, the
+/// id of the arena's game follows so it can reconnect to it with Reconnect
+pub const GOING_AWAY_WARNING: &str = "server going away, reconnect with your id";
+
+/// How long after its last update a game can still be reconnected to
+/// across a restart
+const RESUMABLE_HOURS: i64 = 24;
 
-type GameLedger = Vec<SmallClientInfo>;
+/// What the server knows of an arena connection and the game it plays
+#[derive(Debug, Clone, Default)]
+pub struct ArenaState {
+    /// Whether the arena has sent a valid secret or api key
+    pub authenticated: bool,
+    /// Whether the arena has a game to send updates for, started with
+    /// InitializeGame or reconnected to
+    pub initialized: bool,
+    /// The game of the arena, nil until it is initialized
+    pub id: Uuid,
+    /// How many updates of the game were accepted, over every connection
+    /// the arena made to it
+    pub num_successful_updates: usize,
+    /// The account of the api key the arena authenticated with, None
+    /// for the shared secret
+    pub owner: Option<ApiKeyOwner>,
+}
+
+/// Who may reconnect to a game: the account of the api key that started
+/// it, or the key itself when it has no account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameOwner {
+    User(i64),
+    Key(i64),
+}
+
+impl GameOwner {
+    /// The owner of the games of an arena, None for the shared secret
+    pub fn of(state: &ArenaState) -> Option<GameOwner> {
+        let owner = state.owner.as_ref()?;
+        Some(match owner.user_id {
+            Some(user_id) => GameOwner::User(user_id),
+            None => GameOwner::Key(owner.key_id),
+        })
+    }
+}
+
+// The games being played, with who started them
 // May need a lock-free data structure here
-type Games = HashMap<Uuid, GameLedger>;
+type Games = HashMap<Uuid, Option<GameOwner>>;
 // Ditto
 pub type Arenas = HashMap<Uuid, ArenaState>;
 
 pub type AsyncGames = std::sync::Arc<std::sync::Mutex<Games>>;
 pub type AsyncArenas = std::sync::Arc<std::sync::Mutex<Arenas>>;
+
+/// Closes out the in-memory ledger and arena state of a game once it has
+/// declared game over, so any ids left behind in either map belong to
+/// games that were abandoned part way through
+pub fn close_game(id: Uuid, games: &AsyncGames, arenas: &AsyncArenas) -> Option<ArenaState> {
+    games.lock().unwrap().remove(&id);
+    arenas.lock().unwrap().remove(&id)
+}
+
+/// Whether the secret is the shared server secret of the configuration,
+/// no secret matches when it is not set
+pub fn verify(secret: &str) -> bool {
+    match config::get().shared_secret.as_deref() {
+        Some(expected) => auth::is_secret(secret, expected),
+        None => false,
+    }
+}
+
+/// Authenticates an arena with the shared server secret
+pub fn handle_authenticate(
+    secret: &str,
+    state: &mut ArenaState,
+) -> Result<GlobalServerResponse, GlobalServerResponse> {
+    if !verify(secret) {
+        warn!("[-] Arena failed to authenticate with the shared secret");
+        return Err(GlobalServerResponse::Authenticated(
+            Authenticated::Failure {
+                reason: "invalid secret".to_string(),
+            },
+        ));
+    }
+    debug!("[+] Arena authenticated with the shared secret");
+    state.authenticated = true;
+    Ok(GlobalServerResponse::Authenticated(Authenticated::Success))
+}
+
+/// Points the arena at a game it already started, so it can carry on
+/// sending updates for it. Refused if another key or account started it
+pub fn handle_reconnect(
+    id: &str,
+    state: &mut ArenaState,
+    games: AsyncGames,
+) -> Result<GlobalServerResponse, GlobalServerResponse> {
+    let failure = |reason: &str| {
+        GlobalServerResponse::Reconnected(Reconnected::Failure {
+            reason: reason.to_string(),
+        })
+    };
+    let id = Uuid::parse_str(id).map_err(|_| failure("invalid game id"))?;
+    let owner = match games.lock().unwrap().get(&id) {
+        Some(owner) => *owner,
+        None => return Err(failure("no game is being played with this id")),
+    };
+    if owner != GameOwner::of(state) {
+        warn!(
+            "[-] Arena tried to reconnect to game {} of another owner",
+            id
+        );
+        return Err(failure("not your game"));
+    }
+    state.id = id;
+    info!("[+] Reconnected to game {}", id);
+    Ok(GlobalServerResponse::Reconnected(Reconnected::Success))
+}
+
+/// Authenticates an arena with one of the api keys stored in the database,
+/// attaching the account that owns the key to the arena so its games can
+/// be attributed to it
+pub async fn handle_authenticate_key(
+    secret: &str,
+    state: &mut ArenaState,
+    db_pool: &SqlitePool,
+) -> Result<GlobalServerResponse, GlobalServerResponse> {
+    match auth::verify_api_key(db_pool, secret).await {
+        Ok(owner) => {
+            debug!(
+                "[+] Arena authenticated as {} (key {})",
+                owner.owner, owner.key_id
+            );
+            state.authenticated = true;
+            state.owner = Some(owner);
+            Ok(GlobalServerResponse::Authenticated(Authenticated::Success))
+        }
+        Err(reason) => {
+            warn!("[-] Arena failed to authenticate: {}", reason);
+            Err(GlobalServerResponse::Authenticated(
+                Authenticated::Failure { reason },
+            ))
+        }
+    }
+}
+
+/// Records the bot sitting in each seat of the arena's game, so replays
+/// can show who played and ratings go to the right bots. The bots belong
+/// to the account of the api key the arena authenticated with, if any
+pub async fn handle_register_players(
+    players: Vec<api::SeatRegistration>,
+    state: &ArenaState,
+    queue: &AsyncQueue,
+) -> Result<GlobalServerResponse, GlobalServerResponse> {
+    if !state.authenticated || !state.initialized {
+        return Err(GlobalServerResponse::Error(
+            "players can only be registered for an initialized game".to_string(),
+        ));
+    }
+
+    let players: Vec<_> = players.into_iter().map(Into::into).collect();
+    api::validate_seats(&players).map_err(GlobalServerResponse::Error)?;
+
+    let count = players.len();
+    let owner_id = state.owner.as_ref().and_then(|owner| owner.user_id);
+    queue_funcs::set_game_players(state.id, owner_id, players, queue).await;
+    debug!("[+] Registered {} players for game {}", count, state.id);
+    Ok(GlobalServerResponse::Info(format!(
+        "registered {} players",
+        count
+    )))
+}
+
+/// Tells an arena none of its latest game updates were accepted because
+/// the queue stayed full for its max wait, and how long to wait before
+/// resending them
+pub fn busy_response(state: &ArenaState, full: QueueFull) -> GlobalServerResponse {
+    GlobalServerResponse::Updated(Updated::Failure {
+        reason: format!("{} {} ms", BUSY_REASON, full.retry_after.as_millis()),
+        num_lifetime_updates: state.num_successful_updates,
+    })
+}
+
+/// Tells an arena the server is shutting down, and which id to reconnect
+/// with once it is back up
+pub fn going_away(state: &ArenaState) -> GlobalServerResponse {
+    if state.initialized {
+        GlobalServerResponse::Warning(format!("{}: {}", GOING_AWAY_WARNING, state.id))
+    } else {
+        GlobalServerResponse::Warning("server going away".to_string())
+    }
+}
+
+/// The ledgers of the games still being played when the server last
+/// stopped, so their arenas can reconnect after a restart
+pub async fn resumable_games(db_pool: &SqlitePool) -> Games {
+    let games: Games = database::load_resumable_games(db_pool, RESUMABLE_HOURS)
+        .await
+        .into_iter()
+        .map(|game| {
+            let owner = match (game.owner_id, game.key_id) {
+                (Some(user_id), _) => Some(GameOwner::User(user_id)),
+                (None, Some(key_id)) => Some(GameOwner::Key(key_id)),
+                (None, None) => None,
+            };
+            (game.id, owner)
+        })
+        .collect();
+    info!("[+] {} games can be reconnected to", games.len());
+    games
+}
+
+/// The link to watch a game on the website, handed to its arena
+pub fn game_url(slug: &str) -> String {
+    format!("{}/demo/{}", config::get().host_name, slug)
+}
@@ -0,0 +1,344 @@
+// The server arenas connect to, with the connection handler every arena
+// gets, serving the api next to it:
+//
+//      GET  /ws                      arenas, see handle_connection()
+//      GET  /api/games/{slug}/live   spectators, see spectator::route()
+//      POST /api                     a turn of a game, for the website
+//           /api/...                 the rest of the api, see api::routes()
+//      GET  /healthz, /readyz        liveness and readiness, see metrics::routes()
+//      GET  /metrics                 prometheus metrics, ditto
+//
+// Every connection is handled in its own span, see
+// logging::connection_span(), so its lines carry the game it plays.
+//
+// Every batch of updates an arena sends is queued, and published to the
+// spectators of its game once it is:
+//
+//      Arena -> handle_request -> Queue -> Database
+//                              \-> spectator::publish -> Spectators
+//
+// serve() is handed the queue started by main, so every connection sends
+// its updates to the same queue, and the queue is the one reported on by
+// queue::running().
+
+use super::*;
+use crate::config;
+use crate::logging;
+use crate::metrics;
+use std::net::SocketAddr;
+use tracing::Instrument;
+use warp::ws::{Message, WebSocket};
+use warp::{Rejection, Reply};
+
+/// How long an arena can stay silent before it is disconnected,
+/// arenas send a Heartbeat to keep an idle connection open
+const IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+/// What every arena connection shares
+#[derive(Clone)]
+pub struct Connections {
+    pub queue: AsyncQueue,
+    pub games: AsyncGames,
+    pub arenas: AsyncArenas,
+    pub spectators: spectator::AsyncSpectators,
+    pub db_pool: SqlitePool,
+}
+
+impl Connections {
+    pub fn new(db_pool: SqlitePool, queue: AsyncQueue) -> Self {
+        Connections {
+            queue,
+            games: AsyncGames::default(),
+            arenas: AsyncArenas::default(),
+            spectators: spectator::AsyncSpectators::default(),
+            db_pool,
+        }
+    }
+
+    /// What connections share after a restart, the games still being
+    /// played when the server stopped can be reconnected to
+    pub async fn resume(db_pool: SqlitePool, queue: AsyncQueue) -> Self {
+        let connections = Connections::new(db_pool, queue);
+        *connections.games.lock().unwrap() = resumable_games(&connections.db_pool).await;
+        connections
+    }
+}
+
+/// Authenticates an arena with one of the api keys stored in the database.
+/// The shared secret is only accepted when allow_shared_secret is set, as
+/// it belongs to no account and cannot be revoked
+pub async fn handle_authenticate_arena(
+    secret: &str,
+    state: &mut ArenaState,
+    connections: &Connections,
+) -> Result<GlobalServerResponse, GlobalServerResponse> {
+    if config::get().allow_shared_secret && verify(secret) {
+        return handle_authenticate(secret, state);
+    }
+    handle_authenticate_key(secret, state, &connections.db_pool).await
+}
+
+/// Reconnects an arena to its game, updates carry on from where the
+/// arena left off
+pub fn handle_resume(
+    id: &str,
+    state: &mut ArenaState,
+    connections: &Connections,
+) -> Result<GlobalServerResponse, GlobalServerResponse> {
+    if !state.authenticated {
+        return Err(GlobalServerResponse::Reconnected(Reconnected::Failure {
+            reason: "not authenticated".to_string(),
+        }));
+    }
+    let response = handle_reconnect(id, state, connections.games.clone())?;
+    logging::record_game(state.id);
+    if let Some(previous) = connections.arenas.lock().unwrap().get(&state.id) {
+        state.num_successful_updates = previous.num_successful_updates;
+    }
+    state.initialized = true;
+    Ok(response)
+}
+
+/// Starts the game of an authenticated arena, and hands it the link to
+/// watch it. The game is attributed to the account of the arena's api key
+pub async fn handle_initialize(
+    info: SmallClientInfo,
+    state: &mut ArenaState,
+    connections: &Connections,
+) -> Result<GlobalServerResponse, GlobalServerResponse> {
+    if !state.authenticated {
+        return Err(GlobalServerResponse::Initialized(Initialized::Failure {
+            reason: "not authenticated".to_string(),
+        }));
+    }
+    let closed = || {
+        GlobalServerResponse::Initialized(Initialized::Failure {
+            reason: "the server is shutting down".to_string(),
+        })
+    };
+    let id = queue_funcs::create_id(&connections.queue)
+        .await
+        .ok_or_else(closed)?;
+    if let Some(owner) = &state.owner {
+        queue_funcs::set_game_key(id, owner.key_id, &connections.queue).await;
+        if let Some(user_id) = owner.user_id {
+            queue_funcs::set_game_owner(id, user_id, &connections.queue).await;
+        }
+    }
+    let slug = queue_funcs::get_slug(id, &connections.queue)
+        .await
+        .ok_or_else(closed)?;
+    state.id = id;
+    state.initialized = true;
+    state.num_successful_updates = 0;
+    connections
+        .games
+        .lock()
+        .unwrap()
+        .insert(id, GameOwner::of(state));
+    connections.arenas.lock().unwrap().insert(id, state.clone());
+    info!(
+        "[+] Initialized game {} ({}) for {} players",
+        id,
+        slug,
+        info.players.len()
+    );
+    Ok(GlobalServerResponse::Initialized(Initialized::Success {
+        id: id.to_string(),
+        url: game_url(&slug),
+    }))
+}
+
+/// Queues the updates of the arena's game. While the queue is full
+/// nothing else is read from the arena, and if it stays full for the
+/// queue's max wait the arena is told to resend the updates later
+pub async fn handle_updates(
+    updates: &Vec<GameUpdate>,
+    state: &mut ArenaState,
+    connections: &Connections,
+) -> Result<GlobalServerResponse, GlobalServerResponse> {
+    if !state.initialized {
+        return Err(GlobalServerResponse::Updated(Updated::Failure {
+            reason: "the game is not initialized".to_string(),
+            num_lifetime_updates: state.num_successful_updates,
+        }));
+    }
+    let mut queue = connections.queue.clone();
+    queue_funcs::push_game_updates(state.id, updates, &mut queue)
+        .await
+        .map_err(|full| busy_response(state, full))?;
+    spectator::publish(state.id, updates, &connections.spectators);
+    state.num_successful_updates += updates.len();
+    if let Some(arena) = connections.arenas.lock().unwrap().get_mut(&state.id) {
+        arena.num_successful_updates = state.num_successful_updates;
+    }
+    Ok(GlobalServerResponse::Updated(Updated::Success {
+        num_lifetime_updates: state.num_successful_updates,
+    }))
+}
+
+/// Marks the arena's game as finished, it can no longer be reconnected to
+pub async fn handle_finish(
+    state: &mut ArenaState,
+    connections: &Connections,
+) -> Result<GlobalServerResponse, GlobalServerResponse> {
+    if !state.initialized {
+        return Err(GlobalServerResponse::Updated(Updated::Failure {
+            reason: "the game is not initialized".to_string(),
+            num_lifetime_updates: state.num_successful_updates,
+        }));
+    }
+    queue_funcs::set_game_over(state.id, &connections.queue).await;
+    close_game(state.id, &connections.games, &connections.arenas);
+    spectator::close(state.id, &connections.spectators);
+    state.initialized = false;
+    info!(
+        "[+] Game {} is over after {} updates",
+        state.id, state.num_successful_updates
+    );
+    Ok(GlobalServerResponse::Updated(Updated::GameOverAck))
+}
+
+/// Answers a single message of an arena, None if it needs no answer
+pub async fn handle_request(
+    text: &str,
+    state: &mut ArenaState,
+    connections: &Connections,
+) -> Option<GlobalServerResponse> {
+    let request = match serde_json::from_str::<ArenaRequest>(text) {
+        Ok(request) => request,
+        Err(error) => match serde_json::from_str::<ExtendedArenaRequest>(text) {
+            Ok(ExtendedArenaRequest::RegisterPlayers(players)) => {
+                let response = handle_register_players(players, state, &connections.queue).await;
+                return Some(response.unwrap_or_else(|response| response));
+            }
+            Err(_) => {
+                warn!("[-] Invalid request from arena: {}", error);
+                return Some(GlobalServerResponse::Error(format!(
+                    "invalid request: {}",
+                    error
+                )));
+            }
+        },
+    };
+    let response = match request {
+        ArenaRequest::Authenticate { secret } => {
+            handle_authenticate_arena(&secret, state, connections).await
+        }
+        ArenaRequest::Reconnect { id } => handle_resume(&id, state, connections),
+        ArenaRequest::InitializeGame { info } => handle_initialize(info, state, connections).await,
+        ArenaRequest::GameUpdates(updates) => handle_updates(&updates, state, connections).await,
+        ArenaRequest::GameOver { .. } => handle_finish(state, connections).await,
+        ArenaRequest::Heartbeat => {
+            trace!("[+] Heartbeat from arena");
+            return None;
+        }
+        ArenaRequest::DebugMessage(message) => {
+            debug!("[+] Arena says: {}", message);
+            return None;
+        }
+    };
+    Some(response.unwrap_or_else(|response| {
+        error!("[-] Failed request: {:?}", response);
+        response
+    }))
+}
+
+/// Answers the messages of an arena until it disconnects, or stays
+/// silent for longer than the IDLE_TIMEOUT. Once the server starts
+/// shutting down the arena is told which game to reconnect to later,
+/// and the server waits for that before exiting
+pub async fn handle_connection(ws: WebSocket, connections: Connections) {
+    let _guard = shutdown::guard();
+    let mut connection = metrics::ArenaConnection::new();
+    let (mut tx, mut rx) = ws.split();
+    let mut state = ArenaState::default();
+    loop {
+        let message = tokio::select! {
+            message = timeout(IDLE_TIMEOUT, rx.next()) => message,
+            _ = shutdown::triggered() => {
+                let warning = serde_json::to_string(&going_away(&state))
+                    .expect("Failed to serialize");
+                let _ = tx.send(Message::text(warning)).await;
+                let _ = tx.close().await;
+                break;
+            }
+        };
+        let message = match message {
+            Ok(Some(Ok(message))) => message,
+            Ok(Some(Err(error))) => {
+                warn!("[-] Arena connection failed: {}", error);
+                break;
+            }
+            Ok(None) => break,
+            Err(_) => {
+                debug!("[-] Arena timed out");
+                break;
+            }
+        };
+        if message.is_close() {
+            break;
+        }
+        let Ok(text) = message.to_str() else {
+            continue;
+        };
+        let response = handle_request(text, &mut state, &connections).await;
+        if state.authenticated {
+            connection.authenticated();
+        }
+        if let Some(response) = response {
+            let response = serde_json::to_string(&response).expect("Failed to serialize");
+            if tx.send(Message::text(response)).await.is_err() {
+                break;
+            }
+        }
+    }
+    // Spectators of a game the arena left unfinished would wait forever
+    if state.initialized {
+        spectator::close(state.id, &connections.spectators);
+    }
+    debug!("[-] Arena disconnected");
+}
+
+/// The routes of the server, arenas connect to /ws and the api and the
+/// health and metrics endpoints are served next to it
+pub fn routes(
+    connections: Connections,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let db_pool = connections.db_pool.clone();
+    let live = spectator::route(db_pool.clone(), connections.spectators.clone());
+    let ws = warp::path!("ws")
+        .and(warp::ws())
+        .and(warp::any().map(move || connections.clone()))
+        .map(|ws: warp::ws::Ws, connections: Connections| {
+            ws.on_upgrade(move |socket| {
+                handle_connection(socket, connections).instrument(logging::connection_span())
+            })
+        });
+
+    let with_db = {
+        let db_pool = db_pool.clone();
+        warp::any().map(move || db_pool.clone())
+    };
+    let load_game = warp::path!("api")
+        .and(warp::post())
+        .and(api::json_body())
+        .and(with_db)
+        .and_then(api::load_game);
+
+    ws.or(live)
+        .or(load_game)
+        .or(api::routes(db_pool.clone()))
+        .or(metrics::routes(db_pool))
+}
+
+/// Serves arenas on the address, returns once the server starts shutting
+/// down and stops accepting connections
+pub async fn serve(address: SocketAddr, db_pool: SqlitePool, queue: AsyncQueue) {
+    let connections = Connections::resume(db_pool, queue).await;
+    let (address, server) = warp::serve(routes(connections))
+        .bind_with_graceful_shutdown(address, shutdown::triggered());
+    info!("[+] Serving on {}", address);
+    server.await;
+    info!("[+] Stopped accepting connections");
+}
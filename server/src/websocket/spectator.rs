@@ -0,0 +1,205 @@
+// Read-only live feed of a game for browsers watching it in progress.
+//
+// Each game being watched has a broadcast channel, the arena connection
+// publishes every batch of updates to it right after handing the same
+// batch to the queue, and every spectator socket subscribed to the game
+// forwards them on:
+//
+//      Arena -> handle_updates -> queue::push_game_updates -> Database
+//                              \
+//                               -> spectator::publish -> Spectators
+//
+// A spectator first receives the last turn the arena published, or the
+// latest turn saved in the database if it published none since the server
+// started, then every turn after it as the arena reports it. The channel
+// is closed once the game is over or its arena disconnects.
+
+use futures_util::{SinkExt, StreamExt};
+use splendor_arena::models::GameUpdate;
+use sqlx::sqlite::SqlitePool;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tracing::{debug, error, trace, warn};
+use uuid::Uuid;
+use warp::ws::{Message, WebSocket};
+use warp::Filter;
+
+use crate::api::{DetailedGameUpdate, Response, Success};
+use crate::database;
+
+/// How many turns a slow spectator can fall behind before it starts
+/// skipping turns to catch up
+const SPECTATOR_BUFFER: usize = 64;
+
+/// The channel of a game, with the last turn published to it, which may
+/// not be saved in the database yet
+pub struct Feed {
+    sender: broadcast::Sender<DetailedGameUpdate>,
+    latest: Option<DetailedGameUpdate>,
+}
+
+impl Feed {
+    fn new() -> Self {
+        Feed {
+            sender: broadcast::channel(SPECTATOR_BUFFER).0,
+            latest: None,
+        }
+    }
+}
+
+pub type Spectators = HashMap<Uuid, Feed>;
+pub type AsyncSpectators = Arc<Mutex<Spectators>>;
+
+/// Sends the updates to everyone watching the game, and keeps the last
+/// one for the spectators that join later
+pub fn publish(id: Uuid, updates: &[GameUpdate], spectators: &AsyncSpectators) {
+    let mut spectators = spectators.lock().unwrap();
+    let feed = spectators.entry(id).or_insert_with(Feed::new);
+    for update in updates {
+        let update = DetailedGameUpdate::from_game_update(update);
+        let _ = feed.sender.send(update.clone());
+        feed.latest = Some(update);
+    }
+}
+
+/// Drops the channel of a game, disconnecting its spectators once
+/// they have received every turn already published
+pub fn close(id: Uuid, spectators: &AsyncSpectators) {
+    spectators.lock().unwrap().remove(&id);
+}
+
+/// Subscribes to the turns published after the last one, which is
+/// returned with the receiver so no turn is missed in between
+fn subscribe(
+    id: Uuid,
+    spectators: &AsyncSpectators,
+) -> (
+    broadcast::Receiver<DetailedGameUpdate>,
+    Option<DetailedGameUpdate>,
+) {
+    let mut spectators = spectators.lock().unwrap();
+    let feed = spectators.entry(id).or_insert_with(Feed::new);
+    (feed.sender.subscribe(), feed.latest.clone())
+}
+
+/// Removes the channel of a game once its last spectator has left, if
+/// no arena published to it
+fn unsubscribe(id: Uuid, spectators: &AsyncSpectators) {
+    let mut spectators = spectators.lock().unwrap();
+    if let Some(feed) = spectators.get(&id) {
+        if feed.sender.receiver_count() == 0 && feed.latest.is_none() {
+            spectators.remove(&id);
+        }
+    }
+}
+
+fn to_message(response: &Response) -> Message {
+    Message::text(serde_json::to_string(response).expect("Failed to serialize response"))
+}
+
+/// Forward the current turn and every new turn of a game to a spectator
+/// until either side goes away
+async fn watch(socket: WebSocket, slug: String, db_pool: SqlitePool, spectators: AsyncSpectators) {
+    let (mut outgoing, mut incoming) = socket.split();
+
+    let id = match database::load_uuid_from_slug(&db_pool, &slug).await {
+        Ok(id) => id,
+        Err(_) => {
+            let reason = format!("no game found for {}", slug);
+            let _ = outgoing
+                .send(to_message(&Response::Failure { reason }))
+                .await;
+            let _ = outgoing.close().await;
+            return;
+        }
+    };
+    debug!("[+] Spectator joined game {}", id);
+
+    // The turns published but not saved yet are only known in memory
+    let (mut receiver, published) = subscribe(id, &spectators);
+    let mut last_sent = None;
+    let mut seats = database::load_game_players(&db_pool, id).await;
+    let latest = match published {
+        Some(update) => Some(update),
+        None => database::load_latest_game_update(&db_pool, id)
+            .await
+            .unwrap_or_else(|error| {
+                error!("[!] Cannot show spectators game {}: {}", id, error);
+                None
+            })
+            .map(|update| DetailedGameUpdate::from_game_update(&update)),
+    };
+    if let Some(update) = latest {
+        let update = update.with_seats(&seats);
+        last_sent = Some(update.turn_number);
+        let message = to_message(&Response::Success(Success::GameUpdate(update)));
+        if outgoing.send(message).await.is_err() {
+            unsubscribe(id, &spectators);
+            return;
+        }
+    }
+
+    loop {
+        tokio::select! {
+            update = receiver.recv() => match update {
+                Ok(update) => {
+                    if last_sent.is_some_and(|turn| update.turn_number <= turn) {
+                        continue;
+                    }
+                    last_sent = Some(update.turn_number);
+                    // The arena may register its players after we joined
+                    if seats.is_empty() {
+                        seats = database::load_game_players(&db_pool, id).await;
+                    }
+                    let update = update.with_seats(&seats);
+                    trace!("[+] Sending turn {} of {} to spectator", update.turn_number, id);
+                    let message = to_message(&Response::Success(Success::GameUpdate(update)));
+                    if outgoing.send(message).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("[-] Spectator of {} fell behind, skipped {} turns", id, skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    let _ = outgoing.close().await;
+                    break;
+                }
+            },
+            // Spectators have nothing to say, only watch for them leaving
+            message = incoming.next() => match message {
+                Some(Ok(message)) if !message.is_close() => continue,
+                _ => break,
+            },
+        }
+    }
+
+    drop(receiver);
+    unsubscribe(id, &spectators);
+    debug!("[-] Spectator left game {}", id);
+}
+
+fn with_spectators(
+    spectators: AsyncSpectators,
+) -> impl Filter<Extract = (AsyncSpectators,), Error = Infallible> + Clone {
+    warp::any().map(move || spectators.clone())
+}
+
+/// GET /api/games/{slug}/live
+/// upgrade to a read-only websocket that streams the game as it is played
+pub fn route(
+    db_pool: SqlitePool,
+    spectators: AsyncSpectators,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "games" / String / "live")
+        .and(warp::ws())
+        .and(warp::any().map(move || db_pool.clone()))
+        .and(with_spectators(spectators))
+        .map(
+            |slug: String, ws: warp::ws::Ws, db_pool: SqlitePool, spectators: AsyncSpectators| {
+                ws.on_upgrade(move |socket| watch(socket, slug, db_pool, spectators))
+            },
+        )
+}
@@ -1,54 +1,26 @@
 use super::*;
-use crate::queue::QueueUpdate;
+use crate::database::tests::{create_test_db, default_game_update, simple_save_game_update};
+use crate::queue::{QueueUpdate, Queued};
 use splendor_arena::*;
 use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::Receiver;
 
 pub struct MockEnv {
-    pub queue_reciever: UnboundedReceiver<QueueUpdate>,
+    pub queue_reciever: Receiver<Queued>,
     pub queue_sender: AsyncQueue,
     pub games: AsyncGames,
     pub arenas: AsyncArenas,
     pub ids: Vec<Uuid>,
 }
 
-fn default_game_update() -> GameUpdate {
-    GameUpdate {
-        update_num: 0,
-        info: SmallClientInfo {
-            board: Board {
-                deck_counts: [16, 26, 36],
-                available_cards: vec![vec![0, 1, 2, 3], vec![5, 6, 7, 8], vec![10, 11, 12]],
-                nobles: vec![0, 1, 2, 3, 4],
-                gems: Gems::empty(),
-            },
-            players: vec![
-                PlayerPublicInfo {
-                    gems: Gems::empty(),
-                    developments: Cost::from_gems(&Gems::empty()),
-                    num_reserved: 0,
-                    points: 0,
-                },
-                PlayerPublicInfo {
-                    gems: Gems::empty(),
-                    developments: Cost::from_gems(&Gems::empty()),
-                    num_reserved: 0,
-                    points: 0,
-                },
-                PlayerPublicInfo {
-                    gems: Gems::empty(),
-                    developments: Cost::from_gems(&Gems::empty()),
-                    num_reserved: 0,
-                    points: 0,
-                },
-            ],
-            current_player_num: 0,
-        },
-    }
+/// A queue nothing reads from, for handlers whose queued updates are
+/// not looked at
+fn mock_queue() -> AsyncQueue {
+    crate::queue::with_capacity(16).0
 }
 
 async fn create_mock_env() -> MockEnv {
-    let (qtx, qrx) = tokio::sync::mpsc::unbounded_channel();
+    let (qtx, qrx) = crate::queue::with_capacity(16);
     let games = std::sync::Arc::new(std::sync::Mutex::new(Games::new()));
     let arenas = std::sync::Arc::new(std::sync::Mutex::new(Arenas::new()));
 
@@ -62,6 +34,7 @@ async fn create_mock_env() -> MockEnv {
         initialized: false,
         id: id0,
         num_successful_updates: 0,
+        owner: None,
     };
 
     let arena_authenticated = ArenaState {
@@ -69,6 +42,7 @@ async fn create_mock_env() -> MockEnv {
         initialized: false,
         id: id1,
         num_successful_updates: 0,
+        owner: None,
     };
 
     let arena_initialized = ArenaState {
@@ -76,6 +50,7 @@ async fn create_mock_env() -> MockEnv {
         initialized: true,
         id: id2,
         num_successful_updates: 0,
+        owner: None,
     };
 
     let arena_with_updates = ArenaState {
@@ -83,6 +58,7 @@ async fn create_mock_env() -> MockEnv {
         initialized: true,
         id: id3,
         num_successful_updates: 5,
+        owner: None,
     };
 
     arenas.lock().unwrap().insert(id0, arena_default);
@@ -90,10 +66,10 @@ async fn create_mock_env() -> MockEnv {
     arenas.lock().unwrap().insert(id2, arena_authenticated);
     arenas.lock().unwrap().insert(id3, arena_with_updates);
 
-    games.lock().unwrap().insert(id0, vec![]);
-    games.lock().unwrap().insert(id1, vec![]);
-    games.lock().unwrap().insert(id2, vec![]);
-    games.lock().unwrap().insert(id3, vec![]);
+    games.lock().unwrap().insert(id0, None);
+    games.lock().unwrap().insert(id1, None);
+    games.lock().unwrap().insert(id2, None);
+    games.lock().unwrap().insert(id3, None);
 
     MockEnv {
         queue_reciever: qrx,
@@ -104,123 +80,186 @@ async fn create_mock_env() -> MockEnv {
     }
 }
 
-#[test]
-pub fn empty_api_key_does_not_authenticate() {
-    let secret = "";
-    assert_eq!(
-        verify(secret),
-        false,
-        "an empty secret should not authenticate"
-    );
-    let message = handle_authenticate(secret, &mut ArenaState::default());
+#[tokio::test]
+pub async fn empty_api_key_does_not_authenticate() {
+    let db = create_test_db().await;
+    let connections = server::Connections::new(db, mock_queue());
+    assert!(!verify(""), "an empty secret should not authenticate");
+    let mut state = ArenaState::default();
+    let message = server::handle_authenticate_arena("", &mut state, &connections).await;
     assert!(message.is_err(), "expected error on empty secret, got Ok");
+    assert!(!state.authenticated);
 }
 
-#[test]
-pub fn correct_api_key_authenticates() {
-    let secret = std::env::var("TEST_API_KEY")
-        .expect("TEST_API_KEY must be set to a valid secret for this test to run");
-    assert_eq!(
-        verify(&secret),
-        true,
-        "a correct secret should authenticate"
-    );
-    let message = handle_authenticate(&secret, &mut ArenaState::default());
-    let message = message.expect("unexpected error on correct secret authentication, expected Ok");
+#[tokio::test]
+pub async fn correct_api_key_authenticates() {
+    let db = create_test_db().await;
+    let secret = minted_key(&db).await;
+    let connections = server::Connections::new(db, mock_queue());
+    let mut state = ArenaState::default();
+    let message = server::handle_authenticate_arena(&secret, &mut state, &connections)
+        .await
+        .expect("unexpected error on correct secret authentication, expected Ok");
     assert!(matches!(
         message,
         GlobalServerResponse::Authenticated(Authenticated::Success)
     ));
 }
 
-#[test]
-pub fn correct_api_key_updates_state() {
-    let secret =
-        std::env::var("TEST_API_KEY").expect("TEST_API_KEY must be set for this test to run");
-    assert_eq!(
-        verify(&secret),
-        true,
-        "TEST_API_KEY secret should authenticate"
-    );
+#[tokio::test]
+pub async fn correct_api_key_updates_state() {
+    let db = create_test_db().await;
+    let secret = minted_key(&db).await;
+    let connections = server::Connections::new(db, mock_queue());
     let mut state = ArenaState::default();
-    handle_authenticate(&secret, &mut state)
+    server::handle_authenticate_arena(&secret, &mut state, &connections)
+        .await
         .expect("unexpected error on correct secret authentication, expected Ok");
 
-    assert_eq!(
-        state.authenticated, true,
-        "expected state to be authenticated"
-    );
+    assert!(state.authenticated, "expected state to be authenticated");
+    let owner = state
+        .owner
+        .expect("expected the owner of the key to be attached");
+    assert_eq!(owner.owner, "owner");
 }
 
-#[test]
-pub fn handle_reconnect_fails_on_invalid_uuid() {
-    let mut state = ArenaState::default();
-    let games = Arc::new(Mutex::new(Games::new()));
-    let message = handle_reconnect("this_id_does_not_exist", &mut state, games);
+#[tokio::test]
+pub async fn handle_resume_fails_on_invalid_uuid() {
+    let db = create_test_db().await;
+    let connections = server::Connections::new(db, mock_queue());
+    let mut state = ArenaState {
+        authenticated: true,
+        ..ArenaState::default()
+    };
+    let message = server::handle_resume("this_id_does_not_exist", &mut state, &connections);
     assert!(message.is_err(), "expected error on invalid id, got Ok");
 }
 
-#[test]
-pub fn handle_reconnect_fails_on_unknown_uuid() {
-    let mut state = ArenaState::default();
-    let games = Arc::new(Mutex::new(Games::new()));
+#[tokio::test]
+pub async fn handle_resume_fails_on_unknown_uuid() {
+    let db = create_test_db().await;
+    let connections = server::Connections::new(db, mock_queue());
+    let mut state = ArenaState {
+        authenticated: true,
+        ..ArenaState::default()
+    };
     let uuid = Uuid::new_v4();
-    let message = handle_reconnect(&uuid.to_string(), &mut state, games);
+    let message = server::handle_resume(&uuid.to_string(), &mut state, &connections);
     assert!(message.is_err(), "expected error on unknown id, got Ok");
 }
 
 #[tokio::test]
-pub async fn handle_reconnect_succeeds_on_valid_id() {
+pub async fn handle_resume_succeeds_on_valid_id() {
     let mock = create_mock_env().await;
+    let db = create_test_db().await;
+    let connections = server::Connections {
+        games: mock.games.clone(),
+        arenas: mock.arenas.clone(),
+        ..server::Connections::new(db, mock.queue_sender.clone())
+    };
 
     for id in mock.ids {
-        let arena = mock.arenas.lock().unwrap().get(&id).unwrap().clone();
-        let message = handle_reconnect(&id.to_string(), &mut arena.clone(), mock.games.clone());
-        let message = message.expect("unexpected error on valid id, expected Ok");
-        println!("{:?}", message);
+        let mut state = ArenaState {
+            authenticated: true,
+            ..ArenaState::default()
+        };
+        let message = server::handle_resume(&id.to_string(), &mut state, &connections)
+            .expect("unexpected error on valid id, expected Ok");
         assert!(matches!(
             message,
             GlobalServerResponse::Reconnected(Reconnected::Success)
         ));
+        assert_eq!(state.id, id);
+        assert!(state.initialized, "expected the game to be resumed");
+        let previous = mock.arenas.lock().unwrap().get(&id).unwrap().clone();
+        assert_eq!(
+            state.num_successful_updates, previous.num_successful_updates,
+            "expected updates to carry on from where the arena left off"
+        );
     }
 }
 
 #[tokio::test]
-pub async fn handle_game_update_adds_to_queue() {
+pub async fn handle_updates_adds_to_queue() {
     let mock = create_mock_env().await;
-    let id = mock.ids[0];
-    let state = mock.arenas.lock().unwrap().get(&id).unwrap().clone();
+    let db = create_test_db().await;
+    let connections = server::Connections::new(db, mock.queue_sender.clone());
+    let id = mock.ids[3];
+    let mut state = mock.arenas.lock().unwrap().get(&id).unwrap().clone();
 
-    handle_game_update(
-        &mut state.clone(),
-        mock.queue_sender.clone(),
-        &vec![default_game_update()],
-    )
-    .expect("unexpected error on game update, expected Ok");
+    for _ in 0..3 {
+        server::handle_updates(&vec![default_game_update()], &mut state, &connections)
+            .await
+            .expect("unexpected error on game update, expected Ok");
+    }
+    assert_eq!(state.num_successful_updates, 8);
 
     let mut qrx = mock.queue_reciever;
-    qrx.try_recv()
-        .expect("expected game update to be added to the queue");
+    for _ in 0..3 {
+        match qrx.try_recv() {
+            Ok(Queued {
+                update: QueueUpdate::AddGameInfo { id: queued, .. },
+                ..
+            }) => assert_eq!(queued, id),
+            _ => panic!("expected the game update to be added to the queue"),
+        }
+    }
+    assert!(
+        qrx.try_recv().is_err(),
+        "expected no more messages in the queue"
+    );
+}
 
-    handle_game_update(
-        &mut state.clone(),
-        mock.queue_sender.clone(),
-        &vec![default_game_update()],
-    )
-    .expect("unexpected error on game update, expected Ok");
+#[tokio::test]
+pub async fn handle_updates_refuses_uninitialized_game() {
+    let mock = create_mock_env().await;
+    let db = create_test_db().await;
+    let connections = server::Connections::new(db, mock.queue_sender.clone());
+    let mut state = mock
+        .arenas
+        .lock()
+        .unwrap()
+        .get(&mock.ids[0])
+        .unwrap()
+        .clone();
 
-    handle_game_update(
-        &mut state.clone(),
-        mock.queue_sender.clone(),
-        &vec![default_game_update()],
-    )
-    .expect("unexpected error on game update, expected Ok");
+    let message =
+        server::handle_updates(&vec![default_game_update()], &mut state, &connections).await;
+    assert!(message.is_err(), "expected error on uninitialized game");
+    let mut qrx = mock.queue_reciever;
+    assert!(qrx.try_recv().is_err(), "expected nothing to be queued");
+}
 
-    qrx.try_recv()
-        .expect("expected message to be added to the queue (twice)");
-    qrx.try_recv()
-        .expect("expected message to be added to the queue (thrice)");
+#[tokio::test]
+pub async fn handle_finish_updates_queue() {
+    let mock = create_mock_env().await;
+    let db = create_test_db().await;
+    let connections = server::Connections {
+        games: mock.games.clone(),
+        arenas: mock.arenas.clone(),
+        ..server::Connections::new(db, mock.queue_sender.clone())
+    };
+    let id = mock.ids[3];
+    let mut state = mock.arenas.lock().unwrap().get(&id).unwrap().clone();
 
+    let message = server::handle_finish(&mut state, &connections)
+        .await
+        .expect("unexpected error on game over, expected Ok");
+    assert!(matches!(
+        message,
+        GlobalServerResponse::Updated(Updated::GameOverAck)
+    ));
+    assert!(!state.initialized);
+    assert!(!mock.games.lock().unwrap().contains_key(&id));
+
+    let mut qrx = mock.queue_reciever;
+    match qrx.try_recv() {
+        Ok(Queued {
+            update: QueueUpdate::SetGameOver { id: queued },
+            ..
+        }) => assert_eq!(queued, id),
+        _ => panic!("expected the game over to be added to the queue"),
+    }
     assert!(
         qrx.try_recv().is_err(),
         "expected no more messages in the queue"
@@ -228,20 +267,687 @@ pub async fn handle_game_update_adds_to_queue() {
 }
 
 #[tokio::test]
-pub async fn handle_game_over_updates_queue() {
+pub async fn close_game_removes_only_finished_game() {
     let mock = create_mock_env().await;
-    let id = mock.ids[0];
-    let state = mock.arenas.lock().unwrap().get(&id).unwrap().clone();
+    let finished = mock.ids[3];
 
-    handle_game_over(&mut state.clone(), mock.queue_sender.clone())
-        .expect("unexpected error on game over, expected Ok");
+    let state = close_game(finished, &mock.games, &mock.arenas)
+        .expect("expected the arena state of the finished game to be returned");
+    assert_eq!(state.num_successful_updates, 5);
 
-    let mut qrx = mock.queue_reciever;
-    qrx.try_recv()
-        .expect("expected one message to be added to the queue, but queue is empty");
+    assert!(!mock.games.lock().unwrap().contains_key(&finished));
+    assert!(!mock.arenas.lock().unwrap().contains_key(&finished));
+
+    for id in &mock.ids[..3] {
+        assert!(
+            mock.games.lock().unwrap().contains_key(id),
+            "expected unfinished games to keep their ledger"
+        );
+        assert!(
+            mock.arenas.lock().unwrap().contains_key(id),
+            "expected unfinished games to keep their arena state"
+        );
+    }
+}
+
+#[tokio::test]
+pub async fn stored_api_key_authenticates_as_its_owner() {
+    let db = create_test_db().await;
+    let key = crate::auth::generate_api_key();
+    let key_id =
+        crate::database::save_api_key(&db, &crate::auth::hash_api_key(&key), "owner", None, "")
+            .await;
+
+    let mut state = ArenaState::default();
+    let message = handle_authenticate_key(&key, &mut state, &db)
+        .await
+        .expect("unexpected error on stored api key, expected Ok");
+    assert!(matches!(
+        message,
+        GlobalServerResponse::Authenticated(Authenticated::Success)
+    ));
+    let owner = state
+        .owner
+        .clone()
+        .expect("expected the key owner on the state");
+    assert_eq!(owner.key_id, key_id);
+    assert_eq!(owner.owner, "owner");
+    assert!(state.authenticated, "expected state to be authenticated");
+}
+
+#[tokio::test]
+pub async fn revoked_or_unknown_api_key_does_not_authenticate() {
+    let db = create_test_db().await;
+    let key = crate::auth::generate_api_key();
+    let key_id =
+        crate::database::save_api_key(&db, &crate::auth::hash_api_key(&key), "owner", None, "")
+            .await;
+    crate::database::revoke_api_key(&db, key_id).await;
 
+    let mut state = ArenaState::default();
+    let message = handle_authenticate_key(&key, &mut state, &db).await;
+    assert!(message.is_err(), "expected error on revoked key, got Ok");
+
+    let unknown = crate::auth::generate_api_key();
+    let message = handle_authenticate_key(&unknown, &mut state, &db).await;
+    assert!(message.is_err(), "expected error on unknown key, got Ok");
+    assert!(
+        !state.authenticated,
+        "expected state to stay unauthenticated"
+    );
+    assert!(state.owner.is_none(), "expected no owner to be attached");
+}
+
+#[tokio::test]
+pub async fn games_uploaded_with_a_user_key_are_attributed_to_the_user() {
+    let db = create_test_db().await;
+    let password_hash = crate::auth::hash_password("correct horse").await;
+    let user_id = crate::database::save_user(&db, "owner", &password_hash)
+        .await
+        .expect("could not create user");
+    let key = crate::auth::generate_api_key();
+    crate::database::save_api_key(
+        &db,
+        &crate::auth::hash_api_key(&key),
+        "owner",
+        Some(user_id),
+        "",
+    )
+    .await;
+
+    let mut state = ArenaState::default();
+    handle_authenticate_key(&key, &mut state, &db)
+        .await
+        .expect("unexpected error on stored api key, expected Ok");
+    assert_eq!(state.owner.and_then(|owner| owner.user_id), Some(user_id));
+
+    let id = crate::database::generate_new_id(&db).await;
+    crate::database::save_game_owner(&db, id, user_id).await;
+
+    let filter = crate::database::GameFilter {
+        owner_id: Some(user_id),
+        limit: 10,
+        ..Default::default()
+    };
+    let games = crate::database::list_games(&db, &filter).await;
+    assert_eq!(games.len(), 1);
+    assert_eq!(games[0].uuid, id.to_string());
+    assert_eq!(games[0].owner.as_deref(), Some("owner"));
+}
+
+#[tokio::test]
+pub async fn register_players_queues_seats_for_initialized_game() {
+    let mock = create_mock_env().await;
+    let seat = |name: &str| api::SeatRegistration {
+        name: name.to_string(),
+        version: Some("v1".to_string()),
+        owner: None,
+    };
+    let owner = ApiKeyOwner {
+        key_id: 1,
+        owner: "owner".to_string(),
+        user_id: Some(7),
+    };
+
+    let uninitialized = mock
+        .arenas
+        .lock()
+        .unwrap()
+        .get(&mock.ids[0])
+        .unwrap()
+        .clone();
+    let message = handle_register_players(
+        vec![seat("a"), seat("b")],
+        &uninitialized,
+        &mock.queue_sender,
+    )
+    .await;
+    assert!(
+        message.is_err(),
+        "expected error on uninitialized game, got Ok"
+    );
+
+    let state = ArenaState {
+        owner: Some(owner),
+        ..mock
+            .arenas
+            .lock()
+            .unwrap()
+            .get(&mock.ids[3])
+            .unwrap()
+            .clone()
+    };
+    let message = handle_register_players(vec![seat("a")], &state, &mock.queue_sender).await;
+    assert!(message.is_err(), "expected error on a single seat, got Ok");
+
+    handle_register_players(vec![seat("a"), seat("b")], &state, &mock.queue_sender)
+        .await
+        .expect("unexpected error on registering players, expected Ok");
+
+    let mut qrx = mock.queue_reciever;
+    match qrx.try_recv() {
+        Ok(Queued {
+            update:
+                QueueUpdate::SetGamePlayers {
+                    id,
+                    owner_id,
+                    players,
+                },
+            ..
+        }) => {
+            assert_eq!(id, state.id);
+            assert_eq!(owner_id, Some(7));
+            assert_eq!(players.len(), 2);
+            assert_eq!(players[1].name, "b");
+            assert_eq!(players[1].version.as_deref(), Some("v1"));
+        }
+        _ => panic!("expected the players to be added to the queue"),
+    }
     assert!(
         qrx.try_recv().is_err(),
         "expected no more messages in the queue"
     );
 }
+
+#[tokio::test]
+pub async fn game_updates_are_refused_as_busy_when_the_queue_stays_full() {
+    let db = create_test_db().await;
+    let (queue, mut qrx) = crate::queue::with_capacity(16);
+    let queue = queue.with_max_wait(std::time::Duration::from_millis(50));
+    let capacity = queue.max_capacity();
+    let connections = server::Connections::new(db, queue);
+    let mut state = ArenaState {
+        authenticated: true,
+        initialized: true,
+        id: Uuid::new_v4(),
+        num_successful_updates: 0,
+        owner: None,
+    };
+
+    server::handle_updates(
+        &vec![default_game_update(); capacity - 1],
+        &mut state,
+        &connections,
+    )
+    .await
+    .expect("unexpected error on game update, expected Ok");
+
+    // Two updates do not fit before the wait is over, so neither of them
+    // is queued
+    let message = server::handle_updates(&vec![default_game_update(); 2], &mut state, &connections)
+        .await
+        .expect_err("expected the arena to be told the queue is busy");
+    let busy = busy_response(
+        &state,
+        QueueFull {
+            retry_after: crate::queue::RETRY_AFTER,
+        },
+    );
+    assert_eq!(
+        serde_json::to_string(&message).unwrap(),
+        serde_json::to_string(&busy).unwrap()
+    );
+
+    let mut queued = 0;
+    while qrx.try_recv().is_ok() {
+        queued += 1;
+    }
+    assert_eq!(queued, capacity - 1);
+    server::handle_updates(&vec![default_game_update(); 2], &mut state, &connections)
+        .await
+        .expect("expected the updates to be accepted once there is room");
+    assert_eq!(state.num_successful_updates, capacity + 1);
+}
+
+#[tokio::test]
+pub async fn game_updates_wait_for_room_in_the_queue() {
+    let db = create_test_db().await;
+    let (queue, mut qrx) = crate::queue::with_capacity(16);
+    let capacity = queue.max_capacity();
+    let connections = server::Connections::new(db, queue);
+    let mut state = ArenaState {
+        authenticated: true,
+        initialized: true,
+        id: Uuid::new_v4(),
+        num_successful_updates: 0,
+        owner: None,
+    };
+    server::handle_updates(
+        &vec![default_game_update(); capacity],
+        &mut state,
+        &connections,
+    )
+    .await
+    .expect("unexpected error on game update, expected Ok");
+
+    let updates = vec![default_game_update(); 2];
+    let push = server::handle_updates(&updates, &mut state, &connections);
+    tokio::pin!(push);
+    assert!(
+        tokio::time::timeout(std::time::Duration::from_millis(50), &mut push)
+            .await
+            .is_err(),
+        "expected the updates to wait for room in the queue"
+    );
+    qrx.try_recv().unwrap();
+    qrx.try_recv().unwrap();
+    let message = push
+        .await
+        .expect("expected the updates to be accepted once there is room");
+    assert!(matches!(
+        message,
+        GlobalServerResponse::Updated(Updated::Success { num_lifetime_updates })
+            if num_lifetime_updates == capacity + 2
+    ));
+}
+
+#[tokio::test]
+pub async fn games_are_not_initialized_once_the_queue_is_closed() {
+    let db = create_test_db().await;
+    let connections = server::Connections::new(db, mock_queue());
+    let mut state = ArenaState {
+        authenticated: true,
+        ..ArenaState::default()
+    };
+    let info = default_game_update().info;
+    let message = server::handle_initialize(info, &mut state, &connections).await;
+    assert!(matches!(
+        message,
+        Err(GlobalServerResponse::Initialized(
+            Initialized::Failure { .. }
+        ))
+    ));
+    assert!(!state.initialized);
+    assert!(connections.games.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+pub async fn unfinished_games_can_be_reconnected_to_after_a_restart() {
+    let db = create_test_db().await;
+    let playing = crate::database::generate_new_id(&db).await;
+    let finished = crate::database::generate_new_id(&db).await;
+    for id in [playing, finished] {
+        simple_save_game_update(&db, default_game_update(), id).await;
+    }
+    crate::database::save_game_over(&db, finished).await;
+
+    let games = Arc::new(Mutex::new(resumable_games(&db).await));
+    let mut state = ArenaState::default();
+    handle_reconnect(&playing.to_string(), &mut state, games.clone())
+        .expect("expected the unfinished game to be reconnected to");
+    assert!(handle_reconnect(&finished.to_string(), &mut state, games).is_err());
+}
+
+#[test]
+pub fn going_away_tells_arenas_which_id_to_reconnect_with() {
+    let state = ArenaState {
+        initialized: true,
+        id: Uuid::new_v4(),
+        ..ArenaState::default()
+    };
+    match going_away(&state) {
+        GlobalServerResponse::Warning(message) => {
+            assert!(message.starts_with(GOING_AWAY_WARNING));
+            assert!(message.ends_with(&state.id.to_string()));
+        }
+        other => panic!("expected a warning, got {:?}", other),
+    }
+}
+
+/// Sends a request over the arena's websocket and waits for the answer
+async fn exchange(
+    arena: &mut warp::test::WsClient,
+    request: &ArenaRequest,
+) -> GlobalServerResponse {
+    arena
+        .send_text(serde_json::to_string(request).unwrap())
+        .await;
+    let message = arena
+        .recv()
+        .await
+        .expect("expected an answer from the server");
+    serde_json::from_str(message.to_str().unwrap()).expect("expected a server response")
+}
+
+/// Mints an api key for an arena connecting through the server
+async fn minted_key(db: &SqlitePool) -> String {
+    let key = crate::auth::generate_api_key();
+    crate::database::save_api_key(db, &crate::auth::hash_api_key(&key), "owner", None, "").await;
+    key
+}
+
+#[tokio::test]
+pub async fn the_shared_secret_is_refused_by_default() {
+    let db = create_test_db().await;
+    let (queue, _receiver) = crate::queue::with_capacity(16);
+    let routes = server::routes(server::Connections::new(db, queue));
+    let mut arena = warp::test::ws()
+        .path("/ws")
+        .handshake(routes)
+        .await
+        .expect("expected the arena to connect");
+    let secret = "the shared secret".to_string();
+    assert!(!verify(&secret), "expected no shared secret to be set");
+    let message = exchange(&mut arena, &ArenaRequest::Authenticate { secret }).await;
+    assert!(matches!(
+        message,
+        GlobalServerResponse::Authenticated(Authenticated::Failure { .. })
+    ));
+}
+
+#[tokio::test]
+pub async fn server_boots_with_the_started_queue() {
+    let db = create_test_db().await;
+    let readyz = || warp::test::request().path("/readyz");
+    let response = readyz().reply(&crate::metrics::routes(db.clone())).await;
+    assert_eq!(
+        response.status(),
+        warp::http::StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(response.body(), r#"{"database":true,"queue":false}"#);
+
+    let spool = std::env::temp_dir().join(format!("stourney-boot-{}", Uuid::new_v4()));
+    let queue = crate::queue::start_in(&db, 64, &spool).await;
+    assert!(crate::queue::running().is_some());
+    let response = readyz().reply(&crate::metrics::routes(db.clone())).await;
+    assert_eq!(response.status(), warp::http::StatusCode::OK);
+
+    let connections = server::Connections::new(db.clone(), queue);
+    let routes = server::routes(connections.clone());
+    let mut arena = warp::test::ws()
+        .path("/ws")
+        .handshake(routes)
+        .await
+        .expect("expected the arena to connect");
+    let secret = minted_key(&db).await;
+    let message = exchange(&mut arena, &ArenaRequest::Authenticate { secret }).await;
+    assert!(matches!(
+        message,
+        GlobalServerResponse::Authenticated(Authenticated::Success)
+    ));
+
+    let info = default_game_update().info;
+    let id = match exchange(&mut arena, &ArenaRequest::InitializeGame { info }).await {
+        GlobalServerResponse::Initialized(Initialized::Success { id, .. }) => {
+            Uuid::parse_str(&id).unwrap()
+        }
+        other => panic!("expected the game to be initialized, got {:?}", other),
+    };
+    let updates = vec![default_game_update()];
+    let message = exchange(&mut arena, &ArenaRequest::GameUpdates(updates)).await;
+    assert!(matches!(
+        message,
+        GlobalServerResponse::Updated(Updated::Success {
+            num_lifetime_updates: 1
+        })
+    ));
+    let message = exchange(&mut arena, &ArenaRequest::GameOver { total_updates: 1 }).await;
+    assert!(matches!(
+        message,
+        GlobalServerResponse::Updated(Updated::GameOverAck)
+    ));
+    assert!(!connections.games.lock().unwrap().contains_key(&id));
+    assert!(!connections.arenas.lock().unwrap().contains_key(&id));
+
+    // Answered only once the updates queued before it are saved
+    let queue = crate::queue::running().expect("expected the queue to still be running");
+    crate::queue::get_slug(id, &queue).await.unwrap();
+    let saved = crate::database::load_game_updates(&db, id, None, None)
+        .await
+        .unwrap();
+    assert_eq!(saved.len(), 1);
+    let _ = std::fs::remove_dir_all(spool);
+}
+
+#[tokio::test]
+pub async fn arenas_reconnect_through_the_server_after_a_restart() {
+    let db = create_test_db().await;
+    let playing = crate::database::generate_new_id(&db).await;
+    simple_save_game_update(&db, default_game_update(), playing).await;
+    let secret = minted_key(&db).await;
+    let key = crate::auth::verify_api_key(&db, &secret).await.unwrap();
+    crate::database::save_game_key(&db, playing, key.key_id).await;
+
+    let (queue, _receiver) = crate::queue::with_capacity(16);
+    let connections = server::Connections::resume(db.clone(), queue).await;
+    let mut arena = warp::test::ws()
+        .path("/ws")
+        .handshake(server::routes(connections))
+        .await
+        .expect("expected the arena to connect");
+
+    let reconnect = ArenaRequest::Reconnect {
+        id: playing.to_string(),
+    };
+    let message = exchange(&mut arena, &reconnect).await;
+    assert!(matches!(
+        message,
+        GlobalServerResponse::Reconnected(Reconnected::Failure { .. })
+    ));
+
+    exchange(&mut arena, &ArenaRequest::Authenticate { secret }).await;
+    let message = exchange(&mut arena, &reconnect).await;
+    assert!(matches!(
+        message,
+        GlobalServerResponse::Reconnected(Reconnected::Success)
+    ));
+}
+
+#[tokio::test]
+pub async fn arenas_only_reconnect_to_the_games_of_their_key() {
+    let db = create_test_db().await;
+    let (queue, receiver) = crate::queue::with_capacity(64);
+    tokio::spawn(crate::queue::queue_processer(db.clone(), receiver, None));
+    let connections = server::Connections::new(db.clone(), queue);
+    let connect = || async {
+        warp::test::ws()
+            .path("/ws")
+            .handshake(server::routes(connections.clone()))
+            .await
+            .expect("expected the arena to connect")
+    };
+    let (mine, theirs) = (minted_key(&db).await, minted_key(&db).await);
+
+    let mut arena = connect().await;
+    let secret = mine.clone();
+    exchange(&mut arena, &ArenaRequest::Authenticate { secret }).await;
+    let info = default_game_update().info;
+    let id = match exchange(&mut arena, &ArenaRequest::InitializeGame { info }).await {
+        GlobalServerResponse::Initialized(Initialized::Success { id, .. }) => id,
+        other => panic!("expected the game to be initialized, got {:?}", other),
+    };
+    let reconnect = ArenaRequest::Reconnect { id: id.clone() };
+
+    let mut other = connect().await;
+    exchange(&mut other, &ArenaRequest::Authenticate { secret: theirs }).await;
+    match exchange(&mut other, &reconnect).await {
+        GlobalServerResponse::Reconnected(Reconnected::Failure { reason }) => {
+            assert_eq!(reason, "not your game")
+        }
+        other => panic!("expected the reconnect to be refused, got {:?}", other),
+    }
+
+    let mut again = connect().await;
+    exchange(&mut again, &ArenaRequest::Authenticate { secret: mine }).await;
+    assert!(matches!(
+        exchange(&mut again, &reconnect).await,
+        GlobalServerResponse::Reconnected(Reconnected::Success)
+    ));
+
+    // The key is saved with the game, so it is checked after a restart too
+    let game = Uuid::parse_str(&id).unwrap();
+    crate::queue::get_slug(game, &connections.queue)
+        .await
+        .unwrap();
+    let games = Arc::new(Mutex::new(resumable_games(&db).await));
+    let mut state = ArenaState::default();
+    let secret = minted_key(&db).await;
+    handle_authenticate_key(&secret, &mut state, &db)
+        .await
+        .unwrap();
+    assert!(handle_reconnect(&id, &mut state, games).is_err());
+}
+
+#[tokio::test]
+pub async fn spectators_are_sent_the_updates_the_server_accepts() {
+    let db = create_test_db().await;
+    let (queue, receiver) = crate::queue::with_capacity(64);
+    tokio::spawn(crate::queue::queue_processer(db.clone(), receiver, None));
+    let routes = server::routes(server::Connections::new(db.clone(), queue.clone()));
+    let mut arena = warp::test::ws()
+        .path("/ws")
+        .handshake(routes.clone())
+        .await
+        .expect("expected the arena to connect");
+    let secret = minted_key(&db).await;
+    exchange(&mut arena, &ArenaRequest::Authenticate { secret }).await;
+    let info = default_game_update().info;
+    let (id, url) = match exchange(&mut arena, &ArenaRequest::InitializeGame { info }).await {
+        GlobalServerResponse::Initialized(Initialized::Success { id, url }) => {
+            (Uuid::parse_str(&id).unwrap(), url)
+        }
+        other => panic!("expected the game to be initialized, got {:?}", other),
+    };
+    let slug = url.rsplit('/').next().unwrap().to_string();
+    let turn = |update_num| GameUpdate {
+        update_num,
+        ..default_game_update()
+    };
+    exchange(&mut arena, &ArenaRequest::GameUpdates(vec![turn(0)])).await;
+    // Answered once the first turn is saved, so the spectator starts from it
+    crate::queue::get_slug(id, &queue).await.unwrap();
+
+    let mut spectator = warp::test::ws()
+        .path(&format!("/api/games/{}/live", slug))
+        .handshake(routes)
+        .await
+        .expect("expected the spectator to connect");
+    let turn_of = |message: warp::ws::Message| {
+        let body: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        body["success"]["game_update"]["turnNumber"]
+            .as_u64()
+            .unwrap()
+    };
+    assert_eq!(turn_of(spectator.recv().await.unwrap()), 0);
+
+    exchange(&mut arena, &ArenaRequest::GameUpdates(vec![turn(1)])).await;
+    assert_eq!(turn_of(spectator.recv().await.unwrap()), 1);
+
+    // The game is over, so the spectator is disconnected
+    exchange(&mut arena, &ArenaRequest::GameOver { total_updates: 2 }).await;
+    spectator
+        .recv_closed()
+        .await
+        .expect("expected the spectator to be disconnected");
+}
+
+#[tokio::test]
+pub async fn spectators_start_from_unsaved_turns_and_leave_with_the_arena() {
+    let db = create_test_db().await;
+    let (queue, receiver) = crate::queue::with_capacity(64);
+    tokio::spawn(crate::queue::queue_processer(db.clone(), receiver, None));
+    let connections = server::Connections::new(db.clone(), queue);
+    let routes = server::routes(connections.clone());
+    let mut arena = warp::test::ws()
+        .path("/ws")
+        .handshake(routes.clone())
+        .await
+        .expect("expected the arena to connect");
+    let secret = minted_key(&db).await;
+    exchange(&mut arena, &ArenaRequest::Authenticate { secret }).await;
+    let info = default_game_update().info;
+    let (id, url) = match exchange(&mut arena, &ArenaRequest::InitializeGame { info }).await {
+        GlobalServerResponse::Initialized(Initialized::Success { id, url }) => {
+            (Uuid::parse_str(&id).unwrap(), url)
+        }
+        other => panic!("expected the game to be initialized, got {:?}", other),
+    };
+    let slug = url.rsplit('/').next().unwrap().to_string();
+
+    // Queued but not saved yet, as if the queue was behind
+    let update = GameUpdate {
+        update_num: 3,
+        ..default_game_update()
+    };
+    spectator::publish(id, &[update], &connections.spectators);
+    let mut spectator = warp::test::ws()
+        .path(&format!("/api/games/{}/live", slug))
+        .handshake(routes)
+        .await
+        .expect("expected the spectator to connect");
+    let message = spectator.recv().await.unwrap();
+    let body: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+    assert_eq!(body["success"]["game_update"]["turnNumber"], 3);
+
+    // The arena leaves without declaring game over
+    drop(arena);
+    spectator
+        .recv_closed()
+        .await
+        .expect("expected the spectator to be disconnected");
+    assert!(connections.spectators.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+pub async fn arena_games_and_players_belong_to_the_account_of_its_api_key() {
+    let db = create_test_db().await;
+    let password_hash = crate::auth::hash_password("correct horse").await;
+    let user_id = crate::database::save_user(&db, "owner", &password_hash)
+        .await
+        .expect("could not create user");
+    let key = crate::auth::generate_api_key();
+    let hash = crate::auth::hash_api_key(&key);
+    crate::database::save_api_key(&db, &hash, "owner", Some(user_id), "").await;
+
+    let (queue, receiver) = crate::queue::with_capacity(64);
+    tokio::spawn(crate::queue::queue_processer(db.clone(), receiver, None));
+    let routes = server::routes(server::Connections::new(db.clone(), queue.clone()));
+    let mut arena = warp::test::ws()
+        .path("/ws")
+        .handshake(routes)
+        .await
+        .expect("expected the arena to connect");
+    let message = exchange(&mut arena, &ArenaRequest::Authenticate { secret: key }).await;
+    assert!(matches!(
+        message,
+        GlobalServerResponse::Authenticated(Authenticated::Success)
+    ));
+    let info = default_game_update().info;
+    let id = match exchange(&mut arena, &ArenaRequest::InitializeGame { info }).await {
+        GlobalServerResponse::Initialized(Initialized::Success { id, .. }) => {
+            Uuid::parse_str(&id).unwrap()
+        }
+        other => panic!("expected the game to be initialized, got {:?}", other),
+    };
+
+    let seat = |name: &str| api::SeatRegistration {
+        name: name.to_string(),
+        version: None,
+        owner: None,
+    };
+    let request = ExtendedArenaRequest::RegisterPlayers(vec![seat("a"), seat("b")]);
+    arena
+        .send_text(serde_json::to_string(&request).unwrap())
+        .await;
+    let message = arena.recv().await.unwrap();
+    let message: GlobalServerResponse = serde_json::from_str(message.to_str().unwrap()).unwrap();
+    assert!(matches!(message, GlobalServerResponse::Info(_)));
+
+    // Answered once the players are saved
+    crate::queue::get_slug(id, &queue).await.unwrap();
+    let owners: Vec<Option<i64>> =
+        sqlx::query_scalar("SELECT owner_id FROM bots WHERE name IN ('a', 'b')")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+    assert_eq!(owners, vec![Some(user_id), Some(user_id)]);
+
+    let filter = crate::database::GameFilter {
+        owner_id: Some(user_id),
+        limit: 10,
+        ..Default::default()
+    };
+    let games = crate::database::list_games(&db, &filter).await;
+    assert_eq!(games.len(), 1, "expected the game to be attributed");
+    assert_eq!(games[0].uuid, id.to_string());
+}
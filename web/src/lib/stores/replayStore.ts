@@ -34,6 +34,10 @@ export type PlayerNewDesc = {
   developments : Costs,
   totalPoints : number,
   numReservedCards : number,
+  // Set once the arena has registered the bot playing in this seat
+  name : string | null,
+  version : string | null,
+  owner : string | null,
 }
 
 export type NobleNewDesc = {
@@ -183,7 +187,10 @@ export function updateGamePlayers(update: GameBackendDesc | undefined, turn : nu
       let totalPoints = player.totalPoints;
       let numReservedCards = player.numReservedCards;
 
-      let playerDesc = {name : "Player " + id, 
+      let name = player.name ?? "Player " + id;
+      if (player.version) { name += " " + player.version; }
+
+      let playerDesc = {name : name, 
                         developments : developments, 
                         gems : gems, 
                         totalPoints : totalPoints, 